@@ -1,6 +1,6 @@
 #![allow(clippy::unusual_byte_groupings)]
 
-use criterion::{Criterion, criterion_group, criterion_main};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
 use riscv::RV32ISystem;
 
 fn criterion_benchmark(c: &mut Criterion) {
@@ -21,6 +21,37 @@ fn criterion_benchmark(c: &mut Criterion) {
             }
         })
     });
+
+    c.bench_function("mixed decode", |b| {
+        let program = [
+            0b000000000001_00001_000_00011_0010011,    // ADDI x3, x1, 1
+            0b0000000_00010_00001_000_00100_0110011,   // ADD x4, x1, x2
+            0b000000000000_00001_010_00101_0000011,    // LW x5, 0(x1)
+            0b0000000_00010_00001_010_00100_0100011,   // SW x2, 4(x1)
+            0b00010010001101000101_00110_0110111,      // LUI x6, 0x12345
+            0b00000000000000000000_00111_0010111,      // AUIPC x7, 0
+            0b0_000000_00000_00000_001_0100_0_1100011, // BNE x0, x0, 8 (not taken)
+        ]
+        .repeat(16);
+
+        // setting up a system allocates all of RAM, which would swamp the cycles being measured
+        b.iter_batched(
+            || {
+                let mut rv = RV32ISystem::new();
+                rv.reg_file[1] = 0x2000_0000;
+                rv.reg_file[2] = 0x0203_0405;
+                rv.bus.rom.load(program.clone());
+                rv
+            },
+            |mut rv| {
+                for _ in 0..(program.len() * 5) {
+                    rv.cycle();
+                }
+                rv
+            },
+            BatchSize::LargeInput,
+        )
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,672 @@
+//! A small line based RV32I assembler, mostly for writing test programs.
+//!
+//! Each line holds at most one instruction, optionally preceded by a `label:`. Comments start with
+//! `#` or `//`. Registers can be given as `x0..x31` or by their ABI names, and branches and jumps
+//! take either a label or a byte offset. The common pseudo-instructions (`nop`, `li`, `mv`, `j`,
+//! `ret`, `beqz`, `csrr`, ...) are supported.
+
+use std::collections::HashMap;
+
+use crate::disasm::CSR_NAMES;
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum AsmError {
+    UnknownInstruction(usize, String),
+    InvalidOperand(usize, String),
+    WrongOperandCount(usize, String),
+    ImmediateOutOfRange(usize, i64),
+    UnknownLabel(usize, String),
+    DuplicateLabel(usize, String),
+}
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmError::UnknownInstruction(line, mnemonic) => {
+                write!(f, "Line {}: unknown instruction '{}'", line, mnemonic)
+            }
+            AsmError::InvalidOperand(line, operand) => {
+                write!(f, "Line {}: invalid operand '{}'", line, operand)
+            }
+            AsmError::WrongOperandCount(line, mnemonic) => {
+                write!(
+                    f,
+                    "Line {}: wrong number of operands for '{}'",
+                    line, mnemonic
+                )
+            }
+            AsmError::ImmediateOutOfRange(line, value) => {
+                write!(f, "Line {}: immediate {} is out of range", line, value)
+            }
+            AsmError::UnknownLabel(line, label) => {
+                write!(f, "Line {}: unknown label '{}'", line, label)
+            }
+            AsmError::DuplicateLabel(line, label) => {
+                write!(f, "Line {}: label '{}' is already defined", line, label)
+            }
+        }
+    }
+}
+
+type AsmResult<T> = std::result::Result<T, AsmError>;
+
+/// Register names in the standard calling convention, shared with `RV32ISystem::registers_map`
+pub(crate) const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_OP_IMM: u32 = 0b001_0011;
+const OPCODE_AUIPC: u32 = 0b001_0111;
+const OPCODE_STORE: u32 = 0b010_0011;
+const OPCODE_OP: u32 = 0b011_0011;
+const OPCODE_LUI: u32 = 0b011_0111;
+const OPCODE_BRANCH: u32 = 0b110_0011;
+const OPCODE_JALR: u32 = 0b110_0111;
+const OPCODE_JAL: u32 = 0b110_1111;
+const OPCODE_SYSTEM: u32 = 0b111_0011;
+
+fn encode_r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn encode_i(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (((imm as u32) & 0xFFF) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn encode_s(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 5) & 0x7F) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1F) << 7)
+        | opcode
+}
+
+fn encode_b(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 12) & 1) << 31)
+        | (((imm >> 5) & 0x3F) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xF) << 8)
+        | (((imm >> 11) & 1) << 7)
+        | OPCODE_BRANCH
+}
+
+fn encode_u(imm20: u32, rd: u32, opcode: u32) -> u32 {
+    (imm20 << 12) | (rd << 7) | opcode
+}
+
+fn encode_j(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 20) & 1) << 31)
+        | (((imm >> 1) & 0x3FF) << 21)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xFF) << 12)
+        | (rd << 7)
+        | OPCODE_JAL
+}
+
+/// Splits a value for `li` into the `lui` and `addi` immediates, the `addi` being sign extended
+fn split_immediate(value: i32) -> (u32, i32) {
+    let lower = (value << 20) >> 20;
+    let upper = ((value.wrapping_sub(lower) as u32) >> 12) & 0xF_FFFF;
+    (upper, lower)
+}
+
+struct Line<'a> {
+    number: usize,
+    address: u32,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+impl Line<'_> {
+    fn expect_operands(&self, count: usize) -> AsmResult<()> {
+        match self.operands.len() == count {
+            true => Ok(()),
+            false => Err(AsmError::WrongOperandCount(
+                self.number,
+                self.mnemonic.to_string(),
+            )),
+        }
+    }
+
+    fn reg(&self, index: usize) -> AsmResult<u32> {
+        let operand = self.operands[index];
+        let number = match operand.strip_prefix('x') {
+            Some(number) => number.parse::<u32>().ok().filter(|n| *n < 32),
+            None if operand == "fp" => Some(8),
+            None => ABI_NAMES
+                .iter()
+                .position(|name| *name == operand)
+                .map(|n| n as u32),
+        };
+        number.ok_or_else(|| AsmError::InvalidOperand(self.number, operand.to_string()))
+    }
+
+    fn parse_number(&self, operand: &str) -> AsmResult<i64> {
+        let (negative, digits) = match operand.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, operand),
+        };
+        let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16)
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2)
+        } else {
+            digits.parse::<i64>()
+        };
+        parsed
+            .map(|value| if negative { -value } else { value })
+            .map_err(|_| AsmError::InvalidOperand(self.number, operand.to_string()))
+    }
+
+    fn imm(&self, index: usize, min: i64, max: i64) -> AsmResult<i64> {
+        let value = self.parse_number(self.operands[index])?;
+        match (min..=max).contains(&value) {
+            true => Ok(value),
+            false => Err(AsmError::ImmediateOutOfRange(self.number, value)),
+        }
+    }
+
+    fn imm12(&self, index: usize) -> AsmResult<i32> {
+        self.imm(index, -2048, 2047).map(|v| v as i32)
+    }
+
+    /// An `offset(register)` operand, as used by loads, stores and `jalr`
+    fn offset_reg(&self, index: usize) -> AsmResult<(i32, u32)> {
+        let operand = self.operands[index];
+        let invalid = || AsmError::InvalidOperand(self.number, operand.to_string());
+        let (offset, rest) = operand.split_once('(').ok_or_else(invalid)?;
+        let register = rest.strip_suffix(')').ok_or_else(invalid)?;
+        let offset = match offset.is_empty() {
+            true => 0,
+            false => self.parse_number(offset)?,
+        };
+        if !(-2048..=2047).contains(&offset) {
+            return Err(AsmError::ImmediateOutOfRange(self.number, offset));
+        }
+        let line = Line {
+            operands: vec![register],
+            ..*self
+        };
+        Ok((offset as i32, line.reg(0)?))
+    }
+
+    /// A branch or jump target, either a label or a numeric byte offset
+    fn target(&self, index: usize, labels: &HashMap<&str, u32>, range: i64) -> AsmResult<i32> {
+        let operand = self.operands[index];
+        let offset = match labels.get(operand) {
+            Some(address) => *address as i64 - self.address as i64,
+            None if operand.starts_with(|c: char| c == '-' || c.is_ascii_digit()) => {
+                self.parse_number(operand)?
+            }
+            None => return Err(AsmError::UnknownLabel(self.number, operand.to_string())),
+        };
+        match (-range..range).contains(&offset) && offset & 1 == 0 {
+            true => Ok(offset as i32),
+            false => Err(AsmError::ImmediateOutOfRange(self.number, offset)),
+        }
+    }
+
+    fn csr(&self, index: usize) -> AsmResult<u32> {
+        let operand = self.operands[index];
+        match CSR_NAMES.iter().find(|(_, name)| *name == operand) {
+            Some((address, _)) => Ok(*address),
+            None => self.imm(index, 0, 0xFFF).map(|v| v as u32),
+        }
+    }
+
+    /// Number of words this line assembles to
+    fn size(&self) -> u32 {
+        match self.mnemonic {
+            "li" => match self
+                .operands
+                .get(1)
+                .and_then(|operand| self.parse_number(operand).ok())
+            {
+                Some(value) if !(-2048..=2047).contains(&value) => 2,
+                _ => 1,
+            },
+            _ => 1,
+        }
+    }
+
+    fn assemble(&self, labels: &HashMap<&str, u32>) -> AsmResult<Vec<u32>> {
+        let op_imm = |funct3: u32| -> AsmResult<Vec<u32>> {
+            self.expect_operands(3)?;
+            Ok(vec![encode_i(
+                self.imm12(2)?,
+                self.reg(1)?,
+                funct3,
+                self.reg(0)?,
+                OPCODE_OP_IMM,
+            )])
+        };
+        let shift_imm = |funct7: u32, funct3: u32| -> AsmResult<Vec<u32>> {
+            self.expect_operands(3)?;
+            let shamt = self.imm(2, 0, 31)? as u32;
+            Ok(vec![encode_r(
+                funct7,
+                shamt,
+                self.reg(1)?,
+                funct3,
+                self.reg(0)?,
+                OPCODE_OP_IMM,
+            )])
+        };
+        let op = |funct7: u32, funct3: u32| -> AsmResult<Vec<u32>> {
+            self.expect_operands(3)?;
+            Ok(vec![encode_r(
+                funct7,
+                self.reg(2)?,
+                self.reg(1)?,
+                funct3,
+                self.reg(0)?,
+                OPCODE_OP,
+            )])
+        };
+        let load = |funct3: u32| -> AsmResult<Vec<u32>> {
+            self.expect_operands(2)?;
+            let (offset, rs1) = self.offset_reg(1)?;
+            Ok(vec![encode_i(
+                offset,
+                rs1,
+                funct3,
+                self.reg(0)?,
+                OPCODE_LOAD,
+            )])
+        };
+        let store = |funct3: u32| -> AsmResult<Vec<u32>> {
+            self.expect_operands(2)?;
+            let (offset, rs1) = self.offset_reg(1)?;
+            Ok(vec![encode_s(
+                offset,
+                self.reg(0)?,
+                rs1,
+                funct3,
+                OPCODE_STORE,
+            )])
+        };
+        let branch = |funct3: u32, swap: bool| -> AsmResult<Vec<u32>> {
+            self.expect_operands(3)?;
+            let (rs1, rs2) = match swap {
+                false => (self.reg(0)?, self.reg(1)?),
+                true => (self.reg(1)?, self.reg(0)?),
+            };
+            Ok(vec![encode_b(
+                self.target(2, labels, 1 << 12)?,
+                rs2,
+                rs1,
+                funct3,
+            )])
+        };
+        let branch_zero = |funct3: u32, swap: bool| -> AsmResult<Vec<u32>> {
+            self.expect_operands(2)?;
+            let (rs1, rs2) = match swap {
+                false => (self.reg(0)?, 0),
+                true => (0, self.reg(0)?),
+            };
+            Ok(vec![encode_b(
+                self.target(1, labels, 1 << 12)?,
+                rs2,
+                rs1,
+                funct3,
+            )])
+        };
+        let csr = |funct3: u32| -> AsmResult<Vec<u32>> {
+            self.expect_operands(3)?;
+            let source = match funct3 & 0b100 {
+                0b100 => self.imm(2, 0, 31)? as u32,
+                _ => self.reg(2)?,
+            };
+            Ok(vec![encode_i(
+                self.csr(1)? as i32,
+                source,
+                funct3,
+                self.reg(0)?,
+                OPCODE_SYSTEM,
+            )])
+        };
+        let no_operands = |word: u32| -> AsmResult<Vec<u32>> {
+            self.expect_operands(0)?;
+            Ok(vec![word])
+        };
+
+        match self.mnemonic {
+            "addi" => op_imm(0b000),
+            "slti" => op_imm(0b010),
+            "sltiu" => op_imm(0b011),
+            "xori" => op_imm(0b100),
+            "ori" => op_imm(0b110),
+            "andi" => op_imm(0b111),
+            "slli" => shift_imm(0b000_0000, 0b001),
+            "srli" => shift_imm(0b000_0000, 0b101),
+            "srai" => shift_imm(0b010_0000, 0b101),
+            "add" => op(0b000_0000, 0b000),
+            "sub" => op(0b010_0000, 0b000),
+            "sll" => op(0b000_0000, 0b001),
+            "slt" => op(0b000_0000, 0b010),
+            "sltu" => op(0b000_0000, 0b011),
+            "xor" => op(0b000_0000, 0b100),
+            "srl" => op(0b000_0000, 0b101),
+            "sra" => op(0b010_0000, 0b101),
+            "or" => op(0b000_0000, 0b110),
+            "and" => op(0b000_0000, 0b111),
+            "lb" => load(0b000),
+            "lh" => load(0b001),
+            "lw" => load(0b010),
+            "lbu" => load(0b100),
+            "lhu" => load(0b101),
+            "sb" => store(0b000),
+            "sh" => store(0b001),
+            "sw" => store(0b010),
+            "lui" | "auipc" => {
+                self.expect_operands(2)?;
+                let opcode = match self.mnemonic {
+                    "lui" => OPCODE_LUI,
+                    _ => OPCODE_AUIPC,
+                };
+                Ok(vec![encode_u(
+                    self.imm(1, 0, 0xF_FFFF)? as u32,
+                    self.reg(0)?,
+                    opcode,
+                )])
+            }
+            "jal" => match self.operands.len() {
+                1 => Ok(vec![encode_j(self.target(0, labels, 1 << 20)?, 1)]),
+                _ => {
+                    self.expect_operands(2)?;
+                    Ok(vec![encode_j(
+                        self.target(1, labels, 1 << 20)?,
+                        self.reg(0)?,
+                    )])
+                }
+            },
+            "jalr" => match self.operands.len() {
+                1 => Ok(vec![encode_i(0, self.reg(0)?, 0, 1, OPCODE_JALR)]),
+                _ => {
+                    self.expect_operands(2)?;
+                    let (offset, rs1) = self.offset_reg(1)?;
+                    Ok(vec![encode_i(offset, rs1, 0, self.reg(0)?, OPCODE_JALR)])
+                }
+            },
+            "beq" => branch(0b000, false),
+            "bne" => branch(0b001, false),
+            "blt" => branch(0b100, false),
+            "bge" => branch(0b101, false),
+            "bltu" => branch(0b110, false),
+            "bgeu" => branch(0b111, false),
+            "bgt" => branch(0b100, true),
+            "ble" => branch(0b101, true),
+            "bgtu" => branch(0b110, true),
+            "bleu" => branch(0b111, true),
+            "beqz" => branch_zero(0b000, false),
+            "bnez" => branch_zero(0b001, false),
+            "bltz" => branch_zero(0b100, false),
+            "bgez" => branch_zero(0b101, false),
+            "bgtz" => branch_zero(0b100, true),
+            "blez" => branch_zero(0b101, true),
+            "csrrw" => csr(0b001),
+            "csrrs" => csr(0b010),
+            "csrrc" => csr(0b011),
+            "csrrwi" => csr(0b101),
+            "csrrsi" => csr(0b110),
+            "csrrci" => csr(0b111),
+            "csrr" => {
+                self.expect_operands(2)?;
+                Ok(vec![encode_i(
+                    self.csr(1)? as i32,
+                    0,
+                    0b010,
+                    self.reg(0)?,
+                    OPCODE_SYSTEM,
+                )])
+            }
+            "csrw" => {
+                self.expect_operands(2)?;
+                Ok(vec![encode_i(
+                    self.csr(0)? as i32,
+                    self.reg(1)?,
+                    0b001,
+                    0,
+                    OPCODE_SYSTEM,
+                )])
+            }
+            "ecall" => no_operands(0x0000_0073),
+            "ebreak" => no_operands(0x0010_0073),
+            "mret" => no_operands(0x3020_0073),
+            "wfi" => no_operands(0x1050_0073),
+            "fence" => no_operands(0x0FF0_000F),
+            "fence.i" => no_operands(0x0000_100F),
+            "nop" => no_operands(encode_i(0, 0, 0b000, 0, OPCODE_OP_IMM)),
+            "ret" => no_operands(encode_i(0, 1, 0, 0, OPCODE_JALR)),
+            "mv" => {
+                self.expect_operands(2)?;
+                Ok(vec![encode_i(
+                    0,
+                    self.reg(1)?,
+                    0b000,
+                    self.reg(0)?,
+                    OPCODE_OP_IMM,
+                )])
+            }
+            "not" => {
+                self.expect_operands(2)?;
+                Ok(vec![encode_i(
+                    -1,
+                    self.reg(1)?,
+                    0b100,
+                    self.reg(0)?,
+                    OPCODE_OP_IMM,
+                )])
+            }
+            "neg" => {
+                self.expect_operands(2)?;
+                Ok(vec![encode_r(
+                    0b010_0000,
+                    self.reg(1)?,
+                    0,
+                    0b000,
+                    self.reg(0)?,
+                    OPCODE_OP,
+                )])
+            }
+            "j" => {
+                self.expect_operands(1)?;
+                Ok(vec![encode_j(self.target(0, labels, 1 << 20)?, 0)])
+            }
+            "jr" => {
+                self.expect_operands(1)?;
+                Ok(vec![encode_i(0, self.reg(0)?, 0, 0, OPCODE_JALR)])
+            }
+            "li" => {
+                self.expect_operands(2)?;
+                let rd = self.reg(0)?;
+                let value = self.imm(1, i32::MIN as i64, u32::MAX as i64)? as i32;
+                match self.size() {
+                    1 => Ok(vec![encode_i(value, 0, 0b000, rd, OPCODE_OP_IMM)]),
+                    _ => {
+                        let (upper, lower) = split_immediate(value);
+                        Ok(vec![
+                            encode_u(upper, rd, OPCODE_LUI),
+                            encode_i(lower, rd, 0b000, rd, OPCODE_OP_IMM),
+                        ])
+                    }
+                }
+            }
+            _ => Err(AsmError::UnknownInstruction(
+                self.number,
+                self.mnemonic.to_string(),
+            )),
+        }
+    }
+}
+
+/// Assembles `src` into instruction words, with the first instruction at offset 0
+pub fn assemble(src: &str) -> Result<Vec<u32>, AsmError> {
+    let mut labels: HashMap<&str, u32> = HashMap::new();
+    let mut lines = vec![];
+    let mut address = 0;
+
+    for (index, text) in src.lines().enumerate() {
+        let number = index + 1;
+        let mut text = text;
+        if let Some(position) = text.find('#') {
+            text = &text[..position];
+        }
+        if let Some(position) = text.find("//") {
+            text = &text[..position];
+        }
+        let mut text = text.trim();
+
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || label.contains(char::is_whitespace) {
+                return Err(AsmError::InvalidOperand(number, label.to_string()));
+            }
+            if labels.insert(label, address).is_some() {
+                return Err(AsmError::DuplicateLabel(number, label.to_string()));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (
+                mnemonic,
+                operands.split(',').map(str::trim).collect::<Vec<_>>(),
+            ),
+            None => (text, vec![]),
+        };
+        let line = Line {
+            number,
+            address,
+            mnemonic,
+            operands,
+        };
+        address += line.size() * 4;
+        lines.push(line);
+    }
+
+    let mut words = vec![];
+    for line in lines {
+        words.extend(line.assemble(&labels)?);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    #[test]
+    fn test_assemble_matches_disassembly() {
+        let src = "
+            start:
+                addi x2, x2, -4
+                lui sp, 0x20400
+                jalr x0, 0(ra)
+                sw x14, 0(x15)
+                lw x14, -20(s0)
+                add x14, x13, x14
+                sub x3, x1, x2
+                slli x15, x15, 2
+                srai x15, x15, 2
+                auipc a0, 0
+                bne x1, x2, start  # backwards branch
+                csrr x1, cycle
+                csrrwi x0, mtvec, 1
+                ecall
+                ebreak
+                mret
+                fence
+        ";
+        let expected = [
+            "addi x2,x2,-4",
+            "lui x2,0x20400",
+            "jalr x0,0(x1)",
+            "sw x14,0(x15)",
+            "lw x14,-20(x8)",
+            "add x14,x13,x14",
+            "sub x3,x1,x2",
+            "slli x15,x15,0x2",
+            "srai x15,x15,0x2",
+            "auipc x10,0x0",
+            "bne x1,x2,0",
+            "csrrs x1,cycle,x0",
+            "csrrwi x0,mtvec,1",
+            "ecall",
+            "ebreak",
+            "mret",
+            "fence iorw,iorw",
+        ];
+        let words = assemble(src).unwrap();
+        assert_eq!(words.len(), expected.len());
+        for (i, (word, expected)) in words.iter().zip(expected).enumerate() {
+            assert_eq!(disassemble(i as u32 * 4, *word), expected);
+        }
+    }
+
+    #[test]
+    fn test_assemble_pseudo_instructions() {
+        let src = "
+            nop
+            li a0, 42
+            li a1, 0x12345FFF
+            mv a2, a0
+            j end
+            ret
+            end: beqz a0, end
+        ";
+        assert_eq!(
+            assemble(src),
+            Ok(vec![
+                0x0000_0013,
+                0x02A0_0513,
+                0x1234_65B7,
+                0xFFF5_8593,
+                0x0005_0613,
+                0x0080_006F,
+                0x0000_8067,
+                0x0005_0063,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble("nop\nfoo x1, x2"),
+            Err(AsmError::UnknownInstruction(2, "foo".to_string()))
+        );
+        assert_eq!(
+            assemble("addi x1, x32, 1"),
+            Err(AsmError::InvalidOperand(1, "x32".to_string()))
+        );
+        assert_eq!(
+            assemble("addi x1, x1, 2048"),
+            Err(AsmError::ImmediateOutOfRange(1, 2048))
+        );
+        assert_eq!(
+            assemble("addi x1, x1"),
+            Err(AsmError::WrongOperandCount(1, "addi".to_string()))
+        );
+        assert_eq!(
+            assemble("j nowhere"),
+            Err(AsmError::UnknownLabel(1, "nowhere".to_string()))
+        );
+        assert_eq!(
+            assemble("a: nop\na: nop"),
+            Err(AsmError::DuplicateLabel(2, "a".to_string()))
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Which instructions a run actually exercised, to find encodings the tests never reach
+
+use std::collections::BTreeMap;
+
+use crate::{RV32ISystem, disasm::disassemble, pipeline::decode::DecodedInstruction};
+
+/// The RV32I base instructions, by mnemonic as `disassemble` prints them
+pub const RV32I_MNEMONICS: [&str; 40] = [
+    "lui", "auipc", "jal", "jalr", "beq", "bne", "blt", "bge", "bltu", "bgeu", "lb", "lh", "lw",
+    "lbu", "lhu", "sb", "sh", "sw", "addi", "slti", "sltiu", "xori", "ori", "andi", "slli", "srli",
+    "srai", "add", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and", "fence", "ecall",
+    "ebreak",
+];
+
+/// Name of the `DecodedInstruction` variant, the class of instruction
+fn kind(instruction: DecodedInstruction) -> &'static str {
+    match instruction {
+        DecodedInstruction::None => "None",
+        DecodedInstruction::Alu { .. } => "Alu",
+        DecodedInstruction::Store { .. } => "Store",
+        DecodedInstruction::Load { .. } => "Load",
+        DecodedInstruction::Lui { .. } => "Lui",
+        DecodedInstruction::Jal { .. } => "Jal",
+        DecodedInstruction::Jalr { .. } => "Jalr",
+        DecodedInstruction::Branch { .. } => "Branch",
+        DecodedInstruction::System { .. } => "System",
+        DecodedInstruction::Auipc { .. } => "Auipc",
+        DecodedInstruction::Fence { .. } => "Fence",
+        DecodedInstruction::FenceI { .. } => "FenceI",
+        DecodedInstruction::Mret { .. } => "Mret",
+        DecodedInstruction::Wfi { .. } => "Wfi",
+        DecodedInstruction::Custom { .. } => "Custom",
+    }
+}
+
+/// Retired instruction counts, by instruction class and mnemonic. The mnemonic stands in for the
+/// opcode/funct3/funct7 combination.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Coverage {
+    retired: BTreeMap<(&'static str, String), u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&mut self, instruction: DecodedInstruction, raw_instruction: u32) {
+        let text = disassemble(0, raw_instruction);
+        let mnemonic = text.split(' ').next().unwrap_or_default().to_string();
+        *self
+            .retired
+            .entry((kind(instruction), mnemonic))
+            .or_default() += 1;
+    }
+
+    /// Adds the counts from `other`, to combine the coverage of several runs
+    pub fn merge(&mut self, other: &Coverage) {
+        for (key, count) in &other.retired {
+            *self.retired.entry(key.clone()).or_default() += count;
+        }
+    }
+
+    /// How many instructions with `mnemonic` retired
+    pub fn count(&self, mnemonic: &str) -> u64 {
+        self.retired
+            .iter()
+            .filter(|((_, retired), _)| retired == mnemonic)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// How many instructions of the class `kind` (a `DecodedInstruction` variant name such as
+    /// `"Load"`) retired
+    pub fn count_kind(&self, kind: &str) -> u64 {
+        self.retired
+            .iter()
+            .filter(|((retired, _), _)| *retired == kind)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Base instructions that never retired, in the order of `RV32I_MNEMONICS`
+    pub fn missing_base_instructions(&self) -> Vec<&'static str> {
+        RV32I_MNEMONICS
+            .iter()
+            .copied()
+            .filter(|mnemonic| self.count(mnemonic) == 0)
+            .collect()
+    }
+
+    /// One `class mnemonic count` line per instruction that retired, followed by a line listing
+    /// the base instructions that never did
+    pub fn report(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .retired
+            .iter()
+            .map(|((kind, mnemonic), count)| format!("{:<8} {:<8} {}", kind, mnemonic, count))
+            .collect();
+        let missing = self.missing_base_instructions();
+        if !missing.is_empty() {
+            lines.push(format!("never retired: {}", missing.join(" ")));
+        }
+        lines
+    }
+}
+
+impl RV32ISystem {
+    /// Starts recording every instruction that retires, see `coverage`
+    pub fn enable_coverage(&mut self) {
+        self.coverage.get_or_insert_with(Coverage::new);
+    }
+
+    /// The instructions retired since `enable_coverage`, `None` if it hasn't been called
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub(crate) fn record_coverage(
+        &mut self,
+        instruction: DecodedInstruction,
+        raw_instruction: u32,
+    ) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            if instruction != DecodedInstruction::None {
+                coverage.record(instruction, raw_instruction);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r0, 1
+            0b000000000001_00000_000_00001_0010011,
+            // ADDI r1, r1, 1
+            0b000000000001_00001_000_00001_0010011,
+            // SUB r2, r1, r1
+            0b0100000_00001_00001_000_00010_0110011,
+            // JAL r0, 0
+            0b0_0000000000_0_00000000_00000_1101111,
+        ]);
+        assert_eq!(rv.coverage(), None);
+        rv.enable_coverage();
+        for _ in 0..5 {
+            rv.step();
+        }
+
+        let coverage = rv.coverage().unwrap();
+        assert_eq!(coverage.count("addi"), 2);
+        assert_eq!(coverage.count("sub"), 1);
+        assert_eq!(coverage.count("jal"), 2);
+        assert_eq!(coverage.count_kind("Alu"), 3);
+        assert_eq!(coverage.count_kind("Load"), 0);
+        assert!(!coverage.missing_base_instructions().contains(&"sub"));
+        assert!(coverage.missing_base_instructions().contains(&"add"));
+
+        let report = coverage.report();
+        assert_eq!(report[0], "Alu      addi     2");
+        assert_eq!(report[1], "Alu      sub      1");
+        assert_eq!(report[2], "Jal      jal      2");
+        assert!(report[3].starts_with("never retired: lui auipc jalr"));
+
+        let mut merged = Coverage::new();
+        merged.merge(coverage);
+        merged.merge(coverage);
+        assert_eq!(merged.count("addi"), 4);
+    }
+}
@@ -1,4 +1,9 @@
-use crate::utils::LatchValue;
+use crate::{
+    trap::{
+        MSTATUS_MIE_MASK, MTVEC_MODE_DIRECT, MTVEC_MODE_MASK, MTVEC_MODE_VECTORED, VectorLayout,
+    },
+    utils::LatchValue,
+};
 
 pub const CSR_OPERATION_RW: u8 = 0b001;
 pub const CSR_OPERATION_RS: u8 = 0b010;
@@ -13,6 +18,7 @@ pub const CSRM_MODE_MARCHID: u32 = 0xF12;
 pub const CSRM_MODE_MIMPID: u32 = 0xF13;
 pub const CSRM_MODE_MHARTID: u32 = 0xF14;
 pub const CSRM_MODE_MSTATUS: u32 = 0x300;
+pub const CSRM_MODE_MSTATUSH: u32 = 0x310;
 pub const CSRM_MODE_MTVEC: u32 = 0x305;
 pub const CSRM_MODE_MIE: u32 = 0x304;
 pub const CSRM_MODE_MIP: u32 = 0x344;
@@ -22,8 +28,57 @@ pub const CSRM_MODE_MSCRATCH: u32 = 0x340;
 pub const CSRM_MODE_MTVAL: u32 = 0x343;
 
 pub const MSTATUS_MASK: u32 = (1 << 3) | (1 << 7);
+/// `mip.MTIP`, which follows the machine timer rather than software
+pub const MIP_MTIP_MASK: u32 = 1 << 7;
+
+/// Why a CSR access was refused, guest accesses raise an illegal-instruction trap instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CSRError {
+    /// No CSR is implemented at the address
+    Unknown(u32),
+    /// Write to a CSR that can only be read
+    ReadOnly(u32),
+}
+
+impl std::fmt::Display for CSRError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            CSRError::Unknown(address) => write!(f, "Unknown CSR {:#05X}", address),
+            CSRError::ReadOnly(address) => {
+                write!(f, "Attempt to write read-only CSR {:#05X}", address)
+            }
+        }
+    }
+}
+
+/// The 64-bit counters readable through the user-level counter CSRs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    Cycle,
+    /// There is no separate real-time clock, `time` counts cycles
+    Time,
+    Instret,
+}
+
+/// Whether the CSR at `address` is read-only, which is encoded in its top two bits
+pub fn is_read_only(address: u32) -> bool {
+    (address >> 10) & 0b11 == 0b11
+}
+
+/// Interrupt indices (mcause without the interrupt bit) from highest to lowest priority
+const INTERRUPT_PRIORITY: [u32; 9] = [11, 3, 7, 9, 1, 5, 8, 0, 4];
+
+/// Reset value of `mtvec`: direct mode with the handler one word into ROM, leaving the reset
+/// vector at the start of ROM free for a jump over it
+pub const DEFAULT_MTVEC: u32 = 0x1000_0004;
+/// Reset value of `mie`: the machine software, timer and external interrupts are enabled, so only
+/// `mstatus.MIE` needs setting to take them
+pub const DEFAULT_MIE: u32 = 0x0000_0888;
+/// Reset value of `misa`: RV32 (MXL = 1) with the I base ISA and the M extension
+pub const DEFAULT_MISA: u32 = 0x4000_1100;
+/// The C (compressed instructions) extension bit of `misa`
+const MISA_C: u32 = 1 << 2;
 
-#[derive(Default)]
 pub struct CSRInterface {
     pub cycles: LatchValue<u64>,
     pub instret: LatchValue<u64>,
@@ -53,35 +108,144 @@ pub struct CSRInterface {
     mscratch: u32,
     /// Trap-value register, can hold the address of a faulting instruction
     pub mtval: u32,
-    // (Not a CSR) Memory-mapped 64-bit reg, with a writable value. When mtime == mtimecmp, a timer interrupt fires
-    mtimecmp: LatchValue<u64>,
+    vector_layout: VectorLayout,
 }
 
-impl CSRInterface {
+/// Sets up the initial values of a `CSRInterface`, anything not set keeps its `DEFAULT_*` value or
+/// 0 for the identity registers
+///
+/// ```ignore
+/// rv.csr = CSRInterfaceBuilder::new().mtvec(0x1000_0100).mie(0).build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct CSRInterfaceBuilder {
+    mtvec: u32,
+    mie: u32,
+    misa: u32,
+    mvendorid: u32,
+    marchid: u32,
+    mimpid: u32,
+    mhartid: u32,
+    vector_layout: VectorLayout,
+}
+
+impl CSRInterfaceBuilder {
     pub fn new() -> Self {
-        CSRInterface {
-            cycles: LatchValue::new(0),
-            instret: LatchValue::new(0),
-            misa: 0x4000_0100,
+        Self {
+            mtvec: DEFAULT_MTVEC,
+            mie: DEFAULT_MIE,
+            misa: DEFAULT_MISA,
             mvendorid: 0,
             marchid: 0,
             mimpid: 0,
             mhartid: 0,
+            vector_layout: VectorLayout::default(),
+        }
+    }
+
+    /// Base address and mode of the trap vector table
+    pub fn mtvec(mut self, mtvec: u32) -> Self {
+        self.mtvec = mtvec;
+        self
+    }
+
+    /// Which interrupts start out enabled
+    pub fn mie(mut self, mie: u32) -> Self {
+        self.mie = mie;
+        self
+    }
+
+    /// Width and extensions reported to software. This only changes what is reported, not what
+    /// is implemented.
+    pub fn misa(mut self, misa: u32) -> Self {
+        self.misa = misa;
+        self
+    }
+
+    /// JEDEC manufacturer ID, 0 for a non-commercial implementation
+    pub fn vendor_id(mut self, mvendorid: u32) -> Self {
+        self.mvendorid = mvendorid;
+        self
+    }
+
+    pub fn arch_id(mut self, marchid: u32) -> Self {
+        self.marchid = marchid;
+        self
+    }
+
+    pub fn impl_id(mut self, mimpid: u32) -> Self {
+        self.mimpid = mimpid;
+        self
+    }
+
+    pub fn hart_id(mut self, mhartid: u32) -> Self {
+        self.mhartid = mhartid;
+        self
+    }
+
+    /// How `mtvec` is interpreted when a trap is taken, the spec's direct and vectored modes by
+    /// default
+    pub fn vector_layout(mut self, layout: VectorLayout) -> Self {
+        self.vector_layout = layout;
+        self
+    }
+
+    pub fn build(self) -> CSRInterface {
+        CSRInterface {
+            cycles: LatchValue::new(0),
+            instret: LatchValue::new(0),
+            misa: self.misa,
+            mvendorid: self.mvendorid,
+            marchid: self.marchid,
+            mimpid: self.mimpid,
+            mhartid: self.mhartid,
             mstatus: 0,
-            mtvec: 0x1000_0004,
-            mie: 0x0000_0888,
+            mtvec: self.mtvec,
+            mie: self.mie,
             mip: 0,
             mcause: 0,
             mepc: 0,
             mscratch: 0,
             mtval: 0,
-            mtimecmp: LatchValue::new(0),
+            vector_layout: self.vector_layout,
         }
     }
+}
 
-    pub fn read(&self, address: u32) -> u32 {
-        match address {
-            // User level
+impl Default for CSRInterfaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CSRInterface {
+    /// A CSR interface with every register at its reset value, see `CSRInterfaceBuilder` to change
+    /// them
+    pub fn new() -> Self {
+        CSRInterfaceBuilder::new().build()
+    }
+
+    /// How `mtvec` is interpreted when a trap is taken
+    pub fn vector_layout(&self) -> VectorLayout {
+        self.vector_layout
+    }
+
+    /// `mepc` as read and as the target of MRET. The low bits can't hold an instruction
+    /// address, so they're masked: both of them with only 32-bit instructions (IALIGN = 32), just
+    /// bit 0 if `misa` claims the C extension.
+    pub fn return_address(&self) -> u32 {
+        match self.misa & MISA_C {
+            0 => self.mepc & !0b11,
+            _ => self.mepc & !0b01,
+        }
+    }
+
+    pub fn read(&self, address: u32) -> Result<u32, CSRError> {
+        Ok(match address {
+            // User level. Each counter is split into a low and high CSR, so a guest reading one
+            // half and then the other can see a torn value if the low half wraps in between, and
+            // has to use the `rdcycleh; rdcycle; rdcycleh` retry loop. The host can use
+            // `read_counter` instead.
             0xC00 => *self.cycles.get() as u32,
             0xC01 => *self.cycles.get() as u32,
             0xC02 => *self.instret.get() as u32,
@@ -95,45 +259,134 @@ impl CSRInterface {
             CSRM_MODE_MIMPID => self.mimpid,
             CSRM_MODE_MHARTID => self.mhartid,
             CSRM_MODE_MSTATUS => self.mstatus,
+            // Upper half of mstatus on RV32, only holds the endianness controls, which aren't
+            // supported
+            CSRM_MODE_MSTATUSH => 0,
             CSRM_MODE_MTVEC => self.mtvec,
             CSRM_MODE_MIE => self.mie,
             CSRM_MODE_MIP => self.mip,
             CSRM_MODE_MCAUSE => self.mcause,
-            CSRM_MODE_MEPC => self.mepc,
+            CSRM_MODE_MEPC => self.return_address(),
             CSRM_MODE_MSCRATCH => self.mscratch,
             CSRM_MODE_MTVAL => self.mtval,
-            _ => {
-                panic!("Unknown CSR: {:#08X}", address & 0b111)
-            }
+            _ => return Err(CSRError::Unknown(address)),
+        })
+    }
+
+    /// Full 64-bit value of `counter`
+    pub fn read_counter(&self, counter: Counter) -> u64 {
+        match counter {
+            Counter::Cycle | Counter::Time => *self.cycles.get(),
+            Counter::Instret => *self.instret.get(),
         }
     }
 
-    pub fn write(&mut self, address: u32, value: u32) {
-        let is_read_only = address >> 10;
+    /// `(address, value)` of the machine-mode CSRs that can change while running
+    pub fn machine_state(&self) -> Vec<(u32, u32)> {
+        [
+            CSRM_MODE_MSTATUS,
+            CSRM_MODE_MTVEC,
+            CSRM_MODE_MIE,
+            CSRM_MODE_MIP,
+            CSRM_MODE_MCAUSE,
+            CSRM_MODE_MEPC,
+            CSRM_MODE_MSCRATCH,
+            CSRM_MODE_MTVAL,
+        ]
+        .into_iter()
+        .map(|address| {
+            (
+                address,
+                self.read(address).expect("machine CSRs are implemented"),
+            )
+        })
+        .collect()
+    }
+
+    /// Puts back the values returned by `machine_state`, including those software can't write
+    pub fn restore_machine_state(&mut self, csrs: &[(u32, u32)]) {
+        for &(address, value) in csrs {
+            match address {
+                CSRM_MODE_MSTATUS => self.mstatus = value,
+                CSRM_MODE_MTVEC => self.mtvec = value,
+                CSRM_MODE_MIE => self.mie = value,
+                CSRM_MODE_MIP => self.mip = value,
+                CSRM_MODE_MCAUSE => self.mcause = value,
+                CSRM_MODE_MEPC => self.mepc = value,
+                CSRM_MODE_MSCRATCH => self.mscratch = value,
+                CSRM_MODE_MTVAL => self.mtval = value,
+                _ => panic!("Can't restore CSR {:#05X}", address),
+            }
+        }
+    }
 
-        if is_read_only != 0 {
-            panic!("CSR Write: Attempt to write a read-only register");
+    /// Writes the CSR at `address`. Writes to CSRs that can be read but aren't modelled as
+    /// writable, such as `mstatush`, are ignored. `misa` can't be written, as the extensions it
+    /// reports can't be switched off. Bits outside a CSR's writable fields are dropped here, so CSRRS
+    /// and CSRRC can pass the whole updated value. `mip.MTIP` is one of those: only the timer (or the
+    /// host, through `clear_interrupt`) can clear it. `mtvec` is WARL: writing one of the reserved
+    /// modes (2 or 3) keeps the current mode.
+    pub fn write(&mut self, address: u32, value: u32) -> Result<(), CSRError> {
+        self.read(address)?;
+        if is_read_only(address) || address == CSRM_MODE_MISA {
+            return Err(CSRError::ReadOnly(address));
         }
 
         match address {
             CSRM_MODE_MSTATUS => self.mstatus = value & MSTATUS_MASK,
+            CSRM_MODE_MSTATUSH => {}
+            CSRM_MODE_MTVEC => {
+                self.mtvec = match value & MTVEC_MODE_MASK {
+                    MTVEC_MODE_DIRECT | MTVEC_MODE_VECTORED => value,
+                    _ => (value & !MTVEC_MODE_MASK) | (self.mtvec & MTVEC_MODE_MASK),
+                }
+            }
             CSRM_MODE_MIE => self.mie = value,
-            CSRM_MODE_MIP => self.mip = value,
+            CSRM_MODE_MIP => self.mip = (self.mip & MIP_MTIP_MASK) | (value & !MIP_MTIP_MASK),
             CSRM_MODE_MCAUSE => self.mcause = value,
             CSRM_MODE_MEPC => self.mepc = value,
             CSRM_MODE_MSCRATCH => self.mscratch = value,
             CSRM_MODE_MTVAL => self.mtval = value,
             _ => {}
         }
+        Ok(())
+    }
+
+    /// Sets the `mip` bit for the interrupt `mcause`
+    pub fn raise_interrupt(&mut self, mcause: u32) {
+        self.mip |= 1 << (mcause & 0x1F);
+    }
+
+    /// Clears the `mip` bit for the interrupt `mcause`
+    pub fn clear_interrupt(&mut self, mcause: u32) {
+        self.mip &= !(1 << (mcause & 0x1F));
+    }
+
+    /// The mcause of the highest priority interrupt that is both pending and enabled, if interrupts
+    /// are globally enabled
+    pub fn pending_interrupt(&self) -> Option<u32> {
+        if self.mstatus & MSTATUS_MIE_MASK == 0 {
+            return None;
+        }
+        let pending = self.mip & self.mie;
+        INTERRUPT_PRIORITY
+            .iter()
+            .find(|index| pending & (1 << **index) != 0)
+            .map(|index| 0x8000_0000 | index)
     }
 
     pub fn compute(&mut self) {
-        self.cycles.set(self.cycles.get() + 1);
+        self.cycles.set(self.cycles.get().wrapping_add(1));
     }
 
     pub fn latch_next(&mut self) {
         self.cycles.latch_next();
         self.instret.latch_next();
-        self.mtimecmp.latch_next();
+    }
+}
+
+impl Default for CSRInterface {
+    fn default() -> Self {
+        Self::new()
     }
 }
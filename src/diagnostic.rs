@@ -0,0 +1,197 @@
+use crate::{CycleError, RV32ISystem, trap::TrapState};
+
+/// Something noteworthy that happened during `run_diagnostic`
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Diagnostic {
+    /// A trap was taken, execution carried on in the handler
+    Trap {
+        cycle: u64,
+        mcause: u32,
+        mepc: u32,
+        mtval: u32,
+    },
+    /// A bus access failed in a way the emulator can't model, the cycle wasn't run
+    Error { cycle: u64, error: CycleError },
+}
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostic::Trap {
+                cycle,
+                mcause,
+                mepc,
+                mtval,
+            } => write!(
+                f,
+                "Cycle {}: trap (mcause={:#08X}, mepc={:#08X}, mtval={:#08X})",
+                cycle, mcause, mepc, mtval
+            ),
+            Diagnostic::Error { cycle, error } => write!(f, "Cycle {}: stopped: {}", cycle, error),
+        }
+    }
+}
+
+/// Why `run_diagnostic` stopped
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum StopReason {
+    CycleLimit,
+    /// The program parked itself in a self-loop, see `RV32ISystem::is_halted`
+    Halted,
+    /// See `Diagnostic::Error`, the last diagnostic
+    Error,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DiagnosticRun {
+    pub diagnostics: Vec<Diagnostic>,
+    pub stop: StopReason,
+}
+
+impl RV32ISystem {
+    /// Runs up to `max_cycles` cycles without letting the emulator abort the host, collecting every
+    /// trap and stopping with a diagnostic instead of panicking on a `CycleError`. The failed cycle
+    /// isn't latched, see `try_cycle`.
+    pub fn run_diagnostic(&mut self, max_cycles: u64) -> DiagnosticRun {
+        let mut diagnostics = vec![];
+        for _ in 0..max_cycles {
+            let cycle = self.cycle_count();
+            if let Err(error) = self.try_cycle() {
+                diagnostics.push(Diagnostic::Error { cycle, error });
+                return DiagnosticRun {
+                    diagnostics,
+                    stop: StopReason::Error,
+                };
+            }
+
+            // the trap interface holds the cause for exactly one cycle as the trap begins
+            let trap = self.trap_state();
+            if trap.state == TrapState::SetCSRJump {
+                diagnostics.push(Diagnostic::Trap {
+                    cycle,
+                    mcause: trap.mcause,
+                    mepc: trap.mepc,
+                    mtval: trap.mtval,
+                });
+            }
+            if self.is_halted() {
+                return DiagnosticRun {
+                    diagnostics,
+                    stop: StopReason::Halted,
+                };
+            }
+        }
+        DiagnosticRun {
+            diagnostics,
+            stop: StopReason::CycleLimit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        system_interface::{MMIODevice, MMIOError, MMIOResult},
+        trap::MCAUSE_ILLEGAL_INSTRUCTION,
+    };
+
+    #[test]
+    fn test_illegal_instruction_diagnostic() {
+        let mut program = vec![0x0000_0013; 2];
+        // slli x1, x0, 32 (illegal on RV32)
+        program[0] = 0x0200_1093;
+        // jal x0, 0 in the illegal instruction handler
+        program[1] = 0x0000_006F;
+
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program);
+        let run = rv.run_diagnostic(100);
+        assert_eq!(
+            run.diagnostics,
+            [Diagnostic::Trap {
+                cycle: 2,
+                mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+                mepc: 0x1000_0000,
+                mtval: 0x0200_1093,
+            }]
+        );
+        assert_eq!(run.stop, StopReason::Halted);
+    }
+
+    /// Reports an error on every read that no trap corresponds to
+    struct BrokenDevice;
+
+    impl MMIODevice for BrokenDevice {
+        fn read_byte(&self, address: u32) -> MMIOResult<u8> {
+            Err(MMIOError::UnalignedWrite(address, 0))
+        }
+        fn write_byte(&mut self, _address: u32, _value: u8) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_half_word(&self, address: u32) -> MMIOResult<u16> {
+            Err(MMIOError::UnalignedWrite(address, 0))
+        }
+        fn write_half_word(&mut self, _address: u32, _value: u16) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_word(&self, address: u32) -> MMIOResult<u32> {
+            Err(MMIOError::UnalignedWrite(address, 0))
+        }
+        fn write_word(&mut self, _address: u32, _value: u32) -> MMIOResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bus_error_diagnostic() {
+        let mut rv = RV32ISystem::new();
+        rv.bus
+            .attach(0x4000_0000..0x4000_1000, Box::new(BrokenDevice))
+            .unwrap();
+        rv.reg_file[1] = 0x4000_0000;
+        rv.bus.rom.load(vec![
+            0x0000_0013, // NOP
+            0x0040_A103, // LW x2, 4(x1)
+        ]);
+        let run = rv.run_diagnostic(100);
+        assert_eq!(run.stop, StopReason::Error);
+        assert_eq!(
+            run.diagnostics,
+            [Diagnostic::Error {
+                cycle: 8,
+                error: CycleError::MemoryAccess {
+                    pc: 0x1000_0004,
+                    error: MMIOError::UnalignedWrite(4, 0),
+                },
+            }]
+        );
+
+        // nothing was latched, the load is still waiting on memory access
+        assert_eq!(rv.cycle_count(), 8);
+        assert_eq!(rv.current_line(), 0x1000_0004);
+        assert_eq!(rv.run_diagnostic(1).stop, StopReason::Error);
+    }
+
+    #[test]
+    fn test_fetch_error_diagnostic() {
+        let mut rv = RV32ISystem::new();
+        rv.bus
+            .attach(0x4000_0000..0x4000_1000, Box::new(BrokenDevice))
+            .unwrap();
+        rv.reg_file[1] = 0x4000_0000;
+        // JALR x0, 0(x1)
+        rv.bus.rom.load(vec![0x0000_8067]);
+        let run = rv.run_diagnostic(100);
+        assert_eq!(run.stop, StopReason::Error);
+        let [Diagnostic::Error { error, .. }] = run.diagnostics.as_slice() else {
+            panic!("Expected a single error, got {:?}", run.diagnostics);
+        };
+        assert_eq!(
+            *error,
+            CycleError::Fetch {
+                pc: 0x4000_0000,
+                error: MMIOError::UnalignedWrite(0, 0),
+            }
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Disassembly of RV32I instructions, in the style of `objdump -M numeric,no-aliases`
+
+use std::ops::Range;
+
+use crate::{
+    PROGRAM_ROM_START, RegIndex,
+    utils::{bit, sign_extend_32, slice_32},
+};
+
+fn reg(index: RegIndex) -> String {
+    format!("x{}", index.value())
+}
+
+/// Names of the CSRs this core implements, shared with the assembler
+pub(crate) const CSR_NAMES: [(u32, &str); 20] = [
+    (0xC00, "cycle"),
+    (0xC01, "time"),
+    (0xC02, "instret"),
+    (0xC80, "cycleh"),
+    (0xC81, "timeh"),
+    (0xC82, "instreth"),
+    (0x300, "mstatus"),
+    (0x301, "misa"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    (0x310, "mstatush"),
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0xF11, "mvendorid"),
+    (0xF12, "marchid"),
+    (0xF13, "mimpid"),
+    (0xF14, "mhartid"),
+];
+
+fn csr_name(address: u32) -> String {
+    CSR_NAMES
+        .iter()
+        .find(|(csr, _)| *csr == address)
+        .map_or_else(|| format!("{:#x}", address), |(_, name)| name.to_string())
+}
+
+fn fence_set(bits: u32) -> String {
+    let set: String = [(0b1000, 'i'), (0b0100, 'o'), (0b0010, 'r'), (0b0001, 'w')]
+        .iter()
+        .filter(|(mask, _)| bits & mask != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    match set.is_empty() {
+        true => "0".to_string(),
+        false => set,
+    }
+}
+
+/// Disassembles the instruction `raw_instruction` located at `pc`. Jump and branch targets are
+/// shown as absolute addresses, anything that can't be decoded is shown as `unknown`.
+pub fn disassemble(pc: u32, raw_instruction: u32) -> String {
+    let instruction = raw_instruction;
+    let opcode = instruction & 0x7F;
+    let rd = RegIndex::from_field(instruction, 7);
+    let funct3 = (instruction >> 12) & 0x07;
+    let rs1 = RegIndex::from_field(instruction, 15);
+    let rs2 = RegIndex::from_field(instruction, 20);
+    let funct7 = instruction >> 25;
+    let i_imm = sign_extend_32(12, ((instruction >> 20) & 0xFFF) as i32);
+    let s_imm = sign_extend_32(
+        12,
+        ((((instruction >> 25) & 0x7F) << 5) | ((instruction >> 7) & 0x1F)) as i32,
+    );
+
+    match opcode {
+        0b001_0011 => {
+            let shamt = rs2.value();
+            match (funct3, funct7) {
+                (0b000, _) => format!("addi {},{},{}", reg(rd), reg(rs1), i_imm),
+                (0b010, _) => format!("slti {},{},{}", reg(rd), reg(rs1), i_imm),
+                (0b011, _) => format!("sltiu {},{},{}", reg(rd), reg(rs1), i_imm),
+                (0b100, _) => format!("xori {},{},{}", reg(rd), reg(rs1), i_imm),
+                (0b110, _) => format!("ori {},{},{}", reg(rd), reg(rs1), i_imm),
+                (0b111, _) => format!("andi {},{},{}", reg(rd), reg(rs1), i_imm),
+                (0b001, 0b000_0000) => format!("slli {},{},{:#x}", reg(rd), reg(rs1), shamt),
+                (0b101, 0b000_0000) => format!("srli {},{},{:#x}", reg(rd), reg(rs1), shamt),
+                (0b101, 0b010_0000) => format!("srai {},{},{:#x}", reg(rd), reg(rs1), shamt),
+                _ => "unknown".to_string(),
+            }
+        }
+        0b011_0011 => {
+            let mnemonic = match (funct3, funct7) {
+                (0b000, 0b000_0000) => "add",
+                (0b000, 0b010_0000) => "sub",
+                (0b001, 0b000_0000) => "sll",
+                (0b010, 0b000_0000) => "slt",
+                (0b011, 0b000_0000) => "sltu",
+                (0b100, 0b000_0000) => "xor",
+                (0b101, 0b000_0000) => "srl",
+                (0b101, 0b010_0000) => "sra",
+                (0b110, 0b000_0000) => "or",
+                (0b111, 0b000_0000) => "and",
+                (0b010, 0b001_0000) => "sh1add",
+                (0b100, 0b001_0000) => "sh2add",
+                (0b110, 0b001_0000) => "sh3add",
+                (0b000, 0b000_0001) => "mul",
+                (0b001, 0b000_0001) => "mulh",
+                (0b010, 0b000_0001) => "mulhsu",
+                (0b011, 0b000_0001) => "mulhu",
+                (0b100, 0b000_0001) => "div",
+                (0b101, 0b000_0001) => "divu",
+                (0b110, 0b000_0001) => "rem",
+                (0b111, 0b000_0001) => "remu",
+                _ => return "unknown".to_string(),
+            };
+            format!("{} {},{},{}", mnemonic, reg(rd), reg(rs1), reg(rs2))
+        }
+        0b000_0011 => {
+            let mnemonic = match funct3 {
+                0b000 => "lb",
+                0b001 => "lh",
+                0b010 => "lw",
+                0b100 => "lbu",
+                0b101 => "lhu",
+                _ => return "unknown".to_string(),
+            };
+            format!("{} {},{}({})", mnemonic, reg(rd), i_imm, reg(rs1))
+        }
+        0b010_0011 => {
+            let mnemonic = match funct3 {
+                0b000 => "sb",
+                0b001 => "sh",
+                0b010 => "sw",
+                _ => return "unknown".to_string(),
+            };
+            format!("{} {},{}({})", mnemonic, reg(rs2), s_imm, reg(rs1))
+        }
+        0b011_0111 => format!("lui {},{:#x}", reg(rd), instruction >> 12),
+        0b001_0111 => format!("auipc {},{:#x}", reg(rd), instruction >> 12),
+        0b110_1111 => {
+            let restructured_imm = bit(31, instruction, 20)
+                | slice_32(19, 12, instruction, 19)
+                | bit(20, instruction, 11)
+                | slice_32(30, 21, instruction, 10);
+            let imm32 = sign_extend_32(21, (restructured_imm << 1) as i32);
+            format!("jal {},{:x}", reg(rd), pc.wrapping_add_signed(imm32))
+        }
+        0b110_0111 if funct3 == 0 => format!("jalr {},{}({})", reg(rd), i_imm, reg(rs1)),
+        0b110_0011 => {
+            let mnemonic = match funct3 {
+                0b000 => "beq",
+                0b001 => "bne",
+                0b100 => "blt",
+                0b101 => "bge",
+                0b110 => "bltu",
+                0b111 => "bgeu",
+                _ => return "unknown".to_string(),
+            };
+            let restructured_imm = bit(31, instruction, 12)
+                | bit(7, instruction, 11)
+                | slice_32(30, 25, instruction, 10)
+                | slice_32(11, 8, instruction, 4);
+            let imm32 = sign_extend_32(13, (restructured_imm << 1) as i32);
+            format!(
+                "{} {},{},{:x}",
+                mnemonic,
+                reg(rs1),
+                reg(rs2),
+                pc.wrapping_add_signed(imm32)
+            )
+        }
+        0b111_0011 => match instruction {
+            0x0000_0073 => "ecall".to_string(),
+            0x0010_0073 => "ebreak".to_string(),
+            0x3020_0073 => "mret".to_string(),
+            0x1050_0073 => "wfi".to_string(),
+            _ => {
+                let csr = csr_name(instruction >> 20);
+                let mnemonic = match funct3 {
+                    0b001 => "csrrw",
+                    0b010 => "csrrs",
+                    0b011 => "csrrc",
+                    0b101 => "csrrwi",
+                    0b110 => "csrrsi",
+                    0b111 => "csrrci",
+                    _ => return "unknown".to_string(),
+                };
+                match funct3 & 0b100 {
+                    0b100 => format!("{} {},{},{}", mnemonic, reg(rd), csr, rs1.value()),
+                    _ => format!("{} {},{},{}", mnemonic, reg(rd), csr, reg(rs1)),
+                }
+            }
+        },
+        0b000_1111 => match funct3 {
+            0b000 => format!(
+                "fence {},{}",
+                fence_set((instruction >> 24) & 0xF),
+                fence_set((instruction >> 20) & 0xF)
+            ),
+            0b001 => "fence.i".to_string(),
+            _ => "unknown".to_string(),
+        },
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Disassembles consecutive `words` starting at `start`, one `address: raw  instruction` line per
+/// word. Words inside any of the `data` ranges (such as a vector table of addresses) are shown as
+/// `.word` values rather than decoded.
+pub fn disassemble_words(start: u32, words: &[u32], data: &[Range<u32>]) -> Vec<String> {
+    words
+        .iter()
+        .zip((start..).step_by(4))
+        .map(|(&raw, pc)| {
+            let text = match data.iter().any(|range| range.contains(&pc)) {
+                true => format!(".word {:#010x}", raw),
+                false => disassemble(pc, raw),
+            };
+            format!("{:08x}: {:08x}  {}", pc, raw, text)
+        })
+        .collect()
+}
+
+/// Compares two program ROM images word by word, returning `(address, a, b)` with the
+/// disassembly from each image for every address where they differ. A side is `None` past the end
+/// of the shorter image.
+pub fn diff_roms(a: &[u32], b: &[u32]) -> Vec<(u32, Option<String>, Option<String>)> {
+    (0..a.len().max(b.len()))
+        .zip((PROGRAM_ROM_START..).step_by(4))
+        .filter_map(|(i, pc)| {
+            let (word_a, word_b) = (a.get(i), b.get(i));
+            (word_a != word_b).then(|| {
+                (
+                    pc,
+                    word_a.map(|&raw| disassemble(pc, raw)),
+                    word_b.map(|&raw| disassemble(pc, raw)),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let cases = [
+            (0x1000_0004, 0xffc1_0113, "addi x2,x2,-4"),
+            (0x1000_0008, 0x03c0_006f, "jal x0,10000044"),
+            (0x1000_0054, 0xfb9f_f0ef, "jal x1,1000000c"),
+            (0x1000_0000, 0x2040_0137, "lui x2,0x20400"),
+            (0x1000_0000, 0x0000_8067, "jalr x0,0(x1)"),
+            (0x1000_0000, 0x00e7_a023, "sw x14,0(x15)"),
+            (0x1000_0000, 0xfec4_2703, "lw x14,-20(x8)"),
+            (0x1000_0000, 0x00e6_8733, "add x14,x13,x14"),
+            (0x1000_0000, 0x4020_81b3, "sub x3,x1,x2"),
+            (0x1000_0000, 0x2020_c1b3, "sh2add x3,x1,x2"),
+            (0x1000_0000, 0x0220_81b3, "mul x3,x1,x2"),
+            (0x1000_0000, 0x0220_b1b3, "mulhu x3,x1,x2"),
+            (0x1000_0000, 0x0220_f1b3, "remu x3,x1,x2"),
+            (0x1000_0000, 0x0027_9793, "slli x15,x15,0x2"),
+            (0x1000_0000, 0x4027_d793, "srai x15,x15,0x2"),
+            (0x1000_0000, 0x0000_0517, "auipc x10,0x0"),
+            (0x1000_0010, 0xfe20_98e3, "bne x1,x2,10000000"),
+            (0x1000_0000, 0x0000_0073, "ecall"),
+            (0x1000_0000, 0x0010_0073, "ebreak"),
+            (0x1000_0000, 0x3020_0073, "mret"),
+            (0x1000_0000, 0xc000_20f3, "csrrs x1,cycle,x0"),
+            (0x1000_0000, 0x3050_d073, "csrrwi x0,mtvec,1"),
+            (0x1000_0000, 0x0ff0_000f, "fence iorw,iorw"),
+            (0x1000_0000, 0x0000_100f, "fence.i"),
+            (0x1000_0000, 0xffff_ffff, "unknown"),
+        ];
+        for (pc, raw, expected) in cases {
+            assert_eq!(disassemble(pc, raw), expected, "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn test_disassemble_words_data() {
+        let words = [0x1000_0040, 0x1000_0080, 0xffc1_0113];
+        assert_eq!(
+            disassemble_words(
+                0x1000_0000,
+                &words,
+                &[0x1000_0000..0x1000_0004, 0x1000_0004..0x1000_0008]
+            ),
+            [
+                "10000000: 10000040  .word 0x10000040",
+                "10000004: 10000080  .word 0x10000080",
+                "10000008: ffc10113  addi x2,x2,-4",
+            ]
+        );
+        assert_eq!(
+            disassemble_words(0x1000_0000, &words, &[])[0],
+            "10000000: 10000040  unknown"
+        );
+    }
+
+    #[test]
+    fn test_diff_roms() {
+        let a = [0x0010_0093, 0x0020_0113, 0x0000_006f];
+        let mut b = a;
+        b[1] = 0x0030_0113;
+        assert_eq!(
+            diff_roms(&a, &b),
+            [(
+                0x1000_0004,
+                Some("addi x2,x0,2".to_string()),
+                Some("addi x2,x0,3".to_string())
+            )]
+        );
+        assert!(diff_roms(&a, &a).is_empty());
+        assert_eq!(
+            diff_roms(&a[..2], &a),
+            [(0x1000_0008, None, Some("jal x0,10000008".to_string()))]
+        );
+    }
+}
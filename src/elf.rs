@@ -0,0 +1,280 @@
+//! Loading of statically linked RV32 ELF executables, such as the riscv-tests ISA tests
+
+use crate::{
+    RV32ISystem,
+    system_interface::{MMIODevice, ROM_ERASED_WORD},
+};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// The file ends before a header or segment it describes
+    Truncated,
+    NotElf,
+    /// Not a 32-bit little-endian RISC-V executable
+    Unsupported,
+    /// A segment landed somewhere that can't hold it, at the given address
+    Unmapped(u32),
+}
+impl std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::NotElf => write!(f, "Not an ELF file"),
+            ElfError::Unsupported => {
+                write!(f, "Not a 32-bit little-endian RISC-V executable")
+            }
+            ElfError::Unmapped(addr) => {
+                write!(f, "Segment byte at address {:#08X} can't be loaded", addr)
+            }
+        }
+    }
+}
+
+pub type ElfResult<T> = std::result::Result<T, ElfError>;
+
+fn read_u16(bytes: &[u8], offset: usize) -> ElfResult<u16> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> ElfResult<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ElfError::Truncated)
+}
+
+fn slice(bytes: &[u8], offset: u32, size: u32) -> ElfResult<&[u8]> {
+    let start = offset as usize;
+    bytes
+        .get(start..start + size as usize)
+        .ok_or(ElfError::Truncated)
+}
+
+/// A loadable segment of an executable
+#[derive(PartialEq, Eq, Debug)]
+pub struct Segment<'a> {
+    pub address: u32,
+    /// Contents from the file, the rest of the segment up to `size` is zeroed
+    pub data: &'a [u8],
+    pub size: u32,
+}
+
+/// A parsed ELF executable, borrowing the file contents
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    pub entry: u32,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the ELF header, anything that isn't a 32-bit little-endian RISC-V executable is
+    /// rejected
+    pub fn parse(bytes: &'a [u8]) -> ElfResult<Self> {
+        if bytes.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if bytes[4] != ELFCLASS32
+            || bytes[5] != ELFDATA2LSB
+            || read_u16(bytes, 16)? != ET_EXEC
+            || read_u16(bytes, 18)? != EM_RISCV
+        {
+            return Err(ElfError::Unsupported);
+        }
+        Ok(Self {
+            bytes,
+            entry: read_u32(bytes, 24)?,
+        })
+    }
+
+    /// Every `PT_LOAD` segment, in file order
+    pub fn segments(&self) -> ElfResult<Vec<Segment<'a>>> {
+        let phoff = read_u32(self.bytes, 28)? as usize;
+        let phnum = read_u16(self.bytes, 44)? as usize;
+        let mut segments = vec![];
+        for i in 0..phnum {
+            let header = phoff + i * PHDR_SIZE;
+            if read_u32(self.bytes, header)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u32(self.bytes, header + 4)?;
+            let address = read_u32(self.bytes, header + 12)?;
+            let file_size = read_u32(self.bytes, header + 16)?;
+            let size = read_u32(self.bytes, header + 20)?;
+            segments.push(Segment {
+                address,
+                data: slice(self.bytes, offset, file_size.min(size))?,
+                size,
+            });
+        }
+        Ok(segments)
+    }
+
+    /// Address of the symbol `name` from the symbol table, if there is one
+    pub fn symbol(&self, name: &str) -> ElfResult<Option<u32>> {
+        let shoff = read_u32(self.bytes, 32)? as usize;
+        let shnum = read_u16(self.bytes, 48)? as usize;
+        let section = |index: usize| shoff + index * SHDR_SIZE;
+        for i in 0..shnum {
+            if read_u32(self.bytes, section(i) + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let symtab = slice(
+                self.bytes,
+                read_u32(self.bytes, section(i) + 16)?,
+                read_u32(self.bytes, section(i) + 20)?,
+            )?;
+            let strtab_section = section(read_u32(self.bytes, section(i) + 24)? as usize);
+            let strtab = slice(
+                self.bytes,
+                read_u32(self.bytes, strtab_section + 16)?,
+                read_u32(self.bytes, strtab_section + 20)?,
+            )?;
+            for symbol in symtab.chunks_exact(SYM_SIZE) {
+                let name_offset = read_u32(symbol, 0)? as usize;
+                let symbol_name = strtab
+                    .get(name_offset..)
+                    .and_then(|rest| rest.split(|b| *b == 0).next())
+                    .ok_or(ElfError::Truncated)?;
+                if symbol_name == name.as_bytes() {
+                    return Ok(Some(read_u32(symbol, 4)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl RV32ISystem {
+    /// Loads every segment of `elf` and starts execution at its entry point. Segments in the
+    /// program ROM replace its contents and have to fit in it, anything else is written through
+    /// the bus, so it has to land in RAM or an attached device.
+    pub fn load_elf(&mut self, elf: &Elf) -> ElfResult<()> {
+        let rom_range = self.bus.rom_range();
+        let rom_size = self.bus.rom.size();
+        let mut rom = vec![];
+        for segment in elf.segments()? {
+            let end = segment
+                .address
+                .checked_add(segment.size)
+                .ok_or(ElfError::Unmapped(segment.address))?;
+            let bytes =
+                (0..segment.size).map(|i| segment.data.get(i as usize).copied().unwrap_or(0));
+            for (address, byte) in (segment.address..end).zip(bytes) {
+                if rom_range.contains(&address) {
+                    let offset = address - rom_range.start;
+                    if offset >= rom_size {
+                        return Err(ElfError::Unmapped(address));
+                    }
+                    let offset = offset as usize;
+                    if rom.len() <= offset / 4 {
+                        rom.resize(offset / 4 + 1, ROM_ERASED_WORD);
+                    }
+                    let shift = 8 * (offset % 4);
+                    rom[offset / 4] =
+                        (rom[offset / 4] & !(0xFF << shift)) | ((byte as u32) << shift);
+                } else if self.bus.is_writable_memory(address) {
+                    self.bus
+                        .write_byte(address, byte)
+                        .map_err(|_| ElfError::Unmapped(address))?;
+                } else {
+                    return Err(ElfError::Unmapped(address));
+                }
+            }
+        }
+        if !rom.is_empty() {
+            self.bus.rom.load(rom);
+        }
+        self.set_reset_vector(elf.entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::build_elf;
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Elf::parse(&[0x7F, b'E']).err(), Some(ElfError::Truncated));
+        assert_eq!(Elf::parse(&[0; 64]).err(), Some(ElfError::NotElf));
+
+        let mut elf = build_elf(0x1000_0000, &[], &[]);
+        elf[4] = 2; // ELFCLASS64
+        assert_eq!(Elf::parse(&elf).err(), Some(ElfError::Unsupported));
+    }
+
+    #[test]
+    fn test_load_elf() {
+        let code = [0x13, 0x00, 0x00, 0x00, 0x6F, 0x00, 0x00, 0x00];
+        let data = [0x78, 0x56, 0x34, 0x12, 0xAB];
+        let bytes = build_elf(
+            0x1000_0004,
+            &[(0x1000_0000, &code), (0x2000_0010, &data)],
+            &[("_start", 0x1000_0004), ("tohost", 0x2000_0010)],
+        );
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.entry, 0x1000_0004);
+        assert_eq!(elf.symbol("tohost"), Ok(Some(0x2000_0010)));
+        assert_eq!(elf.symbol("fromhost"), Ok(None));
+
+        let mut rv = RV32ISystem::new();
+        rv.load_elf(&elf).unwrap();
+        assert_eq!(rv.bus.read_word(0x1000_0000), Ok(0x0000_0013));
+        assert_eq!(rv.bus.read_word(0x1000_0004), Ok(0x0000_006F));
+        assert_eq!(rv.bus.read_word(0x1000_0008), Ok(ROM_ERASED_WORD));
+        assert_eq!(rv.bus.read_word(0x2000_0010), Ok(0x1234_5678));
+        assert_eq!(rv.bus.read_byte(0x2000_0014), Ok(0xAB));
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+
+        // nothing is mapped at 0x8000_0000 by default
+        let bytes = build_elf(0x8000_0000, &[(0x8000_0000, &code)], &[]);
+        let mut rv = RV32ISystem::new();
+        assert_eq!(
+            rv.load_elf(&Elf::parse(&bytes).unwrap()),
+            Err(ElfError::Unmapped(0x8000_0000))
+        );
+    }
+
+    #[test]
+    fn test_load_elf_outside_rom() {
+        let code = [0x13, 0x00, 0x00, 0x00];
+        let mut rv = RV32ISystem::new();
+        let rom_end = rv.bus.rom_range().start + rv.bus.rom.size();
+
+        // the last word of the ROM can be loaded, the next one is past it rather than wrapping
+        let bytes = build_elf(rom_end - 4, &[(rom_end - 4, &code)], &[]);
+        rv.load_elf(&Elf::parse(&bytes).unwrap()).unwrap();
+        assert_eq!(rv.bus.read_word(rom_end - 4), Ok(0x0000_0013));
+        let bytes = build_elf(rom_end, &[(rom_end - 2, &code)], &[]);
+        assert_eq!(
+            rv.load_elf(&Elf::parse(&bytes).unwrap()),
+            Err(ElfError::Unmapped(rom_end))
+        );
+
+        // a segment running past the top of the address space
+        let bytes = build_elf(0xFFFF_FFFE, &[(0xFFFF_FFFE, &code)], &[]);
+        assert_eq!(
+            rv.load_elf(&Elf::parse(&bytes).unwrap()),
+            Err(ElfError::Unmapped(0xFFFF_FFFE))
+        );
+    }
+}
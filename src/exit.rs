@@ -0,0 +1,220 @@
+//! Running a program to completion and reporting why it stopped
+
+use crate::{
+    CycleError, RV32ISystem,
+    system_interface::{MMIODevice, MMIOResult},
+    trap::{MCAUSE_BREAKPOINT, MCAUSE_ENVIRONMENT_CALL_FROM_MMODE, TrapState},
+};
+
+/// Why `run_until_halt` stopped
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ExitReason {
+    /// The program wrote the nonzero value to `tohost`, see `RV32ISystem::set_tohost`
+    ToHost(u32),
+    /// An EBREAK was executed, `mepc` is as the trap saved it
+    Breakpoint {
+        mepc: u32,
+    },
+    /// The program parked itself in a self-loop, see `RV32ISystem::is_halted`
+    SelfLoop,
+    CycleLimit,
+    /// Any other exception was taken
+    FatalTrap {
+        mcause: u32,
+        mepc: u32,
+        mtval: u32,
+    },
+    /// A bus access failed in a way the architecture has no trap for, see `RV32ISystem::try_cycle`
+    Error(CycleError),
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::ToHost(value) => write!(f, "Exited with tohost={:#X}", value),
+            ExitReason::Breakpoint { mepc } => write!(f, "Breakpoint (mepc={:#08X})", mepc),
+            ExitReason::SelfLoop => write!(f, "Halted in a self-loop"),
+            ExitReason::CycleLimit => write!(f, "Cycle limit reached"),
+            ExitReason::FatalTrap {
+                mcause,
+                mepc,
+                mtval,
+            } => write!(
+                f,
+                "Fatal trap (mcause={:#08X}, mepc={:#08X}, mtval={:#08X})",
+                mcause, mepc, mtval
+            ),
+            ExitReason::Error(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl RV32ISystem {
+    /// Sets the address of the `tohost` word, which the program writes with a nonzero value to
+    /// exit, and clears it. Only checked by `run_until_halt`. Fails, leaving `tohost` unset, if the
+    /// word can't be written.
+    pub fn set_tohost(&mut self, address: u32) -> MMIOResult<()> {
+        self.bus.write_word(address, 0)?;
+        self.tohost = Some(address);
+        Ok(())
+    }
+
+    /// Runs up to `max_cycles` cycles until the program exits. Environment calls and interrupts go
+    /// through their handlers as normal, every other exception stops the run as the trap is taken.
+    pub fn run_until_halt(&mut self, max_cycles: u64) -> ExitReason {
+        for _ in 0..max_cycles {
+            if let Err(error) = self.try_cycle() {
+                return ExitReason::Error(error);
+            }
+
+            if let Some(tohost) = self.tohost {
+                let value = self.peek_word(tohost);
+                if value != 0 {
+                    return ExitReason::ToHost(value);
+                }
+            }
+
+            // the trap interface holds the cause for exactly one cycle as the trap begins
+            let trap = self.trap_state();
+            if trap.state == TrapState::SetCSRJump && trap.mcause & 0x8000_0000 == 0 {
+                match trap.mcause {
+                    MCAUSE_ENVIRONMENT_CALL_FROM_MMODE => {}
+                    MCAUSE_BREAKPOINT => return ExitReason::Breakpoint { mepc: trap.mepc },
+                    mcause => {
+                        return ExitReason::FatalTrap {
+                            mcause,
+                            mepc: trap.mepc,
+                            mtval: trap.mtval,
+                        };
+                    }
+                }
+            }
+            if self.is_halted() {
+                return ExitReason::SelfLoop;
+            }
+        }
+        ExitReason::CycleLimit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        system_interface::{MMIOError, RAM_START},
+        trap::MCAUSE_ILLEGAL_INSTRUCTION,
+    };
+
+    /// Fails every read with an error no trap covers
+    struct BrokenDevice;
+
+    impl MMIODevice for BrokenDevice {
+        fn read_byte(&self, address: u32) -> MMIOResult<u8> {
+            Err(MMIOError::UnalignedWrite(address, 0))
+        }
+        fn write_byte(&mut self, _address: u32, _value: u8) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_half_word(&self, address: u32) -> MMIOResult<u16> {
+            Err(MMIOError::UnalignedWrite(address, 0))
+        }
+        fn write_half_word(&mut self, _address: u32, _value: u16) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_word(&self, address: u32) -> MMIOResult<u32> {
+            Err(MMIOError::UnalignedWrite(address, 0))
+        }
+        fn write_word(&mut self, _address: u32, _value: u32) -> MMIOResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_exit_to_host() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x2000_00B7, // LUI x1, 0x20000
+            0x0050_0113, // ADDI x2, x0, 5
+            0x0020_A223, // SW x2, 4(x1)
+            0x0000_006F, // JAL x0, 0
+        ]);
+        assert_eq!(rv.set_tohost(RAM_START + 4), Ok(()));
+        assert_eq!(rv.run_until_halt(100), ExitReason::ToHost(5));
+    }
+
+    #[test]
+    fn test_exit_breakpoint() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0000_0013, // NOP
+            0x0010_0073, // EBREAK
+        ]);
+        assert_eq!(
+            rv.run_until_halt(100),
+            ExitReason::Breakpoint { mepc: 0x1000_0004 }
+        );
+    }
+
+    #[test]
+    fn test_exit_self_loop() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0040_006F, // JAL x0, 4
+            0x0000_006F, // JAL x0, 0
+        ]);
+        assert_eq!(rv.run_until_halt(100), ExitReason::SelfLoop);
+    }
+
+    #[test]
+    fn test_exit_cycle_limit() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0000_0013, // NOP
+            0xFFDF_F06F, // JAL x0, -4
+        ]);
+        assert_eq!(rv.run_until_halt(100), ExitReason::CycleLimit);
+        assert_eq!(rv.cycle_count(), 100);
+    }
+
+    #[test]
+    fn test_exit_fatal_trap() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0200_1093, // SLLI x1, x0, 32 (illegal on RV32)
+        ]);
+        assert_eq!(
+            rv.run_until_halt(100),
+            ExitReason::FatalTrap {
+                mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+                mepc: 0x1000_0000,
+                mtval: 0x0200_1093,
+            }
+        );
+    }
+
+    #[test]
+    fn test_exit_bus_error() {
+        let mut rv = RV32ISystem::new();
+        rv.bus
+            .attach(0x4000_0000..0x4000_1000, Box::new(BrokenDevice))
+            .unwrap();
+        rv.bus.rom.load(vec![
+            0x4000_00B7, // LUI x1, 0x40000
+            0x0040_A103, // LW x2, 4(x1)
+        ]);
+        assert_eq!(
+            rv.run_until_halt(100),
+            ExitReason::Error(CycleError::MemoryAccess {
+                pc: 0x1000_0004,
+                error: MMIOError::UnalignedWrite(4, 0),
+            })
+        );
+    }
+
+    #[test]
+    fn test_set_tohost_unmapped() {
+        let mut rv = RV32ISystem::new();
+        assert!(rv.set_tohost(0xF000_0000).is_err());
+        assert_eq!(rv.tohost, None);
+    }
+}
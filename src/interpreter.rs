@@ -0,0 +1,191 @@
+use crate::{
+    CPUState, PipelineState, RV32ISystem,
+    pipeline::{
+        PipelineStage,
+        decode::{DecodedInstruction, decode_instruction, has_reserved_bits},
+        execute::execute_instruction,
+        fetch::InstructionValue,
+        memory_access::{access_width, extend_loaded, load_width, read_aligned, write_aligned},
+    },
+    trap::TrapState,
+};
+
+/// Cycles the staged pipeline takes to run an instruction that doesn't trap
+const CYCLES_PER_INSTRUCTION: u64 = 5;
+
+impl RV32ISystem {
+    /// Runs `count` instructions without modelling the pipeline, for when only the architectural
+    /// results matter. Registers, memory, `instret`, the cycle counter and `mtime` end up exactly as
+    /// if `step` had been called `count` times.
+    ///
+    /// Plain computation, jumps, branches and aligned loads and stores are executed directly.
+    /// Anything that involves the CSRs, traps, custom instructions, misaligned accesses or a bus
+    /// error is handed to `step`, as is everything while call tracking, the energy model, history,
+    /// coverage, invariant checks, VCD recording or a trace hook are enabled, interrupts are
+    /// scheduled or pending or a debug halt is requested.
+    /// Instructions are read through the fetch buffer like the pipeline does, so code modified
+    /// without a `fence.i` behaves the same way.
+    pub fn interpret(&mut self, count: usize) {
+        if *self.state.get() != CPUState::Pipeline(PipelineState::Fetch) {
+            // finish the instruction in flight first
+            self.step();
+        }
+        for _ in 0..count {
+            if !self.interpret_instruction() {
+                self.step();
+            }
+        }
+    }
+
+    /// Runs the next instruction directly, returning `false` without side effects if it needs the
+    /// pipeline
+    fn interpret_instruction(&mut self) -> bool {
+        if self.call_stack.is_some()
+            || self.energy.is_some()
+            || self.history.is_some()
+            || self.coverage.is_some()
+            || self.vcd.is_some()
+            || self.invariant_handler.is_some()
+            || self.trace_hook.is_some()
+            || self.debug_halt_request
+            || !self.scheduled_interrupts.is_empty()
+            || self.csr.pending_interrupt().is_some()
+            || *self.trap.state.get() != TrapState::Idle
+        {
+            return false;
+        }
+
+        let pc = self.next_fetch_address();
+        if !self.bus.permissions(pc).execute || !self.bus.is_mapped(pc) {
+            return false;
+        }
+        // fetch through the buffer, so stale code runs just as it would in the pipeline
+        let Ok((raw_instruction, refill)) = self.stage_if.read_instruction(&self.bus, pc) else {
+            return false;
+        };
+        let decoded = decode_instruction(
+            &InstructionValue {
+                pc,
+                pc_plus_4: pc.wrapping_add(4),
+                raw_instruction,
+            },
+            &self.reg_file,
+            &self.custom_instructions,
+        );
+        if decoded.trap_params.trap
+            || decoded.return_from_trap
+            || (self.strict_decode && has_reserved_bits(raw_instruction))
+        {
+            return false;
+        }
+        let (rd, write_back_value, next_pc) = match decoded.instruction {
+            DecodedInstruction::Alu { rd, .. } => {
+                let executed = execute_instruction(&decoded, &mut self.custom_instructions);
+                (Some(rd), executed.write_back_value, decoded.pc_plus_4)
+            }
+            DecodedInstruction::Lui { rd, imm32 } => (Some(rd), imm32, decoded.pc_plus_4),
+            DecodedInstruction::Auipc { rd, imm32 } => {
+                (Some(rd), pc.wrapping_add(imm32), decoded.pc_plus_4)
+            }
+            DecodedInstruction::Jal { rd, branch_address }
+            | DecodedInstruction::Jalr { rd, branch_address } => {
+                (Some(rd), decoded.pc_plus_4, branch_address)
+            }
+            DecodedInstruction::Branch { .. } => {
+                let executed = execute_instruction(&decoded, &mut self.custom_instructions);
+                let DecodedInstruction::Branch { branch_address, .. } = executed.instruction else {
+                    unreachable!();
+                };
+                (None, 0, branch_address)
+            }
+            DecodedInstruction::Load {
+                funct3,
+                rd,
+                rs1,
+                imm32,
+            } => {
+                let address = rs1.wrapping_add_signed(imm32);
+                let Some(width) = load_width(funct3) else {
+                    return false;
+                };
+                if address & (width - 1) != 0
+                    || !self.bus.permissions(address).read
+                    || !self.bus.is_mapped(address)
+                {
+                    return false;
+                }
+                let Ok(value) = read_aligned(&self.bus, address, width) else {
+                    return false;
+                };
+                (
+                    Some(rd),
+                    extend_loaded(funct3, width, value),
+                    decoded.pc_plus_4,
+                )
+            }
+            DecodedInstruction::Store {
+                funct3,
+                rs1,
+                rs2,
+                imm32,
+            } => {
+                let address = rs1.wrapping_add_signed(imm32);
+                let Some(width) = access_width(funct3) else {
+                    return false;
+                };
+                if address & (width - 1) != 0
+                    || !self.bus.permissions(address).write
+                    || !self.bus.is_mapped(address)
+                {
+                    return false;
+                }
+                if write_aligned(&mut self.bus, address, width, rs2).is_err() {
+                    return false;
+                }
+                self.check_code_write(pc, decoded.instruction);
+                (None, 0, decoded.pc_plus_4)
+            }
+            DecodedInstruction::Fence {}
+            | DecodedInstruction::FenceI {}
+            | DecodedInstruction::Wfi {} => (None, 0, decoded.pc_plus_4),
+            DecodedInstruction::System { .. }
+            | DecodedInstruction::Mret { .. }
+            | DecodedInstruction::Custom { .. }
+            | DecodedInstruction::None => return false,
+        };
+
+        if let Some(buffer) = refill {
+            self.stage_if.latch_buffer(buffer);
+        }
+        if matches!(decoded.instruction, DecodedInstruction::FenceI {}) {
+            self.stage_if.flush_buffer();
+        }
+
+        // x0 is hardwired to zero, writes to it are discarded
+        if let Some(rd) = rd.filter(|rd| !rd.is_zero()) {
+            self.reg_file[rd] = write_back_value;
+        }
+
+        self.halted = next_pc == pc;
+
+        // leave fetch where `step` would have, with no jump or branch left to apply
+        self.stage_if.pc.set(pc);
+        self.stage_if.pc.latch_next();
+        self.stage_if.pc_plus_4.set(next_pc);
+        self.stage_if.pc_plus_4.latch_next();
+        self.stage_ex.clear_redirect();
+        self.stage_ex.latch_next();
+
+        for _ in 0..CYCLES_PER_INSTRUCTION {
+            self.tick_timer();
+        }
+        self.csr
+            .cycles
+            .set(self.csr.cycles.get().wrapping_add(CYCLES_PER_INSTRUCTION));
+        self.csr.cycles.latch_next();
+        self.cycles = self.cycles.wrapping_add(CYCLES_PER_INSTRUCTION);
+        self.csr.instret.set(self.csr.instret.get().wrapping_add(1));
+        self.csr.instret.latch_next();
+        true
+    }
+}
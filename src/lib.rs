@@ -1,27 +1,62 @@
 #![allow(dead_code)]
 #![allow(clippy::unusual_byte_groupings)]
 
+pub mod asm;
+pub mod coverage;
 mod csr;
+pub mod diagnostic;
+pub mod disasm;
+pub mod elf;
+pub mod exit;
+mod interpreter;
 mod pipeline;
 pub mod system_interface;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 pub mod trap;
 mod utils;
+pub mod vcd;
 
+use coverage::Coverage;
 use csr::CSRInterface;
+use disasm::{disassemble, disassemble_words};
 use pipeline::{
     PipelineStage,
-    decode::{DecodedInstruction, InstructionDecode, InstructionDecodeParams},
-    execute::{InstructionExecute, InstructionExecuteParams},
-    fetch::{InstructionFetch, InstructionFetchParams},
-    memory_access::{InstructionMemoryAccess, InstructionMemoryAccessParams},
+    decode::{InstructionDecode, InstructionDecodeParams, decode_instruction},
+    execute::{
+        CustomInstructions, InstructionExecute, InstructionExecuteParams, execute_instruction,
+    },
+    fetch::{InstructionFetch, InstructionFetchParams, InstructionValue},
+    memory_access::{InstructionMemoryAccess, InstructionMemoryAccessParams, access_width},
     write_back::{InstructionWriteBack, InstructionWriteBackParams},
 };
-use system_interface::{RamDevice, RomDevice, SystemInterface};
-use trap::{TrapInterface, TrapParams};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::{Index, IndexMut, Range},
+};
+
+use system_interface::{
+    MMIODevice, MMIOError, MMIOResult, RamDevice, RamWrites, RomDevice, SystemInterface,
+};
+use trap::{
+    MCAUSE_ENVIRONMENT_CALL_FROM_MMODE, MCAUSE_MACHINE_TIMER_INTERRUPT, PipelineTrapParams,
+    TrapInterface, TrapParams, TrapSnapshot,
+};
 use utils::LatchValue;
+use vcd::VcdRecorder;
 
 use crate::pipeline::{decode::DecodedValue, memory_access::MemoryAccessValue};
 
+pub use csr::{CSRError, CSRInterfaceBuilder, Counter};
+pub use pipeline::{
+    decode::{DecodedInstruction, IllegalPolicy},
+    execute::CustomInstruction,
+    memory_access::{MisalignedAccess, MisalignedAccessPolicy},
+};
+pub use system_interface::{
+    CLINT_END, CLINT_START, PROGRAM_ROM_END, PROGRAM_ROM_START, RAM_END, RAM_START,
+};
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum CPUState {
     Pipeline(PipelineState),
@@ -39,6 +74,255 @@ pub enum PipelineState {
 
 pub type RegisterFile = [u32; 32];
 
+/// Index into the register file. Only the low 5 bits are kept, so it is always in range
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct RegIndex(u8);
+
+impl RegIndex {
+    pub const ZERO: RegIndex = RegIndex(0);
+
+    pub const fn new(index: u8) -> Self {
+        RegIndex(index & 0x1F)
+    }
+
+    /// The register field of `instruction` starting at bit `shift` (7 for rd, 15 for rs1, 20 for rs2)
+    pub const fn from_field(instruction: u32, shift: u32) -> Self {
+        RegIndex(((instruction >> shift) & 0x1F) as u8)
+    }
+
+    pub const fn value(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Index<RegIndex> for RegisterFile {
+    type Output = u32;
+
+    fn index(&self, index: RegIndex) -> &u32 {
+        &self[index.0 as usize]
+    }
+}
+
+impl IndexMut<RegIndex> for RegisterFile {
+    fn index_mut(&mut self, index: RegIndex) -> &mut u32 {
+        &mut self[index.0 as usize]
+    }
+}
+
+/// What the CPU should do once a host ECALL handler has run
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EcallAction {
+    /// The ECALL has been serviced by the host, execution continues with the next instruction
+    Resume,
+    /// Raise the environment-call trap as normal
+    Trap,
+}
+
+/// Disassembly of the instruction each pipeline stage worked on in the last cycle, or `"bubble"`
+/// for stages that were stalled or flushed
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PipelineSnapshot {
+    pub fetch: String,
+    pub decode: String,
+    pub execute: String,
+    pub memory_access: String,
+    pub write_back: String,
+}
+
+/// How full the pipeline has been, accumulated over every cycle run, see
+/// `RV32ISystem::pipeline_occupancy_stats`
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct OccupancyStats {
+    pub cycles: u64,
+    /// Sum over every cycle of the number of stages holding an instruction
+    pub occupied: u64,
+    /// The most stages that held an instruction in a single cycle
+    pub max: usize,
+}
+
+impl OccupancyStats {
+    /// Average number of stages holding an instruction per cycle
+    pub fn average(&self) -> f64 {
+        match self.cycles {
+            0 => 0.0,
+            cycles => self.occupied as f64 / cycles as f64,
+        }
+    }
+}
+
+/// The state visible to software at an instruction boundary, without any of the pipeline latches,
+/// so that runs with different timing can be compared. Cycle counts are left out for the same
+/// reason, and so is ROM as the program can't write to it. `mtime` is kept as software can set
+/// it, so runs only compare equal if they took the same number of cycles.
+#[derive(PartialEq, Eq, Clone)]
+pub struct ArchState {
+    pub registers: RegisterFile,
+    /// Address of the next instruction to run
+    pub pc: u32,
+    /// `(address, value)` of each machine-mode CSR that software can change
+    pub csrs: Vec<(u32, u32)>,
+    pub instret: u64,
+    pub mtime: u64,
+    pub mtimecmp: u64,
+    /// Every word of RAM, in address order
+    pub ram: Vec<u32>,
+}
+
+impl std::fmt::Debug for ArchState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // RAM is far too big to print usefully
+        f.debug_struct("ArchState")
+            .field("registers", &self.registers)
+            .field("pc", &self.pc)
+            .field("csrs", &self.csrs)
+            .field("instret", &self.instret)
+            .field("mtime", &self.mtime)
+            .field("mtimecmp", &self.mtimecmp)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Relative energy cost of retiring one instruction of each class, see
+/// `RV32ISystem::enable_energy_model`. LUI and AUIPC count as ALU instructions, JAL and JALR as
+/// jumps, MRET and WFI as system instructions.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct EnergyCosts {
+    pub alu: f64,
+    pub load: f64,
+    pub store: f64,
+    pub branch: f64,
+    pub jump: f64,
+    pub system: f64,
+    pub fence: f64,
+    pub custom: f64,
+}
+
+impl Default for EnergyCosts {
+    fn default() -> Self {
+        Self {
+            alu: 1.0,
+            load: 1.0,
+            store: 1.0,
+            branch: 1.0,
+            jump: 1.0,
+            system: 1.0,
+            fence: 1.0,
+            custom: 1.0,
+        }
+    }
+}
+
+impl EnergyCosts {
+    fn cost(&self, instruction: DecodedInstruction) -> f64 {
+        match instruction {
+            DecodedInstruction::Alu { .. }
+            | DecodedInstruction::Lui { .. }
+            | DecodedInstruction::Auipc { .. } => self.alu,
+            DecodedInstruction::Load { .. } => self.load,
+            DecodedInstruction::Store { .. } => self.store,
+            DecodedInstruction::Branch { .. } => self.branch,
+            DecodedInstruction::Jal { .. } | DecodedInstruction::Jalr { .. } => self.jump,
+            DecodedInstruction::System { .. }
+            | DecodedInstruction::Mret { .. }
+            | DecodedInstruction::Wfi { .. } => self.system,
+            DecodedInstruction::Fence { .. } | DecodedInstruction::FenceI { .. } => self.fence,
+            DecodedInstruction::Custom { .. } => self.custom,
+            DecodedInstruction::None => 0.0,
+        }
+    }
+}
+
+/// What the CPU should do once a host trap handler has run
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TrapAction {
+    /// The trap has been serviced by the host, flush the pipeline and carry on fetching from the
+    /// given address without touching any CSRs
+    Resume(u32),
+    /// Take the trap as normal, through `mtvec`
+    Dispatch,
+}
+
+/// A bus access that failed with an error the architecture has no trap for, e.g. a device
+/// reporting an unaligned write on a read. The cycle it happened in can't complete, see
+/// `RV32ISystem::try_cycle`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum CycleError {
+    /// Fetching the instruction at `pc` failed
+    Fetch { pc: u32, error: MMIOError },
+    /// The load or store of the instruction at `pc` failed
+    MemoryAccess { pc: u32, error: MMIOError },
+}
+impl std::fmt::Display for CycleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CycleError::Fetch { pc, error } => {
+                write!(
+                    f,
+                    "Fetching the instruction at {:#08X} failed: {}",
+                    pc, error
+                )
+            }
+            CycleError::MemoryAccess { pc, error } => {
+                write!(
+                    f,
+                    "Memory access by the instruction at {:#08X} failed: {}",
+                    pc, error
+                )
+            }
+        }
+    }
+}
+
+/// A register name that isn't one of the ABI names, see `RV32ISystem::load_registers_map`
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct UnknownRegister(pub String);
+impl std::fmt::Display for UnknownRegister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown register: {}", self.0)
+    }
+}
+
+/// Host-side handler for traps, see `RV32ISystem::set_trap_handler`
+pub type TrapHandler = Box<dyn FnMut(&mut RV32ISystem, &PipelineTrapParams) -> TrapAction>;
+
+/// Host-side handler for ECALL, see `RV32ISystem::set_ecall_handler`
+pub type EcallHandler = Box<dyn FnMut(&mut RV32ISystem) -> EcallAction>;
+
+/// Host-side handler for stores into code, called with the PC of the store and the address
+/// written, see `RV32ISystem::set_code_write_handler`
+pub type CodeWriteHandler = Box<dyn FnMut(u32, u32)>;
+
+/// A condition the registers should hold after every instruction, see `RV32ISystem::add_invariant`
+pub type InvariantCheck = Box<dyn Fn(&RegisterFile) -> bool>;
+
+/// Host-side handler for broken invariants, called with the PC of the instruction that just
+/// retired and the name of the invariant, see `RV32ISystem::set_invariant_handler`
+pub type InvariantHandler = Box<dyn FnMut(u32, &str)>;
+
+/// An instruction as it retires and its effects, see `RV32ISystem::step` and
+/// `RV32ISystem::set_trace_hook`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RetiredInstruction {
+    pub pc: u32,
+    pub raw_instruction: u32,
+    pub instruction: DecodedInstruction,
+    /// The register written and its new value, `None` for instructions that don't write one or
+    /// that target `x0`
+    pub register_write: Option<(RegIndex, u32)>,
+    /// The address and value of a store, narrowed to the width stored
+    pub memory_write: Option<(u32, u32)>,
+}
+
+/// Host-side hook called with every instruction that retires, see `RV32ISystem::set_trace_hook`
+pub type TraceHook = Box<dyn FnMut(RetiredInstruction)>;
+
+/// Name of the invariant that is always checked once a handler is installed
+pub const INVARIANT_X0_ZERO: &str = "x0 is zero";
+
 pub struct RV32ISystem {
     pub bus: SystemInterface,
     pub csr: CSRInterface,
@@ -54,6 +338,51 @@ pub struct RV32ISystem {
     stage_ex: InstructionExecute,
     stage_ma: InstructionMemoryAccess,
     stage_wb: InstructionWriteBack,
+    ecall_handler: Option<EcallHandler>,
+    trap_handler: Option<TrapHandler>,
+    misaligned_policy: MisalignedAccessPolicy,
+    illegal_policy: IllegalPolicy,
+    misaligned_accesses: Vec<MisalignedAccess>,
+    code_write_handler: Option<CodeWriteHandler>,
+    /// Invariants are only checked while there's a handler to report them to
+    invariant_handler: Option<InvariantHandler>,
+    invariants: Vec<(&'static str, InvariantCheck)>,
+    trace_hook: Option<TraceHook>,
+    /// The instruction retired during the current `step`
+    last_retired: Option<RetiredInstruction>,
+    code_regions: Vec<Range<u32>>,
+    /// Regions `dump_rom` shows as data rather than instructions
+    data_regions: Vec<Range<u32>>,
+    /// Cycles run since reset, counted apart from the `cycle` CSR so nothing the guest or a
+    /// replaced `csr` does can change it
+    cycles: u64,
+    last_step_cycles: u64,
+    custom_instructions: CustomInstructions,
+    /// `None` while call tracking is disabled
+    call_stack: Option<Vec<(u32, u32)>>,
+    /// The cost model and the total so far, `None` while the energy model is disabled
+    energy: Option<(EnergyCosts, f64)>,
+    /// Interrupts still to be raised as `(cycle, mcause)`
+    scheduled_interrupts: Vec<(u64, u32)>,
+    /// Whether the most recently retired instruction jumped or branched to itself
+    halted: bool,
+    /// The state before each recent `step` (without RAM) and the RAM writes made from then until
+    /// the next step, newest last, and how many to keep. The newest step's writes are still being
+    /// logged by the RAM. `None` while history is disabled.
+    history: Option<(VecDeque<(ArchState, RamWrites)>, usize)>,
+    /// `None` while coverage is disabled
+    coverage: Option<Coverage>,
+    /// Set by `request_halt`, taken at the next instruction boundary
+    debug_halt_request: bool,
+    /// Whether the CPU is parked in the debug halt state until `resume`
+    debug_halted: bool,
+    /// See `set_strict_decode`
+    strict_decode: bool,
+    occupancy: OccupancyStats,
+    /// See `set_tohost`
+    tohost: Option<u32>,
+    /// `None` while VCD recording is disabled
+    vcd: Option<VcdRecorder>,
 }
 
 impl RV32ISystem {
@@ -74,25 +403,401 @@ impl RV32ISystem {
             stage_ex: InstructionExecute::new(),
             stage_ma: InstructionMemoryAccess::new(),
             stage_wb: InstructionWriteBack::new(),
+            ecall_handler: None,
+            trap_handler: None,
+            misaligned_policy: MisalignedAccessPolicy::default(),
+            illegal_policy: IllegalPolicy::default(),
+            misaligned_accesses: Vec::new(),
+            code_write_handler: None,
+            invariant_handler: None,
+            invariants: Vec::new(),
+            trace_hook: None,
+            last_retired: None,
+            code_regions: Vec::new(),
+            data_regions: Vec::new(),
+            cycles: 0,
+            last_step_cycles: 0,
+            custom_instructions: vec![],
+            call_stack: None,
+            energy: None,
+            scheduled_interrupts: Vec::new(),
+            halted: false,
+            history: None,
+            coverage: None,
+            debug_halt_request: false,
+            debug_halted: false,
+            strict_decode: false,
+            occupancy: OccupancyStats::default(),
+            tohost: None,
+            vcd: None,
         }
     }
 
-    pub fn compute(&mut self) {
-        let dec_values = self.stage_de.get_decoded_instruction_out();
+    /// Installs a host handler that is invoked whenever an ECALL is decoded, before the trap is
+    /// taken. The handler can inspect and modify the system (e.g. read `a0..a7` from `reg_file`
+    /// and write a return value to `a0`) and decides whether the normal trap still happens.
+    pub fn set_ecall_handler(&mut self, handler: EcallHandler) {
+        self.ecall_handler = Some(handler);
+    }
+
+    /// Installs a host handler that is invoked as any trap (exception or interrupt) is about to be
+    /// taken, after the ECALL handler for environment calls. It gets the trap's cause, `mepc` and
+    /// `mtval`, can inspect and modify the system, and decides whether to resume at an address of
+    /// its choosing or let the trap go through `mtvec` as normal.
+    pub fn set_trap_handler(&mut self, handler: TrapHandler) {
+        self.trap_handler = Some(handler);
+    }
+
+    /// Registers a custom instruction for the major `opcode` (bits 6..0), normally one of the
+    /// reserved custom-0..3 opcodes. Registered opcodes are only consulted for instructions the
+    /// decoder doesn't otherwise recognise.
+    pub fn register_custom(&mut self, opcode: u8, instruction: Box<dyn CustomInstruction>) {
+        self.custom_instructions.push((opcode & 0x7F, instruction));
+    }
+
+    /// Starts tracking calls and returns as instructions retire, see `call_stack`
+    pub fn enable_call_tracking(&mut self) {
+        self.call_stack.get_or_insert_with(Vec::new);
+    }
+
+    /// The calls currently in progress as `(caller_pc, target_pc)`, outermost first. A call is a
+    /// JAL/JALR linking into `x1` or `x5`, and a return is a `jalr x0` through one of them. A JALR
+    /// linking into one and jumping through the other is both, a coroutine swap. Empty unless
+    /// `enable_call_tracking` has been called.
+    pub fn call_stack(&self) -> &[(u32, u32)] {
+        self.call_stack.as_deref().unwrap_or(&[])
+    }
+
+    /// Starts accumulating an energy estimate as instructions retire, using `costs` per
+    /// instruction class. Resets the estimate if the model was already enabled.
+    pub fn enable_energy_model(&mut self, costs: EnergyCosts) {
+        self.energy = Some((costs, 0.0));
+    }
+
+    /// Sum of the costs of every instruction retired since `enable_energy_model`, or 0 if it
+    /// hasn't been called
+    pub fn energy_estimate(&self) -> f64 {
+        self.energy.map_or(0.0, |(_, total)| total)
+    }
+
+    /// Raises the interrupt `mcause` (one of the `MCAUSE_*_INTERRUPT` constants) once `cycle`
+    /// cycles have run, by setting its bit in `mip`. It is taken at the next instruction boundary
+    /// where it is enabled in `mie` and `mstatus.MIE` is set.
+    pub fn schedule_interrupt(&mut self, cycle: u64, mcause: u32) {
+        self.scheduled_interrupts.push((cycle, mcause));
+    }
+
+    fn raise_scheduled_interrupts(&mut self) {
+        let now = self.cycle_count();
+        let csr = &mut self.csr;
+        self.scheduled_interrupts.retain(|(cycle, mcause)| {
+            if *cycle > now {
+                return true;
+            }
+            csr.raise_interrupt(*mcause);
+            false
+        });
+    }
+
+    /// Advances `mtime` by a cycle, mirroring the timer's interrupt line into `mip`
+    pub(crate) fn tick_timer(&mut self) {
+        match self.bus.timer.tick() {
+            Some(true) => self.csr.raise_interrupt(MCAUSE_MACHINE_TIMER_INTERRUPT),
+            Some(false) => self.csr.clear_interrupt(MCAUSE_MACHINE_TIMER_INTERRUPT),
+            None => {}
+        }
+    }
+
+    /// Called as an instruction completes write-back
+    fn retire(&mut self) {
         let mem_values = self.stage_ma.get_memory_access_value_out();
+        if let Some((costs, total)) = self.energy.as_mut() {
+            *total += costs.cost(mem_values.instruction);
+        }
+        self.record_coverage(mem_values.instruction, mem_values.raw_instruction);
+        self.check_invariants(mem_values.pc);
+        self.check_code_write(mem_values.pc, mem_values.instruction);
+        let memory_write = match mem_values.instruction {
+            DecodedInstruction::Store {
+                funct3,
+                rs1,
+                rs2,
+                imm32,
+            } => {
+                let value = match access_width(funct3) {
+                    Some(width) if width < 4 => rs2 & ((1 << (width * 8)) - 1),
+                    _ => rs2,
+                };
+                Some((rs1.wrapping_add_signed(imm32), value))
+            }
+            _ => None,
+        };
+        let retired = RetiredInstruction {
+            pc: mem_values.pc,
+            raw_instruction: mem_values.raw_instruction,
+            instruction: mem_values.instruction,
+            register_write: mem_values
+                .instruction
+                .rd()
+                .filter(|rd| !rd.is_zero())
+                .map(|rd| (rd, mem_values.write_back_value)),
+            memory_write,
+        };
+        if let Some(hook) = self.trace_hook.as_mut() {
+            hook(retired);
+        }
+        self.last_retired = Some(retired);
+        self.halted = match mem_values.instruction {
+            DecodedInstruction::Jal { branch_address, .. }
+            | DecodedInstruction::Jalr { branch_address, .. }
+            | DecodedInstruction::Branch { branch_address, .. } => branch_address == mem_values.pc,
+            _ => false,
+        };
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            let is_link = |index: RegIndex| index.value() == 1 || index.value() == 5;
+            let rs1 = RegIndex::from_field(mem_values.raw_instruction, 15);
+            match mem_values.instruction {
+                // a coroutine swap, returning through one link register and calling through the other
+                DecodedInstruction::Jalr { rd, branch_address }
+                    if is_link(rd) && is_link(rs1) && rd != rs1 =>
+                {
+                    call_stack.pop();
+                    call_stack.push((mem_values.pc, branch_address));
+                }
+                DecodedInstruction::Jal { rd, branch_address }
+                | DecodedInstruction::Jalr { rd, branch_address }
+                    if is_link(rd) =>
+                {
+                    call_stack.push((mem_values.pc, branch_address));
+                }
+                DecodedInstruction::Jalr { rd, .. } if rd.is_zero() && is_link(rs1) => {
+                    call_stack.pop();
+                }
+                _ => {}
+            }
+        }
+    }
 
-        self.mret = dec_values.return_from_trap;
+    /// Whether the program has parked itself in a tight self-loop such as `jal x0, 0`, i.e. the
+    /// last instruction to retire jumped or branched to its own address. The loop keeps running
+    /// (and retiring) as normal, so an interrupt can still break out of it.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
 
-        // prefer traps later in the pipeline
-        let trap_params = match (dec_values, mem_values) {
-            (DecodedValue { .. }, MemoryAccessValue { trap_params, .. }) if trap_params.trap => {
-                Some(trap_params)
+    /// With strict decoding, instructions with reserved values in their fields (such as an
+    /// undefined funct7 for an ALU op) raise illegal-instruction. By default those fields are
+    /// ignored and the nearest defined instruction runs.
+    pub fn set_strict_decode(&mut self, strict: bool) {
+        self.strict_decode = strict;
+    }
+
+    /// Selects how misaligned loads and stores are handled, by default they trap
+    pub fn set_misaligned_access_policy(&mut self, policy: MisalignedAccessPolicy) {
+        self.misaligned_policy = policy;
+    }
+
+    /// Selects how instructions that decode as illegal are handled, by default they trap
+    pub fn set_illegal_policy(&mut self, policy: IllegalPolicy) {
+        self.illegal_policy = policy;
+    }
+
+    /// Misaligned accesses emulated so far under `MisalignedAccessPolicy::Record`, oldest first
+    pub fn misaligned_accesses(&self) -> &[MisalignedAccess] {
+        &self.misaligned_accesses
+    }
+
+    /// Sets how many instructions the fetch stage reads from the bus at once into its fetch buffer.
+    /// This only affects bus traffic, not the results of execution.
+    pub fn set_fetch_width(&mut self, width: usize) {
+        self.stage_if.set_width(width);
+    }
+
+    /// Installs a handler that reports stores into code: the program currently loaded into ROM, or
+    /// any region marked with `mark_code_region`. The store itself still goes ahead as normal, and
+    /// is reported as it retires if any of the bytes it wrote are code.
+    pub fn set_code_write_handler(&mut self, handler: CodeWriteHandler) {
+        self.code_write_handler = Some(handler);
+    }
+
+    /// Starts checking the registers each time an instruction retires, reporting every invariant
+    /// that doesn't hold to `handler`. `x0` being zero is always checked, see `add_invariant` for
+    /// others such as the stack pointer staying in RAM.
+    pub fn set_invariant_handler(&mut self, handler: InvariantHandler) {
+        self.invariant_handler = Some(handler);
+    }
+
+    /// Installs a hook that is called once for every instruction as it retires in write-back,
+    /// i.e. each time `instret` goes up. Stalled cycles and instructions flushed by a trap are
+    /// never reported.
+    pub fn set_trace_hook(&mut self, hook: TraceHook) {
+        self.trace_hook = Some(hook);
+    }
+
+    /// Adds an invariant checked by `set_invariant_handler`, `check` returns whether it holds
+    pub fn add_invariant(&mut self, name: &'static str, check: InvariantCheck) {
+        self.invariants.push((name, check));
+    }
+
+    fn check_invariants(&mut self, pc: u32) {
+        let Some(handler) = self.invariant_handler.as_mut() else {
+            return;
+        };
+        if self.reg_file[0] != 0 {
+            handler(pc, INVARIANT_X0_ZERO);
+        }
+        for (name, check) in &self.invariants {
+            if !check(&self.reg_file) {
+                handler(pc, name);
+            }
+        }
+    }
+
+    /// Marks `region` of the address space (typically code loaded into RAM) as code for the
+    /// purposes of `set_code_write_handler`
+    pub fn mark_code_region(&mut self, region: Range<u32>) {
+        self.code_regions.push(region);
+    }
+
+    /// Marks `region` of the address space (such as a vector table) as data, so `dump_rom` shows it
+    /// as `.word` values instead of disassembling it
+    pub fn mark_data_region(&mut self, region: Range<u32>) {
+        self.data_regions.push(region);
+    }
+
+    /// Disassembly of the program currently loaded into ROM, see `disasm::disassemble_words`
+    pub fn dump_rom(&self) -> Vec<String> {
+        let words: Vec<u32> = (0..self.bus.rom.loaded_size())
+            .step_by(4)
+            .map(|offset| {
+                self.bus
+                    .rom
+                    .read_word(offset)
+                    .expect("ROM reads can't fail")
+            })
+            .collect();
+        disassemble_words(self.rom_base(), &words, &self.data_regions)
+    }
+
+    /// Whether any of the `width` bytes from `address` are code
+    fn is_code_range(&self, address: u32, width: u32) -> bool {
+        let end = address.saturating_add(width);
+        let rom_program = self.rom_base()..self.rom_base() + self.bus.rom.loaded_size();
+        std::iter::once(&rom_program)
+            .chain(&self.code_regions)
+            .any(|region| address < region.end && region.start < end)
+    }
+
+    /// Reports `instruction` to the code write handler if it's a store into code, once the store
+    /// has gone through
+    fn check_code_write(&mut self, pc: u32, instruction: DecodedInstruction) {
+        let DecodedInstruction::Store {
+            funct3, rs1, imm32, ..
+        } = instruction
+        else {
+            return;
+        };
+        let Some(width) = access_width(funct3) else {
+            return;
+        };
+        let address = rs1.wrapping_add_signed(imm32);
+        if !self.is_code_range(address, width) {
+            return;
+        }
+        if let Some(handler) = self.code_write_handler.as_mut() {
+            handler(pc, address);
+        }
+    }
+
+    fn handle_ecall(&mut self) -> EcallAction {
+        match self.ecall_handler.take() {
+            Some(mut handler) => {
+                let action = handler(self);
+                // the handler may have installed a replacement for itself
+                if self.ecall_handler.is_none() {
+                    self.ecall_handler = Some(handler);
+                }
+                action
             }
-            (DecodedValue { trap_params, .. }, MemoryAccessValue { .. }) if trap_params.trap => {
-                Some(trap_params)
+            None => EcallAction::Trap,
+        }
+    }
+
+    fn handle_trap(&mut self, trap_params: &PipelineTrapParams) -> TrapAction {
+        match self.trap_handler.take() {
+            Some(mut handler) => {
+                let action = handler(self, trap_params);
+                // the handler may have installed a replacement for itself
+                if self.trap_handler.is_none() {
+                    self.trap_handler = Some(handler);
+                }
+                action
             }
+            None => TrapAction::Dispatch,
+        }
+    }
+
+    /// Abandons everything in the pipeline and fetches from `address` next cycle
+    fn resume_at(&mut self, address: u32) {
+        self.stage_if.reset();
+        self.stage_de.reset();
+        self.stage_ex.reset();
+        self.stage_ma.reset();
+        self.stage_wb.reset();
+        self.stage_if.pc.set(address);
+        self.stage_if.pc_plus_4.set(address);
+        self.state.set(CPUState::Pipeline(PipelineState::Fetch));
+    }
+
+    pub fn compute(&mut self) {
+        self.raise_scheduled_interrupts();
+        self.tick_timer();
+        let mut dec_values = self.stage_de.get_decoded_instruction_out();
+        let mem_values = self.stage_ma.get_memory_access_value_out();
+        let fetch_trap_params = self.stage_if.get_trap_params_out();
+
+        if dec_values.trap_params.trap
+            && dec_values.trap_params.mcause == MCAUSE_ENVIRONMENT_CALL_FROM_MMODE
+            && self.handle_ecall() == EcallAction::Resume
+        {
+            dec_values.trap_params = PipelineTrapParams::default();
+        }
+
+        let return_from_trap = dec_values.return_from_trap;
+
+        // interrupts are only taken between instructions, resuming at the next one. An mret in
+        // decode always completes first, so a still pending interrupt is taken at the restored pc.
+        let interrupt = match self.state.get() {
+            CPUState::Pipeline(PipelineState::Fetch) => self.csr.pending_interrupt(),
             _ => None,
         };
+
+        // prefer traps later in the pipeline
+        let trap_params = match (fetch_trap_params, dec_values, mem_values) {
+            (_, _, MemoryAccessValue { trap_params, .. }) if trap_params.trap => Some(trap_params),
+            (_, DecodedValue { trap_params, .. }, _) if trap_params.trap => Some(trap_params),
+            (trap_params, _, _) if trap_params.trap => Some(trap_params),
+            _ => interrupt.map(|mcause| PipelineTrapParams {
+                mepc: self.next_fetch_address(),
+                mcause,
+                mtval: 0,
+                trap: true,
+            }),
+        };
+        if let (Some(params), CPUState::Pipeline(_)) = (trap_params.as_ref(), self.state.get()) {
+            if let TrapAction::Resume(address) = self.handle_trap(params) {
+                // nothing else happens this cycle, the pipeline starts over at `address`
+                self.resume_at(address);
+                self.trap_stall = true;
+                self.mret = false;
+                self.csr.compute();
+                return;
+            }
+        }
+        let begin_trap = trap_params.is_some();
+        // a trap takes precedence over an mret in decode, the mret is flushed along with the rest
+        // of the pipeline and runs again once the handler returns
+        self.mret = return_from_trap && !begin_trap;
         self.trap_stall = self.state.get() == &CPUState::Trap || trap_params.is_some() || self.mret;
 
         if self.trap_stall && matches!(self.state.get(), &CPUState::Pipeline(_)) {
@@ -123,33 +828,49 @@ impl RV32ISystem {
             self.stage_wb.reset();
         }
 
+        let fetch_stall =
+            self.trap_stall || *self.state.get() != CPUState::Pipeline(PipelineState::Fetch);
         self.stage_if.compute(InstructionFetchParams {
-            should_stall: self.trap_stall
-                || *self.state.get() != CPUState::Pipeline(PipelineState::Fetch),
-            branch_address: match self.stage_ex.get_execution_value_out().instruction {
-                DecodedInstruction::Jal { branch_address, .. } => Some(branch_address),
-                DecodedInstruction::Branch { branch_address, .. } => Some(branch_address),
-                _ => None,
-            },
+            should_stall: fetch_stall,
+            next_address: self.next_fetch_address(),
             bus: &self.bus,
         });
+        if !fetch_stall {
+            // a jump or branch redirects fetch once, execute may hold it for several more cycles
+            self.stage_ex.clear_redirect();
+        }
         self.stage_de.compute(InstructionDecodeParams {
             should_stall: self.trap_stall
                 || *self.state.get() != CPUState::Pipeline(PipelineState::Decode),
             instruction_in: self.stage_if.get_instruction_value_out(),
             reg_file: &mut self.reg_file,
+            custom_instructions: &self.custom_instructions,
+            strict: self.strict_decode,
+            illegal_policy: self.illegal_policy,
         });
         self.stage_ex.compute(InstructionExecuteParams {
             should_stall: self.trap_stall
                 || *self.state.get() != CPUState::Pipeline(PipelineState::Execute),
             decoded_instruction_in: self.stage_de.get_decoded_instruction_out(),
+            custom_instructions: &mut self.custom_instructions,
         });
+        if !self.trap_stall
+            && *self.state.get() == CPUState::Pipeline(PipelineState::MemoryAccess)
+            && matches!(
+                self.stage_ex.get_execution_value_out().instruction,
+                DecodedInstruction::FenceI {}
+            )
+        {
+            self.stage_if.flush_buffer();
+        }
         self.stage_ma.compute(InstructionMemoryAccessParams {
             should_stall: self.trap_stall
                 || *self.state.get() != CPUState::Pipeline(PipelineState::MemoryAccess),
             execution_value_in: self.stage_ex.get_execution_value_out(),
             bus: &mut self.bus,
             csr: &mut self.csr,
+            misaligned_policy: self.misaligned_policy,
+            misaligned_accesses: &mut self.misaligned_accesses,
         });
         self.stage_wb.compute(InstructionWriteBackParams {
             should_stall: self.trap_stall
@@ -160,11 +881,14 @@ impl RV32ISystem {
         self.csr.compute();
         self.trap.compute(TrapParams {
             csr: &mut self.csr,
-            begin_trap: self.stage_de.get_decoded_instruction_out().trap_params.trap
-                || self.stage_ma.get_memory_access_value_out().trap_params.trap,
-            begin_trap_return: self.stage_de.get_decoded_instruction_out().return_from_trap,
+            begin_trap,
+            begin_trap_return: self.mret,
         });
 
+        if !self.trap_stall && *self.state.get() == CPUState::Pipeline(PipelineState::WriteBack) {
+            self.retire();
+        }
+
         if !self.trap_stall {
             self.state.set(match *self.state.get() {
                 CPUState::Pipeline(PipelineState::Fetch) => {
@@ -180,7 +904,7 @@ impl RV32ISystem {
                     CPUState::Pipeline(PipelineState::WriteBack)
                 }
                 CPUState::Pipeline(PipelineState::WriteBack) => {
-                    self.csr.instret.set(self.csr.instret.get() + 1);
+                    self.csr.instret.set(self.csr.instret.get().wrapping_add(1));
                     CPUState::Pipeline(PipelineState::Fetch)
                 }
                 _ => *self.state.get(),
@@ -199,90 +923,483 @@ impl RV32ISystem {
         self.state.latch_next();
     }
 
+    /// Runs one cycle, panicking on a `CycleError`. See `try_cycle` to handle those instead.
     pub fn cycle(&mut self) {
+        if let Err(e) = self.try_cycle() {
+            panic!("{}", e);
+        }
+    }
+
+    /// Runs one cycle. On a `CycleError` nothing is latched, so the pipeline, registers and CSRs
+    /// are left as they were before the cycle, apart from the timer ticking, scheduled interrupts
+    /// being raised and whatever part of a store made it to the bus.
+    pub fn try_cycle(&mut self) -> Result<(), CycleError> {
+        if self.debug_halt_request && *self.state.get() == CPUState::Pipeline(PipelineState::Fetch)
+        {
+            self.debug_halted = true;
+        }
+        if self.debug_halted {
+            return Ok(());
+        }
         self.compute();
+        if let Some(error) = self
+            .stage_if
+            .get_error_out()
+            .or_else(|| self.stage_ma.get_error_out())
+        {
+            return Err(error);
+        }
         self.latch_next();
-    }
+        self.cycles = self.cycles.wrapping_add(1);
 
-    pub fn current_line(&self) -> u32 {
-        self.stage_if.get_instruction_value_out().pc
+        let occupancy = self.pipeline_occupancy();
+        self.occupancy.cycles += 1;
+        self.occupancy.occupied += occupancy as u64;
+        self.occupancy.max = self.occupancy.max.max(occupancy);
+        self.record_vcd();
+        Ok(())
     }
-}
 
-impl Default for RV32ISystem {
-    fn default() -> Self {
-        Self::new()
+    /// How many of the five stages hold an instruction rather than a bubble this cycle. The
+    /// pipeline is serialized, so this is at most 1, and 0 while a trap is being taken.
+    pub fn pipeline_occupancy(&self) -> usize {
+        [
+            self.stage_if.is_active(),
+            self.stage_de.is_active(),
+            self.stage_ex.is_active(),
+            self.stage_ma.is_active(),
+            self.stage_wb.is_active(),
+        ]
+        .iter()
+        .filter(|active| **active)
+        .count()
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        pipeline::{
-            decode::{DecodedInstruction, DecodedValue},
-            execute::ExecutionValue,
-            fetch::InstructionValue,
-            memory_access::MemoryAccessValue,
-        },
-        system_interface::MMIODevice,
-        trap::{MCAUSE_LOAD_ADDRESS_MISALIGNED, PipelineTrapParams, TrapState},
-    };
 
-    macro_rules! run_instruction {
-        ($rv:expr) => {
-            $rv.cycle();
-            $rv.cycle();
-            $rv.cycle();
-            $rv.cycle();
-            $rv.cycle();
-            assert_eq!(*$rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
-        };
+    /// `pipeline_occupancy` accumulated over every cycle run so far. Instructions run by
+    /// `interpret` bypass the pipeline and aren't counted.
+    pub fn pipeline_occupancy_stats(&self) -> OccupancyStats {
+        self.occupancy
     }
 
-    #[test]
-    fn test_rom_read() {
-        let mut rv = RV32ISystem::new();
-        rv.bus.rom.load(vec![0xDEAD_BEEF, 0xC0DE_CAFE]);
-        assert_eq!(rv.bus.read_word(0x1000_0000), Ok(0xDEAD_BEEF));
-        assert_eq!(rv.bus.read_word(0x1000_0004), Ok(0xC0DE_CAFE));
-        assert_eq!(rv.bus.read_word(0x1000_0008), Ok(0xFFFF_FFFF));
+    /// Asks the CPU to halt for the debugger, like the debug module's halt request. The
+    /// instruction in flight (and any trap it causes) completes, then `cycle` does nothing, the
+    /// cycle counter included, until `resume` is called. Unlike a self-loop nothing is retired
+    /// while halted.
+    pub fn request_halt(&mut self) {
+        self.debug_halt_request = true;
     }
 
-    #[test]
-    fn test_rom_write_does_nothing() {
-        let mut rv = RV32ISystem::new();
-        rv.bus.write_word(0x1000_0000, 0xDEAD_BEEF).unwrap();
-        rv.bus.write_word(0x1000_0004, 0xC0DE_CAFE).unwrap();
-        assert_eq!(rv.bus.read_word(0x1000_0000), Ok(0xFFFF_FFFF));
-        assert_eq!(rv.bus.read_word(0x1000_0004), Ok(0xFFFF_FFFF));
+    /// Whether the CPU has reached the debug halt state requested with `request_halt`
+    pub fn is_debug_halted(&self) -> bool {
+        self.debug_halted
     }
 
-    #[test]
-    fn test_ram_write_read() {
-        let mut rv = RV32ISystem::new();
-        rv.bus.write_word(0x2000_0000, 0xDEAD_BEEF).unwrap();
-        rv.bus.write_word(0x2000_0004, 0xC0DE_CAFE).unwrap();
-        assert_eq!(rv.bus.read_word(0x2000_0000), Ok(0xDEAD_BEEF));
-        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xC0DE_CAFE));
+    /// Leaves the debug halt state, or cancels a halt request that hasn't been taken yet.
+    /// Execution continues from the next instruction.
+    pub fn resume(&mut self) {
+        self.debug_halt_request = false;
+        self.debug_halted = false;
     }
 
-    #[test]
-    fn test_ram_write_wrap_around() {
-        let mut rv = RV32ISystem::new();
-        rv.bus.write_word(0x2040_0000, 0xDEAD_BEEF).unwrap();
-        rv.bus.write_word(0x2040_0004, 0xC0DE_CAFE).unwrap();
-        assert_eq!(rv.bus.read_word(0x2000_0000), Ok(0xDEAD_BEEF));
-        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xC0DE_CAFE));
+    /// Cycles until the CPU is ready to fetch the next instruction, i.e. runs one instruction
+    /// including any trap entry or return it causes. Returns the instruction that retired, or
+    /// `None` if nothing did because it trapped or the CPU is in the debug halt state.
+    pub fn step(&mut self) -> Option<RetiredInstruction> {
+        if let Some((mut history, max_len)) = self.history.take() {
+            if let Some((_, writes)) = history.back_mut() {
+                *writes = self.bus.ram.take_writes();
+            }
+            if history.len() == max_len {
+                history.pop_front();
+            }
+            history.push_back((self.state_without_ram(), vec![]));
+            self.history = Some((history, max_len));
+        }
+        let start = self.cycle_count();
+        self.last_retired = None;
+        loop {
+            self.cycle();
+            if *self.state.get() == CPUState::Pipeline(PipelineState::Fetch) {
+                break;
+            }
+        }
+        self.last_step_cycles = self.cycle_count().wrapping_sub(start);
+        self.last_retired.take()
     }
 
-    #[test]
-    fn test_error_on_misaligned_read() {
-        let rv = RV32ISystem::new();
-        assert_eq!(
-            rv.bus.read_word(0x1000_0005).map_err(|e| format!("{}", e)),
-            Err("Unaligned read from address 0x10000005".to_string())
-        );
+    /// Starts keeping the architectural state from before each of the last `max_len` steps, so they
+    /// can be undone with `step_back`. RAM is kept as a log of the words each step overwrote.
+    pub fn enable_history(&mut self, max_len: usize) {
+        assert!(max_len > 0, "History length must be at least 1");
+        self.history = Some((VecDeque::with_capacity(max_len), max_len));
+        self.bus.ram.log_writes();
+    }
+
+    /// Undoes the most recent `step` still in the history, returning `false` if there is nothing
+    /// left to undo. The cycle counter isn't rewound (though `mtime` is), and anything outside of
+    /// `ArchState` (such as attached devices) isn't restored.
+    pub fn step_back(&mut self) -> bool {
+        let Some((state, writes)) = self
+            .history
+            .as_mut()
+            .and_then(|(history, _)| history.pop_back())
+        else {
+            return false;
+        };
+        let latest_writes = self.bus.ram.take_writes();
+        self.bus.ram.undo_writes(&latest_writes);
+        self.bus.ram.undo_writes(&writes);
+        self.restore_state_without_ram(&state);
+        true
+    }
+
+    /// Puts the system back into `state`, abandoning anything in the pipeline
+    pub fn restore_architectural_state(&mut self, state: &ArchState) {
+        self.restore_state_without_ram(state);
+        self.bus.ram.restore_words(&state.ram);
+    }
+
+    fn restore_state_without_ram(&mut self, state: &ArchState) {
+        self.stage_if.reset();
+        self.stage_de.reset();
+        self.stage_ex.reset();
+        self.stage_ma.reset();
+        self.stage_wb.reset();
+        self.trap.clear();
+        self.state.reset();
+        self.halted = false;
+
+        self.reg_file = state.registers;
+        self.set_reset_vector(state.pc);
+        self.csr.restore_machine_state(&state.csrs);
+        self.csr.instret.set(state.instret);
+        self.csr.instret.latch_next();
+        self.bus.timer.restore(state.mtime, state.mtimecmp);
+        if self.bus.timer.is_pending() {
+            self.csr.raise_interrupt(MCAUSE_MACHINE_TIMER_INTERRUPT);
+        } else {
+            self.csr.clear_interrupt(MCAUSE_MACHINE_TIMER_INTERRUPT);
+        }
+    }
+
+    /// Number of cycles taken by the most recent `step`
+    pub fn last_step_cycles(&self) -> u64 {
+        self.last_step_cycles
+    }
+
+    /// Total cycles run since reset. This is the host's count, the guest's `cycle` CSR normally
+    /// matches it but isn't read here.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Full 64-bit value of `counter`, read in one go. Guests have to read these as separate low
+    /// and high CSRs, which can tear if the low half wraps between the reads.
+    pub fn read_counter64(&self, counter: Counter) -> u64 {
+        self.csr.read_counter(counter)
+    }
+
+    /// Committed outputs of the trap interface this cycle, for debugging trap handling
+    pub fn trap_state(&self) -> TrapSnapshot {
+        self.trap.snapshot()
+    }
+
+    pub fn current_line(&self) -> u32 {
+        self.stage_if.get_instruction_value_out().pc
+    }
+
+    /// Writes `bytes` into RAM starting at `address`. If they don't all fit in RAM nothing is
+    /// written, and the error is an access fault at the first address outside it.
+    pub fn load_ram(&mut self, address: u32, bytes: &[u8]) -> MMIOResult<()> {
+        let ram_range = self.bus.ram_range();
+        if !ram_range.contains(&address) {
+            return Err(MMIOError::AccessFault(address));
+        }
+        let end = u32::try_from(bytes.len())
+            .ok()
+            .and_then(|len| address.checked_add(len));
+        if end.is_none_or(|end| end > ram_range.end) {
+            return Err(MMIOError::AccessFault(ram_range.end));
+        }
+        for (byte_address, byte) in (address..).zip(bytes) {
+            self.bus.write_byte(byte_address, *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Address the program ROM starts at, where execution begins after reset
+    pub fn rom_base(&self) -> u32 {
+        self.bus.rom_range().start
+    }
+
+    /// Address the RAM starts at
+    pub fn ram_base(&self) -> u32 {
+        self.bus.ram_range().start
+    }
+
+    /// Points the next instruction fetch at `address`, e.g. to start executing a program loaded
+    /// with `load_ram`. Should be called before the first cycle.
+    pub fn set_reset_vector(&mut self, address: u32) {
+        self.stage_if.pc.set(address);
+        self.stage_if.pc.latch_next();
+        self.stage_if.pc_plus_4.set(address);
+        self.stage_if.pc_plus_4.latch_next();
+    }
+
+    pub fn pipeline_snapshot(&self) -> PipelineSnapshot {
+        let describe = |active: bool, pc: u32, raw_instruction: u32| match active {
+            true => disassemble(pc, raw_instruction),
+            false => "bubble".to_string(),
+        };
+        let if_values = self.stage_if.get_instruction_value_out();
+        let de_values = self.stage_de.get_decoded_instruction_out();
+        let ex_values = self.stage_ex.get_execution_value_out();
+        let ma_values = self.stage_ma.get_memory_access_value_out();
+        let wb_values = self.stage_wb.get_write_back_value_out();
+        PipelineSnapshot {
+            fetch: describe(
+                self.stage_if.is_active(),
+                if_values.pc,
+                if_values.raw_instruction,
+            ),
+            decode: describe(
+                self.stage_de.is_active(),
+                de_values.pc,
+                de_values.raw_instruction,
+            ),
+            execute: describe(
+                self.stage_ex.is_active(),
+                ex_values.pc,
+                ex_values.raw_instruction,
+            ),
+            memory_access: describe(
+                self.stage_ma.is_active(),
+                ma_values.pc,
+                ma_values.raw_instruction,
+            ),
+            write_back: describe(
+                self.stage_wb.is_active(),
+                wb_values.pc,
+                wb_values.raw_instruction,
+            ),
+        }
+    }
+
+    /// Snapshot of the architectural state, see `ArchState`. Only meaningful between instructions,
+    /// i.e. after `step` or `interpret` rather than part way through with `cycle`.
+    pub fn architectural_state(&self) -> ArchState {
+        ArchState {
+            ram: self.bus.ram.words().to_vec(),
+            ..self.state_without_ram()
+        }
+    }
+
+    /// `architectural_state` with `ram` left empty
+    fn state_without_ram(&self) -> ArchState {
+        ArchState {
+            registers: self.reg_file,
+            pc: self.next_fetch_address(),
+            csrs: self.csr.machine_state(),
+            instret: self.read_counter64(Counter::Instret),
+            mtime: self.bus.timer.mtime(),
+            mtimecmp: self.bus.timer.mtimecmp(),
+            ram: vec![],
+        }
+    }
+
+    /// Address the fetch stage will read from next, taking any pending jump or branch into account
+    fn next_fetch_address(&self) -> u32 {
+        self.stage_ex
+            .get_redirect_out()
+            .unwrap_or(*self.stage_if.pc_plus_4.get())
+    }
+
+    /// Where control goes after the instruction at the next fetch address, without running it:
+    /// the target of a jump, the target or fall through of a branch given the current register
+    /// values, or the following instruction for anything else. Traps (and ECALLs) aren't
+    /// predicted. Only meaningful between instructions.
+    pub fn predict_next_pc(&self) -> u32 {
+        let pc = self.next_fetch_address();
+        let raw_instruction = self.bus.read_word(pc).unwrap_or(0);
+        let decoded = decode_instruction(
+            &InstructionValue {
+                pc,
+                pc_plus_4: pc.wrapping_add(4),
+                raw_instruction,
+            },
+            &self.reg_file,
+            &self.custom_instructions,
+        );
+        match decoded.instruction {
+            DecodedInstruction::Jal { branch_address, .. }
+            | DecodedInstruction::Jalr { branch_address, .. } => branch_address,
+            DecodedInstruction::Branch { .. } => {
+                // branches don't touch the custom instructions
+                match execute_instruction(&decoded, &mut vec![]).instruction {
+                    DecodedInstruction::Branch { branch_address, .. } => branch_address,
+                    _ => decoded.pc_plus_4,
+                }
+            }
+            _ => decoded.pc_plus_4,
+        }
+    }
+
+    /// Reads `len` bytes from `address` for inspection, without the side effects a guest read
+    /// could have. Never fails: unmapped addresses and failing reads give 0.
+    pub fn peek_bytes(&self, address: u32, len: usize) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| self.bus.peek_byte(address.wrapping_add(i)).unwrap_or(0))
+            .collect()
+    }
+
+    /// Reads the little-endian word at `address` like `peek_bytes`, `address` needn't be aligned
+    pub fn peek_word(&self, address: u32) -> u32 {
+        let bytes = self.peek_bytes(address, 4);
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// Register values keyed by their ABI names (`zero`, `ra`, `sp`, ... `t6`), for front-ends
+    /// that export state as JSON
+    pub fn registers_map(&self) -> BTreeMap<&'static str, u32> {
+        asm::ABI_NAMES
+            .iter()
+            .copied()
+            .zip(self.reg_file.iter().copied())
+            .collect()
+    }
+
+    /// Sets the registers named in `registers`, by ABI name as in `registers_map`. Registers that
+    /// aren't named keep their value and `zero` is ignored. If any name is unknown no register is
+    /// changed.
+    pub fn load_registers_map(
+        &mut self,
+        registers: &BTreeMap<&str, u32>,
+    ) -> Result<(), UnknownRegister> {
+        let indexed = registers
+            .iter()
+            .map(|(name, value)| {
+                asm::ABI_NAMES
+                    .iter()
+                    .position(|abi_name| abi_name == name)
+                    .map(|index| (index, *value))
+                    .ok_or_else(|| UnknownRegister(name.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (index, value) in indexed {
+            if index != 0 {
+                self.reg_file[index] = value;
+            }
+        }
+        Ok(())
+    }
+
+    /// Dumps `x0..x31` as consecutive little-endian words, for diffing against the register dumps
+    /// of reference simulators such as spike or qemu.
+    pub fn dump_registers_bin(&self) -> [u8; 128] {
+        let mut dump = [0u8; 128];
+        for (chunk, value) in dump.chunks_exact_mut(4).zip(self.reg_file.iter()) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        dump
+    }
+
+    /// Dumps the PC of the next instruction to be fetched as a little-endian word, to accompany
+    /// `dump_registers_bin`. Only meaningful between instructions, i.e. in the Fetch state.
+    pub fn dump_pc_bin(&self) -> [u8; 4] {
+        self.next_fetch_address().to_le_bytes()
+    }
+}
+
+impl Default for RV32ISystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        csr::{
+            CSRM_MODE_MARCHID, CSRM_MODE_MEPC, CSRM_MODE_MHARTID, CSRM_MODE_MIE, CSRM_MODE_MIP,
+            CSRM_MODE_MISA, CSRM_MODE_MSCRATCH, CSRM_MODE_MSTATUSH, CSRM_MODE_MTVEC, DEFAULT_MISA,
+            MSTATUS_MASK,
+        },
+        pipeline::{
+            decode::{DecodedInstruction, DecodedValue},
+            execute::ExecutionValue,
+            fetch::InstructionValue,
+            memory_access::MemoryAccessValue,
+        },
+        system_interface::{
+            MMIOError, MMIOResult, MTIME_OFFSET, MTIMECMP_OFFSET, Permissions, ROM_ERASED_WORD,
+        },
+        trap::{
+            MCAUSE_ILLEGAL_INSTRUCTION, MCAUSE_INSTRUCTION_ACCESS_FAULT,
+            MCAUSE_INSTRUCTION_ADDRESS_MISALIGNED, MCAUSE_LOAD_ACCESS_FAULT,
+            MCAUSE_LOAD_ADDRESS_MISALIGNED, MCAUSE_MACHINE_EXTERNAL_INTERRUPT,
+            MCAUSE_MACHINE_TIMER_INTERRUPT, MCAUSE_STORE_AMO_ACCESS_FAULT,
+            MCAUSE_STORE_AMO_ADDRESS_MISALIGNED, MSTATUS_MIE_MASK, PipelineTrapParams, TrapState,
+            VectorLayout,
+        },
+    };
+
+    macro_rules! run_instruction {
+        ($rv:expr) => {
+            $rv.cycle();
+            $rv.cycle();
+            $rv.cycle();
+            $rv.cycle();
+            $rv.cycle();
+            assert_eq!(*$rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
+        };
+    }
+
+    #[test]
+    fn test_rom_read() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![0xDEAD_BEEF, 0xC0DE_CAFE]);
+        assert_eq!(rv.bus.read_word(0x1000_0000), Ok(0xDEAD_BEEF));
+        assert_eq!(rv.bus.read_word(0x1000_0004), Ok(0xC0DE_CAFE));
+        assert_eq!(rv.bus.read_word(0x1000_0008), Ok(0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn test_rom_write_does_nothing() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.write_word(0x1000_0000, 0xDEAD_BEEF).unwrap();
+        rv.bus.write_word(0x1000_0004, 0xC0DE_CAFE).unwrap();
+        assert_eq!(rv.bus.read_word(0x1000_0000), Ok(0xFFFF_FFFF));
+        assert_eq!(rv.bus.read_word(0x1000_0004), Ok(0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn test_ram_write_read() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.write_word(0x2000_0000, 0xDEAD_BEEF).unwrap();
+        rv.bus.write_word(0x2000_0004, 0xC0DE_CAFE).unwrap();
+        assert_eq!(rv.bus.read_word(0x2000_0000), Ok(0xDEAD_BEEF));
+        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xC0DE_CAFE));
+    }
+
+    #[test]
+    fn test_ram_write_wrap_around() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.write_word(0x2040_0000, 0xDEAD_BEEF).unwrap();
+        rv.bus.write_word(0x2040_0004, 0xC0DE_CAFE).unwrap();
+        assert_eq!(rv.bus.read_word(0x2000_0000), Ok(0xDEAD_BEEF));
+        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xC0DE_CAFE));
+    }
+
+    #[test]
+    fn test_error_on_misaligned_read() {
+        let rv = RV32ISystem::new();
+        assert_eq!(
+            rv.bus.read_word(0x1000_0005).map_err(|e| format!("{}", e)),
+            Err("Unaligned read from address 0x10000005".to_string())
+        );
     }
 
     #[test]
@@ -337,7 +1454,7 @@ mod tests {
                 raw_instruction,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0010011,
-                    rd: 0b00011,
+                    rd: RegIndex::new(0b00011),
                     funct3: 0b000,
                     imm11_0: 0b000000000001,
                     rs1: 0x0102_0304,
@@ -361,7 +1478,7 @@ mod tests {
                 write_back_value: 0x0102_0305,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0010011,
-                    rd: 0b00011,
+                    rd: RegIndex::new(0b00011),
                     funct3: 0b000,
                     imm11_0: 0b000000000001,
                     rs1: 0x0102_0304,
@@ -386,7 +1503,7 @@ mod tests {
                 write_back_value: 0x0102_0305,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0010011,
-                    rd: 0b00011,
+                    rd: RegIndex::new(0b00011),
                     funct3: 0b000,
                     imm11_0: 0b000000000001,
                     rs1: 0x0102_0304,
@@ -430,7 +1547,7 @@ mod tests {
                 raw_instruction,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b00100,
+                    rd: RegIndex::new(0b00100),
                     funct3: 0b000,
                     imm11_0: 0b000000000001,
                     rs1: 0x0203_0405,
@@ -454,7 +1571,7 @@ mod tests {
                 write_back_value: 0x0305_0709,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b00100,
+                    rd: RegIndex::new(0b00100),
                     funct3: 0b000,
                     imm11_0: 0b000000000001,
                     rs1: 0x0203_0405,
@@ -479,7 +1596,7 @@ mod tests {
                 write_back_value: 0x0305_0709,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b00100,
+                    rd: RegIndex::new(0b00100),
                     funct3: 0b000,
                     imm11_0: 0b000000000001,
                     rs1: 0x0203_0405,
@@ -523,7 +1640,7 @@ mod tests {
                 raw_instruction,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b00100,
+                    rd: RegIndex::new(0b00100),
                     funct3: 0b000,
                     imm11_0: 0b010000000001,
                     rs1: 0x0203_0405,
@@ -547,7 +1664,7 @@ mod tests {
                 write_back_value: 0x0101_0101,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b00100,
+                    rd: RegIndex::new(0b00100),
                     funct3: 0b000,
                     imm11_0: 0b010000000001,
                     rs1: 0x0203_0405,
@@ -572,7 +1689,7 @@ mod tests {
                 write_back_value: 0x0101_0101,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b00100,
+                    rd: RegIndex::new(0b00100),
                     funct3: 0b000,
                     imm11_0: 0b010000000001,
                     rs1: 0x0203_0405,
@@ -616,7 +1733,7 @@ mod tests {
                 raw_instruction,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0010011,
-                    rd: 0b00011,
+                    rd: RegIndex::new(0b00011),
                     funct3: 0b000,
                     imm11_0: 0b111111111111,
                     rs1: 0x0102_0304,
@@ -640,7 +1757,7 @@ mod tests {
                 write_back_value: 0x0102_0303,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0010011,
-                    rd: 0b00011,
+                    rd: RegIndex::new(0b00011),
                     funct3: 0b000,
                     imm11_0: 0b111111111111,
                     rs1: 0x0102_0304,
@@ -665,7 +1782,7 @@ mod tests {
                 write_back_value: 0x0102_0303,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0010011,
-                    rd: 0b00011,
+                    rd: RegIndex::new(0b00011),
                     funct3: 0b000,
                     imm11_0: 0b111111111111,
                     rs1: 0x0102_0304,
@@ -709,7 +1826,7 @@ mod tests {
                 raw_instruction,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b01100,
+                    rd: RegIndex::new(0b01100),
                     funct3: 0b101,
                     imm11_0: 0b000000001011,
                     rs1: 0x8000_0000,
@@ -733,7 +1850,7 @@ mod tests {
                 write_back_value: 0x4000_0000,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b01100,
+                    rd: RegIndex::new(0b01100),
                     funct3: 0b101,
                     imm11_0: 0b000000001011,
                     rs1: 0x8000_0000,
@@ -758,7 +1875,7 @@ mod tests {
                 write_back_value: 0x4000_0000,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b01100,
+                    rd: RegIndex::new(0b01100),
                     funct3: 0b101,
                     imm11_0: 0b000000001011,
                     rs1: 0x8000_0000,
@@ -802,7 +1919,7 @@ mod tests {
                 raw_instruction,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b01100,
+                    rd: RegIndex::new(0b01100),
                     funct3: 0b101,
                     imm11_0: 0b010000001011,
                     rs1: 0x8000_0000,
@@ -826,7 +1943,7 @@ mod tests {
                 write_back_value: 0xC000_0000,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b01100,
+                    rd: RegIndex::new(0b01100),
                     funct3: 0b101,
                     imm11_0: 0b010000001011,
                     rs1: 0x8000_0000,
@@ -851,7 +1968,7 @@ mod tests {
                 write_back_value: 0xC000_0000,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0110011,
-                    rd: 0b01100,
+                    rd: RegIndex::new(0b01100),
                     funct3: 0b101,
                     imm11_0: 0b010000001011,
                     rs1: 0x8000_0000,
@@ -872,6 +1989,196 @@ mod tests {
         assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
     }
 
+    #[test]
+    fn test_mul_instructions() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = -2i32 as u32;
+        rv.reg_file[2] = -3i32 as u32;
+        rv.reg_file[3] = 0x8000_0000;
+
+        let cases = [
+            // MUL r1, r2, r5
+            (0b0000001_00010_00001_000_00101_0110011, 5, 0x0000_0006),
+            // MULH r3, r3, r6
+            (0b0000001_00011_00011_001_00110_0110011, 6, 0x4000_0000),
+            // MULH r1, r2, r7
+            (0b0000001_00010_00001_001_00111_0110011, 7, 0x0000_0000),
+            // MULHSU r1, r3, r8
+            (0b0000001_00011_00001_010_01000_0110011, 8, 0xFFFF_FFFF),
+            // MULHU r1, r2, r9
+            (0b0000001_00010_00001_011_01001_0110011, 9, 0xFFFF_FFFB),
+            // MUL r3, r3, r10
+            (0b0000001_00011_00011_000_01010_0110011, 10, 0x0000_0000),
+        ];
+        rv.bus.rom.load(
+            cases
+                .iter()
+                .map(|(raw_instruction, ..)| *raw_instruction)
+                .collect(),
+        );
+
+        for (raw_instruction, rd, expected) in cases {
+            rv.cycle();
+            rv.cycle();
+            rv.cycle();
+            let executed = rv.stage_ex.get_execution_value_out();
+            assert_eq!(executed.raw_instruction, raw_instruction);
+            assert_eq!(
+                executed.write_back_value,
+                expected,
+                "{}",
+                disassemble(executed.pc, raw_instruction)
+            );
+
+            rv.cycle();
+            rv.cycle();
+            assert_eq!(rv.reg_file[rd], expected);
+            assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
+        }
+    }
+
+    #[test]
+    fn test_div_instructions() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 7;
+        rv.reg_file[2] = 0;
+        rv.reg_file[3] = i32::MIN as u32;
+        rv.reg_file[4] = -1i32 as u32;
+        rv.reg_file[5] = -7i32 as u32;
+        rv.reg_file[6] = 2;
+
+        let cases = [
+            // division by zero
+            // DIV r1, r2, r10
+            (0b0000001_00010_00001_100_01010_0110011, 10, 0xFFFF_FFFF),
+            // DIVU r1, r2, r11
+            (0b0000001_00010_00001_101_01011_0110011, 11, 0xFFFF_FFFF),
+            // REM r1, r2, r12
+            (0b0000001_00010_00001_110_01100_0110011, 12, 0x0000_0007),
+            // REMU r1, r2, r13
+            (0b0000001_00010_00001_111_01101_0110011, 13, 0x0000_0007),
+            // signed overflow
+            // DIV r3, r4, r14
+            (0b0000001_00100_00011_100_01110_0110011, 14, 0x8000_0000),
+            // REM r3, r4, r15
+            (0b0000001_00100_00011_110_01111_0110011, 15, 0x0000_0000),
+            // rounds towards zero, the remainder takes the dividend's sign
+            // DIV r5, r6, r16
+            (0b0000001_00110_00101_100_10000_0110011, 16, 0xFFFF_FFFD),
+            // REM r5, r6, r17
+            (0b0000001_00110_00101_110_10001_0110011, 17, 0xFFFF_FFFF),
+            // DIVU r5, r6, r18
+            (0b0000001_00110_00101_101_10010_0110011, 18, 0x7FFF_FFFC),
+        ];
+        rv.bus.rom.load(
+            cases
+                .iter()
+                .map(|(raw_instruction, ..)| *raw_instruction)
+                .collect(),
+        );
+
+        for (raw_instruction, rd, expected) in cases {
+            rv.cycle();
+            rv.cycle();
+            rv.cycle();
+            let executed = rv.stage_ex.get_execution_value_out();
+            assert_eq!(executed.raw_instruction, raw_instruction);
+            assert_eq!(
+                executed.write_back_value,
+                expected,
+                "{}",
+                disassemble(executed.pc, raw_instruction)
+            );
+
+            rv.cycle();
+            rv.cycle();
+            assert_eq!(rv.reg_file[rd], expected);
+        }
+        assert_eq!(*rv.csr.instret.get(), 9);
+    }
+
+    #[test]
+    fn test_store_half_word() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x2000_0008;
+        rv.reg_file[2] = 0xC0DE_CAFE;
+        rv.bus.write_word(0x2000_0004, 0x0102_0304).unwrap();
+        rv.bus.write_word(0x2000_0008, 0x1122_3344).unwrap();
+        rv.bus.write_word(0x2000_000C, 0x5566_7788).unwrap();
+        rv.bus.write_word(0x2000_0010, 0x99AA_BBCC).unwrap();
+        rv.bus.rom.load(vec![
+            0b0000000_00010_00001_001_00000_0100011, // SH r2, 0(r1)
+            0b0000000_00010_00001_001_00110_0100011, // SH r2, 6(r1)
+        ]);
+
+        // low half of the word
+        run_instruction!(rv);
+        assert_eq!(rv.bus.read_word(0x2000_0008), Ok(0x1122_CAFE));
+        assert_eq!(rv.bus.read_half_word(0x2000_0008), Ok(0xCAFE));
+        assert_eq!(rv.bus.read_half_word(0x2000_000A), Ok(0x1122));
+        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0x0102_0304));
+        assert_eq!(rv.bus.read_word(0x2000_000C), Ok(0x5566_7788));
+
+        // high half of the next word
+        run_instruction!(rv);
+        assert_eq!(rv.bus.read_word(0x2000_000C), Ok(0xCAFE_7788));
+        assert_eq!(rv.bus.read_half_word(0x2000_000C), Ok(0x7788));
+        assert_eq!(rv.bus.read_half_word(0x2000_000E), Ok(0xCAFE));
+        assert_eq!(rv.bus.read_byte(0x2000_000E), Ok(0xFE));
+        assert_eq!(rv.bus.read_byte(0x2000_000F), Ok(0xCA));
+        assert_eq!(rv.bus.read_word(0x2000_0008), Ok(0x1122_CAFE));
+        assert_eq!(rv.bus.read_word(0x2000_0010), Ok(0x99AA_BBCC));
+    }
+
+    #[test]
+    fn test_invalid_memory_width_traps() {
+        for raw_instruction in [
+            0b0000000_00010_00001_100_00000_0100011, // store, funct3 0b100
+            0b0000000_00010_00001_111_00000_0100011, // store, funct3 0b111
+            0b000000000000_00001_011_00011_0000011,  // load, funct3 0b011
+            0b000000000000_00001_110_00011_0000011,  // load, funct3 0b110 (LWU)
+            0b000000000000_00001_111_00011_0000011,  // load, funct3 0b111
+        ] {
+            let mut rv = RV32ISystem::new();
+            rv.reg_file[1] = 0x2000_0000;
+            rv.reg_file[2] = 0xDEAD_BEEF;
+            rv.bus.rom.load(vec![raw_instruction]);
+            let ram_before = rv.bus.read_word(0x2000_0000);
+
+            rv.cycle();
+            rv.cycle();
+            rv.cycle();
+            rv.cycle();
+            assert_eq!(
+                rv.stage_ma.get_memory_access_value_out().trap_params,
+                PipelineTrapParams {
+                    mepc: 0x1000_0000,
+                    mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+                    mtval: raw_instruction,
+                    trap: true,
+                }
+            );
+            rv.cycle();
+            assert_eq!(*rv.state.get(), CPUState::Trap);
+            rv.cycle();
+            rv.cycle();
+            assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+            assert_eq!(rv.csr.mtval, raw_instruction);
+            assert_eq!(rv.bus.read_word(0x2000_0000), ram_before);
+            assert_eq!(rv.reg_file[3], 0);
+
+            // the interpreter leaves them to the pipeline too
+            let mut rv = RV32ISystem::new();
+            rv.reg_file[1] = 0x2000_0000;
+            rv.reg_file[2] = 0xDEAD_BEEF;
+            rv.bus.rom.load(vec![raw_instruction]);
+            rv.interpret(1);
+            assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+            assert_eq!(rv.bus.read_word(0x2000_0000), ram_before);
+            assert_eq!(rv.reg_file[3], 0);
+        }
+    }
+
     #[test]
     fn test_store_instructions() {
         let mut rv = RV32ISystem::new();
@@ -919,17 +2226,17 @@ mod tests {
 
         // SHW r3, r1, imm6
         run_instruction!(rv);
-        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xDEAD_CAFE));
+        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xCAFE_BEEF));
 
         // SB r4, r1, imm5
         run_instruction!(rv);
-        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xDEEA_CAFE));
-        assert_eq!(rv.bus.read_half_word(0x2000_0004), Ok(0xDEEA));
+        assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0xCAFE_EAEF));
+        assert_eq!(rv.bus.read_half_word(0x2000_0004), Ok(0xEAEF));
         assert_eq!(rv.bus.read_half_word(0x2000_0006), Ok(0xCAFE));
-        assert_eq!(rv.bus.read_byte(0x2000_0004), Ok(0xDE));
+        assert_eq!(rv.bus.read_byte(0x2000_0004), Ok(0xEF));
         assert_eq!(rv.bus.read_byte(0x2000_0005), Ok(0xEA));
-        assert_eq!(rv.bus.read_byte(0x2000_0006), Ok(0xCA));
-        assert_eq!(rv.bus.read_byte(0x2000_0007), Ok(0xFE));
+        assert_eq!(rv.bus.read_byte(0x2000_0006), Ok(0xFE));
+        assert_eq!(rv.bus.read_byte(0x2000_0007), Ok(0xCA));
 
         // start with fresh state
         let mut rv = RV32ISystem::new();
@@ -1001,7 +2308,7 @@ mod tests {
                 instruction: DecodedInstruction::Load {
                     funct3: 0b010,
                     rs1: 0x2000_0000,
-                    rd: 0b00010,
+                    rd: RegIndex::new(0b00010),
                     imm32: 0b100,
                 },
                 trap_params: PipelineTrapParams::default(),
@@ -1026,11 +2333,11 @@ mod tests {
                 pc: 0x1000_0004,
                 pc_plus_4: 0x1000_0008,
                 raw_instruction: 0b000000000110_00001_001_00011_0000011,
-                write_back_value: 0xFFFF_BEEF,
+                write_back_value: 0xFFFF_DEAD,
                 instruction: DecodedInstruction::Load {
                     funct3: 0b001,
                     rs1: 0x2000_0000,
-                    rd: 0b00011,
+                    rd: RegIndex::new(0b00011),
                     imm32: 0b110,
                 },
                 trap_params: PipelineTrapParams::default(),
@@ -1041,7 +2348,7 @@ mod tests {
             CPUState::Pipeline(PipelineState::WriteBack)
         );
         rv.cycle();
-        assert_eq!(rv.reg_file[3], 0xFFFF_BEEF);
+        assert_eq!(rv.reg_file[3], 0xFFFF_DEAD);
         assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
 
         // LB r4, r1, imm7
@@ -1055,11 +2362,11 @@ mod tests {
                 pc: 0x1000_0008,
                 pc_plus_4: 0x1000_000C,
                 raw_instruction: 0b000000000111_00001_000_00100_0000011,
-                write_back_value: 0xFFFF_FFEF,
+                write_back_value: 0xFFFF_FFDE,
                 instruction: DecodedInstruction::Load {
                     funct3: 0b000,
                     rs1: 0x2000_0000,
-                    rd: 0b00100,
+                    rd: RegIndex::new(0b00100),
                     imm32: 0b111,
                 },
                 trap_params: PipelineTrapParams::default(),
@@ -1070,7 +2377,7 @@ mod tests {
             CPUState::Pipeline(PipelineState::WriteBack)
         );
         rv.cycle();
-        assert_eq!(rv.reg_file[4], 0xFFFF_FFEF);
+        assert_eq!(rv.reg_file[4], 0xFFFF_FFDE);
         assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
 
         // LHWU r5, r1, imm6
@@ -1084,11 +2391,11 @@ mod tests {
                 pc: 0x1000_000C,
                 pc_plus_4: 0x1000_0010,
                 raw_instruction: 0b000000000110_00001_101_00101_0000011,
-                write_back_value: 0x0000_BEEF,
+                write_back_value: 0x0000_DEAD,
                 instruction: DecodedInstruction::Load {
                     funct3: 0b101,
                     rs1: 0x2000_0000,
-                    rd: 0b00101,
+                    rd: RegIndex::new(0b00101),
                     imm32: 0b110,
                 },
                 trap_params: PipelineTrapParams::default(),
@@ -1099,7 +2406,7 @@ mod tests {
             CPUState::Pipeline(PipelineState::WriteBack)
         );
         rv.cycle();
-        assert_eq!(rv.reg_file[5], 0x0000_BEEF);
+        assert_eq!(rv.reg_file[5], 0x0000_DEAD);
         assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
 
         // LBU r6, r1, imm7
@@ -1113,11 +2420,11 @@ mod tests {
                 pc: 0x1000_0010,
                 pc_plus_4: 0x1000_0014,
                 raw_instruction: 0b000000000111_00001_100_00110_0000011,
-                write_back_value: 0x0000_00EF,
+                write_back_value: 0x0000_00DE,
                 instruction: DecodedInstruction::Load {
                     funct3: 0b100,
                     rs1: 0x2000_0000,
-                    rd: 0b00110,
+                    rd: RegIndex::new(0b00110),
                     imm32: 0b111,
                 },
                 trap_params: PipelineTrapParams::default(),
@@ -1128,7 +2435,7 @@ mod tests {
             CPUState::Pipeline(PipelineState::WriteBack)
         );
         rv.cycle();
-        assert_eq!(rv.reg_file[6], 0x0000_00EF);
+        assert_eq!(rv.reg_file[6], 0x0000_00DE);
         assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
 
         // LW r11, r10, imm-1
@@ -1146,7 +2453,7 @@ mod tests {
                 instruction: DecodedInstruction::Load {
                     funct3: 0b010,
                     rs1: 0x2000_0005,
-                    rd: 0b01011,
+                    rd: RegIndex::new(0b01011),
                     imm32: -1,
                 },
                 trap_params: PipelineTrapParams::default(),
@@ -1180,7 +2487,7 @@ mod tests {
                 pc_plus_4: 0x1000_0004,
                 raw_instruction: 0b10101010101010101010_00001_0110111,
                 instruction: DecodedInstruction::Lui {
-                    rd: 0b00001,
+                    rd: RegIndex::new(0b00001),
                     imm32: 0b10101010101010101010_000000000000,
                 },
                 return_from_trap: false,
@@ -1204,7 +2511,7 @@ mod tests {
                 raw_instruction: 0b101010101010_00001_000_00001_0010011,
                 instruction: DecodedInstruction::Alu {
                     opcode: 0b0010011,
-                    rd: 0b00001,
+                    rd: RegIndex::new(0b00001),
                     funct3: 0b000,
                     imm11_0: 0xAAA,
                     rs1: 0xAAAA_A000,
@@ -1223,9 +2530,29 @@ mod tests {
         assert_eq!(rv.reg_file[1], 0xAAAA_9AAA);
     }
 
+    #[test]
+    fn test_lui_addi_sign_boundary() {
+        let mut rv = RV32ISystem::new();
+
+        rv.bus.rom.load(vec![
+            0b10000000000000000000_00001_0110111,   // LUI r1, 0x80000
+            0b111111111111_00001_000_00001_0010011, // ADDI r1, r1, -1
+        ]);
+
+        // LUI r1, 0x80000
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[1], 0x8000_0000);
+
+        // ADDI r1, r1, -1
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[1], 0x7FFF_FFFF);
+    }
+
     #[test]
     fn test_jal_instructions() {
         let mut rv = RV32ISystem::new();
+        // the gaps between the jumps are all zero words, run them as NOPs
+        rv.set_illegal_policy(IllegalPolicy::Nop);
 
         rv.bus.rom.load(vec![
             0,
@@ -1267,7 +2594,7 @@ mod tests {
                 pc_plus_4: 0x1000_000C,
                 raw_instruction: 0b0_0000011110_0_00000000_00000_1101111,
                 instruction: DecodedInstruction::Jal {
-                    rd: 0b00000,
+                    rd: RegIndex::new(0b00000),
                     branch_address: 0x1000_0044,
                 },
                 return_from_trap: false,
@@ -1317,7 +2644,7 @@ mod tests {
                 pc_plus_4: 0x1000_0058,
                 raw_instruction: 0b1_1111011100_1_11111111_00001_1101111,
                 instruction: DecodedInstruction::Jal {
-                    rd: 0b00001,
+                    rd: RegIndex::new(0b00001),
                     branch_address: 0x1000_000C,
                 },
                 return_from_trap: false,
@@ -1366,8 +2693,8 @@ mod tests {
                 pc: 0x1000_0040,
                 pc_plus_4: 0x1000_0044,
                 raw_instruction: 0b000000000000_00001_000_00000_1100111,
-                instruction: DecodedInstruction::Jal {
-                    rd: 0b00000,
+                instruction: DecodedInstruction::Jalr {
+                    rd: RegIndex::new(0b00000),
                     branch_address: 0x1000_0058,
                 },
                 return_from_trap: false,
@@ -1396,9 +2723,60 @@ mod tests {
         assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
     }
 
+    #[test]
+    fn test_ecall_handler() {
+        let mut rv = RV32ISystem::new();
+        let output = Rc::new(RefCell::new(Vec::new()));
+
+        let handler_output = output.clone();
+        rv.set_ecall_handler(Box::new(move |rv| {
+            // a7 = 1: print the integer in a0
+            if rv.reg_file[17] != 1 {
+                return EcallAction::Trap;
+            }
+            handler_output
+                .borrow_mut()
+                .push(format!("{}", rv.reg_file[10] as i32));
+            rv.reg_file[10] = 0;
+            EcallAction::Resume
+        }));
+
+        rv.bus.rom.load(vec![
+            0b000000101010_00000_000_01010_0010011, // ADDI a0, x0, 42
+            0b000000000001_00000_000_10001_0010011, // ADDI a7, x0, 1
+            0b000000000000_00000_000_00000_1110011, // ECALL
+            0b111111111001_00000_000_01010_0010011, // ADDI a0, x0, -7
+            0b000000000000_00000_000_00000_1110011, // ECALL
+            0b000000000000_00000_000_10001_0010011, // ADDI a7, x0, 0
+            0b000000000000_00000_000_00000_1110011, // ECALL
+        ]);
+
+        for _ in 0..5 {
+            run_instruction!(rv);
+        }
+        assert_eq!(*output.borrow(), vec!["42".to_string(), "-7".to_string()]);
+        assert_eq!(rv.reg_file[10], 0);
+        assert_eq!(*rv.csr.instret.get(), 5);
+
+        // ADDI a7, x0, 0
+        run_instruction!(rv);
+
+        // ECALL, not handled by the host so it traps as normal
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(*rv.trap.mcause.get(), MCAUSE_ENVIRONMENT_CALL_FROM_MMODE);
+        assert_eq!(output.borrow().len(), 2);
+    }
+
     #[test]
     fn test_memory_access_trap() {
         let mut rv = RV32ISystem::new();
+        // the handlers are laid out as this core's vector table
+        rv.csr = CSRInterfaceBuilder::new()
+            .vector_layout(VectorLayout::Table)
+            .build();
         rv.reg_file[2] = 0x2000_0000;
 
         rv.bus.rom.load(vec![
@@ -1447,13 +2825,13 @@ mod tests {
                 instruction: DecodedInstruction::Load {
                     funct3: 0b010,
                     rs1: 0x2000_0000,
-                    rd: 0b01110,
+                    rd: RegIndex::new(0b01110),
                     imm32: 0b1,
                 },
                 trap_params: PipelineTrapParams {
                     mcause: MCAUSE_LOAD_ADDRESS_MISALIGNED,
-                    mepc: 0x1000_0004,
-                    mtval: 0b000000000001_00010_010_01110_0000011,
+                    mepc: 0x1000_0000,
+                    mtval: 0x2000_0001,
                     trap: true,
                 },
             }
@@ -1479,4 +2857,2719 @@ mod tests {
         );
         assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Decode));
     }
+
+    #[test]
+    fn test_dump_bin() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x0102_0304;
+        rv.reg_file[2] = 0xDEAD_BEEF;
+        rv.reg_file[31] = 0x8000_0001;
+
+        let dump = rv.dump_registers_bin();
+        assert_eq!(dump[0..4], [0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(dump[4..8], [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(dump[8..12], [0xEF, 0xBE, 0xAD, 0xDE]);
+        assert_eq!(dump[124..128], [0x01, 0x00, 0x00, 0x80]);
+        assert!(dump[12..124].iter().all(|b| *b == 0));
+
+        assert_eq!(rv.dump_pc_bin(), [0x00, 0x00, 0x00, 0x10]);
+
+        // JAL r0, 0x10
+        rv.bus
+            .rom
+            .load(vec![0b0_0000001000_0_00000000_00000_1101111]);
+        run_instruction!(rv);
+        assert_eq!(rv.dump_pc_bin(), [0x10, 0x00, 0x00, 0x10]);
+
+        // ADDI r0, r0, 0
+        rv.bus.rom.load(vec![0, 0, 0, 0, 0x13]);
+        run_instruction!(rv);
+        assert_eq!(rv.dump_pc_bin(), [0x14, 0x00, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn test_compute_observes_committed_outputs() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x2000_0000;
+        rv.reg_file[2] = 0xDEAD_BEEF;
+        rv.bus.rom.load(vec![
+            // SW r2, 4(r1)
+            0b0000000_00010_00001_010_00100_0100011,
+            // LW r3, 4(r1)
+            0b000000000100_00001_010_00011_0000011,
+            // ADDI r4, r3, 1
+            0b000000000001_00011_000_00100_0010011,
+        ]);
+
+        for _ in 0..15 {
+            let state = *rv.state.get();
+            let if_out = rv.stage_if.get_instruction_value_out();
+            let de_out = rv.stage_de.get_decoded_instruction_out();
+            let ex_out = rv.stage_ex.get_execution_value_out();
+            let ma_out = rv.stage_ma.get_memory_access_value_out();
+
+            // every stage reads the values committed on the previous cycle, nothing computed this
+            // cycle is visible until latch_next
+            rv.compute();
+            assert_eq!(*rv.state.get(), state);
+            assert_eq!(rv.stage_if.get_instruction_value_out(), if_out);
+            assert_eq!(rv.stage_de.get_decoded_instruction_out(), de_out);
+            assert_eq!(rv.stage_ex.get_execution_value_out(), ex_out);
+            assert_eq!(rv.stage_ma.get_memory_access_value_out(), ma_out);
+
+            rv.latch_next();
+            assert_ne!(*rv.state.get(), state);
+        }
+
+        assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
+        assert_eq!(rv.reg_file[3], 0xDEAD_BEEF);
+        assert_eq!(rv.reg_file[4], 0xDEAD_BEF0);
+    }
+
+    #[test]
+    fn test_misaligned_record() {
+        let mut rv = RV32ISystem::new();
+        rv.set_misaligned_access_policy(MisalignedAccessPolicy::Record);
+        rv.reg_file[1] = 0x2000_0000;
+        rv.bus.ram.write_word(0x0000_0000, 0x0011_2233).unwrap();
+        rv.bus.ram.write_word(0x0000_0004, 0x4455_6677).unwrap();
+        rv.bus.rom.load(vec![
+            // LW r2, 1(r1)
+            0b000000000001_00001_010_00010_0000011,
+            // LH r3, 3(r1)
+            0b000000000011_00001_001_00011_0000011,
+            // LW r4, 4(r1)
+            0b000000000100_00001_010_00100_0000011,
+        ]);
+
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[2], 0x7700_1122);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[3], 0x7700);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[4], 0x4455_6677);
+
+        assert_eq!(
+            rv.misaligned_accesses(),
+            [
+                MisalignedAccess {
+                    pc: 0x1000_0000,
+                    address: 0x2000_0001,
+                    width: 4,
+                },
+                MisalignedAccess {
+                    pc: 0x1000_0004,
+                    address: 0x2000_0003,
+                    width: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_misaligned_emulate() {
+        let mut rv = RV32ISystem::new();
+        rv.set_misaligned_access_policy(MisalignedAccessPolicy::Emulate);
+        rv.reg_file[1] = 0x2000_0000;
+        rv.reg_file[2] = 0xDEAD_BEEF;
+        rv.bus.ram.write_word(0x0000_0000, 0x0011_2233).unwrap();
+        rv.bus.ram.write_word(0x0000_0004, 0x4455_6677).unwrap();
+        rv.bus.rom.load(vec![
+            // SW r2, 2(r1)
+            0b0000000_00010_00001_010_00010_0100011,
+            // LH r3, 3(r1)
+            0b000000000011_00001_001_00011_0000011,
+        ]);
+
+        run_instruction!(rv);
+        assert_eq!(rv.bus.ram.read_word(0x0000_0000), Ok(0xBEEF_2233));
+        assert_eq!(rv.bus.ram.read_word(0x0000_0004), Ok(0x4455_DEAD));
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[3], 0xFFFF_ADBE);
+        assert!(rv.misaligned_accesses().is_empty());
+    }
+
+    #[test]
+    fn test_load_ram() {
+        let mut rv = RV32ISystem::new();
+        let program: Vec<u8> = [
+            // ADDI r1, r0, 5
+            0b000000000101_00000_000_00001_0010011u32,
+            // ADDI r2, r1, 7
+            0b000000000111_00001_000_00010_0010011,
+            // ADD r3, r1, r2
+            0b0000000_00010_00001_000_00011_0110011,
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.to_le_bytes())
+        .collect();
+        rv.load_ram(0x2000_0100, &program).unwrap();
+        rv.set_reset_vector(0x2000_0100);
+
+        run_instruction!(rv);
+        run_instruction!(rv);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[3], 17);
+        assert_eq!(rv.current_line(), 0x2000_0108);
+
+        // a trailing partial word leaves the rest of the word untouched
+        rv.load_ram(0x2000_0200, &[0x11, 0x22, 0x33, 0x44, 0x55])
+            .unwrap();
+        assert_eq!(rv.bus.read_word(0x2000_0200), Ok(0x4433_2211));
+        assert_eq!(rv.bus.read_word(0x2000_0204), Ok(0xFFFF_FF55));
+
+        // anything that doesn't fit in RAM is refused without writing part of it
+        assert_eq!(
+            rv.load_ram(0x3000_0000, &[0x11]),
+            Err(MMIOError::AccessFault(0x3000_0000))
+        );
+        assert_eq!(
+            rv.load_ram(0x1000_0000, &[0x11]),
+            Err(MMIOError::AccessFault(0x1000_0000))
+        );
+        assert_eq!(
+            rv.load_ram(0x2FFF_FFFE, &[0x11, 0x22, 0x33]),
+            Err(MMIOError::AccessFault(0x3000_0000))
+        );
+        assert_eq!(rv.bus.read_byte(0x2FFF_FFFE), Ok(0xFF));
+        rv.load_ram(0x2FFF_FFFE, &[0x11, 0x22]).unwrap();
+        assert_eq!(rv.bus.read_half_word(0x2FFF_FFFE), Ok(0x2211));
+    }
+
+    #[test]
+    fn test_call_routine_in_ram() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // LUI r5, 0x20000
+            0x2000_02B7,
+            // ADDI r5, r5, 0x40
+            0x0402_8293,
+            // JALR r1, 0(r5)
+            0x0002_80E7,
+            // ADDI r11, r10, 1
+            0x0015_0593,
+            // JAL r0, 0
+            0x0000_006F,
+        ]);
+        // copied to RAM through the bus, as a boot loader would
+        for (address, instruction) in [
+            // ADDI r10, r0, 41
+            (0x2000_0040, 0x0290_0513),
+            // JALR r0, 0(r1)
+            (0x2000_0044, 0x0000_8067),
+        ] {
+            rv.bus.write_word(address, instruction).unwrap();
+        }
+
+        for _ in 0..3 {
+            rv.step();
+        }
+        assert_eq!(rv.next_fetch_address(), 0x2000_0040);
+        assert_eq!(rv.reg_file[1], 0x1000_000C);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.reg_file[10], 41);
+        assert_eq!(rv.next_fetch_address(), 0x1000_000C);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.reg_file[11], 42);
+        assert!(rv.is_halted());
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        let mut rv = RV32ISystem::new();
+        let events = Rc::new(RefCell::new(vec![]));
+        let hook_events = events.clone();
+        rv.set_trace_hook(Box::new(move |event| hook_events.borrow_mut().push(event)));
+        rv.reg_file[1] = 0x0102_0304;
+        rv.reg_file[2] = 0x0203_0405;
+        rv.reg_file[10] = 0x8000_0000;
+        rv.reg_file[11] = 0x0000_0001;
+        rv.bus.rom.load(vec![
+            0b000000000001_00001_000_00011_0010011,  // ADDI 1, r1, r3
+            0b0000000_00001_00010_000_00100_0110011, // ADD r1, r2, r4
+            0b0100000_00001_00010_000_00100_0110011, // SUB r1, r2, r4
+            0b111111111111_00001_000_00011_0010011,  // ADDI -1, r1, r3
+            0b0000000_01011_01010_101_01100_0110011, // SRL r10, r11, r12
+            0b0100000_01011_01010_101_01100_0110011, // SRA r10, r11, r12
+            0b0000000_00000_00000_000_00000_0010011, // NOP
+        ]);
+
+        // nothing is reported until the first instruction reaches write-back
+        for _ in 0..4 {
+            rv.cycle();
+        }
+        assert!(events.borrow().is_empty());
+        rv.cycle();
+        assert_eq!(events.borrow().len(), 1);
+
+        for _ in 0..6 {
+            rv.step();
+        }
+        let events = events.borrow();
+        assert_eq!(events.len() as u64, *rv.csr.instret.get());
+        assert_eq!(
+            events.iter().map(|event| event.pc).collect::<Vec<_>>(),
+            [
+                0x1000_0000,
+                0x1000_0004,
+                0x1000_0008,
+                0x1000_000C,
+                0x1000_0010,
+                0x1000_0014,
+                0x1000_0018
+            ]
+        );
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| event.register_write.map(|(rd, value)| (rd.value(), value)))
+                .collect::<Vec<_>>(),
+            [
+                Some((3, 0x0102_0305)),
+                Some((4, 0x0305_0709)),
+                Some((4, 0x0101_0101)),
+                Some((3, 0x0102_0303)),
+                Some((12, 0x4000_0000)),
+                Some((12, 0xC000_0000)),
+                None
+            ]
+        );
+        assert_eq!(
+            events[0].raw_instruction,
+            0b000000000001_00001_000_00011_0010011
+        );
+        assert!(matches!(
+            events[1].instruction,
+            DecodedInstruction::Alu { .. }
+        ));
+    }
+
+    #[test]
+    fn test_step_returns_retired_instruction() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x2000_0000;
+        rv.reg_file[2] = 0xDEAD_BEEF;
+        rv.reg_file[3] = 0xC0DE_CAFE;
+        rv.reg_file[4] = 0xABAD_1DEA;
+        rv.bus.rom.load(vec![
+            0b0000000_00010_00001_010_00100_0100011, // SW r2, r1, imm4
+            0b0000000_00011_00001_001_00110_0100011, // SHW r3, r1, imm6
+            0b0000000_00100_00001_000_00101_0100011, // SB r4, r1, imm5
+            0b0000000_00100_00001_010_00101_0000011, // LW r1, imm4, r5
+            0b0000000_00100_00001_001_00101_0100011, // SH r4, r1, imm5
+        ]);
+
+        let retired = rv.step().unwrap();
+        assert_eq!(retired.pc, 0x1000_0000);
+        assert_eq!(
+            retired.raw_instruction,
+            0b0000000_00010_00001_010_00100_0100011
+        );
+        assert_eq!(
+            retired.instruction,
+            DecodedInstruction::Store {
+                funct3: 0b010,
+                rs1: 0x2000_0000,
+                rs2: 0xDEAD_BEEF,
+                imm32: 0b100,
+            }
+        );
+        assert_eq!(retired.register_write, None);
+        assert_eq!(retired.memory_write, Some((0x2000_0004, 0xDEAD_BEEF)));
+
+        let retired = rv.step().unwrap();
+        assert_eq!(retired.pc, 0x1000_0004);
+        assert_eq!(retired.memory_write, Some((0x2000_0006, 0xCAFE)));
+
+        let retired = rv.step().unwrap();
+        assert_eq!(retired.pc, 0x1000_0008);
+        assert_eq!(retired.memory_write, Some((0x2000_0005, 0xEA)));
+
+        let retired = rv.step().unwrap();
+        assert_eq!(retired.pc, 0x1000_000C);
+        assert_eq!(
+            retired.register_write,
+            Some((RegIndex::new(5), 0xCAFE_EAEF))
+        );
+        assert_eq!(retired.memory_write, None);
+
+        // the misaligned store traps instead of retiring
+        assert_eq!(rv.step(), None);
+        assert_eq!(*rv.csr.instret.get(), 4);
+
+        rv.request_halt();
+        assert_eq!(rv.step(), None);
+        assert!(rv.is_debug_halted());
+    }
+
+    #[test]
+    fn test_code_write_handler() {
+        let mut rv = RV32ISystem::new();
+        let writes = Rc::new(RefCell::new(vec![]));
+        let handler_writes = writes.clone();
+        rv.set_code_write_handler(Box::new(move |pc, address| {
+            handler_writes.borrow_mut().push((pc, address));
+        }));
+        rv.mark_code_region(0x2000_0100..0x2000_0200);
+        // only the last two bytes of a word store land in this one
+        rv.mark_code_region(0x2000_0302..0x2000_0400);
+        // stores here fault, so they never write any code
+        rv.mark_code_region(0x2000_0400..0x2000_0500);
+        rv.bus.add_region(0x2000_0400..0x2000_0500, Permissions::RX);
+        // stop stores to ROM from faulting, they are still dropped by the bus
+        rv.bus
+            .add_region(PROGRAM_ROM_START..PROGRAM_ROM_END + 1, Permissions::RWX);
+        rv.reg_file[1] = 0x2000_0100;
+        rv.reg_file[2] = 0xDEAD_BEEF;
+        rv.reg_file[3] = 0x1000_0000;
+        rv.bus.rom.load(vec![
+            // SW r2, 0(r1)
+            0b0000000_00010_00001_010_00000_0100011,
+            // SW r2, 256(r1)
+            0b0001000_00010_00001_010_00000_0100011,
+            // SB r2, -1(r1)
+            0b1111111_00010_00001_000_11111_0100011,
+            // SW r2, 4(r3)
+            0b0000000_00010_00011_010_00100_0100011,
+            // SW r2, 28(r3)
+            0b0000000_00010_00011_010_11100_0100011,
+            // SW r2, 512(r1)
+            0b0010000_00010_00001_010_00000_0100011,
+            // SW r2, 768(r1)
+            0b0011000_00010_00001_010_00000_0100011,
+        ]);
+
+        for _ in 0..6 {
+            run_instruction!(rv);
+        }
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_STORE_AMO_ACCESS_FAULT);
+
+        // the store into RAM code still happens
+        assert_eq!(rv.bus.read_word(0x2000_0100), Ok(0xDEAD_BEEF));
+        assert_eq!(
+            *writes.borrow(),
+            vec![
+                (0x1000_0000, 0x2000_0100),
+                (0x1000_000C, 0x1000_0004),
+                (0x1000_0014, 0x2000_0300)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fetch_width() {
+        let mut rv = RV32ISystem::new();
+        rv.set_fetch_width(2);
+        let instructions = vec![
+            // ADDI r1, r0, 1
+            0b000000000001_00000_000_00001_0010011,
+            // ADDI r2, r1, 1
+            0b000000000001_00001_000_00010_0010011,
+            // ADDI r3, r2, 1
+            0b000000000001_00010_000_00011_0010011,
+            // ADDI r4, r3, 1
+            0b000000000001_00011_000_00100_0010011,
+        ];
+        rv.bus.rom.load(instructions.clone());
+
+        rv.cycle();
+        assert_eq!(
+            rv.stage_if.get_fetch_buffer(),
+            [
+                (0x1000_0000, instructions[0]),
+                (0x1000_0004, instructions[1])
+            ]
+        );
+        for _ in 0..4 {
+            rv.cycle();
+        }
+        assert_eq!(rv.reg_file[1], 1);
+
+        // served from the buffer
+        rv.cycle();
+        assert_eq!(
+            rv.stage_if.get_instruction_value_out().raw_instruction,
+            instructions[1]
+        );
+        assert_eq!(rv.stage_if.get_fetch_buffer()[0].0, 0x1000_0000);
+        for _ in 0..4 {
+            rv.cycle();
+        }
+        assert_eq!(rv.reg_file[2], 2);
+
+        // buffer miss refills the next pair
+        rv.cycle();
+        assert_eq!(
+            rv.stage_if.get_fetch_buffer(),
+            [
+                (0x1000_0008, instructions[2]),
+                (0x1000_000C, instructions[3])
+            ]
+        );
+        for _ in 0..4 {
+            rv.cycle();
+        }
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[3], 3);
+        assert_eq!(rv.reg_file[4], 4);
+    }
+
+    #[test]
+    fn test_fence_i_flushes_fetch_buffer() {
+        // patches the ADDI at 0x2000_0008 to load 42 instead of 1, then runs it
+        let run = |fence: u32, interpret: bool| {
+            let mut rv = RV32ISystem::new();
+            rv.set_fetch_width(4);
+            rv.bus.rom.load(vec![
+                // LUI r2, 0x20000
+                0x2000_0137,
+                // LUI r3, 0x02A00
+                0x02A0_01B7,
+                // ADDI r3, r3, 0x293 (r3 = ADDI r5, r0, 42)
+                0x2931_8193,
+                // JALR r0, 0(r2)
+                0x0001_0067,
+            ]);
+            let ram: Vec<u8> = [
+                // SW r3, 8(r2)
+                0x0031_2423,
+                fence,
+                // ADDI r5, r0, 1
+                0x0010_0293,
+                // JAL r0, 0
+                0x0000_006F,
+            ]
+            .iter()
+            .flat_map(|word: &u32| word.to_le_bytes())
+            .collect();
+            rv.load_ram(0x2000_0000, &ram).unwrap();
+            if interpret {
+                rv.interpret(10);
+            } else {
+                for _ in 0..10 {
+                    rv.step();
+                }
+            }
+            assert!(rv.is_halted());
+            rv.reg_file[5]
+        };
+
+        // the interpreter fetches through the same buffer
+        for interpret in [false, true] {
+            // FENCE.I
+            assert_eq!(run(0x0000_100F, interpret), 42);
+            // NOP, the stale instruction is still buffered
+            assert_eq!(run(0x0000_0013, interpret), 1);
+        }
+    }
+
+    #[test]
+    fn test_fetch_width_bus_faults() {
+        let program = vec![
+            // JALR r0, 0(r1)
+            0b000000000000_00001_000_00000_1100111,
+        ];
+
+        // nothing is mapped at the target
+        let mut rv = RV32ISystem::new();
+        rv.set_fetch_width(4);
+        rv.bus.rom.load(program.clone());
+        rv.reg_file[1] = 0x9000_0000;
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_INSTRUCTION_ACCESS_FAULT);
+        assert_eq!(rv.csr.mepc, 0x9000_0000);
+        assert_eq!(rv.csr.mtval, 0x9000_0000);
+
+        // the target isn't word aligned
+        let mut rv = RV32ISystem::new();
+        rv.set_fetch_width(4);
+        rv.bus.rom.load(program);
+        rv.reg_file[1] = 0x1000_0002;
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_INSTRUCTION_ADDRESS_MISALIGNED);
+        assert_eq!(rv.csr.mepc, 0x1000_0002);
+        assert_eq!(rv.csr.mtval, 0x1000_0002);
+    }
+
+    /// Records every word written to it, reads return the last word written to that offset
+    struct MockDevice {
+        writes: Rc<RefCell<Vec<(u32, u32)>>>,
+    }
+
+    impl MMIODevice for MockDevice {
+        fn read_byte(&self, _address: u32) -> MMIOResult<u8> {
+            Ok(0)
+        }
+        fn write_byte(&mut self, _address: u32, _value: u8) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_half_word(&self, _address: u32) -> MMIOResult<u16> {
+            Ok(0)
+        }
+        fn write_half_word(&mut self, _address: u32, _value: u16) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_word(&self, address: u32) -> MMIOResult<u32> {
+            Ok(self
+                .writes
+                .borrow()
+                .iter()
+                .rev()
+                .find(|(offset, _)| *offset == address)
+                .map_or(0, |(_, value)| *value))
+        }
+        fn write_word(&mut self, address: u32, value: u32) -> MMIOResult<()> {
+            self.writes.borrow_mut().push((address, value));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_attach_device() {
+        let mut rv = RV32ISystem::new();
+        let writes = Rc::new(RefCell::new(vec![]));
+        rv.bus
+            .attach(
+                0x4000_0000..0x4000_1000,
+                Box::new(MockDevice {
+                    writes: writes.clone(),
+                }),
+            )
+            .unwrap();
+        rv.reg_file[1] = 0x4000_0000;
+        rv.reg_file[2] = 0xDEAD_BEEF;
+        rv.bus.rom.load(vec![
+            // SW r2, 8(r1)
+            0b0000000_00010_00001_010_01000_0100011,
+            // LW r3, 8(r1)
+            0b000000001000_00001_010_00011_0000011,
+        ]);
+
+        run_instruction!(rv);
+        assert_eq!(*writes.borrow(), vec![(0x8, 0xDEAD_BEEF)]);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[3], 0xDEAD_BEEF);
+
+        // outside of the attached range nothing is mapped
+        assert_eq!(
+            rv.bus.read_word(0x4000_1000),
+            Err(MMIOError::AccessFault(0x4000_1000))
+        );
+    }
+
+    /// Every byte read pops a counter, like a receive FIFO
+    struct CountingDevice {
+        reads: Rc<RefCell<u8>>,
+    }
+
+    impl MMIODevice for CountingDevice {
+        fn read_byte(&self, _address: u32) -> MMIOResult<u8> {
+            *self.reads.borrow_mut() += 1;
+            Ok(*self.reads.borrow())
+        }
+        fn write_byte(&mut self, _address: u32, _value: u8) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_half_word(&self, _address: u32) -> MMIOResult<u16> {
+            Ok(0)
+        }
+        fn write_half_word(&mut self, _address: u32, _value: u16) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn read_word(&self, _address: u32) -> MMIOResult<u32> {
+            Ok(0)
+        }
+        fn write_word(&mut self, _address: u32, _value: u32) -> MMIOResult<()> {
+            Ok(())
+        }
+        fn peek_byte(&self, _address: u32) -> MMIOResult<u8> {
+            Ok(*self.reads.borrow())
+        }
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![0x1234_5678, 0x9ABC_DEF0]);
+        rv.bus.write_word(0x2000_0000, 0x4433_2211).unwrap();
+        let reads = Rc::new(RefCell::new(7));
+        rv.bus
+            .attach(
+                0x4000_0000..0x4000_0010,
+                Box::new(CountingDevice {
+                    reads: reads.clone(),
+                }),
+            )
+            .unwrap();
+
+        assert_eq!(rv.peek_word(0x1000_0004), 0x9ABC_DEF0);
+        assert_eq!(rv.peek_word(0x1000_0002), 0xDEF0_1234);
+        assert_eq!(rv.peek_bytes(0x2000_0001, 3), [0x22, 0x33, 0x44]);
+        // unmapped
+        assert_eq!(rv.peek_word(0x8000_0001), 0);
+        assert_eq!(rv.peek_word(0x4000_0000), 0x0707_0707);
+        assert_eq!(*reads.borrow(), 7);
+    }
+
+    #[test]
+    fn test_attach_overlap() {
+        let mut rv = RV32ISystem::new();
+        let device = || {
+            Box::new(MockDevice {
+                writes: Rc::new(RefCell::new(vec![])),
+            })
+        };
+        assert_eq!(
+            rv.bus.attach(0x2FFF_F000..0x3000_1000, device()),
+            Err(MMIOError::RegionOverlap(0x2FFF_F000, 0x3000_1000))
+        );
+        assert_eq!(
+            rv.bus.attach(0x0FFF_FFFC..0x1000_0004, device()),
+            Err(MMIOError::RegionOverlap(0x0FFF_FFFC, 0x1000_0004))
+        );
+        assert_eq!(rv.bus.attach(0x4000_0000..0x4000_1000, device()), Ok(()));
+        assert_eq!(
+            rv.bus.attach(0x4000_0800..0x4000_0900, device()),
+            Err(MMIOError::RegionOverlap(0x4000_0800, 0x4000_0900))
+        );
+        // touching ranges don't overlap
+        assert_eq!(rv.bus.attach(0x3000_0000..0x4000_0000, device()), Ok(()));
+    }
+
+    #[test]
+    fn test_nop() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r0, 5
+            0b000000000101_00000_000_00001_0010011,
+            // ADDI r0, r0, 0 (NOP)
+            0b000000000000_00000_000_00000_0010011,
+            0b000000000000_00000_000_00000_0010011,
+            0b000000000000_00000_000_00000_0010011,
+            // ADDI r2, r1, 1
+            0b000000000001_00001_000_00010_0010011,
+            // ADDI r0, r1, 1 (result discarded)
+            0b000000000001_00001_000_00000_0010011,
+        ]);
+
+        run_instruction!(rv);
+        assert_eq!(*rv.csr.instret.get(), 1);
+        let registers = rv.reg_file;
+
+        for nop in 1..=3 {
+            run_instruction!(rv);
+            assert_eq!(*rv.csr.instret.get(), 1 + nop);
+            assert_eq!(rv.current_line(), 0x1000_0000 + nop as u32 * 4);
+            assert_eq!(rv.reg_file, registers);
+        }
+
+        run_instruction!(rv);
+        assert_eq!(*rv.csr.instret.get(), 5);
+        assert_eq!(rv.reg_file[1], 5);
+        assert_eq!(rv.reg_file[2], 6);
+
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[0], 0);
+        assert_eq!(*rv.csr.cycles.get(), 6 * 5);
+    }
+
+    #[test]
+    fn test_store_to_rom_faults() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x1000_0000;
+        rv.reg_file[2] = 0xDEAD_BEEF;
+        rv.bus.rom.load(vec![
+            // SW r2, 8(r1)
+            0b0000000_00010_00001_010_01000_0100011,
+        ]);
+
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(
+            rv.stage_ma.get_memory_access_value_out().trap_params,
+            PipelineTrapParams {
+                mepc: 0x1000_0000,
+                mcause: MCAUSE_STORE_AMO_ACCESS_FAULT,
+                mtval: 0x1000_0008,
+                trap: true,
+            }
+        );
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(rv.trap.mcause.get(), &MCAUSE_STORE_AMO_ACCESS_FAULT);
+    }
+
+    #[test]
+    fn test_load_from_unreadable_region_faults() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.add_region(
+            0x2000_0000..0x2000_1000,
+            Permissions {
+                read: false,
+                write: true,
+                execute: false,
+            },
+        );
+        rv.reg_file[1] = 0x2000_0000;
+        rv.bus.rom.load(vec![
+            // LW r2, 0(r1)
+            0b000000000000_00001_010_00010_0000011,
+        ]);
+
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(
+            rv.stage_ma.get_memory_access_value_out().trap_params.mcause,
+            MCAUSE_LOAD_ACCESS_FAULT
+        );
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(rv.reg_file[2], 0);
+    }
+
+    #[test]
+    fn test_fetch_from_non_executable_region_faults() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.add_region(0x2000_0000..0x2000_1000, Permissions::RW);
+        rv.reg_file[1] = 0x2000_0000;
+        rv.load_ram(0x2000_0000, &0x0000_0013u32.to_le_bytes())
+            .unwrap();
+        rv.bus.rom.load(vec![
+            // JALR r0, 0(r1)
+            0b000000000000_00001_000_00000_1100111,
+        ]);
+
+        run_instruction!(rv);
+        rv.cycle();
+        assert_eq!(
+            rv.stage_if.get_trap_params_out(),
+            PipelineTrapParams {
+                mepc: 0x2000_0000,
+                mcause: MCAUSE_INSTRUCTION_ACCESS_FAULT,
+                mtval: 0x2000_0000,
+                trap: true,
+            }
+        );
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(*rv.trap.state.get(), TrapState::SetCSRJump);
+        assert_eq!(rv.trap.mcause.get(), &MCAUSE_INSTRUCTION_ACCESS_FAULT);
+        assert_eq!(rv.trap.mepc.get(), &0x2000_0000);
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
+        assert_eq!(*rv.csr.instret.get(), 1);
+    }
+
+    #[test]
+    fn test_last_step_cycles() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x2000_0000;
+        rv.bus.rom.load(vec![
+            // ADDI r2, r0, 1
+            0b000000000001_00000_000_00010_0010011,
+            // LW r3, 1(r1) (misaligned)
+            0b000000000001_00001_010_00011_0000011,
+        ]);
+        assert_eq!(rv.last_step_cycles(), 0);
+
+        rv.step();
+        assert_eq!(rv.last_step_cycles(), 5);
+        assert_eq!(rv.cycle_count(), 5);
+        assert_eq!(rv.reg_file[2], 1);
+
+        // 4 pipeline cycles up to the fault in memory access, then 3 to enter the trap handler
+        rv.step();
+        assert_eq!(rv.last_step_cycles(), 7);
+        assert_eq!(rv.cycle_count(), 12);
+        assert_eq!(rv.trap.mcause.get(), &MCAUSE_LOAD_ADDRESS_MISALIGNED);
+
+        // the host's count doesn't follow the `cycle` CSR, here reset along with the CSRs
+        rv.csr = CSRInterfaceBuilder::new().build();
+        rv.step();
+        assert_eq!(rv.read_counter64(Counter::Cycle), rv.last_step_cycles());
+        assert_eq!(rv.cycle_count(), 12 + rv.last_step_cycles());
+    }
+
+    #[test]
+    fn test_reg_index() {
+        assert_eq!(RegIndex::new(31).value(), 31);
+        assert_eq!(RegIndex::new(32), RegIndex::ZERO);
+        assert_eq!(RegIndex::new(0xFF).value(), 31);
+        assert_eq!(RegIndex::from_field(0xFFFF_FFFF, 7).value(), 31);
+        assert_eq!(
+            RegIndex::from_field(0b00101_00000_000_00011_0000000, 7).value(),
+            3
+        );
+        assert_eq!(
+            RegIndex::from_field(0b00101_00000_000_00011_0000000, 20).value(),
+            5
+        );
+
+        let mut reg_file: RegisterFile = [0; 32];
+        reg_file[RegIndex::new(31)] = 0xDEAD_BEEF;
+        assert_eq!(reg_file[31], 0xDEAD_BEEF);
+        assert_eq!(reg_file[RegIndex::new(63)], 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn test_pipeline_snapshot() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r0, 1
+            0b000000000001_00000_000_00001_0010011,
+            // ADDI r2, r0, 2
+            0b000000000010_00000_000_00010_0010011,
+            // ADDI r3, r0, 3
+            0b000000000011_00000_000_00011_0010011,
+        ]);
+        let bubbles = PipelineSnapshot {
+            fetch: "bubble".to_string(),
+            decode: "bubble".to_string(),
+            execute: "bubble".to_string(),
+            memory_access: "bubble".to_string(),
+            write_back: "bubble".to_string(),
+        };
+        assert_eq!(rv.pipeline_snapshot(), bubbles);
+
+        // the pipeline is serialized, so only the stage that just ran holds an instruction
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(
+            rv.pipeline_snapshot(),
+            PipelineSnapshot {
+                decode: "addi x1,x0,1".to_string(),
+                ..bubbles.clone()
+            }
+        );
+
+        // drive the stages by hand to overlap three instructions
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0b000000000001_00000_000_00001_0010011,
+            0b000000000010_00000_000_00010_0010011,
+            0b000000000011_00000_000_00011_0010011,
+        ]);
+        for step in 0..3 {
+            if step >= 2 {
+                rv.stage_ex.compute(InstructionExecuteParams {
+                    should_stall: false,
+                    decoded_instruction_in: rv.stage_de.get_decoded_instruction_out(),
+                    custom_instructions: &mut rv.custom_instructions,
+                });
+            }
+            if step >= 1 {
+                rv.stage_de.compute(InstructionDecodeParams {
+                    should_stall: false,
+                    instruction_in: rv.stage_if.get_instruction_value_out(),
+                    reg_file: &mut rv.reg_file,
+                    custom_instructions: &rv.custom_instructions,
+                    strict: false,
+                    illegal_policy: IllegalPolicy::Trap,
+                });
+            }
+            rv.stage_if.compute(InstructionFetchParams {
+                should_stall: false,
+                next_address: rv.next_fetch_address(),
+                bus: &rv.bus,
+            });
+            rv.stage_if.latch_next();
+            rv.stage_de.latch_next();
+            rv.stage_ex.latch_next();
+        }
+        assert_eq!(
+            rv.pipeline_snapshot(),
+            PipelineSnapshot {
+                fetch: "addi x3,x0,3".to_string(),
+                decode: "addi x2,x0,2".to_string(),
+                execute: "addi x1,x0,1".to_string(),
+                ..bubbles
+            }
+        );
+    }
+
+    #[test]
+    fn test_branch_redirects_fetch_once() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // JAL r0, 12
+            0b0_0000000110_0_00000000_00000_1101111,
+            0x0000_0013,
+            0x0000_0013,
+            // ADDI r1, r0, 1
+            0b000000000001_00000_000_00001_0010011,
+            // ADDI r2, r0, 2
+            0b000000000010_00000_000_00010_0010011,
+        ]);
+        rv.step();
+        assert_eq!(rv.stage_ex.get_redirect_out(), Some(0x1000_000C));
+        assert_eq!(rv.next_fetch_address(), 0x1000_000C);
+
+        // the redirect is applied by the next fetch
+        rv.cycle();
+        assert_eq!(rv.current_line(), 0x1000_000C);
+        assert_eq!(rv.stage_ex.get_redirect_out(), None);
+
+        // execute still holds the jump, but fetch carries on sequentially rather than being sent
+        // back to the target
+        assert!(matches!(
+            rv.stage_ex.get_execution_value_out().instruction,
+            DecodedInstruction::Jal {
+                branch_address: 0x1000_000C,
+                ..
+            }
+        ));
+        assert_eq!(rv.next_fetch_address(), 0x1000_0010);
+        rv.stage_if.compute(InstructionFetchParams {
+            should_stall: false,
+            next_address: rv.next_fetch_address(),
+            bus: &rv.bus,
+        });
+        rv.stage_if.latch_next();
+        assert_eq!(rv.current_line(), 0x1000_0010);
+
+        // a later jump redirects again
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // JAL r0, 0
+            0b0_0000000000_0_00000000_00000_1101111,
+        ]);
+        for _ in 0..3 {
+            rv.step();
+            assert_eq!(rv.next_fetch_address(), 0x1000_0000);
+        }
+    }
+
+    #[test]
+    fn test_memory_map_accessors() {
+        let mut rv = RV32ISystem::new();
+        assert_eq!(rv.rom_base(), PROGRAM_ROM_START);
+        assert_eq!(rv.ram_base(), RAM_START);
+        assert_eq!(rv.bus.rom_range(), PROGRAM_ROM_START..PROGRAM_ROM_END + 1);
+        assert_eq!(rv.bus.ram_range(), RAM_START..RAM_END + 1);
+
+        // the bases line up with the default regions
+        assert_eq!(rv.bus.permissions(rv.rom_base()), Permissions::RX);
+        assert_eq!(rv.bus.permissions(rv.rom_base() - 1), Permissions::RWX);
+        assert_eq!(rv.bus.permissions(rv.ram_base() - 1), Permissions::RX);
+        assert_eq!(rv.bus.permissions(rv.ram_base()), Permissions::RWX);
+
+        // and execution starts at the ROM base, with RAM usable from its base
+        rv.bus.rom.load(vec![0x0000_0013]);
+        rv.cycle();
+        assert_eq!(rv.current_line(), rv.rom_base());
+        let ram_base = rv.ram_base();
+        rv.bus.write_word(ram_base, 0xDEAD_BEEF).unwrap();
+        assert_eq!(rv.bus.ram.read_word(0), Ok(0xDEAD_BEEF));
+    }
+
+    #[test]
+    fn test_predict_next_pc() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r0, 1
+            0x0010_0093,
+            // JAL r0, 8
+            0x0080_006F,
+            // NOP
+            0x0000_0013,
+            // BNE r1, r0, 8 (taken)
+            0x0000_9463,
+            // NOP
+            0x0000_0013,
+            // BEQ r1, r0, 8 (not taken)
+            0x0000_8463,
+            // JAL r1, 8
+            0x0080_00EF,
+            // NOP
+            0x0000_0013,
+            // JALR r0, 0(r1) (ret)
+            0x0000_8067,
+        ]);
+
+        for (pc, next_pc) in [
+            (0x1000_0000, 0x1000_0004),
+            (0x1000_0004, 0x1000_000C),
+            (0x1000_000C, 0x1000_0014),
+            (0x1000_0014, 0x1000_0018),
+            (0x1000_0018, 0x1000_0020),
+            (0x1000_0020, 0x1000_001C),
+        ] {
+            assert_eq!(rv.next_fetch_address(), pc);
+            assert_eq!(rv.predict_next_pc(), next_pc);
+            rv.step();
+            assert_eq!(rv.next_fetch_address(), next_pc);
+        }
+    }
+
+    #[test]
+    fn test_jal_link_and_redirect() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0000_0013, // NOP
+            0x0080_00EF, // JAL x1, 8
+            0x0010_0113, // ADDI x2, x0, 1
+            0x0010_0193, // ADDI x3, x0, 1
+        ]);
+        rv.step();
+
+        // fetch is redirected to the target as soon as the JAL has executed
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(rv.next_fetch_address(), 0x1000_000C);
+
+        // while the link address is the instruction after the JAL
+        rv.cycle();
+        assert_eq!(
+            rv.stage_ma.get_memory_access_value_out().write_back_value,
+            0x1000_0008
+        );
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
+        assert_eq!(rv.reg_file[1], 0x1000_0008);
+
+        rv.step();
+        assert_eq!(rv.current_line(), 0x1000_000C);
+        assert_eq!(rv.reg_file[2], 0);
+        assert_eq!(rv.reg_file[3], 1);
+    }
+
+    #[test]
+    fn test_trap_handler() {
+        let program = vec![
+            0b00100000000000000000_00001_0110111,    // LUI x1, 0x20000
+            0b000000000010_00001_000_00001_0010011,  // ADDI x1, x1, 2
+            0b000000000000_00001_010_00010_0000011,  // LW x2, 0(x1)
+            0b000000000111_00000_000_00011_0010011,  // ADDI x3, x0, 7
+            0b0_0000000000_0_00000000_00000_1101111, // JAL x0, 0
+        ];
+
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        rv.bus.write_word(0x2000_0000, 42).unwrap();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let handler_calls = calls.clone();
+        rv.set_trap_handler(Box::new(move |rv, trap_params| {
+            handler_calls
+                .borrow_mut()
+                .push((trap_params.mcause, trap_params.mepc));
+            // realign the address and run the load again
+            rv.reg_file[1] &= !0b11;
+            TrapAction::Resume(trap_params.mepc)
+        }));
+        while !rv.is_halted() {
+            rv.step();
+        }
+        assert_eq!(
+            *calls.borrow(),
+            [(MCAUSE_LOAD_ADDRESS_MISALIGNED, 0x1000_0008)]
+        );
+        assert_eq!(rv.reg_file[2], 42);
+        assert_eq!(rv.reg_file[3], 7);
+        // the faulting load doesn't retire, it does once it's resumed
+        assert_eq!(*rv.csr.instret.get(), 5);
+        assert_eq!(rv.csr.mcause, 0);
+        assert_eq!(rv.csr.mepc, 0);
+
+        // dispatched through mtvec as normal
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program);
+        rv.set_trap_handler(Box::new(|_, _| TrapAction::Dispatch));
+        for _ in 0..3 {
+            rv.step();
+        }
+        assert_eq!(rv.csr.mcause, MCAUSE_LOAD_ADDRESS_MISALIGNED);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+    }
+
+    #[test]
+    fn test_identity_csrs() {
+        let mut rv = RV32ISystem::new();
+        rv.csr = CSRInterfaceBuilder::new()
+            .vendor_id(0x0000_0489)
+            .arch_id(0x1234)
+            .impl_id(7)
+            .build();
+        rv.bus.rom.load(vec![
+            0xF120_20F3, // CSRRS x1, marchid, x0
+            0xF110_2173, // CSRRS x2, mvendorid, x0
+            0xF130_21F3, // CSRRS x3, mimpid, x0
+            0xF120_9073, // CSRRW x0, marchid, x1
+        ]);
+        rv.step();
+        rv.step();
+        rv.step();
+        assert_eq!(rv.reg_file[1], 0x1234);
+        assert_eq!(rv.reg_file[2], 0x0489);
+        assert_eq!(rv.reg_file[3], 7);
+
+        // read-only, so writing it is an illegal instruction
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(rv.csr.mtval, 0xF120_9073);
+        assert_eq!(rv.csr.read(CSRM_MODE_MARCHID), Ok(0x1234));
+    }
+
+    #[test]
+    fn test_illegal_csr_access() {
+        for (raw_instruction, error) in [
+            // CSRRS x1, 0x7C0, x0 (not implemented)
+            (0x7C00_20F3, CSRError::Unknown(0x7C0)),
+            // CSRRW x0, misa, x1
+            (0x3010_9073, CSRError::ReadOnly(CSRM_MODE_MISA)),
+        ] {
+            let mut rv = RV32ISystem::new();
+            rv.reg_file[1] = 0xDEAD_BEEF;
+            rv.bus.rom.load(vec![raw_instruction]);
+            rv.step();
+            assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+            assert_eq!(rv.csr.mtval, raw_instruction);
+            assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+            assert_eq!(rv.reg_file[1], 0xDEAD_BEEF);
+            assert_eq!(rv.csr.read(CSRM_MODE_MISA), Ok(DEFAULT_MISA));
+
+            let address = raw_instruction >> 20;
+            let host_error = match error {
+                CSRError::Unknown(_) => rv.csr.read(address).err(),
+                CSRError::ReadOnly(_) => rv.csr.write(address, 0).err(),
+            };
+            assert_eq!(host_error, Some(error));
+        }
+    }
+
+    #[test]
+    fn test_debug_halt() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r1, 1
+            0b000000000001_00001_000_00001_0010011;
+            8
+        ]);
+        rv.step();
+
+        // the request is taken once the instruction in flight has retired
+        rv.cycle();
+        rv.cycle();
+        rv.request_halt();
+        assert!(!rv.is_debug_halted());
+        for _ in 0..20 {
+            rv.cycle();
+        }
+        assert!(rv.is_debug_halted());
+        assert_eq!(rv.reg_file[1], 2);
+        assert_eq!(rv.csr.instret.get(), &2);
+        let halted = rv.architectural_state();
+        let cycles = rv.cycle_count();
+
+        rv.step();
+        rv.interpret(4);
+        assert_eq!(rv.architectural_state(), halted);
+        assert_eq!(rv.cycle_count(), cycles);
+
+        rv.resume();
+        assert!(!rv.is_debug_halted());
+        rv.step();
+        assert_eq!(rv.reg_file[1], 3);
+        assert_eq!(rv.next_fetch_address(), 0x1000_000C);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut rv = RV32ISystem::new();
+        rv.enable_history(2);
+        rv.bus.rom.load(vec![
+            0x0010_0093, // ADDI x1, x0, 1
+            0x0010_8093, // ADDI x1, x1, 1
+            0x2000_0137, // LUI x2, 0x20000
+            0x0011_2023, // SW x1, 0(x2)
+        ]);
+        rv.step();
+        let first_step = rv.architectural_state();
+        rv.step();
+        rv.step();
+        assert_eq!(rv.reg_file[1], 2);
+        assert_eq!(rv.reg_file[2], 0x2000_0000);
+
+        assert!(rv.step_back());
+        assert!(rv.step_back());
+        assert_eq!(rv.reg_file, first_step.registers);
+        assert_eq!(rv.architectural_state(), first_step);
+        // only two steps are kept
+        assert!(!rv.step_back());
+
+        // and it carries on from there, including memory
+        rv.step();
+        rv.step();
+        rv.step();
+        assert_eq!(rv.bus.read_word(0x2000_0000), Ok(2));
+        assert!(rv.step_back());
+        assert_eq!(rv.bus.read_word(0x2000_0000), Ok(0xFFFF_FFFF));
+        assert_eq!(rv.next_fetch_address(), 0x1000_000C);
+        assert_eq!(*rv.csr.instret.get(), 3);
+    }
+
+    #[test]
+    fn test_wfi_is_a_nop() {
+        let program = vec![
+            0x1050_0073, // WFI
+            0x0010_0093, // ADDI x1, x0, 1
+        ];
+        let mut rv = RV32ISystem::new();
+        rv.set_strict_decode(true);
+        rv.bus.rom.load(program.clone());
+        let retired = rv.step().unwrap();
+        assert_eq!(retired.instruction, DecodedInstruction::Wfi {});
+        rv.step();
+        assert_eq!(rv.csr.mcause, 0);
+        assert_eq!(rv.reg_file[1], 1);
+
+        let mut interpreted = RV32ISystem::new();
+        interpreted.bus.rom.load(program);
+        interpreted.interpret(2);
+        assert_eq!(interpreted.architectural_state(), rv.architectural_state());
+    }
+
+    #[test]
+    fn test_step_back_restores_timer() {
+        const MTIP: u32 = 1 << 7;
+        let mut rv = RV32ISystem::new();
+        rv.enable_history(1);
+        rv.bus.rom.load(vec![
+            0x0200_4137, // LUI x2, 0x02004 (mtimecmp)
+            0x0001_2023, // SW x0, 0(x2)
+            0x0001_2223, // SW x0, 4(x2)
+        ]);
+        rv.step();
+        rv.step();
+        let mtime = rv.bus.timer.mtime();
+        rv.step();
+        assert_eq!(rv.bus.timer.mtimecmp(), 0);
+        assert_ne!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, 0);
+
+        assert!(rv.step_back());
+        assert_eq!(rv.bus.timer.mtime(), mtime);
+        assert_eq!(rv.bus.timer.mtimecmp(), 0xFFFF_FFFF_0000_0000);
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, 0);
+        // and the deadline is hit again going forwards
+        rv.step();
+        assert_ne!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, 0);
+    }
+
+    #[test]
+    fn test_csr_builder() {
+        let mut rv = RV32ISystem::new();
+        rv.csr = CSRInterfaceBuilder::new()
+            .mtvec(0x1000_0100)
+            .mie(0)
+            .hart_id(3)
+            .build();
+        assert_eq!(rv.csr.read(CSRM_MODE_MTVEC), Ok(0x1000_0100));
+        assert_eq!(rv.csr.read(CSRM_MODE_MIE), Ok(0));
+        assert_eq!(rv.csr.read(CSRM_MODE_MHARTID), Ok(3));
+        assert_eq!(rv.csr.read(CSRM_MODE_MISA), Ok(0x4000_1100));
+
+        // slli x1, x0, 32 (illegal on RV32)
+        rv.bus.rom.load(vec![0x0200_1093]);
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0100);
+
+        // the defaults are unchanged
+        let csr = CSRInterface::default();
+        assert_eq!(csr.read(CSRM_MODE_MTVEC), Ok(0x1000_0004));
+        assert_eq!(csr.read(CSRM_MODE_MIE), Ok(0x0000_0888));
+    }
+
+    #[test]
+    fn test_csr_set_clear_writable_bits() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r5, r0, -1
+            0xFFF0_0293,
+            // CSRRS r1, mstatus, r5
+            0x3002_A0F3,
+            // CSRRS r2, mstatus, r0
+            0x3000_2173,
+            // CSRRC r0, mstatus, r5
+            0x3002_B073,
+            // CSRRS r3, mstatus, r0
+            0x3000_21F3,
+        ]);
+        for _ in 0..5 {
+            rv.step();
+        }
+        assert_eq!(rv.reg_file[1], 0);
+        // only MIE and MPIE are writable
+        assert_eq!(rv.reg_file[2], MSTATUS_MASK);
+        assert_eq!(rv.reg_file[3], 0);
+    }
+
+    #[test]
+    fn test_invariants() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r0, 1
+            0x0010_0093,
+            // LUI r2, 0x10000 (sp into ROM)
+            0x1000_0137,
+            // ADDI r1, r1, 1
+            0x0010_8093,
+            // ADDI r1, r1, 1
+            0x0010_8093,
+        ]);
+        rv.reg_file[2] = 0x2000_1000;
+        let violations = Rc::new(RefCell::new(vec![]));
+        let handler_violations = violations.clone();
+        rv.set_invariant_handler(Box::new(move |pc, name| {
+            handler_violations.borrow_mut().push((pc, name.to_string()));
+        }));
+        rv.add_invariant(
+            "sp in RAM",
+            Box::new(|registers| (RAM_START..=RAM_END).contains(&registers[2])),
+        );
+
+        rv.step();
+        assert!(violations.borrow().is_empty());
+        rv.step();
+        rv.step();
+        assert_eq!(
+            *violations.borrow(),
+            [
+                (0x1000_0004, "sp in RAM".to_string()),
+                (0x1000_0008, "sp in RAM".to_string())
+            ]
+        );
+
+        violations.borrow_mut().clear();
+        rv.reg_file[2] = 0x2000_1000;
+        rv.reg_file[0] = 1;
+        rv.step();
+        assert_eq!(
+            *violations.borrow(),
+            [(0x1000_000C, INVARIANT_X0_ZERO.to_string())]
+        );
+    }
+
+    #[test]
+    fn test_registers_map() {
+        let mut rv = RV32ISystem::new();
+        for (i, register) in rv.reg_file.iter_mut().enumerate().skip(1) {
+            *register = 0x100 + i as u32;
+        }
+        let registers = rv.registers_map();
+        assert_eq!(registers.len(), 32);
+        assert_eq!(registers["zero"], 0);
+        assert_eq!(registers["ra"], 0x101);
+        assert_eq!(registers["sp"], 0x102);
+        assert_eq!(registers["s0"], 0x108);
+        assert_eq!(registers["a0"], 0x10A);
+        assert_eq!(registers["a7"], 0x111);
+        assert_eq!(registers["s11"], 0x11B);
+        assert_eq!(registers["t6"], 0x11F);
+
+        let mut loaded = RV32ISystem::new();
+        assert_eq!(loaded.load_registers_map(&registers), Ok(()));
+        assert_eq!(loaded.reg_file, rv.reg_file);
+        assert_eq!(
+            loaded.load_registers_map(&BTreeMap::from([("zero", 1), ("a0", 7)])),
+            Ok(())
+        );
+        assert_eq!(loaded.reg_file[0], 0);
+        assert_eq!(loaded.reg_file[10], 7);
+        assert_eq!(loaded.reg_file[11], 0x10B);
+
+        // an unknown name leaves every register alone, even those named before it
+        assert_eq!(
+            loaded.load_registers_map(&BTreeMap::from([("a0", 9), ("x99", 1)])),
+            Err(UnknownRegister("x99".to_string()))
+        );
+        assert_eq!(loaded.reg_file[10], 7);
+    }
+
+    #[test]
+    fn test_mtvec_modes() {
+        let with_mtvec = |mtvec| {
+            let mut rv = RV32ISystem::new();
+            rv.csr = CSRInterfaceBuilder::new().mtvec(mtvec).build();
+            rv
+        };
+        let misaligned_load = vec![
+            // LUI r2, 0x20000
+            0x2000_0137,
+            // LW r3, 1(r2)
+            0x0011_2183,
+        ];
+        let timer_interrupt = |rv: &mut RV32ISystem| {
+            rv.bus.rom.load(vec![
+                // CSRRSI r0, mstatus, MIE
+                0x3004_6073,
+            ]);
+            rv.bus
+                .write_word(CLINT_START + MTIMECMP_OFFSET + 4, 0)
+                .unwrap();
+            rv.bus.write_word(CLINT_START + MTIMECMP_OFFSET, 0).unwrap();
+            rv.step();
+            rv.step();
+            assert_eq!(rv.csr.mcause, MCAUSE_MACHINE_TIMER_INTERRUPT);
+        };
+
+        // direct mode sends everything to the base
+        let mut rv = with_mtvec(0x1000_0100);
+        rv.bus.rom.load(misaligned_load.clone());
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_LOAD_ADDRESS_MISALIGNED);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0100);
+
+        let mut rv = with_mtvec(0x1000_0100);
+        timer_interrupt(&mut rv);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0100);
+
+        // vectored mode only spreads out interrupts
+        let mut rv = with_mtvec(0x1000_0101);
+        rv.bus.rom.load(misaligned_load);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_LOAD_ADDRESS_MISALIGNED);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0100);
+
+        let mut rv = with_mtvec(0x1000_0101);
+        timer_interrupt(&mut rv);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0100 + 4 * 7);
+    }
+
+    #[test]
+    fn test_guest_writes_mtvec() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // LUI r1, 0x10000
+            0x1000_00B7,
+            // ADDI r1, r1, 0x101
+            0x1010_8093,
+            // CSRRW r0, mtvec, r1 (vectored)
+            0x3050_9073,
+            // ADDI r2, r1, 2
+            0x0020_8113,
+            // CSRRW r0, mtvec, r2 (reserved mode, stays vectored)
+            0x3051_1073,
+            // CSRRSI r0, mstatus, MIE
+            0x3004_6073,
+        ]);
+        rv.bus
+            .write_word(CLINT_START + MTIMECMP_OFFSET + 4, 0)
+            .unwrap();
+        rv.bus.write_word(CLINT_START + MTIMECMP_OFFSET, 0).unwrap();
+        for _ in 0..5 {
+            rv.step();
+        }
+        assert_eq!(rv.csr.read(CSRM_MODE_MTVEC), Ok(0x1000_0101));
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_MACHINE_TIMER_INTERRUPT);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0100 + 4 * 7);
+    }
+
+    #[test]
+    fn test_zba_instructions() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x4000_0003;
+        rv.reg_file[2] = 0x0000_0100;
+        rv.bus.rom.load(vec![
+            0b0010000_00010_00001_010_00011_0110011, // SH1ADD x3, x1, x2
+            0b0010000_00010_00001_100_00100_0110011, // SH2ADD x4, x1, x2
+            0b0010000_00010_00001_110_00101_0110011, // SH3ADD x5, x1, x2
+        ]);
+        rv.step();
+        rv.step();
+        rv.step();
+        // the top bits are shifted out
+        assert_eq!(rv.reg_file[3], 0x8000_0106);
+        assert_eq!(rv.reg_file[4], 0x0000_010C);
+        assert_eq!(rv.reg_file[5], 0x0000_0118);
+    }
+
+    #[test]
+    fn test_trap_state() {
+        let mut rv = RV32ISystem::new();
+        // slli x1, x0, 32 (illegal on RV32)
+        rv.bus.rom.load(vec![0x0200_1093]);
+        assert_eq!(rv.trap_state(), TrapInterface::new().snapshot());
+
+        // the trap starts once the decoded instruction reaches execute, and the flush is seen the
+        // cycle after
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(rv.trap_state().state, TrapState::Idle);
+        rv.cycle();
+        let snapshot = rv.trap_state();
+        assert_eq!(snapshot.state, TrapState::SetCSRJump);
+        assert!(snapshot.flush);
+        assert!(!snapshot.set_pc);
+        assert_eq!(snapshot.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(snapshot.mtval, 0x0200_1093);
+
+        // then the CSRs are written and the handler address is handed to fetch
+        rv.cycle();
+        let snapshot = rv.trap_state();
+        assert_eq!(snapshot.state, TrapState::Idle);
+        assert!(!snapshot.flush);
+        assert!(snapshot.set_pc);
+        assert!(snapshot.return_to_pipeline_mode);
+        assert_eq!(snapshot.pc_to_set, 0x1000_0004);
+
+        rv.trap.clear();
+        assert_eq!(rv.trap_state(), TrapInterface::new().snapshot());
+    }
+
+    #[test]
+    fn test_dump_rom_vector_table() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // vector table
+            0x1000_0008,
+            0x1000_000C,
+            // addi x2, x2, -4
+            0xFFC1_0113,
+            // jal x0, 0
+            0x0000_006F,
+        ]);
+        rv.mark_data_region(0x1000_0000..0x1000_0008);
+        assert_eq!(
+            rv.dump_rom(),
+            [
+                "10000000: 10000008  .word 0x10000008",
+                "10000004: 1000000c  .word 0x1000000c",
+                "10000008: ffc10113  addi x2,x2,-4",
+                "1000000c: 0000006f  jal x0,1000000c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_counter64() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![0x0000_0013; 8]);
+        for _ in 0..8 {
+            rv.step();
+        }
+
+        for (counter, low, high) in [
+            (Counter::Cycle, 0xC00, 0xC80),
+            (Counter::Time, 0xC01, 0xC81),
+            (Counter::Instret, 0xC02, 0xC82),
+        ] {
+            let composed =
+                ((rv.csr.read(high).unwrap() as u64) << 32) | rv.csr.read(low).unwrap() as u64;
+            assert_eq!(rv.read_counter64(counter), composed);
+        }
+        assert_eq!(rv.read_counter64(Counter::Cycle), 40);
+        assert_eq!(rv.read_counter64(Counter::Instret), 8);
+
+        // past the 32-bit boundary
+        rv.csr.cycles.set(0x1_0000_0005);
+        rv.csr.cycles.latch_next();
+        assert_eq!(rv.csr.read(0xC80), Ok(1));
+        assert_eq!(rv.csr.read(0xC00), Ok(5));
+        assert_eq!(rv.read_counter64(Counter::Cycle), 0x1_0000_0005);
+    }
+
+    #[test]
+    fn test_self_loop() {
+        for program in [
+            // addi x1, x0, 1; jal x0, 0
+            vec![0x0010_0093, 0x0000_006F],
+            // addi x1, x0, 1; beq x0, x0, 0
+            vec![0x0010_0093, 0x0000_0063],
+        ] {
+            let mut rv = RV32ISystem::new();
+            rv.bus.rom.load(program);
+
+            rv.step();
+            assert!(!rv.is_halted());
+            rv.step();
+            assert!(rv.is_halted());
+            assert_eq!(rv.current_line(), 0x1000_0004);
+
+            // each iteration of the loop still retires
+            for iteration in 1..=5 {
+                rv.step();
+                assert!(rv.is_halted());
+                assert_eq!(rv.current_line(), 0x1000_0004);
+                assert_eq!(*rv.csr.instret.get(), 2 + iteration);
+            }
+        }
+
+        // a branch that isn't taken carries on
+        let mut rv = RV32ISystem::new();
+        // bne x0, x0, 0
+        rv.bus.rom.load(vec![0x0000_1063]);
+        rv.step();
+        assert!(!rv.is_halted());
+    }
+
+    #[test]
+    fn test_machine_timer() {
+        const MTIP: u32 = 1 << 7;
+        let mut rv = RV32ISystem::new();
+        rv.bus
+            .write_word(CLINT_START + MTIMECMP_OFFSET + 4, 0)
+            .unwrap();
+        rv.bus
+            .write_word(CLINT_START + MTIMECMP_OFFSET, 12)
+            .unwrap();
+        rv.bus.rom.load(vec![
+            0x3440_22F3, // CSRRS x5, mip, x0
+            0x0000_0013, // NOP
+            0x0000_0013, // NOP
+            0x0000_0013, // NOP
+            0x3440_2373, // CSRRS x6, mip, x0
+        ]);
+
+        while rv.bus.timer.mtime() < 11 {
+            rv.cycle();
+            assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, 0);
+        }
+        rv.cycle();
+        assert_eq!(rv.bus.read_word(CLINT_START + MTIME_OFFSET), Ok(12));
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, MTIP);
+
+        while rv.next_fetch_address() != 0x1000_0014 {
+            rv.step();
+        }
+        assert_eq!(rv.reg_file[5] & MTIP, 0);
+        assert_eq!(rv.reg_file[6] & MTIP, MTIP);
+
+        // moving the deadline on clears it again
+        rv.bus
+            .write_word(CLINT_START + MTIMECMP_OFFSET, 100)
+            .unwrap();
+        rv.cycle();
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, 0);
+    }
+
+    #[test]
+    fn test_machine_timer_interrupt() {
+        const MTIP: u32 = 1 << 7;
+        let program = vec![
+            // csrrsi x0, mstatus, 8 (enable interrupts)
+            0x3004_6073,
+            0x0000_0013,
+            0x0000_0013,
+            0x0000_0013,
+            0x0000_0013,
+            0x0000_0013,
+        ];
+        let set_deadline = |rv: &mut RV32ISystem| {
+            rv.bus
+                .write_word(CLINT_START + MTIMECMP_OFFSET + 4, 0)
+                .unwrap();
+            rv.bus
+                .write_word(CLINT_START + MTIMECMP_OFFSET, 12)
+                .unwrap();
+        };
+
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        set_deadline(&mut rv);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, 0);
+        // pending part way through the third instruction, taken at the next boundary
+        rv.step();
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, MTIP);
+        assert_eq!(rv.step(), None);
+        assert_eq!(rv.csr.mcause, MCAUSE_MACHINE_TIMER_INTERRUPT);
+        assert_eq!(rv.csr.mepc, 0x1000_000C);
+        assert_eq!(*rv.csr.instret.get(), 3);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+
+        // pending but not enabled in mie
+        let mut rv = RV32ISystem::new();
+        rv.csr = CSRInterfaceBuilder::new().mie(0).build();
+        rv.bus.rom.load(program.clone());
+        set_deadline(&mut rv);
+        for _ in 0..5 {
+            rv.step();
+        }
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, MTIP);
+        assert_eq!(rv.csr.mcause, 0);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0014);
+
+        // pending but interrupts never globally enabled
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![0x0000_0013; 6]);
+        set_deadline(&mut rv);
+        for _ in 0..5 {
+            rv.step();
+        }
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, MTIP);
+        assert_eq!(rv.csr.mcause, 0);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0014);
+    }
+
+    #[test]
+    fn test_guest_cannot_clear_timer_interrupt() {
+        const MTIP: u32 = 1 << 7;
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // csrrw x0, mip, x0
+            0x3440_1073,
+            // csrrsi x0, mstatus, 8 (enable interrupts)
+            0x3004_6073,
+            0x0000_0013,
+            0x0000_0013,
+        ]);
+        // already due
+        rv.bus
+            .write_word(CLINT_START + MTIMECMP_OFFSET + 4, 0)
+            .unwrap();
+        rv.bus.write_word(CLINT_START + MTIMECMP_OFFSET, 0).unwrap();
+
+        // clearing mip leaves MTIP set while the timer is still due, so it's taken once enabled
+        rv.step();
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP).unwrap() & MTIP, MTIP);
+        rv.step();
+        assert_eq!(rv.step(), None);
+        assert_eq!(rv.csr.mcause, MCAUSE_MACHINE_TIMER_INTERRUPT);
+        assert_eq!(rv.csr.mepc, 0x1000_0008);
+    }
+
+    #[test]
+    fn test_scheduled_interrupt() {
+        let program = vec![
+            // csrrsi x0, mstatus, 8 (enable interrupts)
+            0x3004_6073,
+            0x0000_0013,
+            0x0000_0013,
+            0x0000_0013,
+            0x0000_0013,
+            0x0000_0013,
+        ];
+
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        rv.schedule_interrupt(20, MCAUSE_MACHINE_EXTERNAL_INTERRUPT);
+        for _ in 0..20 {
+            rv.cycle();
+        }
+        assert_eq!(rv.cycle_count(), 20);
+        assert_eq!(*rv.csr.instret.get(), 4);
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP), Ok(0));
+
+        // raised once cycle 20 has run, and taken straight away as this is an instruction boundary
+        rv.cycle();
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP), Ok(1 << 11));
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
+        assert_eq!(rv.csr.mcause, MCAUSE_MACHINE_EXTERNAL_INTERRUPT);
+        assert_eq!(rv.csr.mepc, 0x1000_0010);
+        assert_eq!(rv.csr.mtval, 0);
+        assert_eq!(*rv.csr.instret.get(), 4);
+        // to the mtvec base in direct mode, with interrupts disabled in the handler
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+        assert_eq!(rv.csr.mstatus & MSTATUS_MIE_MASK, 0);
+
+        // raised part way through an instruction, it waits for the boundary
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        rv.schedule_interrupt(22, MCAUSE_MACHINE_EXTERNAL_INTERRUPT);
+        for _ in 0..5 {
+            rv.step();
+        }
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP), Ok(1 << 11));
+        assert_eq!(rv.csr.mcause, 0);
+        // the next step takes the interrupt
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_MACHINE_EXTERNAL_INTERRUPT);
+        assert_eq!(rv.csr.mepc, 0x1000_0014);
+
+        // not taken while interrupts are disabled
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program[1..].to_vec());
+        rv.schedule_interrupt(0, MCAUSE_MACHINE_EXTERNAL_INTERRUPT);
+        for _ in 0..5 {
+            rv.step();
+        }
+        assert_eq!(rv.csr.read(CSRM_MODE_MIP), Ok(1 << 11));
+        assert_eq!(rv.csr.mcause, 0);
+        assert_eq!(rv.current_line(), 0x1000_0010);
+    }
+
+    #[test]
+    fn test_power_on_fetch() {
+        // nothing loaded, the erased ROM word is fetched from the reset vector
+        let mut rv = RV32ISystem::new();
+        assert_eq!(rv.current_line(), PROGRAM_ROM_START);
+        rv.cycle();
+        assert_eq!(
+            rv.stage_if.get_instruction_value_out(),
+            InstructionValue {
+                raw_instruction: ROM_ERASED_WORD,
+                pc: PROGRAM_ROM_START,
+                pc_plus_4: PROGRAM_ROM_START + 4,
+            }
+        );
+
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![0xDEAD_BEEF, 0xC0DE_CAFE]);
+        rv.cycle();
+        assert_eq!(
+            rv.stage_if.get_instruction_value_out(),
+            InstructionValue {
+                raw_instruction: 0xDEAD_BEEF,
+                pc: PROGRAM_ROM_START,
+                pc_plus_4: PROGRAM_ROM_START + 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_counters_wrap() {
+        let mut rv = RV32ISystem::new();
+        rv.csr.instret.set(u64::MAX);
+        rv.csr.instret.latch_next();
+        rv.csr.cycles.set(u64::MAX - 2);
+        rv.csr.cycles.latch_next();
+        rv.bus.rom.load(vec![
+            // ADDI r0, r0, 0
+            0b000000000000_00000_000_00000_0010011,
+        ]);
+
+        rv.step();
+        assert_eq!(*rv.csr.instret.get(), 0);
+        assert_eq!(*rv.csr.cycles.get(), 2);
+        assert_eq!(rv.last_step_cycles(), 5);
+    }
+
+    #[test]
+    fn test_custom_instruction() {
+        const OPCODE_CUSTOM_0: u8 = 0b000_1011;
+
+        let mut rv = RV32ISystem::new();
+        // popcount rd, rs1
+        rv.register_custom(
+            OPCODE_CUSTOM_0,
+            Box::new(|_raw: u32, rs1: u32, _rs2: u32| Some(rs1.count_ones())),
+        );
+        // discards its result
+        rv.register_custom(0b010_1011, Box::new(|_raw: u32, _rs1: u32, _rs2: u32| None));
+        rv.reg_file[1] = 0xF0F0_0001;
+        rv.reg_file[3] = 0x1234_5678;
+        rv.bus.rom.load(vec![
+            // POPCOUNT r2, r1
+            0b0000000_00000_00001_000_00010_0001011,
+            // custom-1 r3, r1
+            0b0000000_00000_00001_000_00011_0101011,
+            // ADDI r4, r2, 1
+            0b000000000001_00010_000_00100_0010011,
+        ]);
+
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[2], 9);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[3], 0x1234_5678);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[4], 10);
+    }
+
+    #[test]
+    fn test_same_rd_commits_in_program_order() {
+        // the pipeline is serialized, so a long running instruction writes back before the next
+        // instruction is even fetched and can't be overtaken
+        let mut rv = RV32ISystem::new();
+        // div rd, rs1, rs2
+        rv.register_custom(
+            0b000_1011,
+            Box::new(|_raw: u32, rs1: u32, rs2: u32| Some(rs1 / rs2)),
+        );
+        rv.reg_file[1] = 100;
+        rv.reg_file[2] = 7;
+        rv.bus.rom.load(vec![
+            // DIV r3, r1, r2
+            0b0000000_00010_00001_000_00011_0001011,
+            // ADDI r3, r0, 5
+            0b000000000101_00000_000_00011_0010011,
+        ]);
+
+        rv.step();
+        assert_eq!(rv.reg_file[3], 14);
+        rv.step();
+        assert_eq!(rv.reg_file[3], 5);
+    }
+
+    #[test]
+    fn test_csrrw_read() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0xDEAD_BEEF;
+        rv.reg_file[3] = 0xC0DE_CAFE;
+        rv.bus.rom.load(vec![
+            // csrrw x0, mscratch, x1
+            0x3400_9073,
+            // csrrw x2, mscratch, x3
+            0x3401_9173,
+        ]);
+
+        run_instruction!(rv);
+        assert_eq!(rv.csr.read(CSRM_MODE_MSCRATCH), Ok(0xDEAD_BEEF));
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[2], 0xDEAD_BEEF);
+        assert_eq!(rv.csr.read(CSRM_MODE_MSCRATCH), Ok(0xC0DE_CAFE));
+    }
+
+    #[test]
+    fn test_mstatush() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x30;
+        rv.reg_file[2] = 0xFFFF_FFFF;
+        rv.bus.rom.load(vec![
+            // csrrs x2, mstatush, x0
+            0x3100_2173,
+            // csrrw x3, mstatush, x1 (sets MBE and SBE, which are ignored)
+            0x3100_91F3,
+            // csrrs x4, mstatush, x0
+            0x3100_2273,
+        ]);
+
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[2], 0);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[3], 0);
+        run_instruction!(rv);
+        assert_eq!(rv.reg_file[4], 0);
+        assert_eq!(rv.csr.read(CSRM_MODE_MSTATUSH), Ok(0));
+        assert_eq!(rv.trap.state.get(), &TrapState::Idle);
+        assert_eq!(rv.current_line(), 0x1000_0008);
+    }
+
+    #[test]
+    fn test_assembled_countdown_loop() {
+        let program = asm::assemble(
+            "
+                li x1, 5
+            loop:
+                addi x2, x2, 3
+                addi x1, x1, -1
+                bnez x1, loop
+            ",
+        )
+        .unwrap();
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program);
+
+        // li, then 3 instructions for each of the 5 iterations
+        for _ in 0..16 {
+            rv.step();
+        }
+        assert_eq!(rv.current_line(), 0x1000_000C);
+        assert_eq!(rv.reg_file[1], 0);
+        assert_eq!(rv.reg_file[2], 15);
+        assert_eq!(*rv.csr.instret.get(), 16);
+    }
+
+    #[test]
+    fn test_x0_result_not_read_back() {
+        let program = vec![
+            // ADD r0, r1, r2
+            0b0000000_00010_00001_000_00000_0110011,
+            // ADD r3, r0, r4
+            0b0000000_00100_00000_000_00011_0110011,
+        ];
+        // there's no forwarding, the serialized pipeline reads operands from the register file,
+        // where the write to x0 was discarded
+        let mut staged = RV32ISystem::new();
+        let mut interpreted = RV32ISystem::new();
+        for rv in [&mut staged, &mut interpreted] {
+            rv.bus.rom.load(program.clone());
+            rv.reg_file[1] = 5;
+            rv.reg_file[2] = 6;
+            rv.reg_file[4] = 7;
+        }
+        staged.step();
+        staged.step();
+        interpreted.interpret(2);
+        for rv in [staged, interpreted] {
+            assert_eq!(rv.reg_file[0], 0);
+            assert_eq!(rv.reg_file[3], 7);
+        }
+    }
+
+    #[test]
+    fn test_call_stack_coroutine_swap() {
+        let mut rv = RV32ISystem::new();
+        rv.enable_call_tracking();
+        rv.bus.rom.load(vec![
+            // JAL r1, 8
+            0x0080_00EF,
+            // NOP
+            0x0000_0013,
+            // LUI r5, 0x10000
+            0x1000_02B7,
+            // JALR r1, 20(r5)
+            0x0142_80E7,
+            // NOP
+            0x0000_0013,
+            // JALR r0, 0(r1)
+            0x0000_8067,
+        ]);
+        rv.step();
+        assert_eq!(rv.call_stack(), [(0x1000_0000, 0x1000_0008)]);
+        rv.step();
+        rv.step();
+        // the first call is replaced rather than nested under
+        assert_eq!(rv.call_stack(), [(0x1000_000C, 0x1000_0014)]);
+        rv.step();
+        assert_eq!(rv.call_stack(), []);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0010);
+    }
+
+    #[test]
+    fn test_pipeline_occupancy() {
+        let mut rom = vec![0x0000_0013; 16];
+        // SLLI r1, r0, 32 (illegal on RV32)
+        rom[3] = 0x0200_1093;
+        // illegal instruction handler in this core's vector table, JAL r0, 0
+        rom[15] = 0x0000_006F;
+        let mut rv = RV32ISystem::new();
+        rv.csr = CSRInterfaceBuilder::new()
+            .vector_layout(VectorLayout::Table)
+            .build();
+        rv.bus.rom.load(rom);
+
+        let mut occupancy = vec![];
+        for _ in 0..25 {
+            rv.cycle();
+            occupancy.push(rv.pipeline_occupancy());
+        }
+        // one instruction at a time is as full as the serialized pipeline gets, then the trap
+        // drains it for three cycles before the handler is fetched
+        assert_eq!(occupancy[..17], [1; 17]);
+        assert_eq!(occupancy[17..20], [0; 3]);
+        assert_eq!(occupancy[20..], [1; 5]);
+        assert_eq!(rv.current_line(), 0x1000_003C);
+
+        let stats = rv.pipeline_occupancy_stats();
+        assert_eq!(
+            stats,
+            OccupancyStats {
+                cycles: 25,
+                occupied: 22,
+                max: 1,
+            }
+        );
+        assert_eq!(stats.average(), 0.88);
+    }
+
+    #[test]
+    fn test_strict_decode() {
+        let program = vec![
+            // ADDI r1, r0, 5
+            0x0050_0093,
+            // ADD r3, r1, r1 with the reserved funct7 0b0000010
+            0b0000010_00001_00001_000_00011_0110011,
+        ];
+
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        rv.interpret(2);
+        assert_eq!(rv.reg_file[3], 10);
+
+        let mut rv = RV32ISystem::new();
+        rv.set_strict_decode(true);
+        rv.bus.rom.load(program);
+        rv.interpret(2);
+        assert_eq!(rv.reg_file[3], 0);
+        assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+    }
+
+    #[test]
+    fn test_mret_to_misaligned_mepc() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // LUI r1, 0x10000
+            0x1000_00B7,
+            // ADDI r1, r1, 0x12
+            0x0120_8093,
+            // CSRRW r0, mepc, r1
+            0x3410_9073,
+            // MRET
+            0x3020_0073,
+            // JAL r0, 0
+            0x0000_006F,
+        ]);
+        for _ in 0..4 {
+            rv.step();
+        }
+        assert_eq!(rv.csr.mepc, 0x1000_0012);
+        assert_eq!(rv.csr.read(CSRM_MODE_MEPC), Ok(0x1000_0010));
+        assert_eq!(rv.next_fetch_address(), 0x1000_0010);
+        rv.step();
+        assert!(rv.is_halted());
+
+        // with compressed instructions only bit 0 is masked
+        let mut csr = CSRInterfaceBuilder::new()
+            .misa(DEFAULT_MISA | 0b100)
+            .build();
+        csr.mepc = 0x1000_0013;
+        assert_eq!(csr.read(CSRM_MODE_MEPC), Ok(0x1000_0012));
+    }
+
+    #[test]
+    fn test_jal_retires_before_following_fault() {
+        let mut rom = vec![0x0000_0013; 19];
+        // JAL r1, 8
+        rom[0] = 0x0080_00EF;
+        // SLLI r2, r0, 32 (illegal on RV32)
+        rom[2] = 0x0200_1113;
+        // JAL r0, 0
+        rom[3] = 0x0000_006F;
+        // illegal instruction handler, skips the instruction: CSRRS r5, mepc, r0
+        rom[15] = 0x3410_22F3;
+        // ADDI r5, r5, 4
+        rom[16] = 0x0042_8293;
+        // CSRRW r0, mepc, r5
+        rom[17] = 0x3412_9073;
+        // MRET
+        rom[18] = 0x3020_0073;
+        let mut rv = RV32ISystem::new();
+        // the handlers are laid out as this core's vector table
+        rv.csr = CSRInterfaceBuilder::new()
+            .vector_layout(VectorLayout::Table)
+            .build();
+        rv.bus.rom.load(rom);
+
+        // the pipeline is serialized, so the JAL has written back before the faulting
+        // instruction is even fetched and the flush can't squash it
+        rv.step();
+        assert_eq!(rv.reg_file[1], 0x1000_0004);
+        rv.step();
+        assert_eq!(rv.next_fetch_address(), 0x1000_003C);
+        assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(rv.reg_file[1], 0x1000_0004);
+        assert_eq!(rv.csr.instret.get(), &1);
+
+        // the JAL isn't run again after the handler returns
+        for _ in 0..6 {
+            rv.step();
+        }
+        assert_eq!(rv.next_fetch_address(), 0x1000_000C);
+        assert!(rv.is_halted());
+        assert_eq!(rv.reg_file[1], 0x1000_0004);
+    }
+
+    #[test]
+    fn test_trap_takes_precedence_over_mret() {
+        let mut rv = RV32ISystem::new();
+        // the handlers are laid out as this core's vector table
+        rv.csr = CSRInterfaceBuilder::new()
+            .vector_layout(VectorLayout::Table)
+            .build();
+        rv.reg_file[1] = 0x2000_0000;
+        let mut rom = vec![0x0000_0013; 0x12];
+        // LW r3, 1(r1) (misaligned)
+        rom[0] = 0b000000000001_00001_010_00011_0000011;
+        // MRET
+        rom[1] = 0b001100000010_00000_000_00000_1110011;
+        // load address misaligned handler, JAL r0, -0x40 (back to the mret)
+        rom[0x11] = 0b1_1111100000_1_11111111_00000_1101111;
+        rv.bus.rom.load(rom);
+
+        // fetch, decode and execute the load as normal
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(
+            *rv.state.get(),
+            CPUState::Pipeline(PipelineState::MemoryAccess)
+        );
+
+        // the pipeline is serialized, so overlap the mret with the load by hand: the load faults
+        // in memory access on the same cycle the mret is decoded
+        rv.stage_ma.compute(InstructionMemoryAccessParams {
+            should_stall: false,
+            execution_value_in: rv.stage_ex.get_execution_value_out(),
+            bus: &mut rv.bus,
+            csr: &mut rv.csr,
+            misaligned_policy: MisalignedAccessPolicy::Trap,
+            misaligned_accesses: &mut vec![],
+        });
+        rv.stage_if.compute(InstructionFetchParams {
+            should_stall: false,
+            next_address: 0x1000_0004,
+            bus: &rv.bus,
+        });
+        rv.stage_if.latch_next();
+        rv.stage_de.compute(InstructionDecodeParams {
+            should_stall: false,
+            instruction_in: rv.stage_if.get_instruction_value_out(),
+            reg_file: &mut rv.reg_file,
+            custom_instructions: &vec![],
+            strict: false,
+            illegal_policy: IllegalPolicy::Trap,
+        });
+        rv.stage_ma.latch_next();
+        rv.stage_de.latch_next();
+        rv.state.set(CPUState::Pipeline(PipelineState::WriteBack));
+        rv.state.latch_next();
+        assert!(rv.stage_ma.get_memory_access_value_out().trap_params.trap);
+        assert!(rv.stage_de.get_decoded_instruction_out().return_from_trap);
+
+        // the fault wins
+        rv.cycle();
+        assert!(!rv.mret);
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(*rv.trap.state.get(), TrapState::SetCSRJump);
+        assert_eq!(rv.trap.mcause.get(), &MCAUSE_LOAD_ADDRESS_MISALIGNED);
+        rv.cycle();
+        assert_eq!(*rv.trap.state.get(), TrapState::Idle);
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
+        assert_eq!(rv.csr.mcause, MCAUSE_LOAD_ADDRESS_MISALIGNED);
+        assert_eq!(rv.csr.mepc, 0x1000_0000);
+
+        // the handler jumps back to the mret, which now runs
+        rv.step();
+        assert_eq!(rv.current_line(), 0x1000_0044);
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(rv.current_line(), 0x1000_0004);
+        assert!(rv.stage_de.get_decoded_instruction_out().return_from_trap);
+        rv.cycle();
+        assert!(rv.mret);
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(*rv.trap.state.get(), TrapState::ReturnFromTrap);
+    }
+
+    #[test]
+    fn test_mret_with_pending_interrupt() {
+        let mut rom = vec![0x0000_0013; 20];
+        // JAL r0, 0x40
+        rom[0] = 0x0400_006F;
+        // machine timer interrupt handler, MRET without clearing mip
+        rom[8] = 0x3020_0073;
+        // CSRRSI r0, mstatus, MIE
+        rom[16] = 0x3004_6073;
+        // ADDI r1, r1, 1
+        rom[17] = 0x0010_8093;
+        // JAL r0, 0
+        rom[18] = 0x0000_006F;
+        let mut rv = RV32ISystem::new();
+        // the handlers are laid out as this core's vector table
+        rv.csr = CSRInterfaceBuilder::new()
+            .vector_layout(VectorLayout::Table)
+            .build();
+        rv.bus.rom.load(rom);
+        rv.schedule_interrupt(0, MCAUSE_MACHINE_TIMER_INTERRUPT);
+
+        rv.step();
+        rv.step();
+        let mut pcs = vec![];
+        for _ in 0..5 {
+            rv.step();
+            pcs.push(rv.next_fetch_address());
+        }
+        // the mret completes, restoring MIE, then the still pending interrupt is taken before
+        // the interrupted ADDI runs
+        assert_eq!(
+            pcs,
+            [
+                0x1000_0020,
+                0x1000_0044,
+                0x1000_0020,
+                0x1000_0044,
+                0x1000_0020
+            ]
+        );
+        assert_eq!(rv.csr.mepc, 0x1000_0044);
+        assert_eq!(rv.reg_file[1], 0);
+
+        rv.csr.clear_interrupt(MCAUSE_MACHINE_TIMER_INTERRUPT);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.reg_file[1], 1);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0048);
+    }
+
+    #[test]
+    fn test_mret_decodes_to_mret() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r0, 1
+            0b000000000001_00000_000_00001_0010011,
+            // MRET
+            0b001100000010_00000_000_00000_1110011,
+        ]);
+        run_instruction!(rv);
+        assert!(matches!(
+            rv.stage_de.get_decoded_instruction_out().instruction,
+            DecodedInstruction::Alu { .. }
+        ));
+
+        rv.cycle();
+        rv.cycle();
+        let decoded = rv.stage_de.get_decoded_instruction_out();
+        assert_eq!(decoded.pc, 0x1000_0004);
+        assert_eq!(decoded.instruction, DecodedInstruction::Mret {});
+        assert!(decoded.return_from_trap);
+    }
+
+    #[test]
+    fn test_energy_estimate() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            // ADDI r1, r0, 4
+            0b000000000100_00000_000_00001_0010011,
+            // LUI r2, 0x20000
+            0b00100000000000000000_00010_0110111,
+            // SW r1, 0(r2)
+            0b0000000_00001_00010_010_00000_0100011,
+            // LW r3, 0(r2)
+            0b000000000000_00010_010_00011_0000011,
+            // BEQ r1, r3, 8
+            0b0_000000_00011_00001_000_0100_0_1100011,
+            // NOP (skipped)
+            0b000000000000_00000_000_00000_0010011,
+            // JAL r0, 0
+            0b0_0000000000_0_00000000_00000_1101111,
+        ]);
+        rv.step();
+        assert_eq!(rv.energy_estimate(), 0.0);
+
+        rv.enable_energy_model(EnergyCosts {
+            alu: 1.0,
+            load: 4.0,
+            store: 5.0,
+            branch: 2.0,
+            jump: 3.0,
+            ..Default::default()
+        });
+        // LUI, SW, LW, BEQ and JAL twice
+        for _ in 0..6 {
+            rv.step();
+        }
+        assert_eq!(rv.reg_file[3], 4);
+        assert_eq!(rv.energy_estimate(), 1.0 + 5.0 + 4.0 + 2.0 + 2.0 * 3.0);
+
+        rv.enable_energy_model(EnergyCosts::default());
+        assert_eq!(rv.energy_estimate(), 0.0);
+        rv.step();
+        assert_eq!(rv.energy_estimate(), 1.0);
+    }
+
+    #[test]
+    fn test_stages_frozen_during_trap() {
+        fn stage_outputs(rv: &RV32ISystem) -> String {
+            format!(
+                "{:?} {:?} {:?} {:?} {:?}",
+                rv.stage_if.get_instruction_value_out(),
+                rv.stage_de.get_decoded_instruction_out(),
+                rv.stage_ex.get_execution_value_out(),
+                rv.stage_ma.get_memory_access_value_out(),
+                rv.stage_wb.get_write_back_value_out(),
+            )
+        }
+        let flushed = stage_outputs(&RV32ISystem::new());
+
+        let mut program = vec![0x0000_0013; 18];
+        program[0] = 0x0010_0093; // ADDI x1, x0, 1
+        program[1] = 0x0010_2103; // LW x2, 1(x0), misaligned
+        program[17] = 0x0000_006F; // JAL x0, 0 in the handler
+        let mut rv = RV32ISystem::new();
+        // the handlers are laid out as this core's vector table
+        rv.csr = CSRInterfaceBuilder::new()
+            .vector_layout(VectorLayout::Table)
+            .build();
+        rv.bus.rom.load(program);
+        rv.step();
+        while *rv.state.get() != CPUState::Trap {
+            rv.cycle();
+        }
+
+        // from the first trap cycle until the handler is fetched, every stage either holds what
+        // it had or has been flushed, and nothing retires or writes a register
+        let frozen = stage_outputs(&rv);
+        let reg_file = rv.reg_file;
+        let instret = *rv.csr.instret.get();
+        let mut flushed_cycles = 0;
+        loop {
+            assert!(rv.trap_stall);
+            rv.cycle();
+            assert_eq!(rv.reg_file, reg_file);
+            assert_eq!(*rv.csr.instret.get(), instret);
+            if *rv.state.get() != CPUState::Trap {
+                break;
+            }
+            let outputs = stage_outputs(&rv);
+            if outputs == flushed {
+                flushed_cycles += 1;
+            } else {
+                assert_eq!(flushed_cycles, 0, "stages advanced after the flush");
+                assert_eq!(outputs, frozen, "stages advanced during the trap");
+            }
+        }
+        assert!(flushed_cycles > 0);
+
+        // leaving the trap only points fetch at the handler, it's fetched on the next cycle
+        let fetch = rv.stage_if.get_instruction_value_out();
+        assert_eq!(fetch.pc, 0x1000_0044);
+        assert_eq!(fetch.raw_instruction, 0);
+        assert_eq!(rv.csr.mcause, MCAUSE_LOAD_ADDRESS_MISALIGNED);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0044);
+    }
+
+    #[test]
+    fn test_illegal_policy() {
+        let program = vec![
+            0x0200_1093, // SLLI x1, x0, 32 (illegal on RV32)
+            0x0050_0113, // ADDI x2, x0, 5
+        ];
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+
+        let mut rv = RV32ISystem::new();
+        rv.set_illegal_policy(IllegalPolicy::Nop);
+        rv.bus.rom.load(program);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, 0);
+        assert_eq!(rv.reg_file[1], 0);
+        assert_eq!(rv.reg_file[2], 5);
+        assert_eq!(*rv.csr.instret.get(), 2);
+    }
+
+    #[test]
+    fn test_jalr_clears_low_bit() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x1000_000F;
+        rv.bus.rom.load(vec![
+            0b000000000001_00001_000_00101_1100111, // JALR x5, 1(x1)
+            0x0000_0013,                            // NOP
+            0x0000_0013,                            // NOP
+            0x0000_0013,                            // NOP
+            0b000000000001_00000_000_00110_0010011, // ADDI x6, x0, 1
+        ]);
+        rv.step();
+        assert_eq!(rv.reg_file[5], 0x1000_0004);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0010);
+
+        rv.cycle();
+        assert_eq!(rv.stage_if.get_instruction_value_out().pc, 0x1000_0010);
+        while *rv.state.get() != CPUState::Pipeline(PipelineState::Fetch) {
+            rv.cycle();
+        }
+        assert_eq!(rv.reg_file[6], 1);
+        assert_eq!(rv.csr.mcause, 0);
+    }
+
+    #[test]
+    fn test_add_sub_wrap() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0xFFFF_FFFF;
+        rv.reg_file[2] = 1;
+
+        // ADD x3, x1, x2
+        let effect = rv.execute_one(0b0000000_00010_00001_000_00011_0110011);
+        assert_eq!(effect.register_write, Some((3, 0)));
+        // SUB x4, x0, x2
+        let effect = rv.execute_one(0b0100000_00010_00000_000_00100_0110011);
+        assert_eq!(effect.register_write, Some((4, 0xFFFF_FFFF)));
+        // ADDI x5, x0, -1
+        let effect = rv.execute_one(0b111111111111_00000_000_00101_0010011);
+        assert_eq!(effect.register_write, Some((5, 0xFFFF_FFFF)));
+        // AUIPC x6, 0xF0000
+        let effect = rv.execute_one(0b11110000000000000000_00110_0010111);
+        assert_eq!(effect.register_write, Some((6, 0x0000_000C)));
+
+        // and the same through the interpreter
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0xFFFF_FFFF;
+        rv.reg_file[2] = 1;
+        rv.bus.rom.load(vec![
+            0b0000000_00010_00001_000_00011_0110011, // ADD x3, x1, x2
+            0b0100000_00010_00000_000_00100_0110011, // SUB x4, x0, x2
+            0b11110000000000000000_00110_0010111,    // AUIPC x6, 0xF0000
+        ]);
+        rv.interpret(3);
+        assert_eq!(rv.reg_file[3], 0);
+        assert_eq!(rv.reg_file[4], 0xFFFF_FFFF);
+        assert_eq!(rv.reg_file[6], 0x0000_0008);
+    }
+
+    #[test]
+    fn test_register_shift_amount_masked() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 0x8000_0003;
+        rv.reg_file[2] = 0x21;
+
+        // SLL x3, x1, x2
+        let effect = rv.execute_one(0b0000000_00010_00001_001_00011_0110011);
+        assert_eq!(effect.register_write, Some((3, 0x0000_0006)));
+        // SRL x4, x1, x2
+        let effect = rv.execute_one(0b0000000_00010_00001_101_00100_0110011);
+        assert_eq!(effect.register_write, Some((4, 0x4000_0001)));
+        // SRA x5, x1, x2
+        let effect = rv.execute_one(0b0100000_00010_00001_101_00101_0110011);
+        assert_eq!(effect.register_write, Some((5, 0xC000_0001)));
+    }
+
+    #[test]
+    fn test_upper_immediates_written_back() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0b00010010001101000101_00101_0110111, // LUI x5, 0x12345
+            0b00000000000000000001_00110_0010111, // AUIPC x6, 0x1
+        ]);
+
+        for (rd, expected) in [(5, 0x1234_5000), (6, 0x1000_1004)] {
+            for _ in 0..4 {
+                rv.cycle();
+            }
+            // only lands in the register file in the write-back cycle
+            assert_eq!(
+                *rv.state.get(),
+                CPUState::Pipeline(PipelineState::WriteBack)
+            );
+            assert_eq!(rv.reg_file[rd], 0);
+            rv.cycle();
+            assert_eq!(rv.reg_file[rd], expected);
+        }
+    }
+
+    #[test]
+    fn test_undecodable_opcode_traps() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0000_0013, // NOP
+            0xFFFF_FFFF,
+        ]);
+        rv.step();
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(*rv.trap.mcause.get(), MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(*rv.trap.mtval.get(), 0xFFFF_FFFF);
+
+        while *rv.state.get() == CPUState::Trap {
+            rv.cycle();
+        }
+        assert_eq!(rv.csr.mcause, MCAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(rv.csr.mtval, 0xFFFF_FFFF);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+        assert_eq!(*rv.csr.instret.get(), 1);
+    }
+
+    #[test]
+    fn test_ecall_trap() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0000_0013, // NOP
+            0x0000_0073, // ECALL
+        ]);
+        rv.step();
+        rv.cycle();
+        rv.cycle();
+        rv.cycle();
+        assert_eq!(*rv.state.get(), CPUState::Trap);
+        assert_eq!(*rv.trap.mcause.get(), MCAUSE_ENVIRONMENT_CALL_FROM_MMODE);
+        assert_eq!(*rv.trap.mepc.get(), 0x1000_0004);
+
+        while *rv.state.get() == CPUState::Trap {
+            rv.cycle();
+        }
+        assert_eq!(rv.csr.mcause, MCAUSE_ENVIRONMENT_CALL_FROM_MMODE);
+        assert_eq!(rv.csr.mepc, 0x1000_0004);
+        // the ECALL doesn't retire
+        assert_eq!(*rv.csr.instret.get(), 1);
+    }
+
+    #[test]
+    fn test_fence_retires() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0FF0_000F, // FENCE iorw, iorw
+            0x0000_100F, // FENCE.I
+        ]);
+        rv.step();
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+        rv.step();
+        assert_eq!(rv.next_fetch_address(), 0x1000_0008);
+        assert_eq!(*rv.csr.instret.get(), 2);
+        assert_eq!(rv.csr.mcause, 0);
+    }
+
+    #[test]
+    fn test_unmapped_access_fault() {
+        let mut program = vec![0x0000_0013; 20];
+        program[0] = 0x9000_00B7; // LUI x1, 0x90000
+        program[1] = 0x0000_A103; // LW x2, 0(x1)
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        rv.reg_file[2] = 7;
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_LOAD_ACCESS_FAULT);
+        assert_eq!(rv.csr.mtval, 0x9000_0000);
+        assert_eq!(rv.reg_file[2], 7);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+
+        program[1] = 0x0020_A023; // SW x2, 0(x1)
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_STORE_AMO_ACCESS_FAULT);
+        assert_eq!(rv.csr.mtval, 0x9000_0000);
+        assert_eq!(rv.next_fetch_address(), 0x1000_0004);
+
+        // fetching from it faults too
+        let mut rv = RV32ISystem::new();
+        rv.set_reset_vector(0x9000_0000);
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_INSTRUCTION_ACCESS_FAULT);
+        assert_eq!(rv.csr.mtval, 0x9000_0000);
+    }
+
+    #[test]
+    fn test_misaligned_access_traps() {
+        let mut program = vec![0x0000_0013; 20];
+        program[0] = 0x2000_00B7; // LUI x1, 0x20000
+        program[1] = 0x0010_A103; // LW x2, 1(x1)
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program.clone());
+        rv.reg_file[2] = 7;
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_LOAD_ADDRESS_MISALIGNED);
+        assert_eq!(rv.csr.mepc, 0x1000_0004);
+        assert_eq!(rv.csr.mtval, 0x2000_0001);
+        assert_eq!(rv.reg_file[2], 7);
+
+        program[1] = 0x0020_A123; // SW x2, 2(x1)
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(program);
+        rv.reg_file[2] = 7;
+        let ram = rv.peek_bytes(0x2000_0000, 8);
+        rv.step();
+        rv.step();
+        assert_eq!(rv.csr.mcause, MCAUSE_STORE_AMO_ADDRESS_MISALIGNED);
+        assert_eq!(rv.csr.mepc, 0x1000_0004);
+        assert_eq!(rv.csr.mtval, 0x2000_0002);
+        assert_eq!(rv.peek_bytes(0x2000_0000, 8), ram);
+    }
 }
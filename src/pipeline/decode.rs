@@ -1,7 +1,14 @@
-use super::{PipelineStage, fetch::InstructionValue};
+use super::{
+    PipelineStage,
+    execute::{CustomInstructions, FUNCT7_MULDIV, FUNCT7_ZBA},
+    fetch::InstructionValue,
+};
 use crate::{
-    RegisterFile,
-    trap::{MCAUSE_BREAKPOINT, MCAUSE_ENVIRONMENT_CALL_FROM_MMODE, PipelineTrapParams},
+    RegIndex, RegisterFile,
+    trap::{
+        MCAUSE_BREAKPOINT, MCAUSE_ENVIRONMENT_CALL_FROM_MMODE, MCAUSE_ILLEGAL_INSTRUCTION,
+        PipelineTrapParams,
+    },
     utils::{LatchValue, bit, sign_extend_32, slice_32},
 };
 
@@ -13,7 +20,7 @@ pub enum DecodedInstruction {
         funct3: u8,
         shamt: u8,
         imm11_0: u16,
-        rd: u8,
+        rd: RegIndex,
         rs1: u32,
         rs2: u32,
         imm32: i32,
@@ -26,16 +33,21 @@ pub enum DecodedInstruction {
     },
     Load {
         funct3: u8,
-        rd: u8,
+        rd: RegIndex,
         rs1: u32,
         imm32: i32,
     },
     Lui {
-        rd: u8,
+        rd: RegIndex,
         imm32: u32,
     },
     Jal {
-        rd: u8,
+        rd: RegIndex,
+        branch_address: u32,
+    },
+    /// The target is `rs1 + imm` with bit 0 cleared
+    Jalr {
+        rd: RegIndex,
         branch_address: u32,
     },
     Branch {
@@ -47,16 +59,101 @@ pub enum DecodedInstruction {
     System {
         funct3: u8,
         csr_address: u32,
-        rd: u8,
+        rd: RegIndex,
         source: u32,
         should_write: bool,
         should_read: bool,
     },
     Auipc {
-        rd: u8,
+        rd: RegIndex,
         imm32: u32,
     },
     Fence {},
+    /// `fence.i`, invalidates any instructions already fetched
+    FenceI {},
+    /// Return from a trap, the trap interface restores the pc from `mepc`
+    Mret {},
+    /// `wfi`, a hint that runs as a NOP: the CPU carries on without waiting for an interrupt
+    Wfi {},
+    /// A user defined instruction, see `CustomInstruction`
+    Custom {
+        opcode: u8,
+        rd: RegIndex,
+        rs1: u32,
+        rs2: u32,
+    },
+}
+
+impl DecodedInstruction {
+    /// Register the instruction writes back to, if it has one. Writes to `x0` are included, they
+    /// are discarded by write-back.
+    pub fn rd(&self) -> Option<RegIndex> {
+        match *self {
+            DecodedInstruction::Alu { rd, .. }
+            | DecodedInstruction::Load { rd, .. }
+            | DecodedInstruction::Lui { rd, .. }
+            | DecodedInstruction::Jal { rd, .. }
+            | DecodedInstruction::Jalr { rd, .. }
+            | DecodedInstruction::System { rd, .. }
+            | DecodedInstruction::Auipc { rd, .. }
+            | DecodedInstruction::Custom { rd, .. } => Some(rd),
+            DecodedInstruction::None
+            | DecodedInstruction::Store { .. }
+            | DecodedInstruction::Branch { .. }
+            | DecodedInstruction::Fence {}
+            | DecodedInstruction::FenceI {}
+            | DecodedInstruction::Mret {}
+            | DecodedInstruction::Wfi {} => None,
+        }
+    }
+
+    /// Whether every field is within the range the later stages expect: 3 bit `funct3`, 5 bit shift
+    /// amounts, 12 bit immediates and CSR addresses, and an opcode matching the variant. Register
+    /// indices can't be out of range, `RegIndex` masks them.
+    pub(crate) fn is_well_formed(&self) -> bool {
+        match *self {
+            DecodedInstruction::None
+            | DecodedInstruction::Fence {}
+            | DecodedInstruction::FenceI {}
+            | DecodedInstruction::Mret {}
+            | DecodedInstruction::Wfi {}
+            | DecodedInstruction::Lui { .. }
+            | DecodedInstruction::Jal { .. }
+            | DecodedInstruction::Jalr { .. }
+            | DecodedInstruction::Auipc { .. } => true,
+            DecodedInstruction::Alu {
+                opcode,
+                funct3,
+                shamt,
+                imm11_0,
+                ..
+            } => {
+                matches!(opcode, 0b001_0011 | 0b011_0011)
+                    && funct3 < 8
+                    && shamt < 32
+                    && imm11_0 < 0x1000
+            }
+            DecodedInstruction::Store { funct3, .. }
+            | DecodedInstruction::Load { funct3, .. }
+            | DecodedInstruction::Branch { funct3, .. } => funct3 < 8,
+            DecodedInstruction::System {
+                funct3,
+                csr_address,
+                ..
+            } => funct3 < 8 && csr_address < 0x1000,
+            DecodedInstruction::Custom { opcode, .. } => opcode < 0x80,
+        }
+    }
+}
+
+/// Catches decoder bugs in debug builds before they reach the `match`es in later stages
+fn debug_assert_well_formed(decoded: &DecodedValue) {
+    debug_assert!(
+        decoded.instruction.is_well_formed(),
+        "Decoded {:#010x} into malformed {:?}",
+        decoded.raw_instruction,
+        decoded.instruction
+    );
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -76,12 +173,28 @@ pub struct InstructionDecode {
     pc_plus_4: LatchValue<u32>,
     return_from_trap: LatchValue<bool>,
     trap_params: LatchValue<PipelineTrapParams>,
+    /// Whether the stage worked on an instruction in the last cycle, rather than stalling
+    active: LatchValue<bool>,
+}
+
+/// How instructions that decode as illegal are handled
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum IllegalPolicy {
+    /// Raise an illegal-instruction trap
+    #[default]
+    Trap,
+    /// Run them as a NOP, as the emulator used to
+    Nop,
 }
 
 pub struct InstructionDecodeParams<'a> {
     pub should_stall: bool,
     pub instruction_in: InstructionValue,
     pub reg_file: &'a mut RegisterFile,
+    pub custom_instructions: &'a CustomInstructions,
+    /// Raise illegal-instruction for encodings with reserved fields, see `has_reserved_bits`
+    pub strict: bool,
+    pub illegal_policy: IllegalPolicy,
 }
 
 impl InstructionDecode {
@@ -93,9 +206,14 @@ impl InstructionDecode {
             pc_plus_4: LatchValue::new(0),
             return_from_trap: LatchValue::new(false),
             trap_params: LatchValue::new(PipelineTrapParams::default()),
+            active: LatchValue::new(false),
         }
     }
 
+    pub fn is_active(&self) -> bool {
+        *self.active.get()
+    }
+
     pub fn get_decoded_instruction_out(&self) -> DecodedValue {
         DecodedValue {
             instruction: *self.instruction.get(),
@@ -108,191 +226,268 @@ impl InstructionDecode {
     }
 }
 
-impl<'a> PipelineStage<InstructionDecodeParams<'a>> for InstructionDecode {
-    fn compute(&mut self, params: InstructionDecodeParams<'a>) {
-        if params.should_stall {
-            self.return_from_trap.set(false);
-            self.trap_params.set(PipelineTrapParams::default());
-            return;
+/// Reads a register from the register file, with `x0` hardwired to zero
+fn read_register(reg_file: &RegisterFile, address: RegIndex) -> u32 {
+    match address.is_zero() {
+        true => 0,
+        false => reg_file[address],
+    }
+}
+
+/// Whether a standard instruction has a reserved value in one of its fields, such as an undefined
+/// funct7 for a register-register ALU op or funct3 for a load. The decoder normally ignores these
+/// fields and runs the nearest defined instruction, strict decoding treats them as illegal.
+pub(crate) fn has_reserved_bits(instruction: u32) -> bool {
+    let funct3 = (instruction >> 12) & 0x07;
+    let funct7 = instruction >> 25;
+    match instruction & 0x7F {
+        0b011_0011 => match (funct7, funct3) {
+            (0b000_0000, _) | (0b010_0000, 0b000 | 0b101) => false,
+            (muldiv, _) if muldiv == FUNCT7_MULDIV as u32 => false,
+            (zba, 0b010 | 0b100 | 0b110) => zba != FUNCT7_ZBA as u32,
+            _ => true,
+        },
+        0b001_0011 => match funct3 {
+            0b001 => funct7 != 0,
+            0b101 => funct7 & !0b010_0000 != 0,
+            _ => false,
+        },
+        0b000_0011 => matches!(funct3, 0b011 | 0b110 | 0b111),
+        0b010_0011 => funct3 > 0b010,
+        0b110_0011 => matches!(funct3, 0b010 | 0b011),
+        0b110_0111 => funct3 != 0,
+        0b000_1111 => funct3 > 0b001,
+        0b111_0011 => match funct3 {
+            // ECALL, EBREAK, MRET and WFI
+            0b000 => !matches!(
+                instruction,
+                0x0000_0073 | 0x0010_0073 | 0x3020_0073 | 0x1050_0073
+            ),
+            0b100 => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Decodes a fetched instruction, reading its source registers from `reg_file`
+pub(crate) fn decode_instruction(
+    instruction_in: &InstructionValue,
+    reg_file: &RegisterFile,
+    custom_instructions: &CustomInstructions,
+) -> DecodedValue {
+    let mut return_from_trap = false;
+    let mut trap_params = PipelineTrapParams::default();
+    let instruction = instruction_in.raw_instruction;
+
+    // Fields that sit at the same bit positions across the instruction formats are extracted
+    // once up front, rather than per opcode
+    let opcode = (instruction & 0x7F) as u8;
+    let rd = RegIndex::from_field(instruction, 7);
+    let funct3 = ((instruction >> 12) & 0x07) as u8;
+    let rs1_address = RegIndex::from_field(instruction, 15);
+    let rs2_address = RegIndex::from_field(instruction, 20);
+    let imm11_0 = ((instruction >> 20) & 0xFFF) as u16;
+    let rs1 = read_register(reg_file, rs1_address);
+    let rs2 = read_register(reg_file, rs2_address);
+
+    let instruction_out = match opcode {
+        // on RV32 the shift amount is 5 bits, bit 25 would make it a 6 bit (RV64) shift
+        0b001_0011 if funct3 & 0b011 == 0b001 && (instruction >> 25) & 1 == 1 => {
+            trap_params = PipelineTrapParams {
+                mepc: instruction_in.pc,
+                mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+                mtval: instruction,
+                trap: true,
+            };
+            DecodedInstruction::None
         }
-        let instruction = params.instruction_in.raw_instruction;
-        self.raw_instruction.set(instruction);
-        self.pc.set(params.instruction_in.pc);
-        self.pc_plus_4.set(params.instruction_in.pc_plus_4);
-
-        let opcode = (instruction & 0x7F) as u8;
-        match opcode {
-            0b001_0011 | 0b011_0011 => {
-                let imm11_0 = ((instruction >> 20) & 0xFFF) as u16;
-                let rs1_address = ((instruction >> 15) & 0x1F) as u8;
-                let rs2_address = ((instruction >> 20) & 0x1F) as u8;
-                self.instruction.set(DecodedInstruction::Alu {
-                    opcode,
-                    funct3: ((instruction >> 12) & 0x07) as u8,
-                    shamt: rs2_address,
-                    imm11_0,
-                    rd: ((instruction >> 7) & 0x1F) as u8,
-                    rs1: match rs1_address == 0 {
-                        true => 0,
-                        false => params.reg_file[rs1_address as usize],
-                    },
-                    rs2: match rs2_address == 0 {
-                        true => 0,
-                        false => params.reg_file[rs2_address as usize],
-                    },
-                    imm32: sign_extend_32(12, imm11_0 as i32),
-                });
-            }
-            0b010_0011 => {
-                let rs1_address = ((instruction >> 15) & 0x1F) as u8;
-                let rs2_address = ((instruction >> 20) & 0x1F) as u8;
-                self.instruction.set(DecodedInstruction::Store {
-                    funct3: ((instruction >> 12) & 0x07) as u8,
-                    rs1: match rs1_address == 0 {
-                        true => 0,
-                        false => params.reg_file[rs1_address as usize],
-                    },
-                    rs2: match rs2_address == 0 {
-                        true => 0,
-                        false => params.reg_file[rs2_address as usize],
-                    },
-                    imm32: sign_extend_32(
-                        12,
-                        ((((instruction >> 25) & 0x7F) << 5) | ((instruction >> 7) & 0x1F)) as i32,
-                    ),
-                });
-            }
-            0b000_0011 => {
-                let imm11_0 = ((instruction >> 20) & 0xFFF) as u16;
-                let rs1_address = ((instruction >> 15) & 0x1F) as u8;
-                self.instruction.set(DecodedInstruction::Load {
-                    funct3: ((instruction >> 12) & 0x07) as u8,
-                    rd: ((instruction >> 7) & 0x1F) as u8,
-                    rs1: match rs1_address == 0 {
-                        true => 0,
-                        false => params.reg_file[rs1_address as usize],
-                    },
-                    imm32: sign_extend_32(12, imm11_0 as i32),
-                });
+        0b001_0011 | 0b011_0011 => DecodedInstruction::Alu {
+            opcode,
+            funct3,
+            shamt: rs2_address.value(),
+            imm11_0,
+            rd,
+            rs1,
+            rs2,
+            imm32: sign_extend_32(12, imm11_0 as i32),
+        },
+        0b010_0011 => DecodedInstruction::Store {
+            funct3,
+            rs1,
+            rs2,
+            imm32: sign_extend_32(
+                12,
+                ((((instruction >> 25) & 0x7F) << 5) | ((instruction >> 7) & 0x1F)) as i32,
+            ),
+        },
+        0b000_0011 => DecodedInstruction::Load {
+            funct3,
+            rd,
+            rs1,
+            imm32: sign_extend_32(12, imm11_0 as i32),
+        },
+        0b0110111 => DecodedInstruction::Lui {
+            rd,
+            imm32: (instruction >> 12) << 12,
+        },
+        0b1101111 => {
+            let restructured_imm = bit(31, instruction, 20)
+                | slice_32(19, 12, instruction, 19)
+                | bit(20, instruction, 11)
+                | slice_32(30, 21, instruction, 10);
+            let imm32 = sign_extend_32(21, (restructured_imm << 1) as i32);
+            DecodedInstruction::Jal {
+                rd,
+                branch_address: instruction_in.pc.wrapping_add_signed(imm32),
             }
-            0b0110111 => {
-                self.instruction.set(DecodedInstruction::Lui {
-                    rd: ((instruction >> 7) & 0x1F) as u8,
-                    imm32: (instruction >> 12) << 12,
-                });
+        }
+        0b1100111 => DecodedInstruction::Jalr {
+            rd,
+            branch_address: rs1.wrapping_add_signed(sign_extend_32(12, imm11_0 as i32)) & !1,
+        },
+        0b1100011 => {
+            let restructured_imm = bit(31, instruction, 12)
+                | bit(7, instruction, 11)
+                | slice_32(30, 25, instruction, 10)
+                | slice_32(11, 8, instruction, 4);
+            let imm32 = sign_extend_32(13, (restructured_imm << 1) as i32);
+            DecodedInstruction::Branch {
+                funct3,
+                branch_address: instruction_in.pc.wrapping_add_signed(imm32),
+                rs1,
+                rs2,
             }
-            0b1101111 => {
-                let restructured_imm = bit(31, instruction, 20)
-                    | slice_32(19, 12, instruction, 19)
-                    | bit(20, instruction, 11)
-                    | slice_32(30, 21, instruction, 10);
-                let imm32 = sign_extend_32(21, (restructured_imm << 1) as i32);
-                self.instruction.set(DecodedInstruction::Jal {
-                    rd: ((instruction >> 7) & 0x1F) as u8,
-                    branch_address: params.instruction_in.pc.saturating_add_signed(imm32),
-                });
+        }
+        0b1110011 => match instruction >> 7 {
+            0 => {
+                // ECALL, mepc is the ECALL itself so the handler returns past it by adding 4
+                trap_params = PipelineTrapParams {
+                    mepc: instruction_in.pc,
+                    mcause: MCAUSE_ENVIRONMENT_CALL_FROM_MMODE,
+                    mtval: 0,
+                    trap: true,
+                };
+                DecodedInstruction::None
             }
-            0b1100111 => {
-                let imm11_0 = ((instruction >> 20) & 0xFFF) as u16;
-                let i_imm = sign_extend_32(12, imm11_0 as i32);
-                let imm32 = slice_32(11, 1, i_imm as u32, 11);
-                let rs1_address = ((instruction >> 15) & 0x1F) as u8;
-                let rs1 = match rs1_address == 0 {
-                    true => 0,
-                    false => params.reg_file[rs1_address as usize],
+            0b1_00000_000_00000 => {
+                // EBREAK
+                trap_params = PipelineTrapParams {
+                    mepc: instruction_in.pc,
+                    mcause: MCAUSE_BREAKPOINT,
+                    mtval: 0,
+                    trap: true,
                 };
-                self.instruction.set(DecodedInstruction::Jal {
-                    rd: ((instruction >> 7) & 0x1F) as u8,
-                    branch_address: rs1 + imm32,
-                });
+                DecodedInstruction::None
             }
-            0b1100011 => {
-                let restructured_imm = bit(31, instruction, 12)
-                    | bit(7, instruction, 11)
-                    | slice_32(30, 25, instruction, 10)
-                    | slice_32(11, 8, instruction, 4);
-                let imm32 = sign_extend_32(13, (restructured_imm << 1) as i32);
-                let rs1_address = ((instruction >> 15) & 0x1F) as u8;
-                let rs2_address = ((instruction >> 20) & 0x1F) as u8;
-                self.instruction.set(DecodedInstruction::Branch {
-                    funct3: ((instruction >> 12) & 0x07) as u8,
-                    branch_address: params.instruction_in.pc.saturating_add_signed(imm32),
-                    rs1: match rs1_address == 0 {
-                        true => 0,
-                        false => params.reg_file[rs1_address as usize],
-                    },
-                    rs2: match rs2_address == 0 {
-                        true => 0,
-                        false => params.reg_file[rs2_address as usize],
-                    },
-                });
+            0b0011000_00010_00000_000_00000 => {
+                // MRET
+                return_from_trap = true;
+                DecodedInstruction::Mret {}
             }
-            0b1110011 => match instruction >> 7 {
-                0 => {
-                    // ECALL
-                    self.trap_params.set(PipelineTrapParams {
-                        mepc: params.instruction_in.pc_plus_4,
-                        mcause: MCAUSE_ENVIRONMENT_CALL_FROM_MMODE,
-                        mtval: 0,
-                        trap: true,
-                    });
-                    self.instruction.set(DecodedInstruction::None);
-                }
-                0b1_00000_000_00000 => {
-                    // EBREAK
-                    self.trap_params.set(PipelineTrapParams {
-                        mepc: params.instruction_in.pc_plus_4,
-                        mcause: MCAUSE_BREAKPOINT,
-                        mtval: 0,
-                        trap: true,
-                    });
-                    self.instruction.set(DecodedInstruction::None);
-                }
-                _ => {
-                    let rd = ((instruction >> 7) & 0x1F) as u8;
-                    let rs1_address = ((instruction >> 15) & 0x1F) as u8;
-                    let funct3 = ((instruction >> 12) & 0x07) as u8;
-                    let imm11_0 = instruction >> 20;
-
-                    self.return_from_trap
-                        .set(rd == 0 && rs1_address == 0 && imm11_0 == 0x302);
-
-                    let source = match funct3 & 0b100 {
-                        0b100 => rs1_address as u32,
-                        _ => params.reg_file[rs1_address as usize],
-                    };
-                    let should_write = match funct3 & 0b11 {
-                        0b01 => true,
-                        _ => rs1_address != 0,
-                    };
-                    let should_read = match funct3 & 0b11 {
-                        0b01 => rd != 0,
-                        _ => true,
-                    };
-
-                    self.instruction.set(DecodedInstruction::System {
-                        funct3,
-                        csr_address: imm11_0,
-                        rd,
-                        source,
-                        should_write,
-                        should_read,
-                    });
+            0b0001000_00101_00000_000_00000 => DecodedInstruction::Wfi {},
+            _ => {
+                let csr_address = instruction >> 20;
+                let source = match funct3 & 0b100 {
+                    0b100 => rs1_address.value() as u32,
+                    _ => rs1,
+                };
+                let should_write = match funct3 & 0b11 {
+                    0b01 => true,
+                    _ => !rs1_address.is_zero(),
+                };
+                let should_read = match funct3 & 0b11 {
+                    0b01 => !rd.is_zero(),
+                    _ => true,
+                };
+
+                DecodedInstruction::System {
+                    funct3,
+                    csr_address,
+                    rd,
+                    source,
+                    should_write,
+                    should_read,
                 }
-            },
-            0b0010111 => {
-                self.instruction.set(DecodedInstruction::Auipc {
-                    rd: ((instruction >> 7) & 0x1F) as u8,
-                    imm32: (instruction >> 12) << 12,
-                });
-            }
-            0b0001111 => {
-                self.instruction.set(DecodedInstruction::Fence {});
             }
-            _ => {
-                self.instruction.set(DecodedInstruction::None);
+        },
+        0b0010111 => DecodedInstruction::Auipc {
+            rd,
+            imm32: (instruction >> 12) << 12,
+        },
+        0b0001111 if funct3 == 0b001 => DecodedInstruction::FenceI {},
+        0b0001111 => DecodedInstruction::Fence {},
+        _ if custom_instructions
+            .iter()
+            .any(|(custom_opcode, _)| *custom_opcode == opcode) =>
+        {
+            DecodedInstruction::Custom {
+                opcode,
+                rd,
+                rs1,
+                rs2,
             }
         }
+        _ => {
+            trap_params = PipelineTrapParams {
+                mepc: instruction_in.pc,
+                mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+                mtval: instruction,
+                trap: true,
+            };
+            DecodedInstruction::None
+        }
+    };
+
+    let decoded = DecodedValue {
+        instruction: instruction_out,
+        raw_instruction: instruction,
+        pc: instruction_in.pc,
+        pc_plus_4: instruction_in.pc_plus_4,
+        return_from_trap,
+        trap_params,
+    };
+    debug_assert_well_formed(&decoded);
+    decoded
+}
+
+impl<'a> PipelineStage<InstructionDecodeParams<'a>> for InstructionDecode {
+    fn compute(&mut self, params: InstructionDecodeParams<'a>) {
+        if params.should_stall {
+            self.active.set(false);
+            self.return_from_trap.set(false);
+            self.trap_params.set(PipelineTrapParams::default());
+            return;
+        }
+        self.active.set(true);
+        let mut decoded = decode_instruction(
+            &params.instruction_in,
+            params.reg_file,
+            params.custom_instructions,
+        );
+        if params.strict && !decoded.trap_params.trap && has_reserved_bits(decoded.raw_instruction)
+        {
+            decoded.instruction = DecodedInstruction::None;
+            decoded.return_from_trap = false;
+            decoded.trap_params = PipelineTrapParams {
+                mepc: decoded.pc,
+                mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+                mtval: decoded.raw_instruction,
+                trap: true,
+            };
+        }
+        if params.illegal_policy == IllegalPolicy::Nop
+            && decoded.trap_params.trap
+            && decoded.trap_params.mcause == MCAUSE_ILLEGAL_INSTRUCTION
+        {
+            decoded.trap_params = PipelineTrapParams::default();
+        }
+        self.instruction.set(decoded.instruction);
+        self.raw_instruction.set(decoded.raw_instruction);
+        self.pc.set(decoded.pc);
+        self.pc_plus_4.set(decoded.pc_plus_4);
+        self.return_from_trap.set(decoded.return_from_trap);
+        self.trap_params.set(decoded.trap_params);
     }
 
     fn latch_next(&mut self) {
@@ -302,6 +497,7 @@ impl<'a> PipelineStage<InstructionDecodeParams<'a>> for InstructionDecode {
         self.pc_plus_4.latch_next();
         self.return_from_trap.latch_next();
         self.trap_params.latch_next();
+        self.active.latch_next();
     }
 
     fn reset(&mut self) {
@@ -311,5 +507,478 @@ impl<'a> PipelineStage<InstructionDecodeParams<'a>> for InstructionDecode {
         self.pc_plus_4.reset();
         self.return_from_trap.reset();
         self.trap_params.reset();
+        self.active.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u32 = 0x1000_0010;
+
+    fn decode(raw_instruction: u32) -> DecodedValue {
+        decode_at(PC, raw_instruction)
+    }
+
+    fn decode_at(pc: u32, raw_instruction: u32) -> DecodedValue {
+        decode_with(pc, raw_instruction, false)
+    }
+
+    fn decode_strict(raw_instruction: u32) -> DecodedValue {
+        decode_with(PC, raw_instruction, true)
+    }
+
+    fn decode_with(pc: u32, raw_instruction: u32, strict: bool) -> DecodedValue {
+        let mut reg_file = [0u32; 32];
+        reg_file[1] = 0x2000_0000;
+        reg_file[2] = 0xDEAD_BEEF;
+
+        let mut stage = InstructionDecode::new();
+        stage.compute(InstructionDecodeParams {
+            should_stall: false,
+            instruction_in: InstructionValue {
+                pc,
+                pc_plus_4: pc.wrapping_add(4),
+                raw_instruction,
+            },
+            reg_file: &mut reg_file,
+            custom_instructions: &vec![],
+            strict,
+            illegal_policy: IllegalPolicy::Trap,
+        });
+        stage.latch_next();
+        stage.get_decoded_instruction_out()
+    }
+
+    #[test]
+    fn test_decode_shift_amount_bit_5() {
+        let illegal = |raw_instruction: u32| PipelineTrapParams {
+            mepc: PC,
+            mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+            mtval: raw_instruction,
+            trap: true,
+        };
+
+        // SLLI x1, x2, 32
+        let slli = 0b0000001_00000_00010_001_00001_0010011;
+        assert_eq!(decode(slli).trap_params, illegal(slli));
+        assert_eq!(decode(slli).instruction, DecodedInstruction::None);
+        // SRLI x1, x2, 33
+        let srli = 0b0000001_00001_00010_101_00001_0010011;
+        assert_eq!(decode(srli).trap_params, illegal(srli));
+        // SRAI x1, x2, 63
+        let srai = 0b0100001_11111_00010_101_00001_0010011;
+        assert_eq!(decode(srai).trap_params, illegal(srai));
+
+        // 5 bit shift amounts are fine, including SRAI's bit 30
+        for raw_instruction in [
+            0b0000000_11111_00010_001_00001_0010011, // SLLI x1, x2, 31
+            0b0000000_11111_00010_101_00001_0010011, // SRLI x1, x2, 31
+            0b0100000_11111_00010_101_00001_0010011, // SRAI x1, x2, 31
+        ] {
+            let decoded = decode(raw_instruction);
+            assert!(!decoded.trap_params.trap);
+            assert!(matches!(
+                decoded.instruction,
+                DecodedInstruction::Alu { shamt: 31, .. }
+            ));
+        }
+
+        // bit 25 is part of funct7 for register shifts and other immediates, not a shift amount
+        let mulh = 0b0000001_00001_00010_001_00001_0110011; // MULH x1, x2, x1
+        assert!(!decode(mulh).trap_params.trap);
+        let addi = 0b0000001_00000_00010_000_00001_0010011; // ADDI x1, x2, 32
+        assert!(!decode(addi).trap_params.trap);
+    }
+
+    #[test]
+    fn test_decode_regression() {
+        assert_eq!(
+            decode(0b111111111111_00001_000_00011_0010011).instruction, // ADDI x3, x1, -1
+            DecodedInstruction::Alu {
+                opcode: 0b0010011,
+                funct3: 0b000,
+                shamt: 0b11111,
+                imm11_0: 0xFFF,
+                rd: RegIndex::new(3),
+                rs1: 0x2000_0000,
+                rs2: 0,
+                imm32: -1,
+            }
+        );
+        assert_eq!(
+            decode(0b0100000_00001_00010_000_00100_0110011).instruction, // SUB x4, x2, x1
+            DecodedInstruction::Alu {
+                opcode: 0b0110011,
+                funct3: 0b000,
+                shamt: 1,
+                imm11_0: 0b0100000_00001,
+                rd: RegIndex::new(4),
+                rs1: 0xDEAD_BEEF,
+                rs2: 0x2000_0000,
+                imm32: 0b0100000_00001,
+            }
+        );
+        assert_eq!(
+            decode(0b1111111_00010_00001_010_11100_0100011).instruction, // SW x2, -4(x1)
+            DecodedInstruction::Store {
+                funct3: 0b010,
+                rs1: 0x2000_0000,
+                rs2: 0xDEAD_BEEF,
+                imm32: -4,
+            }
+        );
+        assert_eq!(
+            decode(0b000000001000_00001_010_00101_0000011).instruction, // LW x5, 8(x1)
+            DecodedInstruction::Load {
+                funct3: 0b010,
+                rd: RegIndex::new(5),
+                rs1: 0x2000_0000,
+                imm32: 8,
+            }
+        );
+        assert_eq!(
+            decode(0b00010010001101000101_00110_0110111).instruction, // LUI x6, 0x12345
+            DecodedInstruction::Lui {
+                rd: RegIndex::new(6),
+                imm32: 0x1234_5000,
+            }
+        );
+        assert_eq!(
+            decode(0b00010010001101000101_00111_0010111).instruction, // AUIPC x7, 0x12345
+            DecodedInstruction::Auipc {
+                rd: RegIndex::new(7),
+                imm32: 0x1234_5000,
+            }
+        );
+        assert_eq!(
+            decode(0b1_1111111000_1_11111111_00001_1101111).instruction, // JAL x1, -16
+            DecodedInstruction::Jal {
+                rd: RegIndex::new(1),
+                branch_address: PC - 16,
+            }
+        );
+        assert_eq!(
+            decode(0b000000000000_00001_000_00000_1100111).instruction, // JALR x0, 0(x1)
+            DecodedInstruction::Jalr {
+                rd: RegIndex::new(0),
+                branch_address: 0x2000_0000,
+            }
+        );
+        assert_eq!(
+            decode(0b000001000000_00001_000_00101_1100111).instruction, // JALR x5, 0x40(x1)
+            DecodedInstruction::Jalr {
+                rd: RegIndex::new(5),
+                branch_address: 0x2000_0040,
+            }
+        );
+        assert_eq!(
+            decode(0b111111111111_00001_000_00000_1100111).instruction, // JALR x0, -1(x1)
+            DecodedInstruction::Jalr {
+                rd: RegIndex::new(0),
+                branch_address: 0x1FFF_FFFE,
+            }
+        );
+        assert_eq!(
+            decode(0b0_000000_00010_00001_001_0100_0_1100011).instruction, // BNE x1, x2, 8
+            DecodedInstruction::Branch {
+                funct3: 0b001,
+                branch_address: PC + 8,
+                rs1: 0x2000_0000,
+                rs2: 0xDEAD_BEEF,
+            }
+        );
+        assert_eq!(
+            decode(0b001101000000_00001_010_00110_1110011).instruction, // CSRRS x6, mscratch, x1
+            DecodedInstruction::System {
+                funct3: 0b010,
+                csr_address: 0x340,
+                rd: RegIndex::new(6),
+                source: 0x2000_0000,
+                should_write: true,
+                should_read: true,
+            }
+        );
+        assert_eq!(
+            decode(0b001101000000_00101_101_00000_1110011).instruction, // CSRRWI x0, mscratch, 5
+            DecodedInstruction::System {
+                funct3: 0b101,
+                csr_address: 0x340,
+                rd: RegIndex::new(0),
+                source: 5,
+                should_write: true,
+                should_read: false,
+            }
+        );
+        assert_eq!(
+            decode(0b0000_0000_0000_00000_000_00000_0001111).instruction, // FENCE
+            DecodedInstruction::Fence {}
+        );
+        assert_eq!(
+            decode(0b0000_0000_0000_00000_001_00000_0001111).instruction, // FENCE.I
+            DecodedInstruction::FenceI {}
+        );
+        assert_eq!(decode(0xFFFF_FFFF).instruction, DecodedInstruction::None);
+    }
+
+    #[test]
+    fn test_decode_system_traps_regression() {
+        let ecall = decode(0b000000000000_00000_000_00000_1110011);
+        assert_eq!(ecall.instruction, DecodedInstruction::None);
+        assert_eq!(
+            ecall.trap_params,
+            PipelineTrapParams {
+                mepc: PC,
+                mcause: MCAUSE_ENVIRONMENT_CALL_FROM_MMODE,
+                mtval: 0,
+                trap: true,
+            }
+        );
+
+        let ebreak = decode(0b000000000001_00000_000_00000_1110011);
+        assert_eq!(ebreak.instruction, DecodedInstruction::None);
+        assert_eq!(
+            ebreak.trap_params,
+            PipelineTrapParams {
+                mepc: PC,
+                mcause: MCAUSE_BREAKPOINT,
+                mtval: 0,
+                trap: true,
+            }
+        );
+
+        let mret = decode(0b001100000010_00000_000_00000_1110011);
+        assert!(mret.return_from_trap);
+        assert!(!mret.trap_params.trap);
+        assert_eq!(mret.instruction, DecodedInstruction::Mret {});
+
+        let wfi = decode(0b000100000101_00000_000_00000_1110011);
+        assert!(!wfi.trap_params.trap);
+        assert_eq!(wfi.instruction, DecodedInstruction::Wfi {});
+        assert!(!has_reserved_bits(0x1050_0073));
+    }
+
+    fn csr_flags(raw_instruction: u32) -> (bool, bool) {
+        match decode(raw_instruction).instruction {
+            DecodedInstruction::System {
+                should_read,
+                should_write,
+                ..
+            } => (should_read, should_write),
+            other => panic!("Expected a CSR instruction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_csr_read_write() {
+        // (should_read, should_write)
+        // csrrw x0, mscratch, x1 (csrw) does not read
+        assert_eq!(csr_flags(0x3400_9073), (false, true));
+        // csrrw x2, mscratch, x1 reads
+        assert_eq!(csr_flags(0x3400_9173), (true, true));
+        // csrrwi x0, mscratch, 5 does not read, csrrwi x2, mscratch, 0 does
+        assert_eq!(csr_flags(0x3402_d073), (false, true));
+        assert_eq!(csr_flags(0x3400_5173), (true, true));
+        // csrrs/csrrc always read, and only write when rs1/uimm is non-zero
+        assert_eq!(csr_flags(0x3400_2073), (true, false));
+        assert_eq!(csr_flags(0x3400_a073), (true, true));
+        assert_eq!(csr_flags(0x3400_3173), (true, false));
+        assert_eq!(csr_flags(0x3400_b173), (true, true));
+        assert_eq!(csr_flags(0x3400_6173), (true, false));
+        assert_eq!(csr_flags(0x3402_e173), (true, true));
+        assert_eq!(csr_flags(0x3400_7173), (true, false));
+        assert_eq!(csr_flags(0x3402_f173), (true, true));
+    }
+
+    fn encode_jal(rd: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (bit(20, imm, 1) << 31)
+            | (slice_32(10, 1, imm, 0) << 21)
+            | (bit(11, imm, 1) << 20)
+            | (slice_32(19, 12, imm, 0) << 12)
+            | (rd << 7)
+            | 0b1101111
+    }
+
+    fn encode_branch(funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (bit(12, imm, 1) << 31)
+            | (slice_32(10, 5, imm, 0) << 25)
+            | (rs2 << 20)
+            | (rs1 << 15)
+            | (funct3 << 12)
+            | (slice_32(4, 1, imm, 0) << 8)
+            | (bit(11, imm, 1) << 7)
+            | 0b1100011
+    }
+
+    fn jal_target(pc: u32, offset: i32) -> u32 {
+        match decode_at(pc, encode_jal(1, offset)).instruction {
+            DecodedInstruction::Jal { branch_address, .. } => branch_address,
+            other => panic!("Expected a JAL, got {:?}", other),
+        }
+    }
+
+    fn branch_target(pc: u32, offset: i32) -> u32 {
+        match decode_at(pc, encode_branch(0b000, 1, 2, offset)).instruction {
+            DecodedInstruction::Branch { branch_address, .. } => branch_address,
+            other => panic!("Expected a branch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_jal_offset_range() {
+        // largest positive and negative offsets (+-1MiB)
+        assert_eq!(jal_target(PC, 0x000F_FFFE), PC + 0x000F_FFFE);
+        assert_eq!(jal_target(PC, -0x0010_0000), PC - 0x0010_0000);
+
+        // every immediate bit on its own, in both directions
+        for shift in 1..20 {
+            let offset = 1 << shift;
+            assert_eq!(jal_target(PC, offset), PC.wrapping_add_signed(offset));
+            assert_eq!(jal_target(PC, -offset), PC.wrapping_add_signed(-offset));
+        }
+
+        // targets wrap around the address space rather than saturating
+        assert_eq!(jal_target(0x0000_0010, -0x20), 0xFFFF_FFF0);
+        assert_eq!(jal_target(0xFFFF_FFF0, 0x20), 0x0000_0010);
+    }
+
+    #[test]
+    fn test_branch_offset_range() {
+        // largest positive and negative offsets (+-4KiB)
+        assert_eq!(branch_target(PC, 0x0FFE), PC + 0x0FFE);
+        assert_eq!(branch_target(PC, -0x1000), PC - 0x1000);
+
+        // every immediate bit on its own, in both directions
+        for shift in 1..12 {
+            let offset = 1 << shift;
+            assert_eq!(branch_target(PC, offset), PC.wrapping_add_signed(offset));
+            assert_eq!(branch_target(PC, -offset), PC.wrapping_add_signed(-offset));
+        }
+
+        // targets wrap around the address space rather than saturating
+        assert_eq!(branch_target(0x0000_0010, -0x20), 0xFFFF_FFF0);
+        assert_eq!(branch_target(0xFFFF_FFF0, 0x20), 0x0000_0010);
+    }
+
+    #[test]
+    fn test_decode_register_indices_in_range() {
+        // every rd value for each opcode that writes a register, with all other bits set
+        for opcode in [
+            0b001_0011, 0b011_0011, 0b000_0011, 0b011_0111, 0b110_1111, 0b110_0111, 0b001_0111,
+        ] {
+            for rd in 0..32 {
+                let decoded = decode(0xFFFF_F000 | (rd << 7) | opcode).instruction;
+                let rd_index = match decoded {
+                    DecodedInstruction::Alu { rd, .. }
+                    | DecodedInstruction::Load { rd, .. }
+                    | DecodedInstruction::Lui { rd, .. }
+                    | DecodedInstruction::Jal { rd, .. }
+                    | DecodedInstruction::Jalr { rd, .. }
+                    | DecodedInstruction::Auipc { rd, .. } => rd,
+                    other => panic!("Unexpected decode {:?}", other),
+                };
+                assert_eq!(rd_index.value(), rd as u8);
+                assert!(rd_index.value() < 32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_strict_decode() {
+        // ADD r3, r1, r2 with the reserved funct7 0b0000010
+        let reserved = 0b0000010_00010_00001_000_00011_0110011;
+        assert!(!decode(reserved).trap_params.trap);
+        let strict = decode_strict(reserved);
+        assert_eq!(strict.instruction, DecodedInstruction::None);
+        assert_eq!(
+            strict.trap_params,
+            PipelineTrapParams {
+                mepc: PC,
+                mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+                mtval: reserved,
+                trap: true,
+            }
+        );
+
+        for raw in [
+            0x0000_B183, // LW with funct3 0b011
+            0x0020_B023, // SW with funct3 0b011
+            0x0020_A063, // BEQ with funct3 0b010
+            0x0000_91E7, // JALR with funct3 0b001
+            0x4040_9193, // SLLI with funct7 0b0100000
+            0x3410_C073, // CSR op with funct3 0b100
+        ] {
+            assert!(decode_strict(raw).trap_params.trap, "{:#010x}", raw);
+        }
+
+        // defined encodings decode the same either way
+        for raw in [
+            0x0020_81B3, // ADD r3, r1, r2
+            0x4020_81B3, // SUB r3, r1, r2
+            0x2020_A1B3, // SH1ADD r3, r1, r2
+            0x4040_D193, // SRAI r3, r1, 4
+            0x0000_A183, // LW r3, 0(r1)
+            0x0000_0073, // ECALL
+            0x3020_0073, // MRET
+            0x0000_100F, // FENCE.I
+            0x3410_9073, // CSRRW r0, mepc, r1
+        ] {
+            assert_eq!(decode_strict(raw), decode(raw), "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn test_decoded_well_formed() {
+        // a spread of bit patterns across every opcode
+        let mut raw_instruction = 0x1234_5678u32;
+        for opcode in 0..0x80 {
+            for _ in 0..64 {
+                raw_instruction = raw_instruction
+                    .wrapping_mul(1_664_525)
+                    .wrapping_add(1_013_904_223);
+                let decoded = decode((raw_instruction & !0x7F) | opcode);
+                assert!(decoded.instruction.is_well_formed(), "{:?}", decoded);
+            }
+        }
+
+        let corrupted = DecodedInstruction::Alu {
+            opcode: 0b001_0011,
+            funct3: 8,
+            shamt: 0,
+            imm11_0: 0,
+            rd: RegIndex::new(1),
+            rs1: 0,
+            rs2: 0,
+            imm32: 0,
+        };
+        assert!(!corrupted.is_well_formed());
+        let mismatched_opcode = DecodedInstruction::Alu {
+            opcode: 0b000_0011,
+            funct3: 0,
+            shamt: 0,
+            imm11_0: 0,
+            rd: RegIndex::new(1),
+            rs1: 0,
+            rs2: 0,
+            imm32: 0,
+        };
+        assert!(!mismatched_opcode.is_well_formed());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "malformed")]
+    fn test_malformed_decode_asserts() {
+        let mut decoded = decode(0x0000_0013);
+        decoded.instruction = DecodedInstruction::Branch {
+            funct3: 0xFF,
+            branch_address: 0,
+            rs1: 0,
+            rs2: 0,
+        };
+        debug_assert_well_formed(&decoded);
     }
 }
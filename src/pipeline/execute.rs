@@ -1,4 +1,4 @@
-use crate::utils::LatchValue;
+use crate::{RegIndex, utils::LatchValue};
 
 use super::{
     PipelineStage,
@@ -23,6 +23,20 @@ const ALU_OPERATION_SR: u8 = 0b101;
 const ALU_OPERATION_OR: u8 = 0b110;
 const ALU_OPERATION_AND: u8 = 0b111;
 
+/// funct7 of the Zba `shNadd` instructions, which take funct3 2/4/6 for shifts of 1/2/3
+pub(crate) const FUNCT7_ZBA: u16 = 0b001_0000;
+/// funct7 of the RV32M multiply and divide instructions
+pub(crate) const FUNCT7_MULDIV: u16 = 0b000_0001;
+
+const MUL_OPERATION_MUL: u8 = 0b000;
+const MUL_OPERATION_MULH: u8 = 0b001;
+const MUL_OPERATION_MULHSU: u8 = 0b010;
+const MUL_OPERATION_MULHU: u8 = 0b011;
+const DIV_OPERATION_DIV: u8 = 0b100;
+const DIV_OPERATION_DIVU: u8 = 0b101;
+const DIV_OPERATION_REM: u8 = 0b110;
+const DIV_OPERATION_REMU: u8 = 0b111;
+
 const BRANCH_OPERATION_EQ: u8 = 0b000;
 const BRANCH_OPERATION_NE: u8 = 0b001;
 const BRANCH_OPERATION_LT: u8 = 0b100;
@@ -36,11 +50,35 @@ pub struct InstructionExecute {
     raw_instruction: LatchValue<u32>,
     pc: LatchValue<u32>,
     pc_plus_4: LatchValue<u32>,
+    /// Whether the stage worked on an instruction in the last cycle, rather than stalling
+    active: LatchValue<bool>,
+    /// Whether the jump or branch held by the stage still has to redirect fetch
+    redirect_pending: LatchValue<bool>,
+}
+
+/// A user defined instruction, see `RV32ISystem::register_custom`
+pub trait CustomInstruction {
+    /// Executes `raw_instruction` given the values of its `rs1` and `rs2` fields. Returning a
+    /// value writes it to `rd`, `None` leaves the register file untouched.
+    fn execute(&mut self, raw_instruction: u32, rs1: u32, rs2: u32) -> Option<u32>;
+}
+
+impl<F> CustomInstruction for F
+where
+    F: FnMut(u32, u32, u32) -> Option<u32>,
+{
+    fn execute(&mut self, raw_instruction: u32, rs1: u32, rs2: u32) -> Option<u32> {
+        self(raw_instruction, rs1, rs2)
+    }
 }
 
-pub struct InstructionExecuteParams {
+/// Registered custom instructions, keyed by major opcode
+pub type CustomInstructions = Vec<(u8, Box<dyn CustomInstruction>)>;
+
+pub struct InstructionExecuteParams<'a> {
     pub should_stall: bool,
     pub decoded_instruction_in: DecodedValue,
+    pub custom_instructions: &'a mut CustomInstructions,
 }
 
 impl InstructionExecute {
@@ -51,7 +89,33 @@ impl InstructionExecute {
             raw_instruction: LatchValue::new(0),
             pc: LatchValue::new(0),
             pc_plus_4: LatchValue::new(0),
+            active: LatchValue::new(false),
+            redirect_pending: LatchValue::new(false),
+        }
+    }
+
+    /// Address fetch must continue from because of the jump or branch held by the stage, until
+    /// the redirect is applied with `clear_redirect`
+    pub fn get_redirect_out(&self) -> Option<u32> {
+        if !*self.redirect_pending.get() {
+            return None;
         }
+        match *self.instruction.get() {
+            DecodedInstruction::Jal { branch_address, .. } => Some(branch_address),
+            DecodedInstruction::Jalr { branch_address, .. } => Some(branch_address),
+            DecodedInstruction::Branch { branch_address, .. } => Some(branch_address),
+            _ => None,
+        }
+    }
+
+    /// Marks the redirect as applied, so that later fetches carry on sequentially even while the
+    /// stage still holds the same jump or branch
+    pub fn clear_redirect(&mut self) {
+        self.redirect_pending.set(false);
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self.active.get()
     }
 
     pub fn get_execution_value_out(&self) -> ExecutionValue {
@@ -65,121 +129,189 @@ impl InstructionExecute {
     }
 }
 
-impl PipelineStage<InstructionExecuteParams> for InstructionExecute {
-    fn compute(&mut self, params: InstructionExecuteParams) {
-        if params.should_stall {
-            return;
-        }
-        let decoded = params.decoded_instruction_in;
-        self.instruction.set(decoded.instruction);
-        self.raw_instruction.set(decoded.raw_instruction);
-        self.pc.set(decoded.pc);
-        self.pc_plus_4.set(decoded.pc_plus_4);
-
-        match decoded.instruction {
-            DecodedInstruction::Alu {
-                opcode,
-                funct3,
-                shamt,
-                imm11_0,
-                rs1,
-                rs2,
-                imm32,
-                ..
-            } => {
-                let is_register_op = ((opcode >> 5) & 1) == 1;
-                let is_alternate = ((imm11_0 >> 10) & 1) == 1;
-
-                self.write_back_value.set(match funct3 {
-                    ALU_OPERATION_ADD => {
-                        if is_register_op {
-                            if is_alternate { rs1 - rs2 } else { rs1 + rs2 }
+/// Executes a decoded instruction, resolving whether a branch is taken
+pub(crate) fn execute_instruction(
+    decoded: &DecodedValue,
+    custom_instructions: &mut CustomInstructions,
+) -> ExecutionValue {
+    let mut instruction = decoded.instruction;
+    let write_back_value = match decoded.instruction {
+        DecodedInstruction::Alu {
+            opcode,
+            funct3,
+            shamt,
+            imm11_0,
+            rs1,
+            rs2,
+            imm32,
+            ..
+        } => {
+            let is_register_op = ((opcode >> 5) & 1) == 1;
+            let is_alternate = ((imm11_0 >> 10) & 1) == 1;
+            let is_zba = is_register_op && (imm11_0 >> 5) == FUNCT7_ZBA;
+            let is_muldiv = is_register_op && (imm11_0 >> 5) == FUNCT7_MULDIV;
+
+            match funct3 {
+                MUL_OPERATION_MUL if is_muldiv => rs1.wrapping_mul(rs2),
+                MUL_OPERATION_MULH if is_muldiv => {
+                    ((rs1 as i32 as i64 * rs2 as i32 as i64) >> 32) as u32
+                }
+                MUL_OPERATION_MULHSU if is_muldiv => {
+                    ((rs1 as i32 as i64 * rs2 as i64) >> 32) as u32
+                }
+                MUL_OPERATION_MULHU if is_muldiv => ((rs1 as u64 * rs2 as u64) >> 32) as u32,
+                // dividing by zero gives all ones and leaves the dividend as the remainder, and
+                // the one signed overflow (i32::MIN / -1) gives i32::MIN remainder 0, no trap
+                DIV_OPERATION_DIV if is_muldiv => match rs2 {
+                    0 => u32::MAX,
+                    _ => (rs1 as i32).wrapping_div(rs2 as i32) as u32,
+                },
+                DIV_OPERATION_DIVU if is_muldiv => rs1.checked_div(rs2).unwrap_or(u32::MAX),
+                DIV_OPERATION_REM if is_muldiv => match rs2 {
+                    0 => rs1,
+                    _ => (rs1 as i32).wrapping_rem(rs2 as i32) as u32,
+                },
+                DIV_OPERATION_REMU if is_muldiv => rs1.checked_rem(rs2).unwrap_or(rs1),
+                0b010 | 0b100 | 0b110 if is_zba => (rs1 << (funct3 >> 1)).wrapping_add(rs2),
+                ALU_OPERATION_ADD => {
+                    if is_register_op {
+                        if is_alternate {
+                            rs1.wrapping_sub(rs2)
                         } else {
-                            (rs1 as i32).saturating_add(imm32) as u32
+                            rs1.wrapping_add(rs2)
                         }
+                    } else {
+                        rs1.wrapping_add_signed(imm32)
                     }
-                    ALU_OPERATION_SLL => {
-                        if is_register_op {
-                            rs1 << rs2
-                        } else {
-                            rs1 << shamt
-                        }
+                }
+                ALU_OPERATION_SLL => {
+                    if is_register_op {
+                        rs1 << (rs2 & 0x1F)
+                    } else {
+                        rs1 << shamt
                     }
-                    ALU_OPERATION_SLT => {
-                        if is_register_op {
-                            ((rs1 as i32) < (rs2 as i32)).into()
-                        } else {
-                            ((rs1 as i32) < imm32).into()
-                        }
+                }
+                ALU_OPERATION_SLT => {
+                    if is_register_op {
+                        ((rs1 as i32) < (rs2 as i32)).into()
+                    } else {
+                        ((rs1 as i32) < imm32).into()
                     }
-                    ALU_OPERATION_SLTU => {
-                        if is_register_op {
-                            (rs1 < rs2).into()
-                        } else {
-                            (rs1 < (imm32 as u32)).into()
-                        }
+                }
+                ALU_OPERATION_SLTU => {
+                    if is_register_op {
+                        (rs1 < rs2).into()
+                    } else {
+                        (rs1 < (imm32 as u32)).into()
                     }
-                    ALU_OPERATION_XOR => {
-                        if is_register_op {
-                            rs1 ^ rs2
-                        } else {
-                            rs1 ^ (imm32 as u32)
-                        }
+                }
+                ALU_OPERATION_XOR => {
+                    if is_register_op {
+                        rs1 ^ rs2
+                    } else {
+                        rs1 ^ (imm32 as u32)
                     }
-                    ALU_OPERATION_SR => {
-                        if is_register_op {
-                            if is_alternate {
-                                ((rs1 as i32) >> (rs2 as i32)) as u32
-                            } else {
-                                rs1 >> rs2
-                            }
+                }
+                ALU_OPERATION_SR => {
+                    if is_register_op {
+                        if is_alternate {
+                            ((rs1 as i32) >> (rs2 & 0x1F)) as u32
                         } else {
-                            rs1 >> shamt
+                            rs1 >> (rs2 & 0x1F)
                         }
+                    } else {
+                        rs1 >> shamt
                     }
-                    ALU_OPERATION_OR => {
-                        if is_register_op {
-                            rs1 | rs2
-                        } else {
-                            rs1 | (imm32 as u32)
-                        }
+                }
+                ALU_OPERATION_OR => {
+                    if is_register_op {
+                        rs1 | rs2
+                    } else {
+                        rs1 | (imm32 as u32)
                     }
-                    ALU_OPERATION_AND => {
-                        if is_register_op {
-                            rs1 & rs2
-                        } else {
-                            rs1 & (imm32 as u32)
-                        }
+                }
+                ALU_OPERATION_AND => {
+                    if is_register_op {
+                        rs1 & rs2
+                    } else {
+                        rs1 & (imm32 as u32)
                     }
-                    _ => 0,
-                });
+                }
+                _ => 0,
             }
-            DecodedInstruction::Branch {
-                funct3, rs1, rs2, ..
-            } => {
-                let branch_taken = match funct3 {
-                    BRANCH_OPERATION_EQ => rs1 == rs2,
-                    BRANCH_OPERATION_NE => rs1 != rs2,
-                    BRANCH_OPERATION_LT => (rs1 as i32) < (rs2 as i32),
-                    BRANCH_OPERATION_GE => (rs1 as i32) >= (rs2 as i32),
-                    BRANCH_OPERATION_LTU => rs1 < rs2,
-                    BRANCH_OPERATION_GEU => rs1 >= rs2,
-                    _ => false,
+        }
+        DecodedInstruction::Branch {
+            funct3, rs1, rs2, ..
+        } => {
+            let branch_taken = match funct3 {
+                BRANCH_OPERATION_EQ => rs1 == rs2,
+                BRANCH_OPERATION_NE => rs1 != rs2,
+                BRANCH_OPERATION_LT => (rs1 as i32) < (rs2 as i32),
+                BRANCH_OPERATION_GE => (rs1 as i32) >= (rs2 as i32),
+                BRANCH_OPERATION_LTU => rs1 < rs2,
+                BRANCH_OPERATION_GEU => rs1 >= rs2,
+                _ => false,
+            };
+            if !branch_taken {
+                instruction = DecodedInstruction::Branch {
+                    funct3,
+                    branch_address: decoded.pc_plus_4,
+                    rs1,
+                    rs2,
                 };
-                if !branch_taken {
-                    self.instruction.set(DecodedInstruction::Branch {
-                        funct3,
-                        branch_address: decoded.pc_plus_4,
-                        rs1,
-                        rs2,
-                    });
-                }
-                self.write_back_value.set(0);
             }
-            _ => {
-                self.write_back_value.set(0);
+            0
+        }
+        DecodedInstruction::Custom {
+            opcode, rs1, rs2, ..
+        } => {
+            let result = custom_instructions
+                .iter_mut()
+                .find(|(custom_opcode, _)| *custom_opcode == opcode)
+                .and_then(|(_, custom)| custom.execute(decoded.raw_instruction, rs1, rs2));
+            if result.is_none() {
+                // nothing to write back, which is the same as writing to x0
+                instruction = DecodedInstruction::Custom {
+                    opcode,
+                    rd: RegIndex::ZERO,
+                    rs1,
+                    rs2,
+                };
             }
+            result.unwrap_or(0)
+        }
+        _ => 0,
+    };
+
+    ExecutionValue {
+        write_back_value,
+        instruction,
+        raw_instruction: decoded.raw_instruction,
+        pc: decoded.pc,
+        pc_plus_4: decoded.pc_plus_4,
+    }
+}
+
+impl PipelineStage<InstructionExecuteParams<'_>> for InstructionExecute {
+    fn compute(&mut self, params: InstructionExecuteParams) {
+        if params.should_stall {
+            self.active.set(false);
+            return;
         }
+        self.active.set(true);
+        let executed =
+            execute_instruction(&params.decoded_instruction_in, params.custom_instructions);
+        self.write_back_value.set(executed.write_back_value);
+        self.instruction.set(executed.instruction);
+        self.raw_instruction.set(executed.raw_instruction);
+        self.pc.set(executed.pc);
+        self.pc_plus_4.set(executed.pc_plus_4);
+        self.redirect_pending.set(matches!(
+            executed.instruction,
+            DecodedInstruction::Jal { .. }
+                | DecodedInstruction::Jalr { .. }
+                | DecodedInstruction::Branch { .. }
+        ));
     }
 
     fn latch_next(&mut self) {
@@ -188,6 +320,8 @@ impl PipelineStage<InstructionExecuteParams> for InstructionExecute {
         self.raw_instruction.latch_next();
         self.pc.latch_next();
         self.pc_plus_4.latch_next();
+        self.active.latch_next();
+        self.redirect_pending.latch_next();
     }
 
     fn reset(&mut self) {
@@ -196,5 +330,7 @@ impl PipelineStage<InstructionExecuteParams> for InstructionExecute {
         self.raw_instruction.reset();
         self.pc.reset();
         self.pc_plus_4.reset();
+        self.active.reset();
+        self.redirect_pending.reset();
     }
 }
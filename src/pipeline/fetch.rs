@@ -1,6 +1,10 @@
 use super::PipelineStage;
 use crate::{
-    system_interface::{MMIODevice, PROGRAM_ROM_START, SystemInterface},
+    CycleError,
+    system_interface::{MMIODevice, MMIOError, MMIOResult, PROGRAM_ROM_START, SystemInterface},
+    trap::{
+        MCAUSE_INSTRUCTION_ACCESS_FAULT, MCAUSE_INSTRUCTION_ADDRESS_MISALIGNED, PipelineTrapParams,
+    },
     utils::LatchValue,
 };
 
@@ -11,27 +15,67 @@ pub struct InstructionValue {
     pub raw_instruction: u32,
 }
 
+/// Words read by a bus fetch as `(address, word)` pairs
+pub(crate) type FetchBuffer = Vec<(u32, u32)>;
+
 pub struct InstructionFetch {
     pub pc: LatchValue<u32>,
     pub pc_plus_4: LatchValue<u32>,
     raw_instruction: LatchValue<u32>,
+    /// Words read by the last bus fetch, later fetches are served from here while they hit
+    buffer: LatchValue<FetchBuffer>,
+    width: usize,
+    trap_params: LatchValue<PipelineTrapParams>,
+    /// Whether the stage worked on an instruction in the last cycle, rather than stalling
+    active: LatchValue<bool>,
+    /// Set when the bus fails the fetch in a way no trap covers, for the cycle being computed
+    error: Option<CycleError>,
 }
 
 pub struct InstructionFetchParams<'a> {
     pub should_stall: bool,
-    pub branch_address: Option<u32>,
+    pub next_address: u32,
     pub bus: &'a SystemInterface,
 }
 
 impl InstructionFetch {
+    /// At power-on both `pc` and `pc_plus_4` hold the reset vector, so the first fetch reads the
+    /// word at `PROGRAM_ROM_START`. Until then `raw_instruction` is a placeholder that decode never
+    /// sees.
     pub fn new() -> Self {
         Self {
             pc: LatchValue::new(PROGRAM_ROM_START),
             pc_plus_4: LatchValue::new(PROGRAM_ROM_START),
             raw_instruction: LatchValue::new(0x0000_0000),
+            buffer: LatchValue::new(vec![]),
+            width: 1,
+            trap_params: LatchValue::new(PipelineTrapParams::default()),
+            active: LatchValue::new(false),
+            error: None,
         }
     }
 
+    pub fn is_active(&self) -> bool {
+        *self.active.get()
+    }
+
+    /// Sets how many consecutive words are read from the bus into the fetch buffer on a miss. Like
+    /// an instruction cache, the buffer is not kept coherent with stores until a `fence.i`.
+    pub fn set_width(&mut self, width: usize) {
+        assert!(width > 0, "Fetch width must be at least 1");
+        self.width = width;
+        self.buffer.reset();
+    }
+
+    /// Drops every buffered word so the next fetch reads the bus, for `fence.i`
+    pub fn flush_buffer(&mut self) {
+        self.buffer.reset();
+    }
+
+    pub fn get_fetch_buffer(&self) -> &[(u32, u32)] {
+        self.buffer.get()
+    }
+
     pub fn get_instruction_value_out(&self) -> InstructionValue {
         InstructionValue {
             pc: *self.pc.get(),
@@ -39,21 +83,109 @@ impl InstructionFetch {
             raw_instruction: *self.raw_instruction.get(),
         }
     }
+
+    pub fn get_trap_params_out(&self) -> PipelineTrapParams {
+        self.trap_params.get().clone()
+    }
+
+    pub fn get_error_out(&self) -> Option<CycleError> {
+        self.error.clone()
+    }
+
+    /// Reads the instruction at `address` from the fetch buffer, or from the bus on a miss, in
+    /// which case the refilled buffer is returned along with it to replace the current one
+    pub(crate) fn read_instruction(
+        &self,
+        bus: &SystemInterface,
+        address: u32,
+    ) -> MMIOResult<(u32, Option<FetchBuffer>)> {
+        // with a width of 1 every fetch goes to the bus, as a buffer would only ever hit on a jump
+        // to self
+        let buffered = self
+            .buffer
+            .get()
+            .iter()
+            .find(|(buffered, _)| *buffered == address)
+            .map(|(_, word)| *word)
+            .filter(|_| self.width > 1);
+        if let Some(word) = buffered {
+            return Ok((word, None));
+        }
+        // the buffer stops short at the end of whatever is mapped at `address`, or at the first
+        // word after it that can't be read, which only faults once it's fetched
+        let mut buffer = vec![];
+        let addresses = (0..self.width as u32)
+            .map(|i| address.wrapping_add(i * 4))
+            .take_while(|address| bus.is_mapped(*address));
+        for address in addresses {
+            match bus.read_word(address) {
+                Ok(word) => buffer.push((address, word)),
+                Err(_) if !buffer.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((buffer[0].1, Some(buffer)))
+    }
+
+    /// Replaces the fetch buffer straight away, for a refill from `read_instruction` outside of
+    /// `compute`
+    pub(crate) fn latch_buffer(&mut self, buffer: FetchBuffer) {
+        self.buffer.set(buffer);
+        self.buffer.latch_next();
+    }
+
+    /// Traps on the fetch from `address` instead of reading an instruction
+    fn fault(&mut self, address: u32, mcause: u32, mtval: u32) {
+        self.trap_params.set(PipelineTrapParams {
+            mepc: address,
+            mcause,
+            mtval,
+            trap: true,
+        });
+        self.raw_instruction.set(0);
+        self.pc.set(address);
+        self.pc_plus_4.set(address.wrapping_add(4));
+    }
 }
 
 impl<'a> PipelineStage<InstructionFetchParams<'a>> for InstructionFetch {
     fn compute(&mut self, params: InstructionFetchParams<'a>) {
+        self.error = None;
         if params.should_stall {
+            self.active.set(false);
+            self.trap_params.set(PipelineTrapParams {
+                trap: false,
+                ..Default::default()
+            });
             return;
         }
-        let next_address = match params.branch_address {
-            Some(branch_address) => branch_address,
-            None => *self.pc_plus_4.get(),
-        };
-        let value = match params.bus.read_word(next_address) {
-            Ok(instruction) => instruction,
-            Err(e) => {
-                panic!("{}", e);
+        self.active.set(true);
+        let next_address = params.next_address;
+        if !params.bus.permissions(next_address).execute || !params.bus.is_mapped(next_address) {
+            self.fault(next_address, MCAUSE_INSTRUCTION_ACCESS_FAULT, next_address);
+            return;
+        }
+        let value = match self.read_instruction(params.bus, next_address) {
+            Ok((word, refill)) => {
+                if let Some(buffer) = refill {
+                    self.buffer.set(buffer);
+                }
+                word
+            }
+            Err(MMIOError::UnalignedRead(address)) => {
+                self.fault(next_address, MCAUSE_INSTRUCTION_ADDRESS_MISALIGNED, address);
+                return;
+            }
+            Err(MMIOError::AccessFault(address)) => {
+                self.fault(next_address, MCAUSE_INSTRUCTION_ACCESS_FAULT, address);
+                return;
+            }
+            Err(error) => {
+                self.error = Some(CycleError::Fetch {
+                    pc: next_address,
+                    error,
+                });
+                return;
             }
         };
         self.raw_instruction.set(value);
@@ -65,11 +197,17 @@ impl<'a> PipelineStage<InstructionFetchParams<'a>> for InstructionFetch {
         self.raw_instruction.latch_next();
         self.pc.latch_next();
         self.pc_plus_4.latch_next();
+        self.buffer.latch_next();
+        self.trap_params.latch_next();
+        self.active.latch_next();
     }
 
     fn reset(&mut self) {
         self.raw_instruction.reset();
         self.pc.reset();
         self.pc_plus_4.reset();
+        self.buffer.reset();
+        self.trap_params.reset();
+        self.active.reset();
     }
 }
@@ -1,7 +1,11 @@
 use crate::{
+    CycleError,
     csr::{CSR_OPERATION_RC, CSR_OPERATION_RS, CSR_OPERATION_RW, CSRInterface},
-    system_interface::{MMIODevice, MMIOError, SystemInterface},
-    trap::{MCAUSE_LOAD_ADDRESS_MISALIGNED, PipelineTrapParams},
+    system_interface::{MMIODevice, MMIOError, MMIOResult, SystemInterface},
+    trap::{
+        MCAUSE_ILLEGAL_INSTRUCTION, MCAUSE_LOAD_ACCESS_FAULT, MCAUSE_LOAD_ADDRESS_MISALIGNED,
+        MCAUSE_STORE_AMO_ACCESS_FAULT, MCAUSE_STORE_AMO_ADDRESS_MISALIGNED, PipelineTrapParams,
+    },
     utils::{LatchValue, sign_extend_32},
 };
 
@@ -17,9 +21,32 @@ pub struct MemoryAccessValue {
     pub trap_params: PipelineTrapParams,
 }
 
+/// How loads and stores to addresses that are not a multiple of their width are handled
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum MisalignedAccessPolicy {
+    /// Raise an address-misaligned trap
+    #[default]
+    Trap,
+    /// Service the access one byte at a time
+    Emulate,
+    /// Service the access one byte at a time and record it, see `MisalignedAccess`
+    Record,
+}
+
+/// A misaligned load or store that was emulated under `MisalignedAccessPolicy::Record`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MisalignedAccess {
+    pub pc: u32,
+    pub address: u32,
+    /// Access width in bytes
+    pub width: u32,
+}
+
 const WIDTH_BYTE: u8 = 0b000;
 const WIDTH_HALF: u8 = 0b001;
 const WIDTH_WORD: u8 = 0b010;
+const WIDTH_BYTE_UNSIGNED: u8 = 0b100;
+const WIDTH_HALF_UNSIGNED: u8 = 0b101;
 
 pub struct InstructionMemoryAccess {
     write_back_value: LatchValue<u32>,
@@ -28,6 +55,11 @@ pub struct InstructionMemoryAccess {
     instruction: LatchValue<DecodedInstruction>,
     raw_instruction: LatchValue<u32>,
     trap_params: LatchValue<PipelineTrapParams>,
+    /// Whether the stage worked on an instruction in the last cycle, rather than stalling
+    active: LatchValue<bool>,
+    /// Set when the bus fails a load or store in a way no trap covers, for the cycle being
+    /// computed
+    error: Option<CycleError>,
 }
 
 pub struct InstructionMemoryAccessParams<'a> {
@@ -35,6 +67,8 @@ pub struct InstructionMemoryAccessParams<'a> {
     pub execution_value_in: ExecutionValue,
     pub bus: &'a mut SystemInterface,
     pub csr: &'a mut CSRInterface,
+    pub misaligned_policy: MisalignedAccessPolicy,
+    pub misaligned_accesses: &'a mut Vec<MisalignedAccess>,
 }
 
 impl InstructionMemoryAccess {
@@ -46,9 +80,15 @@ impl InstructionMemoryAccess {
             instruction: LatchValue::new(DecodedInstruction::None),
             raw_instruction: LatchValue::new(0),
             trap_params: LatchValue::new(PipelineTrapParams::default()),
+            active: LatchValue::new(false),
+            error: None,
         }
     }
 
+    pub fn is_active(&self) -> bool {
+        *self.active.get()
+    }
+
     pub fn get_memory_access_value_out(&self) -> MemoryAccessValue {
         MemoryAccessValue {
             write_back_value: *self.write_back_value.get(),
@@ -59,17 +99,108 @@ impl InstructionMemoryAccess {
             trap_params: self.trap_params.get().clone(),
         }
     }
+
+    pub fn get_error_out(&self) -> Option<CycleError> {
+        self.error.clone()
+    }
+}
+
+/// Width in bytes of a store with `funct3`
+pub(crate) fn access_width(funct3: u8) -> Option<u32> {
+    match funct3 {
+        WIDTH_BYTE => Some(1),
+        WIDTH_HALF => Some(2),
+        WIDTH_WORD => Some(4),
+        _ => None,
+    }
+}
+
+/// Width in bytes of a load with `funct3`, which adds LBU and LHU to the store widths. LWU and the
+/// rest are RV64 only.
+pub(crate) fn load_width(funct3: u8) -> Option<u32> {
+    match funct3 {
+        WIDTH_BYTE_UNSIGNED => Some(1),
+        WIDTH_HALF_UNSIGNED => Some(2),
+        _ => access_width(funct3),
+    }
+}
+
+/// Trap raised for a load or store whose funct3 doesn't encode a valid width, or a CSR access
+/// that isn't allowed
+fn illegal_instruction_trap_params(execution_value: &ExecutionValue) -> PipelineTrapParams {
+    PipelineTrapParams {
+        mepc: execution_value.pc,
+        mcause: MCAUSE_ILLEGAL_INSTRUCTION,
+        mtval: execution_value.raw_instruction,
+        trap: true,
+    }
+}
+
+/// Reads `width` bytes from a naturally aligned `address` in a single bus access
+pub(crate) fn read_aligned(bus: &SystemInterface, address: u32, width: u32) -> MMIOResult<u32> {
+    match width {
+        1 => bus.read_byte(address).map(|v| v as u32),
+        2 => bus.read_half_word(address).map(|v| v as u32),
+        _ => bus.read_word(address),
+    }
+}
+
+/// Writes the low `width` bytes of `value` to a naturally aligned `address` in a single bus access
+pub(crate) fn write_aligned(
+    bus: &mut SystemInterface,
+    address: u32,
+    width: u32,
+    value: u32,
+) -> MMIOResult<()> {
+    match width {
+        1 => bus.write_byte(address, value as u8),
+        2 => bus.write_half_word(address, value as u16),
+        _ => bus.write_word(address, value),
+    }
+}
+
+/// Extends a loaded value to 32 bits, sign-extending unless `funct3` is LBU/LHU
+pub(crate) fn extend_loaded(funct3: u8, width: u32, value: u32) -> u32 {
+    let should_sign_extend = funct3 & 0b100 == 0;
+    if should_sign_extend && width < 4 {
+        sign_extend_32(width * 8, value as i32) as u32
+    } else {
+        value
+    }
+}
+
+/// Reads `width` bytes one at a time, composing them little-endian like aligned accesses
+fn read_emulated(bus: &SystemInterface, address: u32, width: u32) -> MMIOResult<u32> {
+    (0..width).try_fold(0, |value, i| {
+        Ok(value | ((bus.read_byte(address.wrapping_add(i))? as u32) << (8 * i)))
+    })
+}
+
+/// Writes `width` bytes one at a time, splitting them little-endian like aligned accesses
+fn write_emulated(
+    bus: &mut SystemInterface,
+    address: u32,
+    width: u32,
+    value: u32,
+) -> MMIOResult<()> {
+    for i in 0..width {
+        bus.write_byte(address.wrapping_add(i), (value >> (8 * i)) as u8)?;
+    }
+    Ok(())
 }
 
 impl PipelineStage<InstructionMemoryAccessParams<'_>> for InstructionMemoryAccess {
     fn compute(&mut self, params: InstructionMemoryAccessParams) {
+        self.error = None;
         if params.should_stall {
+            self.active.set(false);
             self.trap_params.set(PipelineTrapParams {
                 trap: false,
                 ..Default::default()
             });
             return;
         }
+        self.active.set(true);
         let execution_value = params.execution_value_in;
         self.instruction.set(execution_value.instruction);
         self.pc.set(execution_value.pc);
@@ -83,40 +214,59 @@ impl PipelineStage<InstructionMemoryAccessParams<'_>> for InstructionMemoryAcces
             DecodedInstruction::Load {
                 funct3, imm32, rs1, ..
             } => {
-                let addr = (imm32 + rs1 as i32) as u32;
-                let should_sign_extend = funct3 & 0b100 == 0;
-                let result = match funct3 & 0b011 {
-                    WIDTH_BYTE => params.bus.read_byte(addr).map(|v| {
-                        if should_sign_extend {
-                            sign_extend_32(8, v as i32) as u32
-                        } else {
-                            v as u32
-                        }
-                    }),
-                    WIDTH_HALF => params.bus.read_half_word(addr).map(|v| {
-                        if should_sign_extend {
-                            sign_extend_32(16, v as i32) as u32
-                        } else {
-                            v as u32
-                        }
-                    }),
-                    WIDTH_WORD => params.bus.read_word(addr),
-                    _ => {
-                        panic!("Invalid funct3 for load operation");
+                let addr = rs1.wrapping_add_signed(imm32);
+                let Some(width) = load_width(funct3) else {
+                    self.trap_params
+                        .set(illegal_instruction_trap_params(&execution_value));
+                    return;
+                };
+                if !params.bus.permissions(addr).read {
+                    self.trap_params.set(PipelineTrapParams {
+                        mepc: execution_value.pc,
+                        mcause: MCAUSE_LOAD_ACCESS_FAULT,
+                        mtval: addr,
+                        trap: true,
+                    });
+                    return;
+                }
+                let result = if addr & (width - 1) != 0
+                    && params.misaligned_policy != MisalignedAccessPolicy::Trap
+                {
+                    if params.misaligned_policy == MisalignedAccessPolicy::Record {
+                        params.misaligned_accesses.push(MisalignedAccess {
+                            pc: execution_value.pc,
+                            address: addr,
+                            width,
+                        });
                     }
+                    read_emulated(params.bus, addr, width)
+                } else {
+                    read_aligned(params.bus, addr, width)
                 };
+                let result = result.map(|v| extend_loaded(funct3, width, v));
                 match result {
                     Ok(value) => self.write_back_value.set(value),
-                    Err(MMIOError::UnalignedRead(_)) => {
+                    Err(MMIOError::UnalignedRead(address)) => {
                         self.trap_params.set(PipelineTrapParams {
-                            mepc: execution_value.pc_plus_4,
+                            mepc: execution_value.pc,
                             mcause: MCAUSE_LOAD_ADDRESS_MISALIGNED,
-                            mtval: execution_value.raw_instruction,
+                            mtval: address,
                             trap: true,
                         });
                     }
-                    Err(e) => {
-                        panic!("Error reading memory: {}", e);
+                    Err(MMIOError::AccessFault(address)) => {
+                        self.trap_params.set(PipelineTrapParams {
+                            mepc: execution_value.pc,
+                            mcause: MCAUSE_LOAD_ACCESS_FAULT,
+                            mtval: address,
+                            trap: true,
+                        });
+                    }
+                    Err(error) => {
+                        self.error = Some(CycleError::MemoryAccess {
+                            pc: execution_value.pc,
+                            error,
+                        });
                     }
                 }
             }
@@ -126,34 +276,67 @@ impl PipelineStage<InstructionMemoryAccessParams<'_>> for InstructionMemoryAcces
                 rs1,
                 rs2,
             } => {
-                let addr = (imm32 + rs1 as i32) as u32;
-                let result = match funct3 {
-                    WIDTH_BYTE => params.bus.write_byte(addr, rs2 as u8),
-                    WIDTH_HALF => params.bus.write_half_word(addr, rs2 as u16),
-                    WIDTH_WORD => params.bus.write_word(addr, rs2),
-                    _ => {
-                        panic!("Invalid funct3 for store operation");
+                let addr = rs1.wrapping_add_signed(imm32);
+                let Some(width) = access_width(funct3) else {
+                    self.trap_params
+                        .set(illegal_instruction_trap_params(&execution_value));
+                    return;
+                };
+                if !params.bus.permissions(addr).write {
+                    self.trap_params.set(PipelineTrapParams {
+                        mepc: execution_value.pc,
+                        mcause: MCAUSE_STORE_AMO_ACCESS_FAULT,
+                        mtval: addr,
+                        trap: true,
+                    });
+                    return;
+                }
+                let result = if addr & (width - 1) != 0
+                    && params.misaligned_policy != MisalignedAccessPolicy::Trap
+                {
+                    if params.misaligned_policy == MisalignedAccessPolicy::Record {
+                        params.misaligned_accesses.push(MisalignedAccess {
+                            pc: execution_value.pc,
+                            address: addr,
+                            width,
+                        });
                     }
+                    write_emulated(params.bus, addr, width, rs2)
+                } else {
+                    write_aligned(params.bus, addr, width, rs2)
                 };
                 match result {
                     Ok(_) => {}
-                    Err(MMIOError::UnalignedWrite(_, _)) => {
+                    Err(MMIOError::UnalignedWrite(address, _)) => {
                         self.trap_params.set(PipelineTrapParams {
-                            mepc: execution_value.pc_plus_4,
-                            mcause: MCAUSE_LOAD_ADDRESS_MISALIGNED,
-                            mtval: execution_value.raw_instruction,
+                            mepc: execution_value.pc,
+                            mcause: MCAUSE_STORE_AMO_ADDRESS_MISALIGNED,
+                            mtval: address,
                             trap: true,
                         });
                     }
-                    Err(e) => {
-                        panic!("Error reading memory: {}", e);
+                    Err(MMIOError::AccessFault(address)) => {
+                        self.trap_params.set(PipelineTrapParams {
+                            mepc: execution_value.pc,
+                            mcause: MCAUSE_STORE_AMO_ACCESS_FAULT,
+                            mtval: address,
+                            trap: true,
+                        });
+                    }
+                    Err(error) => {
+                        self.error = Some(CycleError::MemoryAccess {
+                            pc: execution_value.pc,
+                            error,
+                        });
                     }
                 }
             }
             DecodedInstruction::Lui { imm32, .. } => {
                 self.write_back_value.set(imm32);
             }
-            DecodedInstruction::Jal { .. } => {
+            DecodedInstruction::Jal { .. } | DecodedInstruction::Jalr { .. } => {
+                // the link address travels with the jump itself, so it isn't affected by fetch having
+                // already been redirected to the target
                 self.write_back_value.set(execution_value.pc_plus_4);
             }
             DecodedInstruction::Branch { .. } => {
@@ -167,32 +350,42 @@ impl PipelineStage<InstructionMemoryAccessParams<'_>> for InstructionMemoryAcces
                 should_read,
                 ..
             } => {
-                let csr_value = should_read
+                let result = should_read
                     .then(|| params.csr.read(csr_address))
-                    .unwrap_or(0);
-                self.write_back_value.set(csr_value);
-
-                if should_write {
-                    match funct3 & 0b11 {
-                        CSR_OPERATION_RW => {
-                            params.csr.write(csr_address, source);
-                        }
-                        CSR_OPERATION_RS => {
-                            params.csr.write(csr_address, csr_value | source);
+                    .unwrap_or(Ok(0))
+                    .and_then(|csr_value| {
+                        if should_write {
+                            let value = match funct3 & 0b11 {
+                                CSR_OPERATION_RW => source,
+                                CSR_OPERATION_RS => csr_value | source,
+                                CSR_OPERATION_RC => csr_value & !source,
+                                _ => csr_value,
+                            };
+                            params.csr.write(csr_address, value)?;
                         }
-                        CSR_OPERATION_RC => {
-                            params.csr.write(csr_address, csr_value & !source);
-                        }
-                        _ => {}
-                    }
+                        Ok(csr_value)
+                    });
+                // unknown CSRs and writes to read-only ones are illegal instructions
+                match result {
+                    Ok(csr_value) => self.write_back_value.set(csr_value),
+                    Err(_) => self
+                        .trap_params
+                        .set(illegal_instruction_trap_params(&execution_value)),
                 }
             }
             DecodedInstruction::Auipc { imm32, .. } => {
-                self.write_back_value.set(execution_value.pc + imm32);
+                self.write_back_value
+                    .set(execution_value.pc.wrapping_add(imm32));
             }
-            DecodedInstruction::Fence { .. } => {
+            DecodedInstruction::Fence { .. }
+            | DecodedInstruction::FenceI { .. }
+            | DecodedInstruction::Mret { .. }
+            | DecodedInstruction::Wfi { .. } => {
                 self.write_back_value.set(0);
             }
+            DecodedInstruction::Custom { .. } => {
+                self.write_back_value.set(execution_value.write_back_value);
+            }
             DecodedInstruction::None => {
                 self.write_back_value.set(0);
             }
@@ -206,6 +399,7 @@ impl PipelineStage<InstructionMemoryAccessParams<'_>> for InstructionMemoryAcces
         self.pc_plus_4.latch_next();
         self.raw_instruction.latch_next();
         self.trap_params.latch_next();
+        self.active.latch_next();
     }
 
     fn reset(&mut self) {
@@ -215,5 +409,6 @@ impl PipelineStage<InstructionMemoryAccessParams<'_>> for InstructionMemoryAcces
         self.pc_plus_4.reset();
         self.raw_instruction.reset();
         self.trap_params.reset();
+        self.active.reset();
     }
 }
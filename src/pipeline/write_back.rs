@@ -1,8 +1,19 @@
-use crate::RegisterFile;
+use crate::{RegisterFile, utils::LatchValue};
 
-use super::{PipelineStage, decode::DecodedInstruction, memory_access::MemoryAccessValue};
+use super::{PipelineStage, memory_access::MemoryAccessValue};
 
-pub struct InstructionWriteBack {}
+#[derive(Debug, PartialEq, Eq)]
+pub struct WriteBackValue {
+    pub pc: u32,
+    pub raw_instruction: u32,
+}
+
+pub struct InstructionWriteBack {
+    pc: LatchValue<u32>,
+    raw_instruction: LatchValue<u32>,
+    /// Whether the stage worked on an instruction in the last cycle, rather than stalling
+    active: LatchValue<bool>,
+}
 
 pub struct InstructionWriteBackParams<'a> {
     pub should_stall: bool,
@@ -12,47 +23,52 @@ pub struct InstructionWriteBackParams<'a> {
 
 impl InstructionWriteBack {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            pc: LatchValue::new(0),
+            raw_instruction: LatchValue::new(0),
+            active: LatchValue::new(false),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self.active.get()
+    }
+
+    pub fn get_write_back_value_out(&self) -> WriteBackValue {
+        WriteBackValue {
+            pc: *self.pc.get(),
+            raw_instruction: *self.raw_instruction.get(),
+        }
     }
 }
 
 impl<'a> PipelineStage<InstructionWriteBackParams<'a>> for InstructionWriteBack {
     fn compute(&mut self, params: InstructionWriteBackParams<'a>) {
         if params.should_stall {
+            self.active.set(false);
             return;
         }
+        self.active.set(true);
         let memory_access_value = params.memory_access_value_in;
-        match memory_access_value.instruction {
-            DecodedInstruction::Alu { rd, .. } => {
-                params.reg_file[rd as usize] = memory_access_value.write_back_value;
-            }
-            DecodedInstruction::Store { .. } => {
-                // Store operations do not write back to the register file
-            }
-            DecodedInstruction::Load { rd, .. } => {
-                params.reg_file[rd as usize] = memory_access_value.write_back_value;
-            }
-            DecodedInstruction::Lui { rd, .. } => {
-                params.reg_file[rd as usize] = memory_access_value.write_back_value;
-            }
-            DecodedInstruction::Jal { rd, .. } => {
-                params.reg_file[rd as usize] = memory_access_value.write_back_value;
-            }
-            DecodedInstruction::Branch { .. } => {
-                // Branch operations do not write back to the register file
-            }
-            DecodedInstruction::System { rd, .. } => {
-                params.reg_file[rd as usize] = memory_access_value.write_back_value;
-            }
-            DecodedInstruction::Auipc { rd, .. } => {
-                params.reg_file[rd as usize] = memory_access_value.write_back_value;
-            }
-            DecodedInstruction::Fence { .. } => {}
-            DecodedInstruction::None => {}
+        self.pc.set(memory_access_value.pc);
+        self.raw_instruction
+            .set(memory_access_value.raw_instruction);
+        let rd = memory_access_value.instruction.rd();
+        // x0 is hardwired to zero, writes to it are discarded
+        if let Some(rd) = rd.filter(|rd| !rd.is_zero()) {
+            params.reg_file[rd] = memory_access_value.write_back_value;
         }
     }
 
-    fn latch_next(&mut self) {}
+    fn latch_next(&mut self) {
+        self.pc.latch_next();
+        self.raw_instruction.latch_next();
+        self.active.latch_next();
+    }
 
-    fn reset(&mut self) {}
+    fn reset(&mut self) {
+        self.pc.reset();
+        self.raw_instruction.reset();
+        self.active.reset();
+    }
 }
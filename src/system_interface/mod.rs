@@ -1,13 +1,26 @@
 mod ram;
 mod rom;
+mod timer;
+
+use std::ops::Range;
 
 pub use ram::RamDevice;
-pub use rom::RomDevice;
+pub(crate) use ram::RamWrites;
+pub use rom::{ROM_ERASED_WORD, RomDevice};
+pub use timer::{MTIME_OFFSET, MTIMECMP_OFFSET, TimerDevice};
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum MMIOError {
     UnalignedRead(u32),
     UnalignedWrite(u32, u32),
+    /// A device couldn't be attached at `start..end` as it overlaps ROM, RAM, the timer or
+    /// another device
+    RegionOverlap(u32, u32),
+    /// A device couldn't be attached at `start..end` as the range is empty or runs backwards, e.g.
+    /// from wrapping past the top of the address space
+    InvalidRange(u32, u32),
+    /// Nothing is mapped at the address
+    AccessFault(u32),
 }
 impl std::fmt::Display for MMIOError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -22,11 +35,28 @@ impl std::fmt::Display for MMIOError {
                     addr, value
                 )
             }
+            MMIOError::RegionOverlap(start, end) => {
+                write!(
+                    f,
+                    "Region {:#08X}..{:#08X} overlaps an existing device",
+                    start, end
+                )
+            }
+            MMIOError::InvalidRange(start, end) => {
+                write!(
+                    f,
+                    "Region {:#08X}..{:#08X} is empty or reversed",
+                    start, end
+                )
+            }
+            MMIOError::AccessFault(addr) => {
+                write!(f, "Access fault at unmapped address {:#08X}", addr)
+            }
         }
     }
 }
 
-type MMIOResult<T> = std::result::Result<T, MMIOError>;
+pub type MMIOResult<T> = std::result::Result<T, MMIOError>;
 
 pub trait MMIODevice {
     fn read_byte(&self, address: u32) -> MMIOResult<u8>;
@@ -35,32 +65,215 @@ pub trait MMIODevice {
     fn write_half_word(&mut self, address: u32, value: u16) -> MMIOResult<()>;
     fn read_word(&self, address: u32) -> MMIOResult<u32>;
     fn write_word(&mut self, address: u32, value: u32) -> MMIOResult<()>;
+
+    /// Reads a byte for the host to inspect, e.g. from a debugger. Devices whose reads have side
+    /// effects should override this to read without them.
+    fn peek_byte(&self, address: u32) -> MMIOResult<u8> {
+        self.read_byte(address)
+    }
 }
 
+pub const CLINT_START: u32 = 0x0200_0000;
+pub const CLINT_END: u32 = 0x0200_FFFF;
 pub const PROGRAM_ROM_START: u32 = 0x1000_0000;
 pub const PROGRAM_ROM_END: u32 = 0x1FFF_FFFF;
 pub const RAM_START: u32 = 0x2000_0000;
 pub const RAM_END: u32 = 0x2FFF_FFFF;
 
+/// Access permissions for a region of the address space
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const RX: Permissions = Permissions {
+        read: true,
+        write: false,
+        execute: true,
+    };
+    pub const RW: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: false,
+    };
+    pub const RWX: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: true,
+    };
+}
+
+/// What an entry of the device table routes accesses to. ROM, RAM and the timer stay fields of
+/// `SystemInterface` so programs can be loaded and inspected without going through the bus.
+enum Mapping {
+    Rom,
+    Ram,
+    Timer,
+    Device(Box<dyn MMIODevice>),
+}
+
+/// An entry of the device table, the device sees addresses as an offset from the start of `range`
+struct MappedDevice {
+    range: Range<u32>,
+    mapping: Mapping,
+}
+
 pub struct SystemInterface {
     pub rom: RomDevice,
     pub ram: RamDevice,
+    /// The machine timer, mapped at `CLINT_START`
+    pub timer: TimerDevice,
+    devices: Vec<MappedDevice>,
+    regions: Vec<(Range<u32>, Permissions)>,
 }
 
 impl SystemInterface {
     pub fn new(rom: RomDevice, ram: RamDevice) -> Self {
-        Self { rom, ram }
+        let rom_range = PROGRAM_ROM_START..PROGRAM_ROM_END + 1;
+        let ram_range = RAM_START..RAM_END + 1;
+        Self {
+            rom,
+            ram,
+            timer: TimerDevice::new(),
+            regions: vec![
+                (rom_range.clone(), Permissions::RX),
+                (ram_range.clone(), Permissions::RWX),
+            ],
+            devices: vec![
+                MappedDevice {
+                    range: rom_range,
+                    mapping: Mapping::Rom,
+                },
+                MappedDevice {
+                    range: ram_range,
+                    mapping: Mapping::Ram,
+                },
+                MappedDevice {
+                    range: CLINT_START..CLINT_END + 1,
+                    mapping: Mapping::Timer,
+                },
+            ],
+        }
+    }
+
+    fn mapped_range(&self, is_match: impl Fn(&Mapping) -> bool) -> Range<u32> {
+        self.devices
+            .iter()
+            .find(|mapped| is_match(&mapped.mapping))
+            .map(|mapped| mapped.range.clone())
+            .expect("ROM and RAM are always mapped")
+    }
+
+    /// Addresses the program ROM is mapped to
+    pub fn rom_range(&self) -> Range<u32> {
+        self.mapped_range(|mapping| matches!(mapping, Mapping::Rom))
+    }
+
+    /// Addresses the RAM is mapped to
+    pub fn ram_range(&self) -> Range<u32> {
+        self.mapped_range(|mapping| matches!(mapping, Mapping::Ram))
+    }
+
+    /// Sets the permissions of `range`, overriding any earlier region it overlaps
+    pub fn add_region(&mut self, range: Range<u32>, permissions: Permissions) {
+        self.regions.push((range, permissions));
+    }
+
+    /// Permissions for `address`, anything not covered by a region is unrestricted
+    pub fn permissions(&self, address: u32) -> Permissions {
+        self.regions
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&address))
+            .map_or(Permissions::RWX, |(_, permissions)| *permissions)
+    }
+
+    /// Maps `device` into `range` of the address space, where it sees addresses as an offset from
+    /// the start of `range`. `range` can't be empty or overlap ROM, RAM, the timer or another
+    /// attached device.
+    pub fn attach(&mut self, range: Range<u32>, device: Box<dyn MMIODevice>) -> MMIOResult<()> {
+        if range.is_empty() {
+            return Err(MMIOError::InvalidRange(range.start, range.end));
+        }
+        if self
+            .devices
+            .iter()
+            .any(|mapped| range.start < mapped.range.end && mapped.range.start < range.end)
+        {
+            return Err(MMIOError::RegionOverlap(range.start, range.end));
+        }
+        self.devices.push(MappedDevice {
+            range,
+            mapping: Mapping::Device(device),
+        });
+        Ok(())
+    }
+
+    /// Whether writes to `address` are stored somewhere, i.e. it is in RAM, the timer or an
+    /// attached device
+    pub fn is_writable_memory(&self, address: u32) -> bool {
+        self.devices.iter().any(|mapped| {
+            mapped.range.contains(&address) && !matches!(mapped.mapping, Mapping::Rom)
+        })
+    }
+
+    /// Whether ROM, RAM, the timer or an attached device is mapped at `address`
+    pub fn is_mapped(&self, address: u32) -> bool {
+        self.route(address).is_some()
+    }
+
+    /// The device `address` is mapped to and the offset into it, `None` if it's unmapped
+    fn route(&self, address: u32) -> Option<(&dyn MMIODevice, u32)> {
+        let mapped = self
+            .devices
+            .iter()
+            .find(|mapped| mapped.range.contains(&address))?;
+        let device: &dyn MMIODevice = match &mapped.mapping {
+            Mapping::Rom => &self.rom,
+            Mapping::Ram => &self.ram,
+            Mapping::Timer => &self.timer,
+            Mapping::Device(device) => device.as_ref(),
+        };
+        Some((device, address - mapped.range.start))
+    }
+
+    fn route_mut(&mut self, address: u32) -> Option<(&mut dyn MMIODevice, u32)> {
+        let Self {
+            rom,
+            ram,
+            timer,
+            devices,
+            ..
+        } = self;
+        let mapped = devices
+            .iter_mut()
+            .find(|mapped| mapped.range.contains(&address))?;
+        let offset = address - mapped.range.start;
+        let device: &mut dyn MMIODevice = match &mut mapped.mapping {
+            Mapping::Rom => rom,
+            Mapping::Ram => ram,
+            Mapping::Timer => timer,
+            Mapping::Device(device) => device.as_mut(),
+        };
+        Some((device, offset))
     }
 }
 
 impl MMIODevice for SystemInterface {
     fn read_byte(&self, address: u32) -> MMIOResult<u8> {
-        if (address & PROGRAM_ROM_START) == PROGRAM_ROM_START {
-            self.rom.read_byte(address & 0x0FFF_FFFF)
-        } else if (address & RAM_START) == RAM_START {
-            self.ram.read_byte(address & 0x0FFF_FFFF)
-        } else {
-            Ok(0)
+        match self.route(address) {
+            Some((device, offset)) => device.read_byte(offset),
+            None => Err(MMIOError::AccessFault(address)),
+        }
+    }
+
+    fn peek_byte(&self, address: u32) -> MMIOResult<u8> {
+        match self.route(address) {
+            Some((device, offset)) => device.peek_byte(offset),
+            None => Err(MMIOError::AccessFault(address)),
         }
     }
 
@@ -69,12 +282,9 @@ impl MMIODevice for SystemInterface {
             return Err(MMIOError::UnalignedRead(address));
         }
 
-        if (address & PROGRAM_ROM_START) == PROGRAM_ROM_START {
-            self.rom.read_half_word(address & 0x0FFF_FFFF)
-        } else if (address & RAM_START) == RAM_START {
-            self.ram.read_half_word(address & 0x0FFF_FFFF)
-        } else {
-            Ok(0)
+        match self.route(address) {
+            Some((device, offset)) => device.read_half_word(offset),
+            None => Err(MMIOError::AccessFault(address)),
         }
     }
 
@@ -83,21 +293,17 @@ impl MMIODevice for SystemInterface {
             return Err(MMIOError::UnalignedRead(address));
         }
 
-        if (address & PROGRAM_ROM_START) == PROGRAM_ROM_START {
-            self.rom.read_word(address & 0x0FFF_FFFF)
-        } else if (address & RAM_START) == RAM_START {
-            self.ram.read_word(address & 0x0FFF_FFFF)
-        } else {
-            Ok(0)
+        match self.route(address) {
+            Some((device, offset)) => device.read_word(offset),
+            None => Err(MMIOError::AccessFault(address)),
         }
     }
 
     fn write_byte(&mut self, address: u32, value: u8) -> MMIOResult<()> {
-        if (address & RAM_START) == RAM_START {
-            return self.ram.write_byte(address & 0x0FFF_FFFF, value);
+        match self.route_mut(address) {
+            Some((device, offset)) => device.write_byte(offset, value),
+            None => Err(MMIOError::AccessFault(address)),
         }
-
-        Ok(())
     }
 
     fn write_half_word(&mut self, address: u32, value: u16) -> MMIOResult<()> {
@@ -105,11 +311,10 @@ impl MMIODevice for SystemInterface {
             return Err(MMIOError::UnalignedWrite(address, value as u32));
         }
 
-        if (address & RAM_START) == RAM_START {
-            return self.ram.write_half_word(address & 0x0FFF_FFFF, value);
+        match self.route_mut(address) {
+            Some((device, offset)) => device.write_half_word(offset, value),
+            None => Err(MMIOError::AccessFault(address)),
         }
-
-        Ok(())
     }
 
     fn write_word(&mut self, address: u32, value: u32) -> MMIOResult<()> {
@@ -117,10 +322,100 @@ impl MMIODevice for SystemInterface {
             return Err(MMIOError::UnalignedWrite(address, value));
         }
 
-        if (address & RAM_START) == RAM_START {
-            return self.ram.write_word(address & 0x0FFF_FFFF, value);
+        match self.route_mut(address) {
+            Some((device, offset)) => device.write_word(offset, value),
+            None => Err(MMIOError::AccessFault(address)),
         }
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_table_routing() {
+        let mut rom = RomDevice::new();
+        rom.load(vec![0xDEAD_BEEF, 0xC0DE_CAFE]);
+        let mut bus = SystemInterface::new(rom, RamDevice::new());
+        bus.attach(0x4000_0000..0x4000_1000, Box::new(RamDevice::new()))
+            .unwrap();
+
+        assert_eq!(bus.read_word(PROGRAM_ROM_START + 4), Ok(0xC0DE_CAFE));
+        bus.write_word(RAM_START + 8, 42).unwrap();
+        assert_eq!(bus.ram.read_word(8), Ok(42));
+        bus.write_half_word(0x4000_0010, 7).unwrap();
+        assert_eq!(bus.read_half_word(0x4000_0010), Ok(7));
+        assert!(bus.is_writable_memory(0x4000_0FFC));
+        assert!(!bus.is_writable_memory(PROGRAM_ROM_START));
+
+        // only the table's ranges are mapped, the ROM isn't mirrored above RAM
+        assert_eq!(
+            bus.read_word(0x3000_0004),
+            Err(MMIOError::AccessFault(0x3000_0004))
+        );
+        assert_eq!(
+            bus.write_byte(0x4000_1000, 1),
+            Err(MMIOError::AccessFault(0x4000_1000))
+        );
+        assert!(bus.is_mapped(0x4000_0FFF));
+        assert!(!bus.is_mapped(0x4000_1000));
+        assert_eq!(
+            bus.attach(0x1FFF_F000..0x2000_1000, Box::new(RamDevice::new())),
+            Err(MMIOError::RegionOverlap(0x1FFF_F000, 0x2000_1000))
+        );
+    }
+
+    #[test]
+    fn test_attach_overlapping_devices() {
+        let mut bus = SystemInterface::new(RomDevice::new(), RamDevice::new());
+        bus.attach(0x4000_0000..0x4000_1000, Box::new(RamDevice::new()))
+            .unwrap();
+        bus.write_word(0x4000_0800, 42).unwrap();
+        for (start, end) in [
+            // straddling the start, the end, or inside
+            (0x3FFF_FF00, 0x4000_0100),
+            (0x4000_0F00, 0x4000_2000),
+            (0x4000_0800, 0x4000_0900),
+            // covering it entirely
+            (0x3000_0000, 0x5000_0000),
+        ] {
+            assert_eq!(
+                bus.attach(start..end, Box::new(RamDevice::new())),
+                Err(MMIOError::RegionOverlap(start, end))
+            );
+        }
+        // the first device still serves the whole range, nothing was mapped around it
+        assert_eq!(bus.read_word(0x4000_0800), Ok(42));
+        assert!(!bus.is_mapped(0x3FFF_FF00));
+        assert!(!bus.is_mapped(0x4000_1000));
+        // touching ranges don't overlap
+        bus.attach(0x4000_1000..0x4000_2000, Box::new(RamDevice::new()))
+            .unwrap();
+        assert!(bus.is_mapped(0x4000_1000));
+    }
+
+    #[test]
+    fn test_attach_invalid_range() {
+        let mut bus = SystemInterface::new(RomDevice::new(), RamDevice::new());
+        for (start, end) in [
+            // empty
+            (0x4000_0000, 0x4000_0000),
+            // reversed
+            (0x4000_1000, 0x4000_0000),
+            // wrapped past the top of the address space
+            (0xFFFF_F000, 0x0000_1000),
+        ] {
+            assert_eq!(
+                bus.attach(start..end, Box::new(RamDevice::new())),
+                Err(MMIOError::InvalidRange(start, end))
+            );
+        }
+        assert!(!bus.is_mapped(0x4000_0000));
+        assert!(!bus.is_mapped(0xFFFF_F000));
+        // the top page of the address space can still be mapped
+        bus.attach(0xFFFF_F000..0xFFFF_FFFF, Box::new(RamDevice::new()))
+            .unwrap();
+        assert!(bus.is_mapped(0xFFFF_F000));
     }
 }
@@ -1,17 +1,76 @@
 use super::{MMIODevice, MMIOResult};
 
 const RAM_SIZE: u32 = 1024 * 1024 * 4;
-const RAM_SIZE_BYTES: usize = (RAM_SIZE / 4) as usize;
-const RAM_MASK: u32 = (RAM_SIZE / 4) - 1;
 
+/// `(word index, previous value)` of RAM words as they were written, oldest first
+pub(crate) type RamWrites = Vec<(u32, u32)>;
+
+/// RAM of a fixed size, mirrored through the rest of the range it's mapped to
 pub struct RamDevice {
     ram: Vec<u32>,
+    /// Masks a word index into `ram`
+    mask: u32,
+    /// Every write since the last `take_writes`, `None` while writes aren't being logged
+    writes: Option<RamWrites>,
 }
 
 impl RamDevice {
     pub fn new() -> Self {
-        let ram = vec![0xFFFF_FFFF; RAM_SIZE_BYTES];
-        Self { ram }
+        Self::with_size(RAM_SIZE)
+    }
+
+    /// RAM of `bytes` bytes, which must be a power of two and a multiple of 4
+    pub fn with_size(bytes: u32) -> Self {
+        assert!(
+            bytes.is_power_of_two() && bytes >= 4,
+            "RAM size must be a power of two and a multiple of 4, got {}",
+            bytes
+        );
+        let words = bytes / 4;
+        Self {
+            ram: vec![0xFFFF_FFFF; words as usize],
+            mask: words - 1,
+            writes: None,
+        }
+    }
+
+    /// Size of the RAM in bytes
+    pub fn size(&self) -> u32 {
+        (self.ram.len() * 4) as u32
+    }
+
+    /// Every word of RAM, in address order
+    pub fn words(&self) -> &[u32] {
+        &self.ram
+    }
+
+    /// Replaces the whole of RAM with `words`, as returned by `words`
+    pub fn restore_words(&mut self, words: &[u32]) {
+        self.ram.copy_from_slice(words);
+    }
+
+    /// Starts logging writes, so they can be undone with `undo_writes`
+    pub(crate) fn log_writes(&mut self) {
+        self.writes = Some(vec![]);
+    }
+
+    /// Writes logged since logging started or the last call, logging carries on afresh
+    pub(crate) fn take_writes(&mut self) -> RamWrites {
+        self.writes.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Puts back the words overwritten by `writes`, as returned by `take_writes`
+    pub(crate) fn undo_writes(&mut self, writes: &RamWrites) {
+        for &(index, value) in writes.iter().rev() {
+            self.ram[index as usize] = value;
+        }
+    }
+
+    fn store(&mut self, index: usize, value: u32) {
+        if let Some(writes) = self.writes.as_mut() {
+            writes.push((index as u32, self.ram[index]));
+        }
+        self.ram[index] = value;
     }
 }
 
@@ -23,55 +82,57 @@ impl Default for RamDevice {
 
 impl MMIODevice for RamDevice {
     fn read_byte(&self, address: u32) -> MMIOResult<u8> {
-        let index = ((address >> 2) & RAM_MASK) as usize;
+        let index = ((address >> 2) & self.mask) as usize;
         let value = self.ram[index];
         Ok((match address & 0b11 {
-            0b00 => (value & 0xFF00_0000) >> 24,
-            0b01 => (value & 0x00FF_0000) >> 16,
-            0b10 => (value & 0x0000_FF00) >> 8,
-            _ => value & 0x0000_00FF,
+            0b00 => value & 0x0000_00FF,
+            0b01 => (value & 0x0000_FF00) >> 8,
+            0b10 => (value & 0x00FF_0000) >> 16,
+            _ => (value & 0xFF00_0000) >> 24,
         }) as u8)
     }
 
     fn read_half_word(&self, address: u32) -> MMIOResult<u16> {
-        let index = ((address >> 2) & RAM_MASK) as usize;
+        let index = ((address >> 2) & self.mask) as usize;
         let value = self.ram[index];
         Ok((match address & 0b10 {
-            0b0 => (value & 0xFFFF_0000) >> 16,
-            _ => value & 0x0000_FFFF,
+            0b0 => value & 0x0000_FFFF,
+            _ => (value & 0xFFFF_0000) >> 16,
         }) as u16)
     }
 
     fn read_word(&self, address: u32) -> MMIOResult<u32> {
-        let index = ((address >> 2) & RAM_MASK) as usize;
+        let index = ((address >> 2) & self.mask) as usize;
         Ok(self.ram[index])
     }
 
     fn write_byte(&mut self, address: u32, value: u8) -> MMIOResult<()> {
-        let index = ((address >> 2) & RAM_MASK) as usize;
+        let index = ((address >> 2) & self.mask) as usize;
         let current_value = self.ram[index];
-        self.ram[index] = match address & 0b11 {
-            0b00 => (current_value & 0x00FF_FFFF) | ((value as u32) << 24),
-            0b01 => (current_value & 0xFF00_FFFF) | ((value as u32) << 16),
-            0b10 => (current_value & 0xFFFF_00FF) | ((value as u32) << 8),
-            _ => (current_value & 0xFFFF_FF00) | (value as u32),
+        let value = match address & 0b11 {
+            0b00 => (current_value & 0xFFFF_FF00) | (value as u32),
+            0b01 => (current_value & 0xFFFF_00FF) | ((value as u32) << 8),
+            0b10 => (current_value & 0xFF00_FFFF) | ((value as u32) << 16),
+            _ => (current_value & 0x00FF_FFFF) | ((value as u32) << 24),
         };
+        self.store(index, value);
         Ok(())
     }
 
     fn write_half_word(&mut self, address: u32, value: u16) -> MMIOResult<()> {
-        let index = ((address >> 2) & RAM_MASK) as usize;
+        let index = ((address >> 2) & self.mask) as usize;
         let current_value = self.ram[index];
-        self.ram[index] = match address & 0b10 {
-            0b0 => (current_value & 0x0000_FFFF) | ((value as u32) << 16),
-            _ => (current_value & 0xFFFF_0000) | (value as u32),
+        let value = match address & 0b10 {
+            0b0 => (current_value & 0xFFFF_0000) | (value as u32),
+            _ => (current_value & 0x0000_FFFF) | ((value as u32) << 16),
         };
+        self.store(index, value);
         Ok(())
     }
 
     fn write_word(&mut self, address: u32, value: u32) -> MMIOResult<()> {
-        let index = ((address >> 2) & RAM_MASK) as usize;
-        self.ram[index] = value;
+        let index = ((address >> 2) & self.mask) as usize;
+        self.store(index, value);
         Ok(())
     }
 }
@@ -88,19 +149,19 @@ mod tests {
         assert_eq!(ram.read_word(0x0000_0000), Ok(0xDEAD_BEEF));
         assert_eq!(ram.read_word(0x0000_0004), Ok(0xC0DE_CAFE));
         assert_eq!(ram.read_word(0x0000_0008), Ok(0xFFFF_FFFF));
-        assert_eq!(ram.read_half_word(0x0000_0000), Ok(0xDEAD));
-        assert_eq!(ram.read_half_word(0x0000_0002), Ok(0xBEEF));
-        assert_eq!(ram.read_half_word(0x0000_0004), Ok(0xC0DE));
-        assert_eq!(ram.read_half_word(0x0000_0006), Ok(0xCAFE));
+        assert_eq!(ram.read_half_word(0x0000_0000), Ok(0xBEEF));
+        assert_eq!(ram.read_half_word(0x0000_0002), Ok(0xDEAD));
+        assert_eq!(ram.read_half_word(0x0000_0004), Ok(0xCAFE));
+        assert_eq!(ram.read_half_word(0x0000_0006), Ok(0xC0DE));
         assert_eq!(ram.read_half_word(0x0000_0008), Ok(0xFFFF));
-        assert_eq!(ram.read_byte(0x0000_0000), Ok(0xDE));
-        assert_eq!(ram.read_byte(0x0000_0001), Ok(0xAD));
-        assert_eq!(ram.read_byte(0x0000_0002), Ok(0xBE));
-        assert_eq!(ram.read_byte(0x0000_0003), Ok(0xEF));
-        assert_eq!(ram.read_byte(0x0000_0004), Ok(0xC0));
-        assert_eq!(ram.read_byte(0x0000_0005), Ok(0xDE));
-        assert_eq!(ram.read_byte(0x0000_0006), Ok(0xCA));
-        assert_eq!(ram.read_byte(0x0000_0007), Ok(0xFE));
+        assert_eq!(ram.read_byte(0x0000_0000), Ok(0xEF));
+        assert_eq!(ram.read_byte(0x0000_0001), Ok(0xBE));
+        assert_eq!(ram.read_byte(0x0000_0002), Ok(0xAD));
+        assert_eq!(ram.read_byte(0x0000_0003), Ok(0xDE));
+        assert_eq!(ram.read_byte(0x0000_0004), Ok(0xFE));
+        assert_eq!(ram.read_byte(0x0000_0005), Ok(0xCA));
+        assert_eq!(ram.read_byte(0x0000_0006), Ok(0xDE));
+        assert_eq!(ram.read_byte(0x0000_0007), Ok(0xC0));
         assert_eq!(ram.read_byte(0x0000_0008), Ok(0xFF));
     }
 
@@ -115,14 +176,14 @@ mod tests {
 
         ram.write_half_word(0x0000_0000, 0xABAD).unwrap();
         ram.write_half_word(0x0000_0006, 0x1DEA).unwrap();
-        assert_eq!(ram.read_word(0x0000_0000), Ok(0xABAD_BEEF));
-        assert_eq!(ram.read_word(0x0000_0004), Ok(0xC0DE_1DEA));
+        assert_eq!(ram.read_word(0x0000_0000), Ok(0xDEAD_ABAD));
+        assert_eq!(ram.read_word(0x0000_0004), Ok(0x1DEA_CAFE));
 
         ram.write_byte(0x0000_0000, 0xAA).unwrap();
         ram.write_byte(0x0000_0003, 0xBB).unwrap();
         ram.write_byte(0x0000_0007, 0xCC).unwrap();
-        assert_eq!(ram.read_word(0x0000_0000), Ok(0xAAAD_BEBB));
-        assert_eq!(ram.read_word(0x0000_0004), Ok(0xC0DE_1DCC));
+        assert_eq!(ram.read_word(0x0000_0000), Ok(0xBBAD_ABAA));
+        assert_eq!(ram.read_word(0x0000_0004), Ok(0xCCEA_CAFE));
     }
 
     #[test]
@@ -136,14 +197,14 @@ mod tests {
 
         ram.write_half_word(0x1000_0000, 0xABAD).unwrap();
         ram.write_half_word(0x1000_0006, 0x1DEA).unwrap();
-        assert_eq!(ram.read_word(0x0000_0000), Ok(0xABAD_BEEF));
-        assert_eq!(ram.read_word(0x0000_0004), Ok(0xC0DE_1DEA));
+        assert_eq!(ram.read_word(0x0000_0000), Ok(0xDEAD_ABAD));
+        assert_eq!(ram.read_word(0x0000_0004), Ok(0x1DEA_CAFE));
 
         ram.write_byte(0x1000_0000, 0xAA).unwrap();
         ram.write_byte(0x1000_0003, 0xBB).unwrap();
         ram.write_byte(0x1000_0007, 0xCC).unwrap();
-        assert_eq!(ram.read_word(0x0000_0000), Ok(0xAAAD_BEBB));
-        assert_eq!(ram.read_word(0x0000_0004), Ok(0xC0DE_1DCC));
+        assert_eq!(ram.read_word(0x0000_0000), Ok(0xBBAD_ABAA));
+        assert_eq!(ram.read_word(0x0000_0004), Ok(0xCCEA_CAFE));
     }
 
     #[test]
@@ -157,33 +218,69 @@ mod tests {
         assert_eq!(ram.read_word(0x4000_0000), Ok(0xDEAD_BEEF));
         assert_eq!(ram.read_word(0x4000_0004), Ok(0xC0DE_CAFE));
         assert_eq!(ram.read_word(0x4000_0008), Ok(0xFFFF_FFFF));
-        assert_eq!(ram.read_half_word(0x1000_0000), Ok(0xDEAD));
-        assert_eq!(ram.read_half_word(0x1000_0002), Ok(0xBEEF));
-        assert_eq!(ram.read_half_word(0x1000_0004), Ok(0xC0DE));
-        assert_eq!(ram.read_half_word(0x1000_0006), Ok(0xCAFE));
+        assert_eq!(ram.read_half_word(0x1000_0000), Ok(0xBEEF));
+        assert_eq!(ram.read_half_word(0x1000_0002), Ok(0xDEAD));
+        assert_eq!(ram.read_half_word(0x1000_0004), Ok(0xCAFE));
+        assert_eq!(ram.read_half_word(0x1000_0006), Ok(0xC0DE));
         assert_eq!(ram.read_half_word(0x1000_0008), Ok(0xFFFF));
-        assert_eq!(ram.read_half_word(0x4000_0000), Ok(0xDEAD));
-        assert_eq!(ram.read_half_word(0x4000_0002), Ok(0xBEEF));
-        assert_eq!(ram.read_half_word(0x4000_0004), Ok(0xC0DE));
-        assert_eq!(ram.read_half_word(0x4000_0006), Ok(0xCAFE));
+        assert_eq!(ram.read_half_word(0x4000_0000), Ok(0xBEEF));
+        assert_eq!(ram.read_half_word(0x4000_0002), Ok(0xDEAD));
+        assert_eq!(ram.read_half_word(0x4000_0004), Ok(0xCAFE));
+        assert_eq!(ram.read_half_word(0x4000_0006), Ok(0xC0DE));
         assert_eq!(ram.read_half_word(0x4000_0008), Ok(0xFFFF));
-        assert_eq!(ram.read_byte(0x1000_0000), Ok(0xDE));
-        assert_eq!(ram.read_byte(0x1000_0001), Ok(0xAD));
-        assert_eq!(ram.read_byte(0x1000_0002), Ok(0xBE));
-        assert_eq!(ram.read_byte(0x1000_0003), Ok(0xEF));
-        assert_eq!(ram.read_byte(0x1000_0004), Ok(0xC0));
-        assert_eq!(ram.read_byte(0x1000_0005), Ok(0xDE));
-        assert_eq!(ram.read_byte(0x1000_0006), Ok(0xCA));
-        assert_eq!(ram.read_byte(0x1000_0007), Ok(0xFE));
+        assert_eq!(ram.read_byte(0x1000_0000), Ok(0xEF));
+        assert_eq!(ram.read_byte(0x1000_0001), Ok(0xBE));
+        assert_eq!(ram.read_byte(0x1000_0002), Ok(0xAD));
+        assert_eq!(ram.read_byte(0x1000_0003), Ok(0xDE));
+        assert_eq!(ram.read_byte(0x1000_0004), Ok(0xFE));
+        assert_eq!(ram.read_byte(0x1000_0005), Ok(0xCA));
+        assert_eq!(ram.read_byte(0x1000_0006), Ok(0xDE));
+        assert_eq!(ram.read_byte(0x1000_0007), Ok(0xC0));
         assert_eq!(ram.read_byte(0x1000_0008), Ok(0xFF));
-        assert_eq!(ram.read_byte(0x4000_0000), Ok(0xDE));
-        assert_eq!(ram.read_byte(0x4000_0001), Ok(0xAD));
-        assert_eq!(ram.read_byte(0x4000_0002), Ok(0xBE));
-        assert_eq!(ram.read_byte(0x4000_0003), Ok(0xEF));
-        assert_eq!(ram.read_byte(0x4000_0004), Ok(0xC0));
-        assert_eq!(ram.read_byte(0x4000_0005), Ok(0xDE));
-        assert_eq!(ram.read_byte(0x4000_0006), Ok(0xCA));
-        assert_eq!(ram.read_byte(0x4000_0007), Ok(0xFE));
+        assert_eq!(ram.read_byte(0x4000_0000), Ok(0xEF));
+        assert_eq!(ram.read_byte(0x4000_0001), Ok(0xBE));
+        assert_eq!(ram.read_byte(0x4000_0002), Ok(0xAD));
+        assert_eq!(ram.read_byte(0x4000_0003), Ok(0xDE));
+        assert_eq!(ram.read_byte(0x4000_0004), Ok(0xFE));
+        assert_eq!(ram.read_byte(0x4000_0005), Ok(0xCA));
+        assert_eq!(ram.read_byte(0x4000_0006), Ok(0xDE));
+        assert_eq!(ram.read_byte(0x4000_0007), Ok(0xC0));
         assert_eq!(ram.read_byte(0x4000_0008), Ok(0xFF));
     }
+
+    #[test]
+    fn test_with_size_wrap_around() {
+        let mut ram = RamDevice::with_size(64 * 1024);
+        assert_eq!(ram.size(), 0x1_0000);
+        ram.write_word(0x0000_FFFC, 0xDEAD_BEEF).unwrap();
+        ram.write_word(0x0001_0004, 0xC0DE_CAFE).unwrap();
+        assert_eq!(ram.read_word(0x0001_FFFC), Ok(0xDEAD_BEEF));
+        assert_eq!(ram.read_word(0x0000_0004), Ok(0xC0DE_CAFE));
+        assert_eq!(ram.read_byte(0x0001_0007), Ok(0xC0));
+    }
+
+    #[test]
+    fn test_undo_writes() {
+        let mut ram = RamDevice::new();
+        ram.write_word(0x0000_0000, 0xDEAD_BEEF).unwrap();
+        ram.log_writes();
+        ram.write_byte(0x0000_0000, 0xAA).unwrap();
+        ram.write_half_word(0x0000_0002, 0x1234).unwrap();
+        let first = ram.take_writes();
+        ram.write_word(0x0000_0004, 0xC0DE_CAFE).unwrap();
+        let second = ram.take_writes();
+        assert_eq!(first.len(), 2);
+        assert_eq!(second, [(1, 0xFFFF_FFFF)]);
+
+        ram.undo_writes(&second);
+        ram.undo_writes(&first);
+        assert_eq!(ram.read_word(0x0000_0000), Ok(0xDEAD_BEEF));
+        assert_eq!(ram.read_word(0x0000_0004), Ok(0xFFFF_FFFF));
+    }
+
+    #[test]
+    #[should_panic(expected = "RAM size must be a power of two")]
+    fn test_with_size_not_power_of_two() {
+        RamDevice::with_size(3 * 1024);
+    }
 }
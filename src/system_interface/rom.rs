@@ -4,24 +4,67 @@ const ROM_SIZE: u32 = 1024 * 1024;
 const ROM_SIZE_BYTES: usize = (ROM_SIZE / 4) as usize;
 const ROM_MASK: u32 = (ROM_SIZE / 4) - 1;
 
+/// Value of every ROM word that hasn't been loaded, like erased flash
+pub const ROM_ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+/// Program ROM. Before a program is loaded every word reads as `ROM_ERASED_WORD`
 pub struct RomDevice {
     rom: Vec<u32>,
+    loaded_words: usize,
 }
 
 impl RomDevice {
     pub fn new() -> Self {
-        let rom = vec![0xFFFF_FFFF; ROM_SIZE_BYTES];
-        Self { rom }
+        let rom = vec![ROM_ERASED_WORD; ROM_SIZE_BYTES];
+        Self {
+            rom,
+            loaded_words: 0,
+        }
     }
 
+    /// Replaces the ROM contents with `data`, the rest of the ROM is erased. Anything past the
+    /// end of the ROM is dropped.
     pub fn load(&mut self, data: Vec<u32>) {
-        for i in 0..ROM_SIZE_BYTES {
-            if i >= data.len() {
-                self.rom[i] = 0xFFFF_FFFF;
-            } else {
-                self.rom[i] = data[i];
-            }
+        let loaded_words = data.len().min(ROM_SIZE_BYTES);
+        self.rom[..loaded_words].copy_from_slice(&data[..loaded_words]);
+        // only the previous program can be left behind, everything past it is still erased
+        if loaded_words < self.loaded_words {
+            self.rom[loaded_words..self.loaded_words].fill(ROM_ERASED_WORD);
         }
+        self.loaded_words = loaded_words;
+    }
+
+    /// Replaces the ROM contents with the raw image `bytes`, as laid out in memory. Words are
+    /// little-endian and a trailing partial word is padded with erased bytes.
+    pub fn load_bytes(&mut self, bytes: &[u8]) {
+        self.load(
+            bytes
+                .chunks(4)
+                .map(|chunk| {
+                    let mut word = ROM_ERASED_WORD.to_le_bytes();
+                    word[..chunk.len()].copy_from_slice(chunk);
+                    u32::from_le_bytes(word)
+                })
+                .collect(),
+        );
+    }
+
+    /// Overwrites the word at `address`, as a flash programmer would. Bus writes are ignored.
+    /// A word past the loaded program extends it, so the next `load` erases it as well.
+    pub fn program_word(&mut self, address: u32, value: u32) {
+        let index = ((address >> 2) & ROM_MASK) as usize;
+        self.rom[index] = value;
+        self.loaded_words = self.loaded_words.max(index + 1);
+    }
+
+    /// Capacity in bytes, the ROM repeats through the rest of its mapping
+    pub fn size(&self) -> u32 {
+        ROM_SIZE
+    }
+
+    /// Size in bytes of the program written by the last `load`, and any words programmed past it
+    pub fn loaded_size(&self) -> u32 {
+        (self.loaded_words * 4) as u32
     }
 }
 
@@ -36,10 +79,10 @@ impl MMIODevice for RomDevice {
         let index = ((address >> 2) & ROM_MASK) as usize;
         let value = self.rom[index];
         Ok((match address & 0b11 {
-            0b00 => (value & 0xFF00_0000) >> 24,
-            0b01 => (value & 0x00FF_0000) >> 16,
-            0b10 => (value & 0x0000_FF00) >> 8,
-            _ => value & 0x0000_00FF,
+            0b00 => value & 0x0000_00FF,
+            0b01 => (value & 0x0000_FF00) >> 8,
+            0b10 => (value & 0x00FF_0000) >> 16,
+            _ => (value & 0xFF00_0000) >> 24,
         }) as u8)
     }
 
@@ -47,8 +90,8 @@ impl MMIODevice for RomDevice {
         let index = ((address >> 2) & ROM_MASK) as usize;
         let value = self.rom[index];
         Ok((match address & 0b10 {
-            0 => (value & 0xFFFF_0000) >> 16,
-            _ => value & 0x0000_FFFF,
+            0 => value & 0x0000_FFFF,
+            _ => (value & 0xFFFF_0000) >> 16,
         }) as u16)
     }
 
@@ -80,22 +123,92 @@ mod tests {
         assert_eq!(rom.read_word(0x0000_0000), Ok(0xDEAD_BEEF));
         assert_eq!(rom.read_word(0x0000_0004), Ok(0xC0DE_CAFE));
         assert_eq!(rom.read_word(0x0000_0008), Ok(0xFFFF_FFFF));
-        assert_eq!(rom.read_half_word(0x0000_0000), Ok(0xDEAD));
-        assert_eq!(rom.read_half_word(0x0000_0002), Ok(0xBEEF));
-        assert_eq!(rom.read_half_word(0x0000_0004), Ok(0xC0DE));
-        assert_eq!(rom.read_half_word(0x0000_0006), Ok(0xCAFE));
+        assert_eq!(rom.read_half_word(0x0000_0000), Ok(0xBEEF));
+        assert_eq!(rom.read_half_word(0x0000_0002), Ok(0xDEAD));
+        assert_eq!(rom.read_half_word(0x0000_0004), Ok(0xCAFE));
+        assert_eq!(rom.read_half_word(0x0000_0006), Ok(0xC0DE));
         assert_eq!(rom.read_half_word(0x0000_0008), Ok(0xFFFF));
-        assert_eq!(rom.read_byte(0x0000_0000), Ok(0xDE));
-        assert_eq!(rom.read_byte(0x0000_0001), Ok(0xAD));
-        assert_eq!(rom.read_byte(0x0000_0002), Ok(0xBE));
-        assert_eq!(rom.read_byte(0x0000_0003), Ok(0xEF));
-        assert_eq!(rom.read_byte(0x0000_0004), Ok(0xC0));
-        assert_eq!(rom.read_byte(0x0000_0005), Ok(0xDE));
-        assert_eq!(rom.read_byte(0x0000_0006), Ok(0xCA));
-        assert_eq!(rom.read_byte(0x0000_0007), Ok(0xFE));
+        assert_eq!(rom.read_byte(0x0000_0000), Ok(0xEF));
+        assert_eq!(rom.read_byte(0x0000_0001), Ok(0xBE));
+        assert_eq!(rom.read_byte(0x0000_0002), Ok(0xAD));
+        assert_eq!(rom.read_byte(0x0000_0003), Ok(0xDE));
+        assert_eq!(rom.read_byte(0x0000_0004), Ok(0xFE));
+        assert_eq!(rom.read_byte(0x0000_0005), Ok(0xCA));
+        assert_eq!(rom.read_byte(0x0000_0006), Ok(0xDE));
+        assert_eq!(rom.read_byte(0x0000_0007), Ok(0xC0));
         assert_eq!(rom.read_byte(0x0000_0008), Ok(0xFF));
     }
 
+    #[test]
+    fn test_load_erases_previous_program() {
+        let mut rom = RomDevice::new();
+        rom.load(vec![1, 2, 3, 4]);
+        rom.load(vec![5, 6]);
+        assert_eq!(rom.loaded_size(), 8);
+        assert_eq!(rom.read_word(0x0000_0000), Ok(5));
+        assert_eq!(rom.read_word(0x0000_0004), Ok(6));
+        assert_eq!(rom.read_word(0x0000_0008), Ok(ROM_ERASED_WORD));
+        assert_eq!(rom.read_word(0x0000_000C), Ok(ROM_ERASED_WORD));
+    }
+
+    #[test]
+    fn test_load_erases_programmed_words() {
+        let mut rom = RomDevice::new();
+        rom.load(vec![1, 2]);
+        rom.program_word(0x0000_0010, 3);
+        assert_eq!(rom.loaded_size(), 20);
+        assert_eq!(rom.read_word(0x0000_0010), Ok(3));
+
+        rom.load(vec![4]);
+        assert_eq!(rom.loaded_size(), 4);
+        assert_eq!(rom.read_word(0x0000_0010), Ok(ROM_ERASED_WORD));
+    }
+
+    #[test]
+    fn test_load_full_capacity() {
+        let mut rom = RomDevice::new();
+        rom.load((0..ROM_SIZE_BYTES as u32).collect());
+        assert_eq!(rom.loaded_size(), ROM_SIZE);
+        assert_eq!(rom.read_word(0x0000_0000), Ok(0));
+        assert_eq!(rom.read_word(ROM_SIZE - 4), Ok(ROM_SIZE_BYTES as u32 - 1));
+
+        // one word too many, the extra word is dropped rather than wrapping round
+        let mut rom = RomDevice::new();
+        rom.load((1..=ROM_SIZE_BYTES as u32 + 1).collect());
+        assert_eq!(rom.loaded_size(), ROM_SIZE);
+        assert_eq!(rom.read_word(0x0000_0000), Ok(1));
+        assert_eq!(rom.read_word(ROM_SIZE - 4), Ok(ROM_SIZE_BYTES as u32));
+    }
+
+    #[test]
+    fn test_load_bytes_byte_order() {
+        let bytes: Vec<u8> = (0x10..0x1A).collect();
+        let mut rom = RomDevice::new();
+        rom.load_bytes(&bytes);
+        assert_eq!(rom.loaded_size(), 12);
+
+        for (address, byte) in bytes.iter().enumerate() {
+            assert_eq!(rom.read_byte(address as u32), Ok(*byte));
+        }
+        for address in (0..8).step_by(2) {
+            let expected = u16::from_le_bytes([bytes[address], bytes[address + 1]]);
+            assert_eq!(rom.read_half_word(address as u32), Ok(expected));
+        }
+        assert_eq!(rom.read_word(0), Ok(0x1312_1110));
+        assert_eq!(rom.read_word(4), Ok(0x1716_1514));
+        assert_eq!(rom.read_half_word(8), Ok(0x1918));
+
+        // the partial last word is padded out as erased
+        assert_eq!(rom.read_word(8), Ok(0xFFFF_1918));
+        assert_eq!(rom.read_byte(10), Ok(0xFF));
+        assert_eq!(rom.read_word(12), Ok(ROM_ERASED_WORD));
+
+        // the same as packing the words up front, like the binary loader does
+        let mut words = RomDevice::new();
+        words.load(vec![0x1312_1110, 0x1716_1514, 0xFFFF_1918]);
+        assert_eq!(words.rom, rom.rom);
+    }
+
     #[test]
     fn test_write_does_nothing() {
         let mut rom = RomDevice::new();
@@ -121,33 +234,33 @@ mod tests {
         assert_eq!(rom.read_word(0x0040_0000), Ok(0xDEAD_BEEF));
         assert_eq!(rom.read_word(0x0040_0004), Ok(0xC0DE_CAFE));
         assert_eq!(rom.read_word(0x0040_0008), Ok(0xFFFF_FFFF));
-        assert_eq!(rom.read_half_word(0x0010_0000), Ok(0xDEAD));
-        assert_eq!(rom.read_half_word(0x0010_0002), Ok(0xBEEF));
-        assert_eq!(rom.read_half_word(0x0010_0004), Ok(0xC0DE));
-        assert_eq!(rom.read_half_word(0x0010_0006), Ok(0xCAFE));
+        assert_eq!(rom.read_half_word(0x0010_0000), Ok(0xBEEF));
+        assert_eq!(rom.read_half_word(0x0010_0002), Ok(0xDEAD));
+        assert_eq!(rom.read_half_word(0x0010_0004), Ok(0xCAFE));
+        assert_eq!(rom.read_half_word(0x0010_0006), Ok(0xC0DE));
         assert_eq!(rom.read_half_word(0x0010_0008), Ok(0xFFFF));
-        assert_eq!(rom.read_half_word(0x0040_0000), Ok(0xDEAD));
-        assert_eq!(rom.read_half_word(0x0040_0002), Ok(0xBEEF));
-        assert_eq!(rom.read_half_word(0x0040_0004), Ok(0xC0DE));
-        assert_eq!(rom.read_half_word(0x0040_0006), Ok(0xCAFE));
+        assert_eq!(rom.read_half_word(0x0040_0000), Ok(0xBEEF));
+        assert_eq!(rom.read_half_word(0x0040_0002), Ok(0xDEAD));
+        assert_eq!(rom.read_half_word(0x0040_0004), Ok(0xCAFE));
+        assert_eq!(rom.read_half_word(0x0040_0006), Ok(0xC0DE));
         assert_eq!(rom.read_half_word(0x0040_0008), Ok(0xFFFF));
-        assert_eq!(rom.read_byte(0x0010_0000), Ok(0xDE));
-        assert_eq!(rom.read_byte(0x0010_0001), Ok(0xAD));
-        assert_eq!(rom.read_byte(0x0010_0002), Ok(0xBE));
-        assert_eq!(rom.read_byte(0x0010_0003), Ok(0xEF));
-        assert_eq!(rom.read_byte(0x0010_0004), Ok(0xC0));
-        assert_eq!(rom.read_byte(0x0010_0005), Ok(0xDE));
-        assert_eq!(rom.read_byte(0x0010_0006), Ok(0xCA));
-        assert_eq!(rom.read_byte(0x0010_0007), Ok(0xFE));
+        assert_eq!(rom.read_byte(0x0010_0000), Ok(0xEF));
+        assert_eq!(rom.read_byte(0x0010_0001), Ok(0xBE));
+        assert_eq!(rom.read_byte(0x0010_0002), Ok(0xAD));
+        assert_eq!(rom.read_byte(0x0010_0003), Ok(0xDE));
+        assert_eq!(rom.read_byte(0x0010_0004), Ok(0xFE));
+        assert_eq!(rom.read_byte(0x0010_0005), Ok(0xCA));
+        assert_eq!(rom.read_byte(0x0010_0006), Ok(0xDE));
+        assert_eq!(rom.read_byte(0x0010_0007), Ok(0xC0));
         assert_eq!(rom.read_byte(0x0010_0008), Ok(0xFF));
-        assert_eq!(rom.read_byte(0x0040_0000), Ok(0xDE));
-        assert_eq!(rom.read_byte(0x0040_0001), Ok(0xAD));
-        assert_eq!(rom.read_byte(0x0040_0002), Ok(0xBE));
-        assert_eq!(rom.read_byte(0x0040_0003), Ok(0xEF));
-        assert_eq!(rom.read_byte(0x0040_0004), Ok(0xC0));
-        assert_eq!(rom.read_byte(0x0040_0005), Ok(0xDE));
-        assert_eq!(rom.read_byte(0x0040_0006), Ok(0xCA));
-        assert_eq!(rom.read_byte(0x0040_0007), Ok(0xFE));
+        assert_eq!(rom.read_byte(0x0040_0000), Ok(0xEF));
+        assert_eq!(rom.read_byte(0x0040_0001), Ok(0xBE));
+        assert_eq!(rom.read_byte(0x0040_0002), Ok(0xAD));
+        assert_eq!(rom.read_byte(0x0040_0003), Ok(0xDE));
+        assert_eq!(rom.read_byte(0x0040_0004), Ok(0xFE));
+        assert_eq!(rom.read_byte(0x0040_0005), Ok(0xCA));
+        assert_eq!(rom.read_byte(0x0040_0006), Ok(0xDE));
+        assert_eq!(rom.read_byte(0x0040_0007), Ok(0xC0));
         assert_eq!(rom.read_byte(0x0040_0008), Ok(0xFF));
     }
 }
@@ -0,0 +1,167 @@
+use super::{MMIODevice, MMIOResult};
+
+/// Offset of the low word of `mtimecmp`, the high word follows it
+pub const MTIMECMP_OFFSET: u32 = 0x4000;
+/// Offset of the low word of `mtime`, the high word follows it
+pub const MTIME_OFFSET: u32 = 0xBFF8;
+const MTIMECMPH_OFFSET: u32 = MTIMECMP_OFFSET + 4;
+const MTIMEH_OFFSET: u32 = MTIME_OFFSET + 4;
+
+/// The machine timer of a CLINT: a free-running 64-bit `mtime` counting cycles and the `mtimecmp`
+/// it is compared against. Both are read and written a 32-bit word at a time, anything else in
+/// the device reads as 0 and ignores writes.
+pub struct TimerDevice {
+    mtime: u64,
+    mtimecmp: u64,
+    /// Whether the timer interrupt was pending as of the last `tick`
+    pending: bool,
+}
+
+impl TimerDevice {
+    pub fn new() -> Self {
+        Self {
+            mtime: 0,
+            // nothing fires until software programs a deadline
+            mtimecmp: u64::MAX,
+            pending: false,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn mtimecmp(&self) -> u64 {
+        self.mtimecmp
+    }
+
+    /// Sets both registers at once, with the interrupt state following from them
+    pub(crate) fn restore(&mut self, mtime: u64, mtimecmp: u64) {
+        self.mtime = mtime;
+        self.mtimecmp = mtimecmp;
+        self.pending = self.is_pending();
+    }
+
+    /// Whether the timer interrupt is pending, i.e. `mtime >= mtimecmp`
+    pub fn is_pending(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    /// Advances `mtime` by one, returning the new state of the timer interrupt if it changed since
+    /// the last tick, including from writes to either register
+    pub(crate) fn tick(&mut self) -> Option<bool> {
+        self.mtime = self.mtime.wrapping_add(1);
+        let pending = self.is_pending();
+        if pending == self.pending {
+            return None;
+        }
+        self.pending = pending;
+        Some(pending)
+    }
+
+    /// The register word at the word aligned `offset`
+    fn register(&mut self, offset: u32) -> Option<(&mut u64, u32)> {
+        match offset {
+            MTIMECMP_OFFSET => Some((&mut self.mtimecmp, 0)),
+            MTIMECMPH_OFFSET => Some((&mut self.mtimecmp, 32)),
+            MTIME_OFFSET => Some((&mut self.mtime, 0)),
+            MTIMEH_OFFSET => Some((&mut self.mtime, 32)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for TimerDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MMIODevice for TimerDevice {
+    fn read_byte(&self, address: u32) -> MMIOResult<u8> {
+        Ok((self.read_word(address & !0b11)? >> (8 * (address & 0b11))) as u8)
+    }
+
+    fn read_half_word(&self, address: u32) -> MMIOResult<u16> {
+        Ok((self.read_word(address & !0b11)? >> (8 * (address & 0b10))) as u16)
+    }
+
+    fn read_word(&self, address: u32) -> MMIOResult<u32> {
+        Ok(match address {
+            MTIMECMP_OFFSET => self.mtimecmp as u32,
+            MTIMECMPH_OFFSET => (self.mtimecmp >> 32) as u32,
+            MTIME_OFFSET => self.mtime as u32,
+            MTIMEH_OFFSET => (self.mtime >> 32) as u32,
+            _ => 0,
+        })
+    }
+
+    fn write_byte(&mut self, address: u32, value: u8) -> MMIOResult<()> {
+        if let Some((register, shift)) = self.register(address & !0b11) {
+            let shift = shift + 8 * (address & 0b11);
+            *register = (*register & !(0xFF << shift)) | ((value as u64) << shift);
+        }
+        Ok(())
+    }
+
+    fn write_half_word(&mut self, address: u32, value: u16) -> MMIOResult<()> {
+        if let Some((register, shift)) = self.register(address & !0b11) {
+            let shift = shift + 8 * (address & 0b10);
+            *register = (*register & !(0xFFFF << shift)) | ((value as u64) << shift);
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: u32, value: u32) -> MMIOResult<()> {
+        if let Some((register, shift)) = self.register(address) {
+            *register = (*register & !(0xFFFF_FFFF << shift)) | ((value as u64) << shift);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let mut timer = TimerDevice::new();
+        assert_eq!(timer.read_word(MTIMECMP_OFFSET), Ok(0xFFFF_FFFF));
+        assert_eq!(timer.read_word(MTIMECMP_OFFSET + 4), Ok(0xFFFF_FFFF));
+
+        timer.write_word(MTIME_OFFSET, 0xFFFF_FFFE).unwrap();
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.mtime(), 0x1_0000_0000);
+        assert_eq!(timer.read_word(MTIME_OFFSET), Ok(0));
+        assert_eq!(timer.read_word(MTIME_OFFSET + 4), Ok(1));
+
+        timer.write_word(MTIMECMP_OFFSET + 4, 0).unwrap();
+        timer.write_half_word(MTIMECMP_OFFSET + 2, 0x1234).unwrap();
+        timer.write_byte(MTIMECMP_OFFSET, 0x56).unwrap();
+        assert_eq!(timer.mtimecmp(), 0x1234_FF56);
+        assert_eq!(timer.read_half_word(MTIMECMP_OFFSET + 2), Ok(0x1234));
+        assert_eq!(timer.read_byte(MTIMECMP_OFFSET), Ok(0x56));
+
+        // the rest of the device is empty
+        timer.write_word(0, 0xDEAD_BEEF).unwrap();
+        assert_eq!(timer.read_word(0), Ok(0));
+    }
+
+    #[test]
+    fn test_tick_reports_changes() {
+        let mut timer = TimerDevice::new();
+        timer.write_word(MTIMECMP_OFFSET + 4, 0).unwrap();
+        timer.write_word(MTIMECMP_OFFSET, 2).unwrap();
+        assert_eq!(timer.tick(), None);
+        assert_eq!(timer.tick(), Some(true));
+        assert_eq!(timer.tick(), None);
+        assert!(timer.is_pending());
+
+        // a new deadline clears it
+        timer.write_word(MTIMECMP_OFFSET, 10).unwrap();
+        assert_eq!(timer.tick(), Some(false));
+        assert!(!timer.is_pending());
+    }
+}
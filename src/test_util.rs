@@ -0,0 +1,327 @@
+use std::fmt::Write;
+
+use crate::{CPUState, PipelineState, RV32ISystem, system_interface::MMIODevice, trap::TrapState};
+
+/// Expected machine state for tests, checked in one go with a readable diff of everything that
+/// doesn't match
+///
+/// ```ignore
+/// ExpectedState::new()
+///     .pc(0x1000_0084)
+///     .register(15, 8)
+///     .memory_word(0x2000_0000, 42)
+///     .assert_matches(&rv);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ExpectedState {
+    pc: Option<u32>,
+    registers: Vec<(usize, u32)>,
+    memory_words: Vec<(u32, u32)>,
+}
+
+impl ExpectedState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects `current_line` to be `pc`
+    pub fn pc(mut self, pc: u32) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    /// Expects register `x<index>` to hold `value`
+    pub fn register(mut self, index: usize, value: u32) -> Self {
+        self.registers.push((index, value));
+        self
+    }
+
+    /// Expects the word at `address` on the bus to be `value`
+    pub fn memory_word(mut self, address: u32, value: u32) -> Self {
+        self.memory_words.push((address, value));
+        self
+    }
+
+    /// One line per mismatch, empty if `rv` is in the expected state
+    pub fn diff(&self, rv: &RV32ISystem) -> Vec<String> {
+        let mut diff = vec![];
+        if let Some(pc) = self.pc {
+            let actual = rv.current_line();
+            if actual != pc {
+                diff.push(format!("pc: expected {:#010X}, found {:#010X}", pc, actual));
+            }
+        }
+        for &(index, value) in &self.registers {
+            let actual = rv.reg_file[index];
+            if actual != value {
+                diff.push(format!(
+                    "x{}: expected {:#010X}, found {:#010X}",
+                    index, value, actual
+                ));
+            }
+        }
+        for &(address, value) in &self.memory_words {
+            match rv.bus.read_word(address) {
+                Ok(actual) if actual == value => {}
+                Ok(actual) => diff.push(format!(
+                    "[{:#010X}]: expected {:#010X}, found {:#010X}",
+                    address, value, actual
+                )),
+                Err(e) => diff.push(format!(
+                    "[{:#010X}]: expected {:#010X}, {}",
+                    address, value, e
+                )),
+            }
+        }
+        diff
+    }
+
+    /// Panics listing every mismatch if `rv` isn't in the expected state
+    pub fn assert_matches(&self, rv: &RV32ISystem) {
+        let diff = self.diff(rv);
+        if diff.is_empty() {
+            return;
+        }
+        let mut message = String::from("machine state mismatch:");
+        for line in diff {
+            write!(message, "\n  {}", line).unwrap();
+        }
+        panic!("{}", message);
+    }
+}
+
+/// Everything one instruction did, see `RV32ISystem::execute_one`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct InstructionEffect {
+    /// The register written and its new value, `None` for x0
+    pub register_write: Option<(usize, u32)>,
+    /// The address stored to and the value stored, narrowed to the store's width
+    pub memory_write: Option<(u32, u32)>,
+    /// The cause of the trap the instruction raised
+    pub trap: Option<u32>,
+    /// Where execution carries on from
+    pub pc: u32,
+}
+
+impl RV32ISystem {
+    /// Places `raw` at the next address to be fetched and runs it to retirement, or until its trap
+    /// has been taken
+    pub fn execute_one(&mut self, raw: u32) -> InstructionEffect {
+        let pc = self.next_fetch_address();
+        if self.bus.rom_range().contains(&pc) {
+            self.bus.rom.program_word(pc, raw);
+        } else {
+            self.bus
+                .write_word(pc, raw)
+                .expect("the pc should be writable");
+        }
+        self.stage_if.flush_buffer();
+
+        let mut trap = None;
+        self.last_retired = None;
+        loop {
+            self.cycle();
+            let snapshot = self.trap_state();
+            if snapshot.state == TrapState::SetCSRJump {
+                trap = Some(snapshot.mcause);
+            }
+            if *self.state.get() == CPUState::Pipeline(PipelineState::Fetch) {
+                break;
+            }
+        }
+
+        let retired = self.last_retired.take();
+        InstructionEffect {
+            register_write: retired
+                .and_then(|retired| retired.register_write)
+                .map(|(rd, value)| (rd.value() as usize, value)),
+            memory_write: retired.and_then(|retired| retired.memory_write),
+            trap,
+            pc: self.next_fetch_address(),
+        }
+    }
+}
+
+/// Builds a minimal RV32 ELF executable with one `PT_LOAD` segment per `(address, contents)`
+/// pair in `segments`, and a symbol table holding `symbols`
+pub fn build_elf(entry: u32, segments: &[(u32, &[u8])], symbols: &[(&str, u32)]) -> Vec<u8> {
+    const EHDR_SIZE: usize = 52;
+    const PHDR_SIZE: usize = 32;
+    const SHDR_SIZE: usize = 40;
+
+    let push_u16 = |bytes: &mut Vec<u8>, value: u16| bytes.extend_from_slice(&value.to_le_bytes());
+    let push_u32 = |bytes: &mut Vec<u8>, value: u32| bytes.extend_from_slice(&value.to_le_bytes());
+
+    // segment contents follow the program headers, then the symbol and string tables
+    let mut data_offset = EHDR_SIZE + segments.len() * PHDR_SIZE;
+    let mut segment_offsets = vec![];
+    for (_, contents) in segments {
+        segment_offsets.push(data_offset);
+        data_offset += contents.len();
+    }
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; 16];
+    for (name, value) in symbols {
+        push_u32(&mut symtab, strtab.len() as u32);
+        push_u32(&mut symtab, *value);
+        push_u32(&mut symtab, 0);
+        symtab.extend_from_slice(&[0x10, 0, 1, 0]);
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    let symtab_offset = data_offset;
+    let strtab_offset = symtab_offset + symtab.len();
+    let shoff = strtab_offset + strtab.len();
+
+    let mut elf = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+    elf.resize(16, 0);
+    push_u16(&mut elf, 2); // ET_EXEC
+    push_u16(&mut elf, 243); // EM_RISCV
+    push_u32(&mut elf, 1);
+    push_u32(&mut elf, entry);
+    push_u32(&mut elf, EHDR_SIZE as u32);
+    push_u32(&mut elf, shoff as u32);
+    push_u32(&mut elf, 0);
+    push_u16(&mut elf, EHDR_SIZE as u16);
+    push_u16(&mut elf, PHDR_SIZE as u16);
+    push_u16(&mut elf, segments.len() as u16);
+    push_u16(&mut elf, SHDR_SIZE as u16);
+    push_u16(&mut elf, 3);
+    push_u16(&mut elf, 0);
+
+    for ((address, contents), offset) in segments.iter().zip(&segment_offsets) {
+        for value in [1, *offset as u32, *address, *address] {
+            push_u32(&mut elf, value);
+        }
+        for value in [contents.len() as u32, contents.len() as u32, 0b111, 4] {
+            push_u32(&mut elf, value);
+        }
+    }
+    for (_, contents) in segments {
+        elf.extend_from_slice(contents);
+    }
+    elf.extend_from_slice(&symtab);
+    elf.extend_from_slice(&strtab);
+
+    // null section, .symtab linked to .strtab, .strtab
+    elf.resize(elf.len() + SHDR_SIZE, 0);
+    for value in [
+        0,
+        2,
+        0,
+        0,
+        symtab_offset as u32,
+        symtab.len() as u32,
+        2,
+        1,
+        4,
+        16,
+    ] {
+        push_u32(&mut elf, value);
+    }
+    for value in [
+        0,
+        3,
+        0,
+        0,
+        strtab_offset as u32,
+        strtab.len() as u32,
+        0,
+        0,
+        1,
+        0,
+    ] {
+        push_u32(&mut elf, value);
+    }
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trap::MCAUSE_LOAD_ADDRESS_MISALIGNED;
+
+    fn system() -> RV32ISystem {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[2] = 0x2040_0000;
+        rv.reg_file[15] = 8;
+        rv.bus.write_word(0x2000_0000, 42).unwrap();
+        rv.bus.rom.load(vec![0x0000_0013]);
+        rv.cycle();
+        rv
+    }
+
+    #[test]
+    fn test_matching_state() {
+        let rv = system();
+        let expected = ExpectedState::new()
+            .pc(0x1000_0000)
+            .register(2, 0x2040_0000)
+            .register(15, 8)
+            .memory_word(0x2000_0000, 42);
+        assert!(expected.diff(&rv).is_empty());
+        expected.assert_matches(&rv);
+    }
+
+    #[test]
+    fn test_mismatch_diff() {
+        let rv = system();
+        let expected = ExpectedState::new()
+            .pc(0x1000_0004)
+            .register(2, 0x2040_0000)
+            .register(15, 7)
+            .memory_word(0x2000_0000, 41);
+        assert_eq!(
+            expected.diff(&rv),
+            [
+                "pc: expected 0x10000004, found 0x10000000",
+                "x15: expected 0x00000007, found 0x00000008",
+                "[0x20000000]: expected 0x00000029, found 0x0000002A",
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "machine state mismatch:\n  x15: expected 0x00000007, found 0x00000008"
+    )]
+    fn test_mismatch_panics() {
+        ExpectedState::new()
+            .register(2, 0x2040_0000)
+            .register(15, 7)
+            .assert_matches(&system());
+    }
+
+    #[test]
+    fn test_execute_one() {
+        let mut rv = RV32ISystem::new();
+        rv.reg_file[1] = 3;
+        // ADDI x2, x1, 5
+        assert_eq!(
+            rv.execute_one(0x0050_8113),
+            InstructionEffect {
+                register_write: Some((2, 8)),
+                memory_write: None,
+                trap: None,
+                pc: 0x1000_0004,
+            }
+        );
+
+        // BEQ x1, x1, 16
+        let effect = rv.execute_one(0x0010_8863);
+        assert_eq!(effect.register_write, None);
+        assert_eq!(effect.pc, 0x1000_0014);
+
+        // SB x2, 1(x3)
+        rv.reg_file[2] = 0x1234_5678;
+        rv.reg_file[3] = 0x2000_0000;
+        let effect = rv.execute_one(0x0021_80A3);
+        assert_eq!(effect.memory_write, Some((0x2000_0001, 0x78)));
+
+        // LW x4, 2(x3), misaligned
+        let effect = rv.execute_one(0x0021_A203);
+        assert_eq!(effect.register_write, None);
+        assert_eq!(effect.trap, Some(MCAUSE_LOAD_ADDRESS_MISALIGNED));
+        assert_eq!(effect.pc, 0x1000_0004);
+    }
+}
@@ -35,6 +35,62 @@ pub const MSTATUS_MIE_MASK: u32 = 1 << MSTATUS_MIE_BIT;
 pub const MSTATUS_MPIE_BIT: u32 = 7;
 pub const MSTATUS_MPIE_MASK: u32 = 1 << MSTATUS_MPIE_BIT;
 
+/// Handlers are laid out as a table of jumps from the `mtvec` base: one slot per interrupt (causes
+/// 0 to 11), followed by one slot per exception
+pub const INTERRUPT_VECTOR_SLOTS: u32 = 12;
+/// Offset from the `mtvec` base to the first exception slot
+pub const EXCEPTION_VECTOR_OFFSET: u32 = INTERRUPT_VECTOR_SLOTS * 4;
+
+/// Address of the handler slot for `mcause` in the vector table at `mtvec`
+pub fn trap_vector(mtvec: u32, mcause: u32) -> u32 {
+    let index = mcause & 0x7FFF_FFFF;
+    let is_interrupt = (mcause & 0x8000_0000) != 0;
+    let offset = if is_interrupt {
+        0
+    } else {
+        EXCEPTION_VECTOR_OFFSET
+    };
+    (mtvec & 0xFFFF_FFFC)
+        .wrapping_add(offset)
+        .wrapping_add(index << 2)
+}
+
+/// `mtvec` mode bits for sending every trap to the base, in the `Spec` layout
+pub const MTVEC_MODE_DIRECT: u32 = 0;
+/// `mtvec` mode bits for vectored interrupts, in the `Spec` layout
+pub const MTVEC_MODE_VECTORED: u32 = 1;
+/// The mode field of `mtvec`
+pub const MTVEC_MODE_MASK: u32 = 0b11;
+
+/// How a trap's cause picks its handler address from `mtvec`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorLayout {
+    /// This core's vector table, see `trap_vector`. The mode bits of `mtvec` are ignored.
+    Table,
+    /// As in the privileged spec, chosen by the mode bits of `mtvec`. Direct mode (0) sends every
+    /// trap to the base, vectored mode (1) sends interrupts to `base + 4 * cause` and exceptions
+    /// to the base. The reserved modes act as direct.
+    #[default]
+    Spec,
+}
+
+impl VectorLayout {
+    /// Handler address for `mcause` with the given `mtvec`
+    pub fn vector(self, mtvec: u32, mcause: u32) -> u32 {
+        match self {
+            VectorLayout::Table => trap_vector(mtvec, mcause),
+            VectorLayout::Spec => {
+                let base = mtvec & !MTVEC_MODE_MASK;
+                let is_interrupt = (mcause & 0x8000_0000) != 0;
+                match (mtvec & MTVEC_MODE_MASK, is_interrupt) {
+                    (MTVEC_MODE_VECTORED, true) => base.wrapping_add((mcause & 0x7FFF_FFFF) << 2),
+                    _ => base,
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub enum TrapState {
     #[default]
@@ -52,6 +108,19 @@ pub struct PipelineTrapParams {
     pub trap: bool,
 }
 
+/// Committed values of every `TrapInterface` latch, see `TrapInterface::snapshot`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrapSnapshot {
+    pub state: TrapState,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub return_to_pipeline_mode: bool,
+    pub set_pc: bool,
+    pub pc_to_set: u32,
+    pub flush: bool,
+}
+
 pub struct TrapParams<'a> {
     pub csr: &'a mut CSRInterface,
     pub begin_trap: bool,
@@ -112,11 +181,8 @@ impl TrapInterface {
                     // unset MIE
                     params.csr.mstatus &= !MSTATUS_MIE_MASK;
 
-                    let index = mcause & 0x7FFF_FFFF;
-                    let is_interrupt = (mcause & 0x8000_0000) != 0;
-                    let offset = if is_interrupt { 0 } else { 48 };
                     self.pc_to_set
-                        .set((params.csr.mtvec & 0xFFFF_FFFC) + offset + (index << 2));
+                        .set(params.csr.vector_layout().vector(params.csr.mtvec, *mcause));
                     self.set_pc.set(true);
                     self.return_to_pipeline_mode.set(true);
                     self.state.set(TrapState::Idle);
@@ -127,7 +193,7 @@ impl TrapInterface {
                     self.state.set(TrapState::Idle);
                 }
                 TrapState::ReturnFromTrap => {
-                    self.pc_to_set.set(params.csr.mepc);
+                    self.pc_to_set.set(params.csr.return_address());
                     self.state.set(TrapState::SetPc);
 
                     // move the current MPIE to MIE
@@ -144,6 +210,32 @@ impl TrapInterface {
         }
     }
 
+    /// The values currently latched, i.e. what the rest of the system sees this cycle
+    pub fn snapshot(&self) -> TrapSnapshot {
+        TrapSnapshot {
+            state: self.state.get().clone(),
+            mepc: *self.mepc.get(),
+            mcause: *self.mcause.get(),
+            mtval: *self.mtval.get(),
+            return_to_pipeline_mode: *self.return_to_pipeline_mode.get(),
+            set_pc: *self.set_pc.get(),
+            pc_to_set: *self.pc_to_set.get(),
+            flush: *self.flush.get(),
+        }
+    }
+
+    /// Resets every latch, abandoning any trap or trap return in progress
+    pub fn clear(&mut self) {
+        self.state.reset();
+        self.mepc.reset();
+        self.mcause.reset();
+        self.mtval.reset();
+        self.return_to_pipeline_mode.reset();
+        self.set_pc.reset();
+        self.pc_to_set.reset();
+        self.flush.reset();
+    }
+
     pub fn latch_next(&mut self) {
         self.state.latch_next();
         self.mepc.latch_next();
@@ -155,3 +247,95 @@ impl TrapInterface {
         self.flush.latch_next();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trap_vector() {
+        let cases = [
+            (MCAUSE_MACHINE_SOFTWARE_INTERRUPT, 0x1000_000C),
+            (MCAUSE_MACHINE_TIMER_INTERRUPT, 0x1000_001C),
+            (MCAUSE_MACHINE_EXTERNAL_INTERRUPT, 0x1000_002C),
+            // the exceptions follow the last interrupt slot
+            (MCAUSE_INSTRUCTION_ADDRESS_MISALIGNED, 0x1000_0030),
+            (MCAUSE_ILLEGAL_INSTRUCTION, 0x1000_0038),
+            (MCAUSE_LOAD_ACCESS_FAULT, 0x1000_0044),
+            (MCAUSE_STORE_AMO_ACCESS_FAULT, 0x1000_004C),
+            (MCAUSE_ENVIRONMENT_CALL_FROM_MMODE, 0x1000_005C),
+        ];
+        for (mcause, expected) in cases {
+            assert_eq!(
+                trap_vector(0x1000_0000, mcause),
+                expected,
+                "{:#010X}",
+                mcause
+            );
+        }
+        // the mode bits of mtvec are ignored
+        assert_eq!(
+            trap_vector(0x1000_0003, MCAUSE_MACHINE_TIMER_INTERRUPT),
+            0x1000_001C
+        );
+    }
+
+    #[test]
+    fn test_spec_vector_layout() {
+        for mtvec in [0x2000_0100, 0x2000_0102] {
+            assert_eq!(
+                VectorLayout::Spec.vector(mtvec, MCAUSE_LOAD_ADDRESS_MISALIGNED),
+                0x2000_0100
+            );
+            assert_eq!(
+                VectorLayout::Spec.vector(mtvec, MCAUSE_MACHINE_TIMER_INTERRUPT),
+                0x2000_0100
+            );
+        }
+        assert_eq!(
+            VectorLayout::Spec.vector(0x2000_0101, MCAUSE_LOAD_ADDRESS_MISALIGNED),
+            0x2000_0100
+        );
+        assert_eq!(
+            VectorLayout::Spec.vector(0x2000_0101, MCAUSE_MACHINE_TIMER_INTERRUPT),
+            0x2000_011C
+        );
+        assert_eq!(
+            VectorLayout::Table.vector(0x2000_0100, MCAUSE_LOAD_ADDRESS_MISALIGNED),
+            trap_vector(0x2000_0100, MCAUSE_LOAD_ADDRESS_MISALIGNED)
+        );
+    }
+
+    #[test]
+    fn test_set_csr_jump() {
+        for (mcause, expected) in [
+            (MCAUSE_LOAD_ACCESS_FAULT, 0x2000_0000),
+            (MCAUSE_MACHINE_TIMER_INTERRUPT, 0x2000_001C),
+        ] {
+            let mut csr = CSRInterface::new();
+            csr.mtvec = 0x2000_0001;
+            let mut trap = TrapInterface::new();
+            trap.mepc.set(0x1000_0008);
+            trap.mcause.set(mcause);
+            trap.mtval.set(0x1234);
+            trap.compute(TrapParams {
+                csr: &mut csr,
+                begin_trap: true,
+                begin_trap_return: false,
+            });
+            trap.latch_next();
+            trap.compute(TrapParams {
+                csr: &mut csr,
+                begin_trap: false,
+                begin_trap_return: false,
+            });
+            trap.latch_next();
+
+            assert_eq!(*trap.pc_to_set.get(), expected);
+            assert!(*trap.set_pc.get());
+            assert_eq!(csr.mepc, 0x1000_0008);
+            assert_eq!(csr.mcause, mcause);
+            assert_eq!(csr.mtval, 0x1234);
+        }
+    }
+}
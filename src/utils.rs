@@ -62,6 +62,25 @@ pub fn bit(index: u32, value: u32, position: u32) -> u32 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_latch_value() {
+        let mut latch = LatchValue::new(1);
+        latch.set(2);
+        // the committed value is only replaced on latch_next
+        assert_eq!(*latch.get(), 1);
+        latch.set(3);
+        assert_eq!(*latch.get(), 1);
+        latch.latch_next();
+        assert_eq!(*latch.get(), 3);
+        // latching again without a set holds the value
+        latch.latch_next();
+        assert_eq!(*latch.get(), 3);
+        latch.reset();
+        assert_eq!(*latch.get(), 1);
+        latch.latch_next();
+        assert_eq!(*latch.get(), 1);
+    }
+
     #[test]
     fn test_sign_extend_32() {
         assert_eq!(sign_extend_32(8, 0xFF), -1);
@@ -0,0 +1,179 @@
+//! Value Change Dump of the pipeline's signals, for viewing the cycle model in a waveform viewer
+//! such as GTKWave
+
+use std::{fmt::Write, io, path::Path};
+
+use crate::{CPUState, RV32ISystem, trap::TrapState};
+
+/// The recorded signals and their widths in bits, in the order they're sampled
+pub const VCD_SIGNALS: [(&str, u32); 12] = [
+    ("if_pc", 32),
+    ("de_pc", 32),
+    ("ex_pc", 32),
+    ("ma_pc", 32),
+    ("wb_pc", 32),
+    ("trap_stall", 1),
+    ("mret", 1),
+    ("in_trap", 1),
+    ("trap_state", 3),
+    ("mstatus", 32),
+    ("mcause", 32),
+    ("mepc", 32),
+];
+
+/// Value changes recorded so far, see `RV32ISystem::enable_vcd`
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct VcdRecorder {
+    last: Option<Vec<u32>>,
+    changes: String,
+}
+
+/// Short identifier VCD uses for the signal at `index` in place of its name
+fn identifier(index: usize) -> char {
+    (b'!' + index as u8) as char
+}
+
+impl VcdRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&mut self, time: u64, values: Vec<u32>) {
+        let mut changes = String::new();
+        for (index, (&value, (_, width))) in values.iter().zip(VCD_SIGNALS).enumerate() {
+            if self.last.as_ref().is_some_and(|last| last[index] == value) {
+                continue;
+            }
+            match width {
+                1 => writeln!(changes, "{}{}", value & 1, identifier(index)),
+                _ => writeln!(changes, "b{:b} {}", value, identifier(index)),
+            }
+            .unwrap();
+        }
+        if !changes.is_empty() {
+            writeln!(self.changes, "#{}", time).unwrap();
+            self.changes.push_str(&changes);
+        }
+        self.last = Some(values);
+    }
+
+    /// The complete dump, header included
+    pub fn output(&self) -> String {
+        let mut output = String::from("$timescale 1ns $end\n$scope module rv32i $end\n");
+        for (index, (name, width)) in VCD_SIGNALS.iter().enumerate() {
+            writeln!(
+                output,
+                "$var wire {} {} {} $end",
+                width,
+                identifier(index),
+                name
+            )
+            .unwrap();
+        }
+        output.push_str("$upscope $end\n$enddefinitions $end\n");
+        output.push_str(&self.changes);
+        output
+    }
+}
+
+impl RV32ISystem {
+    /// Starts recording the pipeline's signals after every cycle, one nanosecond per cycle. While
+    /// enabled `interpret` falls back to `step` so every cycle is seen.
+    pub fn enable_vcd(&mut self) {
+        self.vcd.get_or_insert_with(VcdRecorder::new);
+    }
+
+    /// The signals recorded since `enable_vcd`, `None` if it hasn't been called
+    pub fn vcd(&self) -> Option<&VcdRecorder> {
+        self.vcd.as_ref()
+    }
+
+    /// Writes the recorded signals to a `.vcd` file at `path`
+    pub fn write_vcd(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let vcd = self
+            .vcd
+            .as_ref()
+            .ok_or_else(|| io::Error::other("VCD recording isn't enabled"))?;
+        std::fs::write(path, vcd.output())
+    }
+
+    pub(crate) fn record_vcd(&mut self) {
+        if self.vcd.is_none() {
+            return;
+        }
+        let trap_state = match self.trap.state.get() {
+            TrapState::Idle => 0,
+            TrapState::SetCSRJump => 1,
+            TrapState::SetPc => 2,
+            TrapState::ReturnFromTrap => 3,
+        };
+        let values = vec![
+            self.stage_if.get_instruction_value_out().pc,
+            self.stage_de.get_decoded_instruction_out().pc,
+            self.stage_ex.get_execution_value_out().pc,
+            self.stage_ma.get_memory_access_value_out().pc,
+            self.stage_wb.get_write_back_value_out().pc,
+            self.trap_stall as u32,
+            self.mret as u32,
+            (*self.state.get() == CPUState::Trap) as u32,
+            trap_state,
+            self.csr.mstatus,
+            self.csr.mcause,
+            self.csr.mepc,
+        ];
+        let time = self.cycle_count();
+        if let Some(vcd) = self.vcd.as_mut() {
+            vcd.record(time, values);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vcd() {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(vec![
+            0x0010_0093, // ADDI x1, x0, 1
+            0x0000_006F, // JAL x0, 0
+        ]);
+        assert_eq!(rv.vcd(), None);
+        rv.enable_vcd();
+        for _ in 0..12 {
+            rv.cycle();
+        }
+        let output = rv.vcd().unwrap().output();
+
+        let (header, body) = output.split_once("$enddefinitions $end\n").unwrap();
+        let names: Vec<&str> = header
+            .lines()
+            .filter_map(|line| line.strip_prefix("$var wire "))
+            .map(|line| line.split(' ').nth(2).unwrap())
+            .collect();
+        assert_eq!(names, VCD_SIGNALS.map(|(name, _)| name));
+
+        // every line is a timestamp or a change to a declared signal
+        let mut times = vec![];
+        for line in body.lines() {
+            if let Some(time) = line.strip_prefix('#') {
+                times.push(time.parse::<u64>().unwrap());
+                continue;
+            }
+            let (value, id) = match line.strip_prefix('b') {
+                Some(vector) => vector.split_once(' ').unwrap(),
+                None => line.split_at(1),
+            };
+            assert!(value.chars().all(|c| c == '0' || c == '1'), "{}", line);
+            let index = id.chars().next().unwrap() as usize - '!' as usize;
+            assert!(index < VCD_SIGNALS.len(), "{}", line);
+        }
+        assert_eq!(times[0], 1);
+        assert!(times.len() > 1);
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+        // decode picks up the ADDI in the second cycle
+        assert!(body.contains("#2\n"));
+        assert!(body.contains(&format!("b{:b} \"\n", 0x1000_0000)));
+    }
+}
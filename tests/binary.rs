@@ -1,7 +1,11 @@
 use riscv::{
-    CPUState, PipelineState, RV32ISystem,
+    CPUState, CSRInterfaceBuilder, PipelineState, RAM_START, RV32ISystem,
+    coverage::{Coverage, RV32I_MNEMONICS},
+    elf::Elf,
+    exit::ExitReason,
     system_interface::MMIODevice,
-    trap::{MCAUSE_LOAD_ADDRESS_MISALIGNED, TrapState},
+    test_util::{ExpectedState, build_elf},
+    trap::{MCAUSE_LOAD_ADDRESS_MISALIGNED, TrapState, VectorLayout},
 };
 
 macro_rules! run_instruction {
@@ -150,12 +154,28 @@ fn test_binary_1() {
     // 10000074:    0000006f    jal x0,10000074 <main+0x30>
     run_instruction!(rv);
     assert_eq!(rv.current_line(), 0x1000_0074);
+    assert!(rv.is_halted());
     run_instruction!(rv);
     assert_eq!(rv.current_line(), 0x1000_0074);
     run_instruction!(rv);
     assert_eq!(rv.current_line(), 0x1000_0074);
 }
 
+#[test]
+fn test_binary_1_elf() {
+    let root_dir = std::env::current_dir().expect("Failed to get current directory");
+    let image = std::fs::read(root_dir.join("tests/binaries/binary1.bin"))
+        .expect("Failed to read binary file");
+    let bytes = build_elf(0x1000_0000, &[(0x1000_0000, &image)], &[]);
+
+    let mut rv = RV32ISystem::new();
+    rv.load_elf(&Elf::parse(&bytes).unwrap()).unwrap();
+    assert_eq!(rv.run_until_halt(1000), ExitReason::SelfLoop);
+    assert_eq!(rv.current_line(), 0x1000_0074);
+    assert_eq!(rv.bus.read_word(0x2000_0000), Ok(0x3004_0F00));
+    assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0x0000_002C));
+}
+
 #[test]
 fn test_binary_2() {
     let instructions = load_binary("binary2.bin");
@@ -164,12 +184,16 @@ fn test_binary_2() {
     rv.bus.rom.load(instructions);
 
     run_to_line!(rv, 0x1000_0034);
-    assert_eq!(rv.reg_file[14], 5);
-    assert_eq!(rv.reg_file[15], 8);
+    ExpectedState::new()
+        .register(14, 5)
+        .register(15, 8)
+        .assert_matches(&rv);
 
     run_to_line!(rv, 0x1000_0084);
-    assert_eq!(rv.bus.read_word(0x2000_0000), Ok(0x0000_002A) /* 42 */);
-    assert_eq!(rv.bus.read_word(0x2000_0004), Ok(0x0000_0001));
+    ExpectedState::new()
+        .memory_word(0x2000_0000, 0x0000_002A /* 42 */)
+        .memory_word(0x2000_0004, 0x0000_0001)
+        .assert_matches(&rv);
 }
 
 #[test]
@@ -258,6 +282,10 @@ fn test_binary_6() {
     let instructions = load_binary("binary6.bin");
 
     let mut rv = RV32ISystem::new();
+    // the trap handlers are laid out as this core's vector table
+    rv.csr = CSRInterfaceBuilder::new()
+        .vector_layout(VectorLayout::Table)
+        .build();
     rv.bus.rom.load(instructions);
 
     // 10000084:    01010413    addi x8,x2,16
@@ -280,9 +308,10 @@ fn test_binary_6() {
     );
     rv.cycle();
     assert_eq!(*rv.state.get(), CPUState::Trap);
-    assert_eq!(*rv.trap.mepc.get(), 0x1000_008C);
+    assert_eq!(*rv.trap.mepc.get(), 0x1000_0088);
     assert_eq!(*rv.trap.mcause.get(), MCAUSE_LOAD_ADDRESS_MISALIGNED);
-    assert_eq!(*rv.trap.mtval.get(), 0x0011_2703);
+    // the misaligned address, x2 + 1
+    assert_eq!(*rv.trap.mtval.get(), 0x203F_FFED);
     assert_eq!(*rv.trap.state.get(), TrapState::SetCSRJump);
     rv.cycle();
     assert_eq!(*rv.state.get(), CPUState::Trap);
@@ -306,6 +335,11 @@ fn test_binary_6() {
     assert_eq!(rv.reg_file[14], 0x2000_0000);
     assert_eq!(rv.reg_file[15], 0x2000_0000);
 
+    // mepc is the faulting load, this handler was built back when it was the next instruction and
+    // returns without stepping past it
+    assert_eq!(rv.csr.mepc, 0x1000_0088);
+    rv.csr.mepc += 4;
+
     rv.cycle();
     assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Decode));
     rv.cycle();
@@ -329,6 +363,10 @@ fn test_binary_7() {
     let instructions = load_binary("binary7.bin");
 
     let mut rv = RV32ISystem::new();
+    // the trap handlers are laid out as this core's vector table
+    rv.csr = CSRInterfaceBuilder::new()
+        .vector_layout(VectorLayout::Table)
+        .build();
     rv.bus.rom.load(instructions);
 
     run_to_line!(rv, 0x1000_0098);
@@ -358,6 +396,11 @@ fn test_binary_7() {
     assert_eq!(rv.current_line(), 0x1000_0174);
     run_to_line!(rv, 0x1000_019C);
 
+    // mepc is the ECALL itself, this handler was built back when it was the next instruction and
+    // returns without stepping past it
+    assert_eq!(rv.csr.mepc, 0x1000_009C);
+    rv.csr.mepc += 4;
+
     // 100001a0:    30200073    mret
     rv.cycle();
     assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Decode));
@@ -391,3 +434,196 @@ fn test_binary_7() {
     assert_eq!(*rv.state.get(), CPUState::Pipeline(PipelineState::Fetch));
     assert_eq!(rv.current_line(), 0x1000_0040);
 }
+
+#[test]
+fn test_counter_csrs() {
+    let mut rv = RV32ISystem::new();
+    rv.bus.rom.load(vec![
+        0x0000_0013, // addi x0,x0,0
+        0x0000_0013, // addi x0,x0,0
+        0xC000_20F3, // csrr x1,cycle
+        0xC020_2173, // csrr x2,instret
+        0xC010_21F3, // csrr x3,time
+        0xC800_2273, // csrr x4,cycleh
+        0xC820_22F3, // csrr x5,instreth
+        0xC020_2373, // csrr x6,instret
+    ]);
+
+    // each instruction takes 5 cycles, and the CSR is read in the memory access stage (the 4th)
+    run_instruction!(rv);
+    run_instruction!(rv);
+    run_instruction!(rv);
+    assert_eq!(rv.reg_file[1], 2 * 5 + 3);
+    run_instruction!(rv);
+    assert_eq!(rv.reg_file[2], 3);
+    run_instruction!(rv);
+    assert_eq!(rv.reg_file[3], 4 * 5 + 3);
+    run_instruction!(rv);
+    assert_eq!(rv.reg_file[4], 0);
+    run_instruction!(rv);
+    assert_eq!(rv.reg_file[5], 0);
+    run_instruction!(rv);
+    assert_eq!(rv.reg_file[6], 7);
+
+    assert_eq!(*rv.csr.cycles.get(), 8 * 5);
+    assert_eq!(*rv.csr.instret.get(), 8);
+}
+
+#[test]
+fn test_binary_1_call_stack() {
+    let instructions = load_binary("binary1.bin");
+
+    let mut rv = RV32ISystem::new();
+    rv.enable_call_tracking();
+    rv.bus.rom.load(instructions);
+
+    // 10000008:    03c0006f    jal x0,10000044 <main>
+    run_to_line!(rv, 0x1000_0044);
+    assert_eq!(rv.call_stack(), []);
+
+    // 10000054:    fb9ff0ef    jal x1,1000000c <fortyTwoWithSideEffects>
+    run_to_line!(rv, 0x1000_000C);
+    assert_eq!(rv.call_stack(), [(0x1000_0054, 0x1000_000C)]);
+
+    // 10000040:    00008067    jalr x0,0(x1)
+    run_to_line!(rv, 0x1000_0058);
+    assert_eq!(rv.call_stack(), []);
+}
+
+#[test]
+fn test_architectural_state_matches_across_modes() {
+    for binary in ["binary1.bin", "binary3.bin", "binary5.bin"] {
+        let instructions = load_binary(binary);
+
+        let mut staged = RV32ISystem::new();
+        staged.bus.rom.load(instructions.clone());
+        let mut interpreted = RV32ISystem::new();
+        interpreted.bus.rom.load(instructions);
+        assert_eq!(
+            interpreted.architectural_state(),
+            staged.architectural_state()
+        );
+
+        for _ in 0..10 {
+            for _ in 0..10 {
+                staged.step();
+            }
+            interpreted.interpret(10);
+            assert_eq!(
+                interpreted.csr.instret.get(),
+                staged.csr.instret.get(),
+                "{}",
+                binary
+            );
+            assert_eq!(
+                interpreted.architectural_state(),
+                staged.architectural_state(),
+                "{}",
+                binary
+            );
+        }
+    }
+}
+
+#[test]
+fn test_interpret_matches_step() {
+    for binary in [
+        "binary1.bin",
+        "binary2.bin",
+        "binary3.bin",
+        "binary4.bin",
+        "binary5.bin",
+    ] {
+        let instructions = load_binary(binary);
+
+        let mut staged = RV32ISystem::new();
+        staged.bus.rom.load(instructions.clone());
+        for _ in 0..200 {
+            staged.step();
+        }
+
+        let mut interpreted = RV32ISystem::new();
+        interpreted.bus.rom.load(instructions);
+        interpreted.interpret(200);
+
+        assert_eq!(interpreted.reg_file, staged.reg_file, "{}", binary);
+        assert_eq!(
+            interpreted.dump_pc_bin(),
+            staged.dump_pc_bin(),
+            "{}",
+            binary
+        );
+        assert_eq!(
+            interpreted.csr.instret.get(),
+            staged.csr.instret.get(),
+            "{}",
+            binary
+        );
+        assert_eq!(
+            interpreted.cycle_count(),
+            staged.cycle_count(),
+            "{}",
+            binary
+        );
+        for address in (RAM_START..RAM_START + 0x100)
+            .chain(0x203F_FF00..0x2040_0000)
+            .step_by(4)
+        {
+            assert_eq!(
+                interpreted.bus.read_word(address),
+                staged.bus.read_word(address),
+                "{} {:#010X}",
+                binary,
+                address
+            );
+        }
+
+        // and the two carry on identically from there
+        interpreted.step();
+        staged.step();
+        assert_eq!(interpreted.reg_file, staged.reg_file, "{}", binary);
+        assert_eq!(
+            interpreted.current_line(),
+            staged.current_line(),
+            "{}",
+            binary
+        );
+    }
+}
+
+#[test]
+fn test_binary_instruction_coverage() {
+    let mut coverage = Coverage::new();
+    for binary in [
+        "binary1.bin",
+        "binary2.bin",
+        "binary3.bin",
+        "binary4.bin",
+        "binary5.bin",
+    ] {
+        let mut rv = RV32ISystem::new();
+        rv.bus.rom.load(load_binary(binary));
+        rv.enable_coverage();
+        for _ in 0..200 {
+            rv.step();
+        }
+        coverage.merge(rv.coverage().unwrap());
+    }
+
+    // run with --nocapture to see which base instructions the binaries never reach
+    for line in coverage.report() {
+        println!("{}", line);
+    }
+    for kind in ["Alu", "Load", "Store", "Branch", "Jal"] {
+        assert!(
+            coverage.count_kind(kind) > 0,
+            "no {} instruction retired",
+            kind
+        );
+    }
+    assert!(
+        coverage.missing_base_instructions().len() < RV32I_MNEMONICS.len(),
+        "{:?}",
+        coverage.report()
+    );
+}
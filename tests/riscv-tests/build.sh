@@ -0,0 +1,18 @@
+#!/bin/sh
+# Builds the rv32ui-p ISA tests run by tests/riscv_tests.rs, writing the ELF files next to this
+# script. Needs a C preprocessor, llvm-mc and ld.lld: `LD_LLD` can point at another lld, such as
+# the `gcc-ld/ld.lld` shipped with rustup toolchains.
+set -eu
+cd "$(dirname "$0")"
+CPP=${CPP:-cpp}
+LLVM_MC=${LLVM_MC:-llvm-mc}
+LD_LLD=${LD_LLD:-ld.lld}
+BUILD=build
+mkdir -p "$BUILD"
+for test in addi add sll lw sw beq; do
+    "$CPP" -x assembler-with-cpp -P -D__riscv_xlen=32 -Ienv/p -Iisa/macros/scalar \
+        "isa/rv32ui/$test.S" -o "$BUILD/$test.s"
+    "$LLVM_MC" -triple=riscv32 -mattr=-relax -filetype=obj "$BUILD/$test.s" -o "$BUILD/$test.o"
+    "$LD_LLD" -static -nostdlib -T env/p/link.ld "$BUILD/$test.o" -o "rv32ui-p-$test"
+done
+rm -rf "$BUILD"
@@ -0,0 +1,101 @@
+//! Runs the `rv32ui-p-*` ISA tests from the riscv-tests project, as prebuilt ELF files under
+//! `tests/riscv-tests/`, see `build.sh` there to rebuild them.
+//!
+//! The tests report through the `tohost` convention: `tohost` is written with 1 on success, or
+//! `(test case << 1) | 1` on failure. The `p` environment does this from its trap vector on ECALL,
+//! which it installs by writing `mtvec`. It probes CSRs this core doesn't have (`satp`, the PMP and
+//! delegation registers) with `mtvec` pointing just past the access, so the illegal instruction
+//! traps skip over them.
+
+use riscv::{
+    RV32ISystem,
+    elf::Elf,
+    system_interface::{MMIODevice, Permissions, RamDevice},
+    test_util::build_elf,
+};
+
+/// Where the riscv-tests are linked
+const TEST_RAM_START: u32 = 0x8000_0000;
+const TEST_RAM_END: u32 = 0x8040_0000;
+/// Instructions to run before giving up on a test that never writes `tohost`
+const MAX_INSTRUCTIONS: usize = 100_000;
+
+fn run_isa_test(bytes: &[u8]) -> Result<(), String> {
+    let elf = Elf::parse(bytes).map_err(|e| e.to_string())?;
+    let tohost = elf
+        .symbol("tohost")
+        .map_err(|e| e.to_string())?
+        .ok_or("no tohost symbol")?;
+
+    let mut rv = RV32ISystem::new();
+    rv.bus
+        .attach(TEST_RAM_START..TEST_RAM_END, Box::new(RamDevice::new()))
+        .unwrap();
+    rv.bus
+        .add_region(TEST_RAM_START..TEST_RAM_END, Permissions::RWX);
+    rv.load_elf(&elf).map_err(|e| e.to_string())?;
+    rv.bus.write_word(tohost, 0).unwrap();
+
+    for _ in 0..MAX_INSTRUCTIONS {
+        rv.step();
+        match rv.bus.read_word(tohost).unwrap() {
+            0 => {}
+            1 => return Ok(()),
+            value => return Err(format!("failed test case {}", value >> 1)),
+        }
+    }
+    Err(format!("no result after {} instructions", MAX_INSTRUCTIONS))
+}
+
+macro_rules! isa_test {
+    ($name:ident, $file:literal) => {
+        #[test]
+        fn $name() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/riscv-tests/", $file);
+            let bytes = std::fs::read(path).unwrap_or_else(|e| panic!("Reading {}: {}", path, e));
+            if let Err(e) = run_isa_test(&bytes) {
+                panic!("{}: {}", $file, e);
+            }
+        }
+    };
+}
+
+isa_test!(rv32ui_addi, "rv32ui-p-addi");
+isa_test!(rv32ui_add, "rv32ui-p-add");
+isa_test!(rv32ui_sll, "rv32ui-p-sll");
+isa_test!(rv32ui_lw, "rv32ui-p-lw");
+isa_test!(rv32ui_sw, "rv32ui-p-sw");
+isa_test!(rv32ui_beq, "rv32ui-p-beq");
+
+/// A stand-in for an ISA test that reports `gp` the same way its trap vector does
+fn reporting_program(gp: u32) -> Vec<u8> {
+    let program = [
+        // addi x3, x0, gp
+        (gp << 20) | 0x0000_0193,
+        // lui x5, 0x80001 (tohost)
+        0x8000_12B7,
+        // sw x3, 0(x5)
+        0x0032_A023,
+        // jal x0, 0
+        0x0000_006F,
+    ];
+    let code: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+    build_elf(
+        TEST_RAM_START,
+        &[(TEST_RAM_START, &code), (TEST_RAM_START + 0x1000, &[0; 8])],
+        &[("tohost", TEST_RAM_START + 0x1000)],
+    )
+}
+
+#[test]
+fn test_harness_reports_results() {
+    assert_eq!(run_isa_test(&reporting_program(1)), Ok(()));
+    assert_eq!(
+        run_isa_test(&reporting_program((3 << 1) | 1)),
+        Err("failed test case 3".to_string())
+    );
+    assert_eq!(
+        run_isa_test(&build_elf(TEST_RAM_START, &[], &[])),
+        Err("no tohost symbol".to_string())
+    );
+}